@@ -0,0 +1,48 @@
+// Keeps a local copy of every added torrent and magnet link, so a transfer can be added again or
+// cross-seeded later without having the client grab it again.
+use crate::state::Source;
+use anyhow::Result;
+use log::{info, warn};
+use std::{
+    fs,
+    path::Path,
+    time::{Duration, SystemTime},
+};
+
+/// Writes the torrent or magnet link to dir as `<hash>.torrent` or `<hash>.magnet`, then prunes
+/// files older than max_age_days. Archiving is best effort, so failures are only logged.
+pub fn store(dir: &str, max_age_days: u64, hash: &str, source: &Source) {
+    let dir = Path::new(dir);
+    let hash = hash.to_lowercase();
+    let result = fs::create_dir_all(dir).and_then(|_| match source {
+        Source::Torrent(bytes) => fs::write(dir.join(format!("{}.torrent", hash)), bytes),
+        Source::Magnet(url) => fs::write(dir.join(format!("{}.magnet", hash)), url),
+    });
+    if let Err(e) = result {
+        warn!("Unable to archive {} in {}: {}", hash, dir.display(), e);
+    }
+    if max_age_days > 0 {
+        if let Err(e) = prune(dir, Duration::from_secs(max_age_days * 24 * 60 * 60)) {
+            warn!("Unable to prune torrent archive {}: {}", dir.display(), e);
+        }
+    }
+}
+
+fn prune(dir: &Path, max_age: Duration) -> Result<()> {
+    let now = SystemTime::now();
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let path = entry.path();
+        let archived = path
+            .extension()
+            .is_some_and(|e| e == "torrent" || e == "magnet");
+        let age = now
+            .duration_since(entry.metadata()?.modified()?)
+            .unwrap_or_default();
+        if archived && age > max_age {
+            fs::remove_file(&path)?;
+            info!("Pruned {} from the torrent archive", path.display());
+        }
+    }
+    Ok(())
+}
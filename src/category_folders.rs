@@ -0,0 +1,192 @@
+// Per-category folders on put.io. With category_folders, transfers are saved into
+// `putioarr/.categories/<category>` rather than the putioarr folder itself, so put.io records which
+// arr or category added them. Polling reads the category back from there, which survives a lost
+// state database and keeps transfers added to put.io by hand from being attributed to an arr.
+// With category_directories, transfers are downloaded into `download_directory/<category>` too.
+use crate::{
+    services::{arr::arr_for, putio::PutIOTransfer},
+    AppData,
+};
+use anyhow::{Context, Result};
+use std::{
+    collections::HashMap,
+    path::{Component, Path},
+    sync::RwLock,
+};
+
+/// Folder in the putioarr folder holding a folder per category.
+const CATEGORIES_FOLDER: &str = ".categories";
+
+/// Known category folders, refreshed from put.io on every poll.
+#[derive(Default)]
+pub struct CategoryFolders {
+    /// Id of CATEGORIES_FOLDER, once it was found or created.
+    parent: RwLock<Option<u64>>,
+    /// Category of each category folder, by folder id.
+    folders: RwLock<HashMap<u64, String>>,
+}
+
+/// Returns the folder new transfers of a category are saved into: its category folder, created
+/// when needed, or the putioarr folder when category_folders is off or there is no category.
+pub async fn folder_for(
+    app_data: &AppData,
+    root_folder_id: u64,
+    category: Option<&str>,
+) -> Result<u64> {
+    let Some(category) = category.filter(|_| app_data.config.category_folders) else {
+        return Ok(root_folder_id);
+    };
+    let known = app_data
+        .category_folders
+        .folders
+        .read()
+        .unwrap()
+        .iter()
+        .find(|(_, c)| c.as_str() == category)
+        .map(|(id, _)| *id);
+    if let Some(id) = known {
+        return Ok(id);
+    }
+
+    let parent = parent_folder(app_data, root_folder_id).await?;
+    let id = find_or_create(app_data, category, parent)
+        .await
+        .with_context(|| {
+            format!(
+                "Unable to create folder for category {} on put.io",
+                category
+            )
+        })?;
+    app_data
+        .category_folders
+        .folders
+        .write()
+        .unwrap()
+        .insert(id, category.to_string());
+    Ok(id)
+}
+
+/// Reloads the category folders from put.io, picking up ones created by another instance or by
+/// hand.
+pub async fn refresh(app_data: &AppData, root_folder_id: u64) -> Result<()> {
+    if !app_data.config.category_folders {
+        return Ok(());
+    }
+    let parent = parent_folder(app_data, root_folder_id).await?;
+    let folders = app_data
+        .putio
+        .list_files(parent)
+        .await?
+        .files
+        .into_iter()
+        .filter(|f| f.file_type == "FOLDER")
+        .map(|f| (f.id, f.name))
+        .collect();
+    *app_data.category_folders.folders.write().unwrap() = folders;
+    Ok(())
+}
+
+/// The category a transfer was saved under, if it is in a category folder.
+pub fn category(app_data: &AppData, transfer: &PutIOTransfer) -> Option<String> {
+    let folders = app_data.category_folders.folders.read().unwrap();
+    folders.get(&transfer.save_parent_id?).cloned()
+}
+
+/// Records the category of a transfer found in a category folder. The folder is what put.io has,
+/// so it wins over the category remembered when the transfer was added.
+pub fn reconcile(app_data: &AppData, transfer: &PutIOTransfer) {
+    let (Some(hash), Some(category)) = (&transfer.hash, category(app_data, transfer)) else {
+        return;
+    };
+    if app_data.state.category(hash).as_deref() != Some(category.as_str()) {
+        app_data.state.set_category(hash, &category);
+    }
+}
+
+/// Whether putioarr added this transfer, i.e. it saves into the putioarr folder or one of the
+/// category folders.
+pub fn owns(app_data: &AppData, root_folder_id: u64, transfer: &PutIOTransfer) -> bool {
+    transfer.is_owned(root_folder_id) || category(app_data, transfer).is_some()
+}
+
+/// Directory a transfer is downloaded into: the subdirectory of its category with
+/// category_directories, or else the download directory. Categories that aren't a plain name, like
+/// ones with a slash, are ignored.
+pub fn download_directory(app_data: &AppData, hash: Option<&str>) -> String {
+    category_directory(app_data, &app_data.config.download_directory, hash)
+}
+
+/// Directory a transfer is reported to be downloaded into, see [`reported_path`].
+pub fn reported_directory(app_data: &AppData, hash: Option<&str>) -> String {
+    reported_path(app_data, hash, &download_directory(app_data, hash))
+}
+
+/// Where the arrs see a path in download_directory, for arrs on another host or in a container
+/// that mounts it elsewhere: below the reported_download_directory of the [[arr]] instance of the
+/// transfer's category, or else the global one.
+pub fn reported_path(app_data: &AppData, hash: Option<&str>, path: &str) -> String {
+    let category = hash.and_then(|hash| app_data.state.category(hash));
+    let reported = arr_for(app_data, category.as_deref())
+        .and_then(|arr| arr.config().reported_download_directory.as_ref())
+        .or(app_data.config.reported_download_directory.as_ref());
+    let relative = Path::new(path).strip_prefix(&app_data.config.download_directory);
+    match (reported, relative) {
+        (Some(reported), Ok(relative)) if relative.as_os_str().is_empty() => reported.clone(),
+        (Some(reported), Ok(relative)) => Path::new(reported)
+            .join(relative)
+            .to_string_lossy()
+            .to_string(),
+        _ => path.to_string(),
+    }
+}
+
+/// Directory a transfer is downloaded into before it is moved into its download directory, with
+/// incomplete_directory. Has the same category subdirectory.
+pub fn incomplete_directory(app_data: &AppData, hash: Option<&str>) -> Option<String> {
+    let directory = app_data.config.incomplete_directory.as_ref()?;
+    Some(category_directory(app_data, directory, hash))
+}
+
+fn category_directory(app_data: &AppData, directory: &str, hash: Option<&str>) -> String {
+    let category = hash
+        .filter(|_| app_data.config.category_directories)
+        .and_then(|hash| app_data.state.category(hash))
+        .filter(|category| is_plain_name(category));
+    match category {
+        Some(category) => Path::new(directory)
+            .join(category)
+            .to_string_lossy()
+            .to_string(),
+        None => directory.to_string(),
+    }
+}
+
+fn is_plain_name(name: &str) -> bool {
+    let mut components = Path::new(name).components();
+    matches!(
+        (components.next(), components.next()),
+        (Some(Component::Normal(_)), None)
+    )
+}
+
+async fn parent_folder(app_data: &AppData, root_folder_id: u64) -> Result<u64> {
+    if let Some(id) = *app_data.category_folders.parent.read().unwrap() {
+        return Ok(id);
+    }
+    let id = find_or_create(app_data, CATEGORIES_FOLDER, root_folder_id)
+        .await
+        .context("Unable to create categories folder on put.io")?;
+    *app_data.category_folders.parent.write().unwrap() = Some(id);
+    Ok(id)
+}
+
+pub(crate) async fn find_or_create(app_data: &AppData, name: &str, parent_id: u64) -> Result<u64> {
+    let files = app_data.putio.list_files(parent_id).await?.files;
+    if let Some(folder) = files
+        .iter()
+        .find(|f| f.name == name && f.file_type == "FOLDER")
+    {
+        return Ok(folder.id);
+    }
+    Ok(app_data.putio.create_folder(name, parent_id).await?.file.id)
+}
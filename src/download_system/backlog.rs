@@ -0,0 +1,113 @@
+// Size of the download system's backlog, exposed on /metrics and checked against the backlog_*
+// thresholds so a stalled pipeline doesn't go unnoticed.
+use super::{download::DownloadTargetMessage, transfer::TransferMessage};
+use crate::{services::notify::notify, AppData};
+use async_channel::{Receiver, Sender};
+use std::{sync::OnceLock, time::Duration};
+use tracing::{info, warn};
+
+/// The channels between the producer and the workers, kept to measure how many messages are
+/// waiting in them and to start workers while running.
+#[derive(Default)]
+pub struct Channels {
+    transfers: OnceLock<(Sender<TransferMessage>, Receiver<TransferMessage>)>,
+    downloads: OnceLock<(
+        Sender<DownloadTargetMessage>,
+        Receiver<DownloadTargetMessage>,
+    )>,
+}
+
+impl Channels {
+    pub fn attach(
+        &self,
+        transfers: (Sender<TransferMessage>, Receiver<TransferMessage>),
+        downloads: (
+            Sender<DownloadTargetMessage>,
+            Receiver<DownloadTargetMessage>,
+        ),
+    ) {
+        let _ = self.transfers.set(transfers);
+        let _ = self.downloads.set(downloads);
+    }
+
+    pub fn transfers(&self) -> Option<&(Sender<TransferMessage>, Receiver<TransferMessage>)> {
+        self.transfers.get()
+    }
+
+    pub fn downloads(
+        &self,
+    ) -> Option<&(
+        Sender<DownloadTargetMessage>,
+        Receiver<DownloadTargetMessage>,
+    )> {
+        self.downloads.get()
+    }
+
+    /// Files and directories waiting for a download worker.
+    pub fn download_backlog(&self) -> usize {
+        self.downloads.get().map_or(0, |(s, _)| s.len())
+    }
+}
+
+pub struct Backlog {
+    /// Transfers waiting in the download queue.
+    pub queued: usize,
+    /// Messages waiting for an orchestration worker.
+    pub orchestration: usize,
+    /// Files and directories waiting for a download worker.
+    pub downloads: usize,
+    /// How long the oldest transfer in the download queue has been waiting.
+    pub oldest: Duration,
+}
+
+impl Backlog {
+    pub fn measure(app_data: &AppData) -> Self {
+        Self {
+            queued: app_data.queue.len(),
+            orchestration: app_data
+                .channels
+                .transfers
+                .get()
+                .map_or(0, |(s, _)| s.len()),
+            downloads: app_data.channels.download_backlog(),
+            oldest: app_data.queue.oldest(),
+        }
+    }
+
+    /// Describes how the backlog exceeds the configured thresholds, if it does.
+    fn excess(&self, app_data: &AppData) -> Option<String> {
+        let max_depth = app_data.config.backlog_max_depth;
+        let max_age = Duration::from_secs(app_data.config.backlog_max_age_minutes * 60);
+        if max_depth > 0 && self.queued > max_depth {
+            Some(format!(
+                "{} transfers are waiting for download, more than backlog_max_depth ({})",
+                self.queued, max_depth
+            ))
+        } else if !max_age.is_zero() && self.oldest > max_age {
+            Some(format!(
+                "A transfer has been waiting for download for {} minutes, more than backlog_max_age_minutes ({})",
+                self.oldest.as_secs() / 60,
+                app_data.config.backlog_max_age_minutes
+            ))
+        } else {
+            None
+        }
+    }
+}
+
+/// Warns and sends a notification when the backlog exceeds the configured thresholds. alerting
+/// keeps track of whether we already did, so this happens once until the backlog recovers.
+pub async fn check(app_data: &AppData, alerting: &mut bool) {
+    match Backlog::measure(app_data).excess(app_data) {
+        Some(message) if !*alerting => {
+            warn!("{}", message);
+            notify(&app_data.config, "backlog", &message).await;
+            *alerting = true;
+        }
+        None if *alerting => {
+            info!("Download backlog is back under its thresholds");
+            *alerting = false;
+        }
+        _ => {}
+    }
+}
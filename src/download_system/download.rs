@@ -1,20 +1,44 @@
 // Module for handling file downloads and directory creation
-use super::transfer::{DownloadTarget, TargetType};
-use crate::AppData;
+use super::{
+    failure::SIZE_MISMATCH,
+    throttle::Throttle,
+    transfer::{DownloadTarget, InStage, StageSpan, TargetType},
+};
+use crate::{
+    instance_lock::while_held,
+    services::notify::notify,
+    utils::{free_space, prepare_created_directory, set_owner},
+    AppData,
+};
 use actix_web::web::Data;
-use anyhow::{bail, Context, Result};
+use anyhow::{bail, Result};
 use async_channel::{Receiver, Sender};
 use colored::*;
-use file_owner::PathExt;
 use futures::StreamExt;
-use log::{error, info};
-use nix::unistd::Uid;
-use std::{fs, path::Path};
+use nix::errno::Errno;
+use std::{fs, path::Path, sync::atomic::Ordering, time::Duration};
+use tokio::io::{AsyncWriteExt, BufWriter};
+use tokio::time::sleep;
+use tracing::{error, info, info_span, warn, Instrument};
+
+/// How many bytes we write between free space checks.
+const DISK_CHECK_INTERVAL: u64 = 64 * 1024 * 1024;
+/// Start of the error message of a download that stopped receiving data.
+const STALLED: &str = "Download stalled";
+/// Start of the error message of a download that lost its connection while paused.
+const PAUSED: &str = "Download paused";
+/// Write buffer used with network_filesystem, so NFS and SMB servers get large sequential writes.
+const NETWORK_WRITE_BUFFER: usize = 8 * 1024 * 1024;
+/// How often an operation that fails with a transient error is retried with network_filesystem.
+const NETWORK_RETRIES: u32 = 5;
+/// Suffix of files being downloaded. Unlike the extension of the file itself, the arrs never take
+/// it for something to import.
+const PARTIAL_SUFFIX: &str = ".putioarr.partial";
 
 /// Worker struct responsible for processing download tasks
 #[derive(Clone)]
 pub struct Worker {
-    _id: usize,
+    id: usize,
     app_data: Data<AppData>,
     drx: Receiver<DownloadTargetMessage>,
 }
@@ -22,25 +46,59 @@ pub struct Worker {
 impl Worker {
     /// Creates and starts a new worker with the given ID and channels
     pub fn start(id: usize, app_data: Data<AppData>, drx: Receiver<DownloadTargetMessage>) {
-        let s = Self {
-            _id: id,
-            app_data,
-            drx,
-        };
+        let s = Self { id, app_data, drx };
 
-        let _join_handle = actix_rt::spawn(async move { s.work().await });
+        let span = info_span!("worker", pool = "download", worker_id = id);
+        actix_rt::spawn(
+            async move {
+                let pool = &s.app_data.download_pool;
+                pool.supervise("Download", s.id, || {
+                    let worker = s.clone();
+                    async move { worker.work().await }
+                })
+                .await
+            }
+            .instrument(span),
+        );
     }
 
     /// Main worker loop that processes download targets
     async fn work(&self) -> Result<()> {
         loop {
-            // Wait for a DownloadTarget
-            let dtm = self.drx.recv().await?;
+            // Wait for a DownloadTarget, checking now and then whether we were scaled down
+            let idle = self.app_data.polling_interval();
+            let dtm = match tokio::time::timeout(idle, self.drx.recv()).await {
+                Ok(Ok(dtm)) => dtm,
+                // The channel closed, nothing left to download
+                Ok(Err(_)) => return Ok(()),
+                Err(_) if self.app_data.download_pool.try_retire() => return Ok(()),
+                Err(_) => continue,
+            };
 
+            // Left over from a transfer that stopped downloading when the lock was lost
+            if dtm.tx.is_closed() {
+                continue;
+            }
             // Download the target
-            let done_status = match download_target(&self.app_data, &dtm.download_target).await {
-                Ok(_) => DownloadDoneStatus::Success(dtm.download_target),
-                Err(_) => DownloadDoneStatus::Failed(dtm.download_target),
+            let span = info_span!(
+                parent: dtm.span.stage(),
+                "target",
+                path = %dtm.download_target.to,
+                worker_id = self.id
+            );
+            let download = download_target(&self.app_data, &dtm.download_target)
+                .instrument(span)
+                .in_stage(dtm.span);
+            let Some(result) = while_held(self.app_data.lock.as_ref(), download).await else {
+                self.app_data.activity.finish_file(&dtm.download_target.to);
+                continue;
+            };
+            let done_status = match result {
+                Ok(_) => {
+                    self.app_data.state.finish_target(&dtm.download_target.to);
+                    DownloadDoneStatus::Success(dtm.download_target)
+                }
+                Err(e) => DownloadDoneStatus::Failed(dtm.download_target, e.to_string()),
             };
             dtm.tx.send(done_status).await?;
         }
@@ -49,13 +107,21 @@ impl Worker {
 
 /// Handles the download of a target, which can be either a directory or file
 async fn download_target(app_data: &Data<AppData>, target: &DownloadTarget) -> Result<()> {
+    // With category_directories, the directory of a category is created with its first download
+    if target.top_level {
+        if let Some(parent) = Path::new(&target.to).parent().filter(|p| !p.exists()) {
+            retry_transient(app_data, || Ok(fs::create_dir_all(parent)?)).await?;
+            retry_transient(app_data, || prepare_created_directory(app_data, parent)).await?;
+        }
+    }
     match target.target_type {
         TargetType::Directory => {
             if !Path::new(&target.to).exists() {
-                fs::create_dir(&target.to)?;
-                if Uid::effective().is_root() {
-                    target.to.clone().set_owner(app_data.config.uid)?;
-                }
+                retry_transient(app_data, || Ok(fs::create_dir(&target.to)?)).await?;
+                retry_transient(app_data, || {
+                    prepare_created_directory(app_data, Path::new(&target.to))
+                })
+                .await?;
                 info!("{}: directory created", &target);
             }
         }
@@ -63,7 +129,9 @@ async fn download_target(app_data: &Data<AppData>, target: &DownloadTarget) -> R
             // Delete file if already exists
             if !Path::new(&target.to).exists() {
                 info!("{}: download {}", &target, "started".yellow());
-                match fetch(target, app_data.config.uid).await {
+                let result = fetch(app_data, target).await;
+                app_data.activity.finish_file(&target.to);
+                match result {
                     Ok(_) => info!("{}: download {}", &target, "succeeded".green()),
                     Err(e) => {
                         error!("{}: download {}: {}", &target, "failed".red(), e);
@@ -78,36 +146,302 @@ async fn download_target(app_data: &Data<AppData>, target: &DownloadTarget) -> R
     Ok(())
 }
 
-/// Downloads a file from a URL to a temporary location and then moves it to the final destination
-async fn fetch(target: &DownloadTarget, uid: u32) -> Result<()> {
-    let tmp_path = format!("{}.downloading", &target.to);
-    let mut tmp_file = tokio::fs::File::create(&tmp_path).await?;
-
-    let url = target.from.clone().context("No URL found")?;
-    let mut byte_stream = reqwest::get(url).await?.bytes_stream();
+/// Downloads a file from a URL to a temporary location and then moves it to the final destination.
+/// A download that stalls is started over with a fresh URL, up to download_stall_retries times.
+async fn fetch(app_data: &Data<AppData>, target: &DownloadTarget) -> Result<()> {
+    wait_for_disk_space(app_data).await?;
+    app_data.pauses.wait(&target.transfer_hash).await;
 
-    while let Some(item) = byte_stream.next().await {
-        tokio::io::copy(&mut item?.as_ref(), &mut tmp_file).await?;
+    let tmp_path = partial_path(&target.to);
+    let mut url = match (&target.from, target.file_id) {
+        (Some(url), _) => url.clone(),
+        (None, Some(file_id)) => app_data.putio.url(file_id).await?,
+        (None, None) => bail!("No URL found"),
+    };
+    let retries = app_data.config.download_stall_retries;
+    let mut stalls = 0;
+    let mut transient_errors = 0;
+    let downloaded = loop {
+        match fetch_to(app_data, &url, &tmp_path, target).await {
+            Ok(downloaded) => break downloaded,
+            // Not the download's fault, so it doesn't count as an attempt
+            Err(e) if e.to_string().starts_with(PAUSED) => {
+                warn!("{}: {}, starting over", target, e);
+                if let Some(file_id) = target.file_id {
+                    url = app_data.putio.url(file_id).await?;
+                }
+            }
+            Err(e) if is_transient(app_data, &e) && transient_errors < NETWORK_RETRIES => {
+                transient_errors += 1;
+                warn!(
+                    "{}: {}, starting over ({} of {})",
+                    target, e, transient_errors, NETWORK_RETRIES
+                );
+                sleep(Duration::from_secs(transient_errors.into())).await;
+            }
+            Err(e) if e.to_string().starts_with(STALLED) && stalls < retries => {
+                stalls += 1;
+                warn!(
+                    "{}: {}, retrying with a fresh URL ({} of {})",
+                    target, e, stalls, retries
+                );
+                if let Some(file_id) = target.file_id {
+                    url = app_data.putio.url(file_id).await?;
+                }
+            }
+            Err(e) => {
+                if e.to_string().starts_with(STALLED) {
+                    app_data
+                        .stalled
+                        .lock()
+                        .unwrap()
+                        .insert(target.transfer_hash.clone());
+                }
+                bail!(e)
+            }
+        }
+    };
+    if let Some(size) = target.size.filter(|size| *size != downloaded) {
+        let _ = fs::remove_file(&tmp_path);
+        bail!(
+            "{}: put.io reports {} bytes, received {}",
+            SIZE_MISMATCH,
+            size,
+            downloaded
+        );
     }
-    if Uid::effective().is_root() {
-        tmp_path.clone().set_owner(uid)?;
+    retry_transient(app_data, || set_owner(app_data, Path::new(&tmp_path))).await?;
+
+    // The temporary file is next to the target, so this stays on the same mount
+    retry_transient(app_data, || Ok(fs::rename(&tmp_path, &target.to)?)).await?;
+    app_data.state.increment("downloaded_bytes", downloaded);
+    // A zip counts as the files extracted from it instead
+    if target.file_id.is_some() {
+        app_data.state.increment("files_added", 1);
     }
 
-    fs::rename(&tmp_path, &target.to)?;
+    Ok(())
+}
 
+/// Where a file is downloaded to before it is moved into place.
+pub fn partial_path(to: &str) -> String {
+    format!("{}{}", to, PARTIAL_SUFFIX)
+}
+
+/// Removes the partial downloads of the targets at paths, and of the files extracted into them,
+/// left behind when putioarr stopped in the middle of a download. Downloads start over, so they are
+/// of no use. Only call this while nothing downloads.
+pub fn remove_partials(paths: &[String]) -> Result<()> {
+    for path in paths {
+        let partial = partial_path(path);
+        if Path::new(&partial).is_file() {
+            fs::remove_file(&partial)?;
+            info!("Removed partial download {}", partial);
+        }
+        if Path::new(path).is_dir() {
+            remove_partials_below(Path::new(path))?;
+        }
+    }
+    Ok(())
+}
+
+fn remove_partials_below(dir: &Path) -> Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let path = entry.path();
+        if entry.file_type()?.is_dir() {
+            remove_partials_below(&path)?;
+        } else if entry
+            .file_name()
+            .to_string_lossy()
+            .ends_with(PARTIAL_SUFFIX)
+        {
+            fs::remove_file(&path)?;
+            info!("Removed partial download {}", path.display());
+        }
+    }
     Ok(())
 }
 
+/// Streams url into path, starting from scratch. Fails when no data arrives for
+/// download_stall_seconds. Stops reading while the transfer of target is paused. Returns the
+/// number of bytes written.
+///
+/// With network_filesystem, writes are buffered into large sequential ones and the file is synced
+/// once at the end, rather than leaving it to the server to flush many small writes.
+async fn fetch_to(
+    app_data: &Data<AppData>,
+    url: &str,
+    path: &str,
+    target: &DownloadTarget,
+) -> Result<u64> {
+    let network = app_data.config.network_filesystem;
+    let file = tokio::fs::File::create(path).await?;
+    let mut tmp_file = match network {
+        true => BufWriter::with_capacity(NETWORK_WRITE_BUFFER, file),
+        false => BufWriter::new(file),
+    };
+    let mut byte_stream = app_data.putio.download(url).await?.bytes_stream();
+    let stall_seconds = app_data.config.download_stall_seconds;
+    // A worker downloads one file at a time, so this limits the worker
+    let throttle = Throttle::new(app_data.config.max_worker_download_rate_kbps, Vec::new());
+
+    app_data.activity.start_file(target);
+
+    let mut since_disk_check = 0;
+    let mut downloaded = 0;
+    loop {
+        // The connection may not survive a long pause
+        let paused = app_data.pauses.wait(&target.transfer_hash).await;
+        let item = if stall_seconds > 0 {
+            let stall_timeout = Duration::from_secs(stall_seconds);
+            match tokio::time::timeout(stall_timeout, byte_stream.next()).await {
+                Ok(item) => item,
+                Err(_) if paused => bail!("{}: connection lost", PAUSED),
+                Err(_) => bail!("{}: no data received for {}s", STALLED, stall_seconds),
+            }
+        } else {
+            byte_stream.next().await
+        };
+        let Some(item) = item else {
+            break;
+        };
+        let item = match item {
+            Ok(item) => item,
+            Err(_) if paused => bail!("{}: connection lost", PAUSED),
+            // The client's idle timeout fired before ours
+            Err(e) if e.is_timeout() => {
+                bail!("{}: no data received for {}s", STALLED, stall_seconds)
+            }
+            Err(e) => bail!(e),
+        };
+        app_data.download_throttle.consume(item.len() as u64).await;
+        throttle.consume(item.len() as u64).await;
+        tmp_file.write_all(&item).await?;
+        downloaded += item.len() as u64;
+        app_data.download_pool.add_downloaded(item.len() as u64);
+        app_data
+            .activity
+            .add_downloaded(&target.to, item.len() as u64);
+        since_disk_check += item.len() as u64;
+        if since_disk_check >= DISK_CHECK_INTERVAL {
+            wait_for_disk_space(app_data).await?;
+            since_disk_check = 0;
+        }
+    }
+    tmp_file.flush().await?;
+    if network {
+        tmp_file.get_ref().sync_all().await?;
+    }
+    Ok(downloaded)
+}
+
+/// Whether an error is one that network filesystems report for transient conditions, like ESTALE
+/// after a server failover or EBUSY while the server still holds a file. Only with
+/// network_filesystem, since locally these point at real problems.
+fn is_transient(app_data: &AppData, e: &anyhow::Error) -> bool {
+    let code = e
+        .downcast_ref::<std::io::Error>()
+        .and_then(|e| e.raw_os_error());
+    app_data.config.network_filesystem
+        && matches!(code, Some(code) if code == Errno::ESTALE as i32 || code == Errno::EBUSY as i32)
+}
+
+/// Runs a filesystem operation, retrying it with a growing delay while it fails with a transient
+/// error.
+async fn retry_transient<T>(app_data: &AppData, mut op: impl FnMut() -> Result<T>) -> Result<T> {
+    let mut attempts = 0;
+    loop {
+        match op() {
+            Err(e) if is_transient(app_data, &e) && attempts < NETWORK_RETRIES => {
+                attempts += 1;
+                warn!("{}, retrying ({} of {})", e, attempts, NETWORK_RETRIES);
+                sleep(Duration::from_secs(attempts.into())).await;
+            }
+            result => return result,
+        }
+    }
+}
+
+/// Blocks while the download directory has less free space than `min_free_space_gb`, so downloads
+/// pause instead of filling up the filesystem.
+pub(crate) async fn wait_for_disk_space(app_data: &Data<AppData>) -> Result<()> {
+    let min_free = app_data.config.min_free_space_gb * 1_073_741_824;
+    loop {
+        let free = free_space(&app_data.config.download_directory)?;
+        if free >= min_free {
+            if app_data.disk_full.swap(false, Ordering::SeqCst) {
+                info!("Download directory has enough free space again, resuming downloads");
+            }
+            return Ok(());
+        }
+
+        if !app_data.disk_full.swap(true, Ordering::SeqCst) {
+            let message = format!(
+                "Download directory is low on space ({:.2} GB free), pausing downloads",
+                free as f64 / 1_073_741_824.0
+            );
+            warn!("{}", message);
+            notify(&app_data.config, "disk_full", &message).await;
+        }
+        sleep(app_data.polling_interval()).await;
+    }
+}
+
+/// Hands targets to the download workers and waits until all of them are done. Directories are
+/// created before any file is fetched, so files never race their parent directory. Returns the
+/// errors of the targets that failed, so none means every target succeeded.
+pub async fn download_all(
+    dtx: &Sender<DownloadTargetMessage>,
+    targets: &[DownloadTarget],
+    span: &StageSpan,
+) -> Result<Vec<String>> {
+    let (directories, files): (Vec<&DownloadTarget>, Vec<&DownloadTarget>) = targets
+        .iter()
+        .partition(|t| t.target_type == TargetType::Directory);
+
+    let mut errors = vec![];
+    for batch in [directories, files] {
+        // Each target gets a channel for the download worker to report back on.
+        let mut done_channels = vec![];
+        for target in batch {
+            let (done_tx, done_rx) = async_channel::bounded(1);
+            dtx.send(DownloadTargetMessage {
+                download_target: target.clone(),
+                tx: done_tx,
+                span: span.clone(),
+            })
+            .await?;
+            done_channels.push(done_rx);
+        }
+
+        // A worker that panicked drops its channel without reporting back.
+        for done_rx in done_channels {
+            match done_rx.recv().await {
+                Ok(DownloadDoneStatus::Success(_)) => {}
+                Ok(DownloadDoneStatus::Failed(target, e)) => {
+                    errors.push(format!("{}: {}", target.to, e))
+                }
+                Err(_) => errors.push(String::from("download worker stopped")),
+            }
+        }
+    }
+    Ok(errors)
+}
+
 /// Message struct containing a download target and a channel for status updates
 #[derive(Debug, Clone)]
 pub struct DownloadTargetMessage {
     pub download_target: DownloadTarget,
     pub tx: Sender<DownloadDoneStatus>,
+    /// Span of the transfer's stage the target is downloaded in.
+    pub span: StageSpan,
 }
 
 /// Enum representing the status of a completed download
 #[derive(Debug, Clone)]
 pub enum DownloadDoneStatus {
     Success(DownloadTarget),
-    Failed(DownloadTarget),
+    /// The target and why it failed.
+    Failed(DownloadTarget, String),
 }
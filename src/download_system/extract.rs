@@ -0,0 +1,164 @@
+// Extracts downloaded RAR and ZIP archives with extract_archives, since many releases come as
+// multi-part RARs the arrs can't import. Files are extracted next to the archive, and the arrs are
+// expected to import those instead of the archives.
+use super::{
+    download::partial_path,
+    transfer::{DownloadTarget, FileKind, TargetType},
+};
+use crate::{utils::set_owner, AppData};
+use actix_web::web::Data;
+use anyhow::{bail, Context, Result};
+use regex::Regex;
+use std::{
+    fs::{self, File},
+    io,
+    path::{Component, Path},
+    sync::OnceLock,
+};
+use tokio::process::Command;
+use tracing::info;
+use zip::ZipArchive;
+
+/// Names of archives and their volumes: .zip, .rar, .part01.rar and .r00.
+fn archive_pattern() -> &'static Regex {
+    static PATTERN: OnceLock<Regex> = OnceLock::new();
+    PATTERN.get_or_init(|| Regex::new(r"(?i)\.(zip|rar|r\d{2,3})$").unwrap())
+}
+
+/// Later volumes of a multi-part RAR, which are extracted along with the first one.
+fn later_volume_pattern() -> &'static Regex {
+    static PATTERN: OnceLock<Regex> = OnceLock::new();
+    PATTERN.get_or_init(|| Regex::new(r"(?i)(\.part0*([2-9]|[1-9]\d+)\.rar|\.r\d{2,3})$").unwrap())
+}
+
+/// Whether the file is an archive or one of its volumes.
+pub fn is_archive(path: &str) -> bool {
+    archive_pattern().is_match(path)
+}
+
+/// Extracts the archives among the downloaded targets next to them. Returns targets for the
+/// extracted files. Files that exist already are left alone, so extracting again is safe.
+pub async fn extract_archives(
+    app_data: &Data<AppData>,
+    targets: &[DownloadTarget],
+) -> Result<Vec<DownloadTarget>> {
+    let mut extracted = vec![];
+    for target in targets.iter().filter(|t| {
+        t.target_type == TargetType::File
+            && is_archive(&t.to)
+            && !later_volume_pattern().is_match(&t.to)
+    }) {
+        let archive = Path::new(&target.to);
+        let dir = archive.parent().context("Archive without a directory")?;
+        let names = if target.to.to_lowercase().ends_with(".zip") {
+            let app_data = app_data.clone();
+            let (archive, dir) = (archive.to_path_buf(), dir.to_path_buf());
+            tokio::task::spawn_blocking(move || extract_zip(&app_data, &archive, &dir)).await??
+        } else {
+            extract_rar(app_data, archive, dir).await?
+        };
+        info!("{}: extracted {} files", target, names.len());
+        extracted.extend(names.into_iter().map(|name| {
+            let to = dir.join(&name);
+            DownloadTarget {
+                from: None,
+                file_id: None,
+                size: fs::metadata(&to).ok().map(|m| m.len()),
+                crc32: None,
+                to: to.to_string_lossy().to_string(),
+                target_type: TargetType::File,
+                top_level: false,
+                transfer_hash: target.transfer_hash.clone(),
+                file_kind: FileKind::of(&name, None),
+            }
+        }));
+    }
+    Ok(extracted)
+}
+
+/// Whether a path from an archive stays inside the directory it is extracted to.
+fn is_enclosed(name: &Path) -> bool {
+    name.components().all(|c| matches!(c, Component::Normal(_)))
+}
+
+/// Extracts a ZIP archive into dir. Returns the paths of the files in it, relative to dir.
+fn extract_zip(app_data: &AppData, archive: &Path, dir: &Path) -> Result<Vec<String>> {
+    let mut zip = ZipArchive::new(File::open(archive)?)?;
+    let mut names = vec![];
+    for i in 0..zip.len() {
+        let mut entry = zip.by_index(i)?;
+        let Some(name) = entry.enclosed_name().filter(|n| is_enclosed(n)) else {
+            bail!(
+                "{} has an entry outside of it: {}",
+                archive.display(),
+                entry.name()
+            );
+        };
+        let to = dir.join(&name);
+        if entry.is_dir() {
+            fs::create_dir_all(&to)?;
+            continue;
+        }
+        names.push(name.to_string_lossy().to_string());
+        if to.exists() {
+            continue;
+        }
+        if let Some(parent) = to.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let tmp_path = partial_path(&to.to_string_lossy());
+        io::copy(&mut entry, &mut File::create(&tmp_path)?)?;
+        set_owner(app_data, Path::new(&tmp_path))?;
+        fs::rename(&tmp_path, &to)?;
+    }
+    Ok(names)
+}
+
+/// Extracts a RAR archive, and the volumes that follow it, into dir with the unrar command, as
+/// there is no Rust implementation of RAR. Returns the paths of the files in it, relative to dir.
+async fn extract_rar(app_data: &AppData, archive: &Path, dir: &Path) -> Result<Vec<String>> {
+    let list = Command::new("unrar")
+        .args(["lb", "-p-"])
+        .arg(archive)
+        .output()
+        .await
+        .context("Unable to run unrar, is it installed?")?;
+    if !list.status.success() {
+        bail!(
+            "Unable to list {}: {}",
+            archive.display(),
+            String::from_utf8_lossy(&list.stderr).trim()
+        );
+    }
+    let names: Vec<String> = String::from_utf8_lossy(&list.stdout)
+        .lines()
+        .map(str::to_string)
+        .collect();
+    if let Some(name) = names.iter().find(|n| !is_enclosed(Path::new(n))) {
+        bail!("{} has an entry outside of it: {}", archive.display(), name);
+    }
+
+    // -o- skips files that exist, -p- fails on passwords instead of asking for one
+    let output = Command::new("unrar")
+        .args(["x", "-o-", "-p-", "-y"])
+        .arg(archive)
+        .arg(format!("{}/", dir.display()))
+        .output()
+        .await?;
+    if !output.status.success() {
+        bail!(
+            "Unable to extract {}: {}",
+            archive.display(),
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    // unrar lists directories too
+    let files: Vec<String> = names
+        .into_iter()
+        .filter(|n| dir.join(n).is_file())
+        .collect();
+    for name in &files {
+        set_owner(app_data, &dir.join(name))?;
+    }
+    Ok(files)
+}
@@ -0,0 +1,79 @@
+// Categories of transfer failures, so notifications and clients can say what went wrong and what
+// to do about it instead of only that something did.
+
+/// Start of the error message of a file whose size doesn't match what put.io reports.
+pub const SIZE_MISMATCH: &str = "Size mismatch";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FailureCategory {
+    /// put.io was unable to fetch the transfer, like a magnet without peers.
+    Putio,
+    /// Downloading files from put.io to the download directory failed.
+    Download,
+    /// A downloaded file doesn't match what put.io has.
+    Verification,
+    /// Removing a finished transfer or its files from put.io failed.
+    Cleanup,
+}
+
+impl FailureCategory {
+    /// Name used in notifications and the state database.
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Putio => "putio",
+            Self::Download => "download",
+            Self::Verification => "verification",
+            Self::Cleanup => "cleanup",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "putio" => Some(Self::Putio),
+            "download" => Some(Self::Download),
+            "verification" => Some(Self::Verification),
+            "cleanup" => Some(Self::Cleanup),
+            _ => None,
+        }
+    }
+
+    pub fn description(self) -> &'static str {
+        match self {
+            Self::Putio => "put.io fetch error",
+            Self::Download => "local download error",
+            Self::Verification => "verification mismatch",
+            Self::Cleanup => "cleanup failure",
+        }
+    }
+
+    /// What the user can do about it.
+    pub fn remediation(self) -> &'static str {
+        match self {
+            Self::Putio => "Check the transfer on put.io, or search for another release",
+            Self::Download => {
+                "Check the download directory's permissions and free space, and the connection to put.io"
+            }
+            Self::Verification => {
+                "Run putioarr verify on the transfer, or remove it and download it again"
+            }
+            Self::Cleanup => "Remove the transfer and its files on put.io by hand",
+        }
+    }
+
+    /// Categorizes the error of a failed download attempt.
+    pub fn classify(error: &str) -> Self {
+        match error.contains(SIZE_MISMATCH) {
+            true => Self::Verification,
+            false => Self::Download,
+        }
+    }
+}
+
+/// Sums up the errors download_all returned for the targets that failed.
+pub fn summarize(errors: &[String]) -> String {
+    match errors {
+        [] => String::from("no targets failed"),
+        [error] => error.clone(),
+        [error, rest @ ..] => format!("{} (and {} more failed targets)", error, rest.len()),
+    }
+}
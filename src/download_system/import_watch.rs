@@ -0,0 +1,109 @@
+// Tells that a transfer was imported without asking an arr instance for its history: from the
+// imports the arrs report through their webhook, or, with detect_imports_on_disk, from what
+// happened to its files. The arrs move the files out of the download directory or hardlink them
+// into their library. Copies look like files that were left alone, so those aren't detected.
+use super::transfer::DownloadTarget;
+use anyhow::Result;
+use notify::{RecommendedWatcher, RecursiveMode, Watcher};
+use std::{
+    collections::{HashMap, HashSet},
+    fs,
+    os::unix::fs::MetadataExt,
+    path::Path,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::sync::{mpsc, Notify};
+
+/// Imports the arrs reported through their webhook, of the transfers waiting to be imported.
+#[derive(Default)]
+pub struct ImportReports {
+    /// By lowercase hash of the download.
+    waiting: Mutex<HashMap<String, Reported>>,
+}
+
+#[derive(Default)]
+struct Reported {
+    /// Paths of the imported files, as the arr sees them.
+    paths: HashSet<String>,
+    /// Woken by every report, also those without a path.
+    notify: Arc<Notify>,
+}
+
+impl ImportReports {
+    /// Starts taking reports for a download. Returns what is woken by them.
+    pub fn watch(&self, hash: &str) -> Arc<Notify> {
+        let mut waiting = self.waiting.lock().unwrap();
+        waiting
+            .entry(hash.to_lowercase())
+            .or_default()
+            .notify
+            .clone()
+    }
+
+    /// Records an import. Returns whether the download was waited on.
+    pub fn report(&self, hash: &str, path: Option<String>) -> bool {
+        let mut waiting = self.waiting.lock().unwrap();
+        let Some(reported) = waiting.get_mut(&hash.to_lowercase()) else {
+            return false;
+        };
+        reported.paths.extend(path);
+        reported.notify.notify_one();
+        true
+    }
+
+    /// Whether every file among paths, as the arr sees them, was reported imported.
+    pub fn imported(&self, hash: &str, paths: &[String]) -> bool {
+        let waiting = self.waiting.lock().unwrap();
+        waiting
+            .get(&hash.to_lowercase())
+            .is_some_and(|reported| paths.iter().all(|path| reported.paths.contains(path)))
+    }
+
+    /// Stops taking reports for a download.
+    pub fn forget(&self, hash: &str) {
+        self.waiting.lock().unwrap().remove(&hash.to_lowercase());
+    }
+}
+
+/// Whether every file among targets was moved away or hardlinked elsewhere.
+pub fn taken(targets: &[&DownloadTarget]) -> bool {
+    targets.iter().all(|t| match fs::metadata(&t.to) {
+        Ok(metadata) => metadata.nlink() > 1,
+        Err(_) => true,
+    })
+}
+
+/// Watches the directories of files for changes, like them being moved or hardlinked.
+pub struct FileWatch {
+    /// Stops watching when dropped.
+    _watcher: RecommendedWatcher,
+    changes: mpsc::UnboundedReceiver<()>,
+}
+
+impl FileWatch {
+    pub fn new(targets: &[&DownloadTarget]) -> Result<Self> {
+        let (tx, changes) = mpsc::unbounded_channel();
+        let mut watcher = notify::recommended_watcher(move |_| {
+            let _ = tx.send(());
+        })?;
+        let directories: HashSet<&Path> = targets
+            .iter()
+            .filter_map(|t| Path::new(&t.to).parent())
+            .collect();
+        for directory in directories {
+            watcher.watch(directory, RecursiveMode::NonRecursive)?;
+        }
+        Ok(Self {
+            _watcher: watcher,
+            changes,
+        })
+    }
+
+    /// Waits until something changed, or the timeout passed.
+    pub async fn changed(&mut self, timeout: Duration) {
+        let _ = tokio::time::timeout(timeout, self.changes.recv()).await;
+        // A move or an import of several files comes with a burst of events
+        while self.changes.try_recv().is_ok() {}
+    }
+}
@@ -0,0 +1,80 @@
+// With incomplete_directory, transfers are downloaded there and only moved into download_directory
+// once all their files are downloaded and extracted, so the arrs never come across a transfer that
+// is still being downloaded. Both directories have to be on the same filesystem, since the move is
+// a rename.
+use super::transfer::{DownloadTarget, TargetType};
+use crate::{category_folders, utils::prepare_created_directory, AppData};
+use anyhow::{Context, Result};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+/// Moves the downloaded targets of a transfer from incomplete_directory into download_directory.
+/// Returns the targets with their new paths.
+pub fn move_into_place(
+    app_data: &AppData,
+    hash: &str,
+    targets: &[DownloadTarget],
+) -> Result<Vec<DownloadTarget>> {
+    let (Some(from), to) = (
+        category_folders::incomplete_directory(app_data, Some(hash)),
+        category_folders::download_directory(app_data, Some(hash)),
+    ) else {
+        return Ok(targets.to_vec());
+    };
+    let destination = |path: &str| -> Result<PathBuf> {
+        let relative = Path::new(path)
+            .strip_prefix(&from)
+            .with_context(|| format!("{} is not in {}", path, from))?;
+        Ok(Path::new(&to).join(relative))
+    };
+    if !Path::new(&to).exists() {
+        fs::create_dir_all(&to)?;
+        prepare_created_directory(app_data, Path::new(&to))?;
+    }
+
+    // A directory moves in one go, unless an earlier attempt left one in its place already
+    let mut moved = Vec::new();
+    for target in targets
+        .iter()
+        .filter(|t| t.top_level && t.target_type == TargetType::Directory)
+    {
+        let destination = destination(&target.to)?;
+        if !destination.exists() {
+            fs::rename(&target.to, &destination)
+                .with_context(|| format!("Unable to move {}", target.to))?;
+            moved.push(Path::new(&target.to));
+        }
+    }
+    // Then the files that weren't moved along, like those extracted next to an archive
+    for target in targets.iter().filter(|t| {
+        t.target_type == TargetType::File
+            && !moved.iter().any(|dir| Path::new(&t.to).starts_with(dir))
+    }) {
+        let destination = destination(&target.to)?;
+        if let Some(parent) = destination.parent().filter(|p| !p.exists()) {
+            fs::create_dir_all(parent)?;
+            prepare_created_directory(app_data, parent)?;
+        }
+        fs::rename(&target.to, &destination)
+            .with_context(|| format!("Unable to move {}", target.to))?;
+    }
+    for target in targets
+        .iter()
+        .filter(|t| t.top_level && t.target_type == TargetType::Directory)
+        .filter(|t| Path::new(&t.to).exists())
+    {
+        fs::remove_dir_all(&target.to)?;
+    }
+
+    targets
+        .iter()
+        .map(|target| {
+            Ok(DownloadTarget {
+                to: destination(&target.to)?.to_string_lossy().to_string(),
+                ..target.clone()
+            })
+        })
+        .collect()
+}
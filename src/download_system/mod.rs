@@ -3,10 +3,27 @@
 use crate::AppData;
 use actix_web::web::Data;
 use anyhow::Result;
+use tracing::warn;
 
+pub mod backlog;
 pub mod download;
+pub mod extract;
+pub mod failure;
+pub mod import_watch;
+pub mod incomplete;
+pub mod once;
 pub mod orchestration;
+pub mod pause;
+pub mod permissions;
+pub mod queue;
+pub mod scaling;
+pub mod space;
+pub mod speedtest;
+pub mod sync;
+pub mod throttle;
 pub mod transfer;
+pub mod verify;
+pub mod zip_download;
 
 /// Starts the download system by initializing workers and communication channels.
 ///
@@ -18,23 +35,74 @@ pub mod transfer;
 pub async fn start(app_data: Data<AppData>) -> Result<()> {
     let (sender, receiver) = async_channel::unbounded();
     let (download_sender, download_receiver) = async_channel::unbounded();
+    app_data.channels.attach(
+        (sender.clone(), receiver),
+        (download_sender, download_receiver),
+    );
     let data = app_data.clone();
-    let tx = sender.clone();
-    actix_rt::spawn(async { transfer::produce_transfers(data, tx).await });
-
-    for id in 0..app_data.config.orchestration_workers {
+    actix_rt::spawn(async { transfer::produce_transfers(data, sender).await });
+    if app_data.lock.is_some() {
         let data = app_data.clone();
-        let tx = sender.clone();
-        let rx = receiver.clone();
-        let dtx = download_sender.clone();
-        orchestration::Worker::start(id, data, tx, rx, dtx);
+        actix_rt::spawn(async move { keep_lock(&data).await });
     }
 
-    for id in 0..app_data.config.download_workers {
-        let drx = download_receiver.clone();
-        let data = app_data.clone();
-        download::Worker::start(id, data, drx)
+    set_orchestration_workers(&app_data, app_data.config.orchestration_workers);
+    if app_data.config.download_workers_max.is_some() {
+        actix_rt::spawn(scaling::autoscale(app_data.clone()));
+    } else {
+        set_download_workers(&app_data, app_data.config.download_workers);
     }
 
     Ok(())
 }
+
+/// Keeps the lock on the putioarr folder, or takes it over while standing by. Never returns.
+pub(crate) async fn keep_lock(app_data: &AppData) {
+    let Some(lock) = &app_data.lock else {
+        return std::future::pending().await;
+    };
+    let root_folder_id = *app_data.root_folder_id.read().unwrap();
+    lock.keep(
+        app_data.putio.as_ref(),
+        root_folder_id,
+        app_data.polling_interval(),
+    )
+    .await
+}
+
+/// Removes the partial downloads of an earlier run, before any download starts. Since instances
+/// may share the state database, only call this while holding the lock.
+pub(crate) fn remove_partials(app_data: &AppData) {
+    if let Err(e) = download::remove_partials(&app_data.state.download_target_paths()) {
+        warn!("Unable to remove partial downloads: {:#}", e);
+    }
+}
+
+/// Changes the number of orchestration workers. Extra workers retire once they are idle.
+pub fn set_orchestration_workers(app_data: &Data<AppData>, count: usize) {
+    let pool = &app_data.orchestration_pool;
+    pool.set_target(count);
+    let Some((tx, rx)) = app_data.channels.transfers() else {
+        return;
+    };
+    let Some((dtx, _)) = app_data.channels.downloads() else {
+        return;
+    };
+    while pool.active() < count {
+        let id = pool.register();
+        orchestration::Worker::start(id, app_data.clone(), tx.clone(), rx.clone(), dtx.clone());
+    }
+}
+
+/// Changes the number of download workers. Extra workers retire once they are idle.
+pub fn set_download_workers(app_data: &Data<AppData>, count: usize) {
+    let pool = &app_data.download_pool;
+    pool.set_target(count);
+    let Some((_, drx)) = app_data.channels.downloads() else {
+        return;
+    };
+    while pool.active() < count {
+        let id = pool.register();
+        download::Worker::start(id, app_data.clone(), drx.clone());
+    }
+}
@@ -0,0 +1,198 @@
+// A single pass of the pipeline for cron or systemd timers: download what put.io has finished
+// and clean up what is done seeding, then exit.
+use super::{
+    download::{self, DownloadTargetMessage},
+    extract::extract_archives,
+    failure::{summarize, FailureCategory},
+    incomplete::move_into_place,
+    orchestration::{record_download, record_moved, remove_from_putio, seed_limit_reached},
+    permissions,
+    transfer::{assign_local_names, forget_vanished, InStage, StageSpan, Transfer},
+    zip_download::download_targets,
+};
+use crate::{
+    category_folders,
+    report::{report, Outcome},
+    services::putio::PutIOTransferStatus,
+    state::Stage,
+    AppData,
+};
+use actix_web::web::Data;
+use anyhow::{bail, Result};
+use async_channel::Sender;
+use chrono::Utc;
+use colored::Colorize;
+use tracing::{info, warn};
+
+/// Downloads every finished transfer in the putioarr folder. Transfers that are done seeding are
+/// removed from put.io afterwards, the others are left for a later pass. Files that were
+/// downloaded in an earlier pass are skipped, so passes can be repeated safely.
+pub async fn run(app_data: Data<AppData>) -> Result<()> {
+    let root_folder_id = *app_data.root_folder_id.read().unwrap();
+    if let Some(lock) = &app_data.lock {
+        lock.acquire(app_data.putio.as_ref(), root_folder_id)
+            .await?;
+    }
+    let result = tokio::select! {
+        result = process(&app_data, root_folder_id) => result,
+        _ = super::keep_lock(&app_data) => unreachable!("the lock is kept until we are done"),
+    };
+    if let Some(lock) = &app_data.lock {
+        lock.release(app_data.putio.as_ref()).await;
+    }
+    result
+}
+
+async fn process(app_data: &Data<AppData>, root_folder_id: u64) -> Result<()> {
+    super::remove_partials(app_data);
+    let (dtx, drx) = async_channel::unbounded();
+    for id in 0..app_data.config.download_workers {
+        download::Worker::start(id, app_data.clone(), drx.clone());
+    }
+
+    category_folders::refresh(app_data, root_folder_id).await?;
+    let transfers = app_data.putio.list_transfers().await?.transfers;
+    forget_vanished(app_data, &transfers);
+    let transfers: Vec<_> = transfers
+        .into_iter()
+        .filter(|t| {
+            category_folders::owns(app_data, root_folder_id, t)
+                && !t.is_stream()
+                && t.is_downloadable()
+        })
+        .collect();
+    transfers
+        .iter()
+        .for_each(|t| category_folders::reconcile(app_data, t));
+    info!("Found {} finished transfers", transfers.len());
+    let names = assign_local_names(app_data, &transfers);
+
+    let mut failed = 0;
+    for putio_transfer in &transfers {
+        let mut transfer = Transfer {
+            local_name: names[&putio_transfer.id].clone(),
+            ..Transfer::from(app_data.clone(), putio_transfer)
+        };
+        let span = transfer.span("download");
+        let mut seeding = putio_transfer.status == PutIOTransferStatus::Seeding;
+        if let Some(reason) = seed_limit_reached(app_data, putio_transfer).filter(|_| seeding) {
+            match app_data.putio.stop_seeding(putio_transfer.id).await {
+                Ok(_) => {
+                    info!("{}: {}, stopped seeding", transfer, reason);
+                    seeding = false;
+                }
+                Err(e) => warn!("{}: unable to stop seeding: {}", transfer, e),
+            }
+        }
+        if !process_transfer(app_data, &dtx, &mut transfer, seeding, &span)
+            .in_stage(span.clone())
+            .await
+        {
+            failed += 1;
+        }
+    }
+
+    if failed > 0 {
+        bail!("{} transfers failed to download", failed);
+    }
+    Ok(())
+}
+
+/// Downloads a transfer and removes it from put.io unless it is still seeding. Returns whether
+/// the download succeeded.
+async fn process_transfer(
+    app_data: &Data<AppData>,
+    dtx: &Sender<DownloadTargetMessage>,
+    transfer: &mut Transfer,
+    seeding: bool,
+    span: &StageSpan,
+) -> bool {
+    if app_data.state.stage(&transfer.key()) == Some(Stage::Kept) {
+        info!("{}: done, kept on put.io", transfer);
+        return true;
+    }
+    // Downloaded by an earlier run, the arrs may have moved the files already
+    if app_data.state.stage(&transfer.key()) == Some(Stage::Downloaded) {
+        if seeding {
+            info!("{}: downloaded before, still seeding", transfer);
+        } else {
+            info!("{}: downloaded before, done seeding", transfer);
+            remove_from_putio(app_data, transfer).await;
+            report(app_data, transfer, Outcome::Cleaned, None).await;
+        }
+        return true;
+    }
+
+    transfer.lifecycle.download_started_at = Some(Utc::now());
+    let downloaded = match transfer.get_download_targets().await {
+        Ok(mut targets) => {
+            // Left for a later pass, when there may be space for it
+            if let Ok(false) = app_data.disk_space.claim(app_data, transfer, &targets) {
+                return true;
+            }
+            record_download(app_data, &transfer.key(), &targets);
+            let mut downloaded = download_targets(app_data, dtx, &targets, span).await;
+            if app_data.config.extract_archives
+                && downloaded.as_ref().is_ok_and(|errors| errors.is_empty())
+            {
+                match extract_archives(app_data, &targets).await {
+                    Ok(extracted) => targets.extend(extracted),
+                    Err(e) => downloaded = Err(e),
+                }
+            }
+            if app_data.config.incomplete_directory.is_some()
+                && downloaded.as_ref().is_ok_and(|errors| errors.is_empty())
+            {
+                let hash = transfer.hash.as_deref().unwrap_or("0000");
+                match move_into_place(app_data, hash, &targets) {
+                    Ok(moved) => {
+                        targets = moved;
+                        record_moved(app_data, &transfer.key(), &targets);
+                    }
+                    Err(e) => downloaded = Err(e),
+                }
+            }
+            if downloaded.as_ref().is_ok_and(|errors| errors.is_empty()) {
+                if let Err(e) = permissions::apply(app_data, &targets) {
+                    downloaded = Err(e);
+                }
+            }
+            app_data.disk_space.release(&transfer.key());
+            transfer.targets = Some(targets);
+            downloaded
+        }
+        Err(e) => Err(e),
+    };
+    match downloaded {
+        Ok(errors) if errors.is_empty() && seeding => {
+            info!("{}: downloaded, still seeding", transfer);
+            app_data.state.finish_download(&transfer.key());
+            true
+        }
+        Ok(errors) if errors.is_empty() => {
+            info!("{}: download {}", transfer, "done".blue());
+            app_data.state.increment("transfers_completed", 1);
+            transfer.lifecycle.downloaded_at = Some(Utc::now());
+            transfer.lifecycle.attempts = 1;
+            remove_from_putio(app_data, transfer).await;
+            report(app_data, transfer, Outcome::Cleaned, None).await;
+            true
+        }
+        Ok(errors) => {
+            let category = FailureCategory::classify(&errors.join("\n"));
+            warn!(
+                "{}: download failed, {}: {}",
+                transfer,
+                category.description(),
+                summarize(&errors)
+            );
+            app_data.state.increment("failures", 1);
+            false
+        }
+        Err(e) => {
+            warn!("{}: download failed: {:#}", transfer, e);
+            app_data.state.increment("failures", 1);
+            false
+        }
+    }
+}
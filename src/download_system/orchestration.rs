@@ -3,27 +3,44 @@
 // and handling the lifecycle of transfers from download to seeding.
 
 use crate::{
+    category_folders,
     download_system::{
-        download::{DownloadDoneStatus, DownloadTargetMessage},
-        transfer::Transfer,
+        download::{wait_for_disk_space, DownloadTargetMessage},
+        extract::extract_archives,
+        failure::{summarize, FailureCategory},
+        import_watch::{taken, FileWatch},
+        incomplete::move_into_place,
+        pause, permissions,
+        transfer::{DownloadTarget, FileKind, InStage, StageSpan, TargetType, Transfer},
+        zip_download::download_targets,
     },
-    services::putio::{self, PutIOTransferStatus},
-    AppData,
+    instance_lock::while_held,
+    provenance, putio_trash,
+    report::{report, Outcome},
+    services::{
+        arr::{arr_for, check_imported},
+        notify::notify_failure,
+        opensubtitles::fetch_subtitles,
+        putio::{PutIOTransfer, PutIOTransferStatus},
+    },
+    status::Phase,
+    views, AppData, CleanupMode,
 };
 use actix_web::web::Data;
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use async_channel::{Receiver, Sender};
+use chrono::Utc;
 use colored::*;
-use log::{info, warn};
-use std::{fs, time::Duration};
-use tokio::{fs::metadata, time::sleep};
+use std::{fs, io::ErrorKind, path::Path};
+use tokio::time::sleep;
+use tracing::{error, info, info_span, warn, Instrument};
 
 use super::transfer::TransferMessage;
 
 /// Worker structure responsible for handling download and transfer operations
 #[derive(Clone)]
 pub struct Worker {
-    _id: usize,
+    id: usize,
     app_data: Data<AppData>,
     tx: Sender<TransferMessage>,
     rx: Receiver<TransferMessage>,
@@ -40,104 +57,547 @@ impl Worker {
         dtx: Sender<DownloadTargetMessage>,
     ) {
         let s = Self {
-            _id: id,
+            id,
             app_data,
             tx,
             rx,
             dtx,
         };
-        let _join_handle = actix_rt::spawn(async move { s.work().await });
+        let span = info_span!("worker", pool = "orchestration", worker_id = id);
+        actix_rt::spawn(
+            async move {
+                let pool = &s.app_data.orchestration_pool;
+                pool.supervise("Orchestration", s.id, || {
+                    let worker = s.clone();
+                    async move { worker.work().await }
+                })
+                .await
+            }
+            .instrument(span),
+        );
     }
 
     /// Main worker loop that processes incoming transfer messages
     async fn work(&self) -> Result<()> {
         loop {
-            let msg = self.rx.recv().await?;
+            // Wait for a message, checking now and then whether we were scaled down
+            let idle = self.app_data.polling_interval();
+            let msg = match tokio::time::timeout(idle, self.rx.recv()).await {
+                Ok(Ok(msg)) => msg,
+                Ok(Err(_)) => return Ok(()),
+                Err(_) if self.app_data.orchestration_pool.try_retire() => return Ok(()),
+                Err(_) => continue,
+            };
             let app_data = self.app_data.clone();
             match msg {
                 // Handle downloads that are queued
-                TransferMessage::QueuedForDownload(t) => {
-                    info!("{}: transfer {}", t, "started".yellow());
-                    let targets = t.get_download_targets().await?;
-                    // Create a communications channel for the download worker to communicate status back.
-                    let done_channels: &Vec<(
-                        Sender<DownloadDoneStatus>,
-                        Receiver<DownloadDoneStatus>,
-                    )> = &targets.iter().map(|_| async_channel::unbounded()).collect();
-
-                    // Send download targets to workers
-                    for (i, target) in targets.iter().enumerate() {
-                        let (done_tx, _) = done_channels[i].clone();
-                        self.dtx
-                            .send(DownloadTargetMessage {
-                                download_target: target.clone(),
-                                tx: done_tx,
-                            })
-                            .await?;
+                TransferMessage::QueuedForDownload => {
+                    // No new transfers while the download directory is low on space
+                    if let Err(e) = wait_for_disk_space(&self.app_data).await {
+                        warn!("Unable to check free space: {:#}", e);
                     }
-
-                    // Wait for all the workers having sent back their status.
-                    let mut all_downloaded = vec![];
-                    for (_, done_rx) in done_channels {
-                        all_downloaded.push(done_rx.recv().await?);
-                    }
-
-                    // Check if all downloads were successful
-                    if all_downloaded.iter().all(|d| match d {
-                        DownloadDoneStatus::Success(_) => true,
-                        DownloadDoneStatus::Failed(_) => false,
-                    }) {
-                        info!("{}: download {}", t, "done".blue());
-                        self.tx
-                            .send(TransferMessage::Downloaded(Transfer {
-                                targets: Some(targets),
-                                ..t
-                            }))
-                            .await?;
-                    } else {
-                        // TODO: figure out what to do here..
-                        warn!("{}: not all targets downloaded", t)
+                    // Every message matches one queued transfer, but not necessarily the one
+                    // that was queued with it, since clients may have reordered the queue.
+                    let Some(t) = self.app_data.queue.pop() else {
+                        continue;
+                    };
+                    let Some(t) = self.app_data.pauses.hold(t) else {
+                        continue;
+                    };
+                    let key = t.key();
+                    let span = t.span("download");
+                    let download = self.download(t, &span).in_stage(span.clone());
+                    let result = while_held(self.app_data.lock.as_ref(), download).await;
+                    self.app_data.disk_space.release(&key);
+                    match result {
+                        Some(result) => result?,
+                        None => {
+                            info!("Lost the lock, stopped downloading {}", key);
+                            self.app_data.activity.clear_phase(&key);
+                        }
                     }
                 }
                 // Handle completed downloads
                 TransferMessage::Downloaded(t) => {
-                    let tx = self.tx.clone();
-                    actix_rt::spawn(async { watch_seeding(app_data, t).await });
+                    actix_rt::spawn(async move {
+                        let key = t.key();
+                        let watch = async {
+                            let import_span = t.span("import");
+                            let imported = watch_for_import(&app_data, &t)
+                                .in_stage(import_span.clone())
+                                .await;
+                            if imported && app_data.config.cleanup_mode == CleanupMode::Delete {
+                                remove_local_files(&t).in_stage(import_span).await;
+                            }
+                            let span = t.span("seeding");
+                            watch_seeding(app_data.clone(), *t, imported)
+                                .in_stage(span)
+                                .await
+                        };
+                        if while_held(app_data.lock.as_ref(), watch).await.is_none() {
+                            info!("Lost the lock, stopped watching {}", key);
+                            app_data.activity.clear_phase(&key);
+                        }
+                    });
                 }
             }
         }
     }
+
+    /// Downloads all targets of a transfer and hands it over to seeding once they succeeded.
+    async fn download(&self, mut t: Transfer, span: &StageSpan) -> Result<()> {
+        // Generated already when it waited for space
+        let mut targets = match t.targets.take() {
+            Some(targets) => targets,
+            None => match t.get_download_targets().await {
+                Ok(targets) => targets,
+                Err(e) => return self.failed(t, FailureCategory::Download, e).await,
+            },
+        };
+        match self.app_data.disk_space.claim(&self.app_data, &t, &targets) {
+            Ok(true) => {}
+            Ok(false) => {
+                t.targets = Some(targets);
+                return self.requeue(t).await;
+            }
+            Err(e) => warn!("{}: unable to check free space: {:#}", t, e),
+        }
+        info!("{}: transfer {}", t, "started".yellow());
+        t.lifecycle.download_started_at = Some(Utc::now());
+        record_download(&self.app_data, &t.key(), &targets);
+        let errors = download_targets(&self.app_data, &self.dtx, &targets, span).await?;
+        if !errors.is_empty() {
+            let category = FailureCategory::classify(&errors.join("\n"));
+            return self.failed(t, category, anyhow!(summarize(&errors))).await;
+        }
+        if self.app_data.config.extract_archives {
+            match extract_archives(&self.app_data, &targets).await {
+                Ok(extracted) if !extracted.is_empty() => {
+                    targets.extend(extracted);
+                    record_download(&self.app_data, &t.key(), &targets);
+                }
+                Ok(_) => {}
+                Err(e) => return self.failed(t, FailureCategory::Download, e).await,
+            }
+        }
+        if self.app_data.config.incomplete_directory.is_some() {
+            let hash = t.hash.as_deref().unwrap_or("0000");
+            match move_into_place(&self.app_data, hash, &targets) {
+                Ok(moved) => {
+                    info!("{}: moved into the download directory", t);
+                    targets = moved;
+                    record_moved(&self.app_data, &t.key(), &targets);
+                }
+                Err(e) => return self.failed(t, FailureCategory::Download, e).await,
+            }
+        }
+        if let Err(e) = permissions::apply(&self.app_data, &targets) {
+            return self.failed(t, FailureCategory::Download, e).await;
+        }
+        info!("{}: download {}", t, "done".blue());
+        fetch_subtitles(&self.app_data, &targets).await;
+        if self.app_data.config.provenance {
+            provenance::record(&self.app_data, &t, &targets);
+        }
+        if let Some(dir) = &self.app_data.config.symlink_views {
+            let category = t
+                .hash
+                .as_deref()
+                .and_then(|h| self.app_data.state.category(h));
+            for target in targets.iter().filter(|t| t.top_level) {
+                views::link(dir, category.as_deref(), Path::new(&target.to));
+            }
+        }
+        self.app_data.state.increment("transfers_completed", 1);
+        t.lifecycle.downloaded_at = Some(Utc::now());
+        t.lifecycle.attempts = self
+            .app_data
+            .state
+            .failure(&t.key())
+            .map_or(0, |f| f.attempts)
+            + 1;
+        self.app_data.state.clear_failures(&t.key());
+        self.app_data.state.finish_download(&t.key());
+        if let Some(hash) = &t.hash {
+            self.app_data.stalled.lock().unwrap().remove(hash);
+        }
+        self.tx
+            .send(TransferMessage::Downloaded(Box::new(Transfer {
+                targets: Some(targets),
+                ..t
+            })))
+            .await?;
+        Ok(())
+    }
+
+    /// Records a failed attempt. The transfer is queued again after a polling interval until it
+    /// runs out of attempts, after which it is marked as failed and left alone.
+    async fn failed(
+        &self,
+        mut t: Transfer,
+        category: FailureCategory,
+        e: anyhow::Error,
+    ) -> Result<()> {
+        let state = &self.app_data.state;
+        state.increment("failures", 1);
+        let max_attempts = self.app_data.config.max_attempts;
+        // Counted on the transfer when the database fails, so it is retried or given up on all the
+        // same
+        let attempts = state
+            .record_failure(&t.key(), category.name(), &e.to_string())
+            .unwrap_or_else(|db_error| {
+                warn!("{}: unable to record failure: {}", t, db_error);
+                t.lifecycle.attempts + 1
+            });
+        t.lifecycle.attempts = attempts;
+        if attempts >= max_attempts {
+            state.mark_failed(&t.key());
+            state.forget_transfer(&t.key());
+            let message = format!(
+                "{}: giving up after {} attempts, {}: {}",
+                t.name,
+                attempts,
+                category.description(),
+                e
+            );
+            error!(
+                "{}: giving up after {} attempts, {}: {}",
+                t,
+                attempts,
+                category.description(),
+                e
+            );
+            notify_failure(&self.app_data.config, category, &message).await;
+            report(&self.app_data, &t, Outcome::Failed, Some(&e.to_string())).await;
+            return Ok(());
+        }
+        warn!(
+            "{}: attempt {} of {} failed, retrying, {}: {}",
+            t,
+            attempts,
+            max_attempts,
+            category.description(),
+            e
+        );
+        self.requeue(t).await
+    }
+
+    /// Queues a transfer again after a polling interval.
+    async fn requeue(&self, t: Transfer) -> Result<()> {
+        let app_data = self.app_data.clone();
+        let tx = self.tx.clone();
+        actix_rt::spawn(async move {
+            let lock = app_data.lock.as_ref();
+            while_held(lock, sleep(app_data.polling_interval())).await?;
+            app_data.queue.push(t);
+            tx.send(TransferMessage::QueuedForDownload).await.ok()
+        });
+        Ok(())
+    }
 }
 
-/// Monitors a transfer's seeding status and handles cleanup
-async fn watch_seeding(app_data: Data<AppData>, transfer: Transfer) -> Result<()> {
-    info!("{}: watching seeding", transfer);
+/// Records the targets of a transfer that starts downloading, so a restart can tell what it was
+/// doing.
+pub(crate) fn record_download(app_data: &AppData, key: &str, targets: &[DownloadTarget]) {
+    let targets: Vec<(String, String)> = targets
+        .iter()
+        .filter_map(|t| Some((t.to.clone(), serde_json::to_string(t).ok()?)))
+        .collect();
+    app_data.state.start_download(key, &targets);
+}
+
+/// Records the targets of a transfer that was moved out of incomplete_directory in place of the
+/// ones it was downloaded to.
+pub(crate) fn record_moved(app_data: &AppData, key: &str, targets: &[DownloadTarget]) {
+    app_data.state.forget_transfer(key);
+    record_download(app_data, key, targets);
+    for target in targets {
+        app_data.state.finish_target(&target.to);
+    }
+}
+
+/// Waits until the arr instance of the transfer's category imported it, so it stays on put.io
+/// until then. With detect_imports_on_disk, files taken out of the download directory count as
+/// imported as well, also for transfers without an instance. Transfers without a hash or a way to
+/// tell aren't waited for, and neither are ones a client removed in the meantime. Returns whether
+/// the transfer was imported, which only then makes it safe to delete its files.
+///
+/// Seed limits apply while waiting, since an arr may never import. The transfer only stops seeding
+/// then, and stays on put.io until the wait is over.
+async fn watch_for_import(app_data: &AppData, transfer: &Transfer) -> bool {
+    let Some(hash) = &transfer.hash else {
+        return false;
+    };
+    let import = wait_for_import(app_data, transfer, hash);
+    tokio::pin!(import);
+    let imported = tokio::select! {
+        imported = &mut import => imported,
+        _ = stop_seeding_at_limit(app_data, transfer) => import.await,
+    };
+    app_data.import_reports.forget(hash);
+    imported
+}
+
+/// Stops seeding a transfer once it reaches a seed limit. Returns when it no longer seeds, and
+/// never without seed limits.
+async fn stop_seeding_at_limit(app_data: &AppData, transfer: &Transfer) {
+    let config = &app_data.config;
+    if config.seed_ratio_limit.is_none() && config.seed_time_limit_minutes.is_none() {
+        return std::future::pending().await;
+    }
     loop {
-        let putio_transfer =
-            putio::get_transfer(&app_data.config.putio.api_key, transfer.transfer_id)
-                .await?
-                .transfer;
-        // Check if seeding has stopped
-        if putio_transfer.status != PutIOTransferStatus::Seeding {
-            info!("{}: stopped seeding", transfer);
-            // Clean up remote resources
-            putio::remove_transfer(&app_data.config.putio.api_key, transfer.transfer_id).await?;
-            info!("{}: removed from put.io", transfer);
-            match putio::delete_file(&app_data.config.putio.api_key, transfer.file_id.unwrap())
-                .await
-            {
-                Ok(_) => {
-                    info!("{}: deleted remote files", transfer);
+        match app_data.putio.get_transfer(transfer.transfer_id).await {
+            Ok(response) if response.transfer.status != PutIOTransferStatus::Seeding => return,
+            Ok(response) => {
+                if let Some(reason) = seed_limit_reached(app_data, &response.transfer) {
+                    match app_data.putio.stop_seeding(transfer.transfer_id).await {
+                        Ok(_) => {
+                            info!("{}: {}, stopped seeding", transfer, reason);
+                            return;
+                        }
+                        Err(e) => warn!("{}: unable to stop seeding: {}", transfer, e),
+                    }
                 }
-                Err(_) => {
-                    warn!("{}: unable to delete remote files", transfer);
+            }
+            // Left to watch_seeding, once the wait is over
+            Err(e) if e.to_string().contains("404 Not Found") => return,
+            Err(e) => warn!("{}: unable to get seeding status: {}", transfer, e),
+        }
+        sleep(app_data.polling_interval()).await;
+    }
+}
+
+async fn wait_for_import(app_data: &AppData, transfer: &Transfer, hash: &str) -> bool {
+    let category = app_data.state.category(hash);
+    let arr = arr_for(app_data, category.as_deref());
+    let on_disk = app_data.config.detect_imports_on_disk;
+    if arr.is_none() && !on_disk {
+        return false;
+    }
+    let targets = transfer.targets.as_deref().unwrap_or_default();
+    let name = arr.map_or("the arrs", |arr| arr.config().name.as_str());
+    info!("{}: waiting for {} to import", transfer, name);
+    app_data
+        .activity
+        .set_phase(&transfer.key(), Phase::AwaitingImport);
+
+    // The files the arr imports, or any video, audio or ebook without one
+    let files: Vec<&DownloadTarget> = targets
+        .iter()
+        .filter(|t| t.target_type == TargetType::File)
+        .filter(|t| match arr {
+            Some(arr) => arr.imports(t.file_kind),
+            None => t.file_kind != FileKind::Other,
+        })
+        .collect();
+    let mut watch = match on_disk {
+        true => FileWatch::new(&files)
+            .inspect_err(|e| warn!("{}: unable to watch files: {}", transfer, e))
+            .ok(),
+        false => None,
+    };
+    // The arr reports imports through its webhook, which saves waiting for the next check
+    let reported = app_data.import_reports.watch(hash);
+    let paths: Vec<String> = files
+        .iter()
+        .map(|t| category_folders::reported_path(app_data, Some(hash), &t.to))
+        .collect();
+    loop {
+        if app_data.import_reports.imported(hash, &paths) {
+            info!("{}: imported by {}, as it reported", transfer, name);
+            return true;
+        }
+        if let Some(arr) = arr {
+            match check_imported(app_data, arr, hash, targets).await {
+                Ok(true) => {
+                    info!("{}: imported by {}", transfer, name);
+                    return true;
                 }
-            };
-            break;
+                Ok(false) => {}
+                Err(e) => warn!("{}: unable to check import: {}", transfer, e),
+            }
+        }
+        if on_disk && taken(&files) {
+            info!(
+                "{}: files taken from the download directory, imported",
+                transfer
+            );
+            return true;
+        }
+        let changed = async {
+            match &mut watch {
+                Some(watch) => watch.changed(app_data.polling_interval()).await,
+                None => sleep(app_data.polling_interval()).await,
+            }
+        };
+        tokio::select! {
+            _ = changed => {}
+            _ = reported.notified() => {}
+        }
+        if app_data.state.stage(&transfer.key()).is_none() {
+            info!("{}: removed before it was imported", transfer);
+            return false;
+        }
+    }
+}
+
+/// Monitors a transfer's seeding status and handles cleanup. Seeding is stopped once it reaches
+/// seed_ratio_limit or seed_time_limit_minutes.
+///
+/// Errors are logged rather than propagated, so a failing put.io call never takes down the task
+/// and leaves a transfer behind on put.io.
+async fn watch_seeding(app_data: Data<AppData>, transfer: Transfer, imported: bool) {
+    info!("{}: watching seeding", transfer);
+    app_data.activity.set_phase(&transfer.key(), Phase::Seeding);
+    loop {
+        match app_data.putio.get_transfer(transfer.transfer_id).await {
+            Ok(response) if response.transfer.status != PutIOTransferStatus::Seeding => {
+                info!("{}: stopped seeding", transfer);
+                clean_up(&app_data, &transfer, imported).await;
+                break;
+            }
+            Ok(response) => {
+                if let Some(reason) = seed_limit_reached(&app_data, &response.transfer) {
+                    match app_data.putio.stop_seeding(transfer.transfer_id).await {
+                        Ok(_) => {
+                            info!("{}: {}, stopped seeding", transfer, reason);
+                            clean_up(&app_data, &transfer, imported).await;
+                            break;
+                        }
+                        Err(e) => warn!("{}: unable to stop seeding: {}", transfer, e),
+                    }
+                }
+            }
+            Err(e) if e.to_string().contains("404 Not Found") => {
+                info!("{}: no longer on put.io", transfer);
+                app_data.state.forget_transfer(&transfer.key());
+                report(&app_data, &transfer, Outcome::Removed, None).await;
+                break;
+            }
+            Err(e) => warn!("{}: unable to get seeding status: {}", transfer, e),
         }
-        sleep(Duration::from_secs(app_data.config.polling_interval)).await;
+        sleep(app_data.polling_interval()).await;
     }
 
+    app_data.activity.clear_phase(&transfer.key());
     info!("{}: done seeding", transfer);
-    Ok(())
+}
+
+/// Why a seeding transfer should stop, if it reached a seed limit.
+pub(crate) fn seed_limit_reached(app_data: &AppData, transfer: &PutIOTransfer) -> Option<String> {
+    let config = &app_data.config;
+    if let (Some(limit), Some(ratio)) = (config.seed_ratio_limit, transfer.current_ratio) {
+        if ratio >= limit {
+            return Some(format!("reached ratio {:.2}", ratio));
+        }
+    }
+    if let (Some(limit), Some(seconds)) = (config.seed_time_limit_minutes, transfer.seconds_seeding)
+    {
+        if seconds >= limit * 60 {
+            return Some(format!("seeded for {} minutes", seconds / 60));
+        }
+    }
+    None
+}
+
+/// Removes a transfer that is done seeding from put.io. With cleanup_mode delete_after_seeding,
+/// the files of an imported transfer are deleted as well.
+async fn clean_up(app_data: &Data<AppData>, transfer: &Transfer, imported: bool) {
+    let span = transfer.span("cleanup");
+    remove_from_putio(app_data, transfer)
+        .in_stage(span.clone())
+        .await;
+    if imported && app_data.config.cleanup_mode == CleanupMode::DeleteAfterSeeding {
+        remove_local_files(transfer).in_stage(span).await;
+    }
+    report(app_data, transfer, Outcome::Cleaned, None).await;
+}
+
+/// Removes the transfer and its files from put.io, logging anything that goes wrong. With
+/// keep_remote_transfers or keep_remote_files, those stay.
+pub(crate) async fn remove_from_putio(app_data: &Data<AppData>, transfer: &Transfer) {
+    if let Some(hash) = &transfer.hash {
+        app_data.state.forget_priority(hash);
+        app_data.state.forget_source(hash);
+        app_data.state.forget_file_tree(hash);
+        app_data.state.forget_category(hash);
+        app_data.state.forget_renamed_files(hash);
+        pause::forget(app_data, hash);
+        app_data.stalled.lock().unwrap().remove(hash);
+    }
+    app_data.state.clear_failures(&transfer.key());
+    if app_data.config.keep_remote_transfers {
+        app_data.state.keep_transfer(&transfer.key());
+        info!("{}: keeping transfer on put.io", transfer);
+    } else {
+        app_data.state.forget_transfer(&transfer.key());
+        remove_transfer(app_data, transfer).await;
+    }
+
+    if app_data.config.keep_remote_files {
+        info!("{}: keeping remote files", transfer);
+        return;
+    }
+    let Some(file_id) = transfer.file_id else {
+        warn!("{}: no remote files to delete", transfer);
+        return;
+    };
+    if let Some(days) = app_data.config.remote_trash_days {
+        match putio_trash::trash(app_data, file_id, days).await {
+            Ok(_) => info!("{}: moved remote files to the trash", transfer),
+            Err(e) => {
+                let message = format!("{}: unable to trash remote files: {}", transfer.name, e);
+                warn!("{}: unable to trash remote files: {}", transfer, e);
+                notify_failure(&app_data.config, FailureCategory::Cleanup, &message).await;
+            }
+        }
+        return;
+    }
+    match app_data.putio.delete_file(file_id).await {
+        Ok(_) => info!("{}: deleted remote files", transfer),
+        Err(e) => {
+            let message = format!("{}: unable to delete remote files: {}", transfer.name, e);
+            warn!("{}: unable to delete remote files: {}", transfer, e);
+            notify_failure(&app_data.config, FailureCategory::Cleanup, &message).await;
+        }
+    }
+}
+
+/// Removes the transfer from put.io's list of transfers.
+async fn remove_transfer(app_data: &Data<AppData>, transfer: &Transfer) {
+    match app_data.putio.remove_transfer(transfer.transfer_id).await {
+        Ok(_) => info!("{}: removed from put.io", transfer),
+        // Stopping a transfer's seeding may have removed it already
+        Err(e) if e.to_string().contains("404 Not Found") => {
+            info!("{}: already removed from put.io", transfer)
+        }
+        Err(e) => {
+            let message = format!("{}: unable to remove from put.io: {}", transfer.name, e);
+            warn!("{}: unable to remove from put.io: {}", transfer, e);
+            notify_failure(&app_data.config, FailureCategory::Cleanup, &message).await;
+        }
+    }
+}
+
+/// Deletes the downloaded files of a transfer and its directory. Files the arr moved away are gone
+/// already, and those it hardlinked stay in its library.
+async fn remove_local_files(transfer: &Transfer) {
+    let targets = transfer.targets.as_deref().unwrap_or_default();
+    // Files first, since those extracted from an archive may sit next to the transfer's directory
+    let files = targets.iter().filter(|t| t.target_type == TargetType::File);
+    let directories = targets
+        .iter()
+        .filter(|t| t.target_type == TargetType::Directory && t.top_level);
+    for target in files.chain(directories) {
+        let result = match target.target_type {
+            TargetType::Directory => fs::remove_dir_all(&target.to),
+            TargetType::File => fs::remove_file(&target.to),
+        };
+        match result {
+            Ok(_) if target.top_level => info!("{}: deleted {}", transfer, target.to),
+            Ok(_) => {}
+            Err(e) if e.kind() == ErrorKind::NotFound => {}
+            Err(e) => warn!("{}: unable to delete {}: {}", transfer, target.to, e),
+        }
+    }
 }
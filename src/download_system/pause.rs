@@ -0,0 +1,113 @@
+// Transfers paused by a client through torrent-stop. A queued transfer is held back instead of
+// taking up an orchestration worker, and a running download stops reading between chunks. put.io
+// has no way to pause a transfer, so it carries on there.
+use super::transfer::{Transfer, TransferMessage};
+use crate::AppData;
+use anyhow::Result;
+use std::{collections::HashSet, sync::Mutex};
+use tokio::sync::Notify;
+use tracing::info;
+
+#[derive(Default)]
+pub struct Pauses {
+    /// Lowercase hashes of the paused transfers.
+    paused: Mutex<HashSet<String>>,
+    /// Transfers a worker picked up while they were paused, to queue again once resumed.
+    held: Mutex<Vec<Transfer>>,
+    resumed: Notify,
+}
+
+impl Pauses {
+    /// Starts out with the transfers that were paused before a restart.
+    pub fn new(hashes: Vec<String>) -> Self {
+        Self {
+            paused: Mutex::new(hashes.into_iter().collect()),
+            ..Default::default()
+        }
+    }
+
+    pub fn is_paused(&self, hash: &str) -> bool {
+        self.paused.lock().unwrap().contains(&hash.to_lowercase())
+    }
+
+    /// Holds back a transfer while it is paused. Returns it when it isn't.
+    pub fn hold(&self, transfer: Transfer) -> Option<Transfer> {
+        let paused = self.paused.lock().unwrap();
+        match &transfer.hash {
+            Some(hash) if paused.contains(&hash.to_lowercase()) => {
+                info!("{}: paused, holding back", transfer);
+                self.held.lock().unwrap().push(transfer);
+                None
+            }
+            _ => Some(transfer),
+        }
+    }
+
+    /// Waits while the transfer is paused. Returns whether it was.
+    pub async fn wait(&self, hash: &str) -> bool {
+        let mut waited = false;
+        loop {
+            // Created before checking, so a resume in between isn't missed
+            let resumed = self.resumed.notified();
+            if !self.is_paused(hash) {
+                return waited;
+            }
+            waited = true;
+            resumed.await;
+        }
+    }
+
+    /// Forgets the pause and any held back transfer.
+    fn take(&self, hash: &str) -> Option<Vec<Transfer>> {
+        let hash = hash.to_lowercase();
+        let mut paused = self.paused.lock().unwrap();
+        if !paused.remove(&hash) {
+            return None;
+        }
+        let mut held = self.held.lock().unwrap();
+        let (taken, kept) = held
+            .drain(..)
+            .partition(|t| t.hash.as_ref().is_some_and(|h| h.to_lowercase() == hash));
+        *held = kept;
+        Some(taken)
+    }
+}
+
+/// Pauses the download of a transfer until it is resumed, also across restarts.
+pub fn pause(app_data: &AppData, hash: &str) {
+    if app_data
+        .pauses
+        .paused
+        .lock()
+        .unwrap()
+        .insert(hash.to_lowercase())
+    {
+        app_data.state.set_paused(hash);
+        info!("{}: download paused", hash);
+    }
+}
+
+/// Resumes the download of a transfer, queueing it again if it was held back.
+pub async fn resume(app_data: &AppData, hash: &str) -> Result<()> {
+    let Some(held) = app_data.pauses.take(hash) else {
+        return Ok(());
+    };
+    app_data.state.forget_paused(hash);
+    app_data.pauses.resumed.notify_waiters();
+    info!("{}: download resumed", hash);
+    for transfer in held {
+        app_data.queue.push(transfer);
+        if let Some((tx, _)) = app_data.channels.transfers() {
+            tx.send(TransferMessage::QueuedForDownload).await?;
+        }
+    }
+    Ok(())
+}
+
+/// Forgets the pause of a transfer that was removed.
+pub fn forget(app_data: &AppData, hash: &str) {
+    if app_data.pauses.take(hash).is_some() {
+        app_data.state.forget_paused(hash);
+        app_data.pauses.resumed.notify_waiters();
+    }
+}
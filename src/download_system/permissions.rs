@@ -0,0 +1,55 @@
+// Gives everything a transfer downloaded the configured owner and permissions before it is
+// reported as downloaded, whatever the umask, an extracted archive or an earlier run left it with.
+// Files get uid, gid and file_mode, directories uid, gid and dir_mode.
+use super::transfer::{DownloadTarget, TargetType};
+use crate::{
+    utils::{prepare_created_directory, set_owner},
+    AppData,
+};
+use anyhow::{Context, Result};
+use std::{fs, os::unix::fs::PermissionsExt, path::Path};
+
+/// Applies ownership and permissions to the targets and everything below them.
+pub fn apply(app_data: &AppData, targets: &[DownloadTarget]) -> Result<()> {
+    let top_level: Vec<&Path> = targets
+        .iter()
+        .filter(|t| t.top_level)
+        .map(|t| Path::new(&t.to))
+        .collect();
+    // Files extracted next to an archive aren't below a top level target
+    let loose = targets.iter().filter(|t| {
+        t.target_type == TargetType::File
+            && !top_level
+                .iter()
+                .any(|dir| Path::new(&t.to).starts_with(dir))
+    });
+    for path in top_level
+        .iter()
+        .copied()
+        .chain(loose.map(|t| Path::new(&t.to)))
+        .filter(|path| path.exists())
+    {
+        apply_to(app_data, path)
+            .with_context(|| format!("Unable to set permissions of {}", path.display()))?;
+    }
+    Ok(())
+}
+
+fn apply_to(app_data: &AppData, path: &Path) -> Result<()> {
+    let metadata = fs::symlink_metadata(path)?;
+    if metadata.is_symlink() {
+        return Ok(());
+    }
+    if !metadata.is_dir() {
+        set_owner(app_data, path)?;
+        if let Some(mode) = app_data.config.file_mode {
+            fs::set_permissions(path, fs::Permissions::from_mode(mode & 0o7777))?;
+        }
+        return Ok(());
+    }
+    prepare_created_directory(app_data, path)?;
+    for entry in fs::read_dir(path)? {
+        apply_to(app_data, &entry?.path())?;
+    }
+    Ok(())
+}
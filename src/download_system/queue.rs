@@ -0,0 +1,128 @@
+// Local download queue. Transfers wait here until an orchestration worker picks them up, which
+// lets clients reorder them through the queue-move-* methods.
+use super::transfer::Transfer;
+use std::{
+    cmp::Reverse,
+    collections::VecDeque,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+/// Direction of a queue-move-* request.
+#[derive(Debug, Clone, Copy)]
+pub enum QueueMove {
+    Top,
+    Up,
+    Down,
+    Bottom,
+}
+
+#[derive(Default)]
+pub struct DownloadQueue {
+    transfers: Mutex<VecDeque<Queued>>,
+}
+
+struct Queued {
+    transfer: Transfer,
+    since: Instant,
+}
+
+impl DownloadQueue {
+    /// Adds a transfer behind the ones with the same or a higher priority.
+    pub fn push(&self, transfer: Transfer) {
+        let mut transfers = self.transfers.lock().unwrap();
+        let position = transfers
+            .iter()
+            .position(|q| q.transfer.priority < transfer.priority)
+            .unwrap_or(transfers.len());
+        transfers.insert(
+            position,
+            Queued {
+                transfer,
+                since: Instant::now(),
+            },
+        );
+    }
+
+    pub fn pop(&self) -> Option<Transfer> {
+        self.transfers
+            .lock()
+            .unwrap()
+            .pop_front()
+            .map(|q| q.transfer)
+    }
+
+    /// Empties the queue, for when another instance takes over.
+    pub fn clear(&self) {
+        self.transfers.lock().unwrap().clear();
+    }
+
+    pub fn len(&self) -> usize {
+        self.transfers.lock().unwrap().len()
+    }
+
+    /// How long the transfer that has been queued the longest has been waiting.
+    pub fn oldest(&self) -> Duration {
+        self.transfers
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|q| q.since.elapsed())
+            .max()
+            .unwrap_or_default()
+    }
+
+    /// Transfer ids in the order they will be downloaded.
+    pub fn transfer_ids(&self) -> Vec<u64> {
+        self.transfers
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|q| q.transfer.transfer_id)
+            .collect()
+    }
+
+    /// Changes the priority of the selected transfers and reorders the queue accordingly.
+    pub fn set_priority(&self, selected: impl Fn(&Transfer) -> bool, priority: i64) {
+        let mut transfers = self.transfers.lock().unwrap();
+        for queued in transfers.iter_mut().filter(|q| selected(&q.transfer)) {
+            queued.transfer.priority = priority;
+        }
+        transfers
+            .make_contiguous()
+            .sort_by_key(|q| Reverse(q.transfer.priority));
+    }
+
+    /// Moves the selected transfers, keeping their order relative to each other like
+    /// Transmission does.
+    pub fn move_transfers(&self, selected: impl Fn(&Transfer) -> bool, direction: QueueMove) {
+        let mut transfers = self.transfers.lock().unwrap();
+        match direction {
+            QueueMove::Top | QueueMove::Bottom => {
+                let (mut moved, rest): (VecDeque<_>, VecDeque<_>) =
+                    transfers.drain(..).partition(|q| selected(&q.transfer));
+                if let QueueMove::Top = direction {
+                    moved.extend(rest);
+                    *transfers = moved;
+                } else {
+                    *transfers = rest;
+                    transfers.extend(moved);
+                }
+            }
+            QueueMove::Up => {
+                for i in 1..transfers.len() {
+                    if selected(&transfers[i].transfer) && !selected(&transfers[i - 1].transfer) {
+                        transfers.swap(i, i - 1);
+                    }
+                }
+            }
+            QueueMove::Down => {
+                for i in (0..transfers.len().saturating_sub(1)).rev() {
+                    if selected(&transfers[i].transfer) && !selected(&transfers[i + 1].transfer) {
+                        transfers.swap(i, i + 1);
+                    }
+                }
+            }
+        }
+    }
+}
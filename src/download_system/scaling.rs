@@ -0,0 +1,151 @@
+// Grows and shrinks the pool of download workers between download_workers_min and
+// download_workers_max, based on how many targets wait for a worker and whether adding workers
+// still increases throughput.
+use super::set_download_workers;
+use crate::AppData;
+use actix_web::web::Data;
+use anyhow::Result;
+use std::{
+    future::Future,
+    sync::atomic::{AtomicU64, AtomicUsize, Ordering},
+    time::Duration,
+};
+use tokio::time::sleep;
+use tracing::{error, info, Instrument};
+
+/// A scale up has to increase throughput by this factor for another one to follow.
+const MIN_SPEEDUP: f64 = 1.1;
+/// How long to wait before restarting a failed worker, so a persistent error doesn't spin.
+const RESTART_DELAY: Duration = Duration::from_secs(5);
+
+/// Keeps count of a kind of worker, so the number of workers can change while running.
+pub struct WorkerPool {
+    /// Workers currently running.
+    active: AtomicUsize,
+    /// Number of workers to run. Workers above it retire once they are idle.
+    target: AtomicUsize,
+    next_id: AtomicUsize,
+    /// Bytes downloaded by the pool's workers, to measure throughput.
+    downloaded: AtomicU64,
+    /// Number of times a worker failed and was restarted.
+    restarts: AtomicU64,
+}
+
+impl Default for WorkerPool {
+    fn default() -> Self {
+        Self {
+            active: AtomicUsize::new(0),
+            target: AtomicUsize::new(usize::MAX),
+            next_id: AtomicUsize::new(0),
+            downloaded: AtomicU64::new(0),
+            restarts: AtomicU64::new(0),
+        }
+    }
+}
+
+impl WorkerPool {
+    /// Counts a newly started worker as active and returns its id.
+    pub fn register(&self) -> usize {
+        self.active.fetch_add(1, Ordering::SeqCst);
+        self.next_id.fetch_add(1, Ordering::SeqCst)
+    }
+
+    pub fn target(&self) -> usize {
+        self.target.load(Ordering::SeqCst)
+    }
+
+    pub fn set_target(&self, target: usize) {
+        self.target.store(target, Ordering::SeqCst);
+    }
+
+    /// Called by idle workers. Returns whether the worker should exit, in which case it is no
+    /// longer counted as active.
+    pub fn try_retire(&self) -> bool {
+        let target = self.target.load(Ordering::SeqCst);
+        self.active
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |active| {
+                (active > target).then(|| active - 1)
+            })
+            .is_ok()
+    }
+
+    pub fn active(&self) -> usize {
+        self.active.load(Ordering::SeqCst)
+    }
+
+    pub fn add_downloaded(&self, bytes: u64) {
+        self.downloaded.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    pub fn restarts(&self) -> u64 {
+        self.restarts.load(Ordering::Relaxed)
+    }
+
+    /// Runs a worker until it returns, which it does when it retires or its channel closes,
+    /// restarting it whenever it fails or panics, so failures don't silently shrink the pool.
+    pub async fn supervise<F, Fut>(&self, kind: &str, id: usize, work: F)
+    where
+        F: Fn() -> Fut,
+        Fut: Future<Output = Result<()>> + 'static,
+    {
+        loop {
+            let failure = match actix_rt::spawn(work().in_current_span()).await {
+                Ok(Ok(())) => return,
+                Ok(Err(e)) => format!("{:#}", e),
+                Err(e) => e.to_string(),
+            };
+            self.restarts.fetch_add(1, Ordering::Relaxed);
+            error!("{} worker {} failed, restarting: {}", kind, id, failure);
+            sleep(RESTART_DELAY).await;
+        }
+    }
+}
+
+/// Adjusts the number of download workers every polling interval. A worker is added while
+/// targets are waiting, as long as the previous addition made downloads faster, and one is
+/// retired while none are waiting.
+pub async fn autoscale(app_data: Data<AppData>) {
+    let config = &app_data.config;
+    let pool = &app_data.download_pool;
+    let min = config.download_workers_min.max(1);
+    let max = config.download_workers_max.unwrap_or(min).max(min);
+    set_download_workers(&app_data, config.download_workers.clamp(min, max));
+    info!("Autoscaling between {} and {} download workers", min, max);
+
+    let mut last_downloaded = pool.downloaded.load(Ordering::Relaxed);
+    // Throughput when we last added a worker, until the backlog clears.
+    let mut scaled_up_at: Option<f64> = None;
+    loop {
+        let interval = app_data.polling_interval();
+        sleep(interval).await;
+        let downloaded = pool.downloaded.load(Ordering::Relaxed);
+        let throughput = (downloaded - last_downloaded) as f64 / interval.as_secs_f64();
+        last_downloaded = downloaded;
+
+        // The target may have been changed through the API in the meantime.
+        let target = pool.target();
+        let waiting = app_data.channels.download_backlog();
+        if waiting > 0 {
+            let speedup = scaled_up_at.is_none_or(|before| throughput > before * MIN_SPEEDUP);
+            if target < max && speedup {
+                set_download_workers(&app_data, target + 1);
+                scaled_up_at = Some(throughput);
+                info!(
+                    "{} targets waiting at {:.2} MB/s, scaled up to {} download workers",
+                    waiting,
+                    throughput / 1_048_576.0,
+                    target + 1
+                );
+            }
+        } else {
+            scaled_up_at = None;
+            if target > min {
+                set_download_workers(&app_data, target - 1);
+                info!(
+                    "No targets waiting, scaling down to {} download workers",
+                    target - 1
+                );
+            }
+        }
+    }
+}
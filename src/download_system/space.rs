@@ -0,0 +1,101 @@
+// Checks that a transfer fits in the download directory before its download starts. Transfers that
+// don't are held back until enough space frees up, instead of failing once the disk is full.
+use super::{
+    download::partial_path,
+    transfer::{DownloadTarget, TargetType, Transfer},
+};
+use crate::{utils::free_space, AppData};
+use anyhow::Result;
+use std::{
+    collections::{HashMap, HashSet},
+    fs,
+    path::Path,
+    sync::Mutex,
+};
+use tracing::{info, warn};
+
+#[derive(Default)]
+pub struct DiskSpace {
+    /// Targets of the transfers being downloaded, by transfer key. What they still need to
+    /// download isn't taken from the free space yet, so it is counted against it.
+    claimed: Mutex<HashMap<String, Vec<DownloadTarget>>>,
+    /// Keys of the transfers held back until there is space for them.
+    waiting: Mutex<HashSet<String>>,
+}
+
+impl DiskSpace {
+    pub fn is_waiting(&self, key: &str) -> bool {
+        self.waiting.lock().unwrap().contains(key)
+    }
+
+    /// How many transfers are waiting for space.
+    pub fn waiting(&self) -> usize {
+        self.waiting.lock().unwrap().len()
+    }
+
+    /// Claims the space the targets of a transfer still need. Returns false when that would leave
+    /// less than free_space_reserve_gb free, in which case the transfer should wait.
+    pub fn claim(
+        &self,
+        app_data: &AppData,
+        transfer: &Transfer,
+        targets: &[DownloadTarget],
+    ) -> Result<bool> {
+        let key = transfer.key();
+        let mut claimed = self.claimed.lock().unwrap();
+        let free = free_space(&app_data.config.download_directory)?;
+        let others: u64 = claimed
+            .iter()
+            .filter(|(k, _)| **k != key)
+            .map(|(_, targets)| needed_bytes(targets))
+            .sum();
+        let needed = needed_bytes(targets);
+        let reserve = app_data.config.free_space_reserve_gb * 1_073_741_824;
+        let fits = free.saturating_sub(others) >= needed + reserve;
+
+        let mut waiting = self.waiting.lock().unwrap();
+        if !fits {
+            if waiting.insert(key) {
+                warn!(
+                    "{}: needs {:.2} GB and {} GB to spare, but only {:.2} GB is free, waiting for space",
+                    transfer,
+                    needed as f64 / 1_073_741_824.0,
+                    app_data.config.free_space_reserve_gb,
+                    free.saturating_sub(others) as f64 / 1_073_741_824.0
+                );
+            }
+            return Ok(false);
+        }
+        if waiting.remove(&key) {
+            info!("{}: enough free space, starting download", transfer);
+        }
+        claimed.insert(key, targets.to_vec());
+        Ok(true)
+    }
+
+    /// Releases the space claimed by a transfer once its download ended, one way or another.
+    pub fn release(&self, key: &str) {
+        self.claimed.lock().unwrap().remove(key);
+    }
+
+    /// Forgets the waiting transfers that are no longer on put.io, by the keys of those that are.
+    pub fn retain(&self, keys: &HashSet<String>) {
+        self.waiting
+            .lock()
+            .unwrap()
+            .retain(|key| keys.contains(key));
+    }
+}
+
+/// Bytes the files among targets still take up once downloaded. Partial downloads are started
+/// over, so they only count for what they will grow by.
+fn needed_bytes(targets: &[DownloadTarget]) -> u64 {
+    targets
+        .iter()
+        .filter(|t| t.target_type == TargetType::File && !Path::new(&t.to).exists())
+        .map(|t| {
+            let partial = fs::metadata(partial_path(&t.to)).map_or(0, |m| m.len());
+            t.size.unwrap_or(0).saturating_sub(partial)
+        })
+        .sum()
+}
@@ -0,0 +1,133 @@
+// Measures download speed from put.io over each tunnel route and with different numbers of
+// connections, for choosing worker counts and a route.
+use super::transfer::{fetch_file_tree, FileNode};
+use crate::AppData;
+use actix_web::web::Data;
+use anyhow::{Context, Result};
+use serde::Serialize;
+use std::time::{Duration, Instant};
+use tracing::{info, warn};
+
+#[derive(Debug, Serialize)]
+pub struct Measurement {
+    pub route: String,
+    pub connections: usize,
+    pub bytes: u64,
+    /// Bytes per second over all connections.
+    pub speed: f64,
+}
+
+/// Downloads a file from put.io for duration with every combination of route and connection
+/// count, and returns the speed of each. Without a file, the largest file in the putioarr folder
+/// is used; without routes, all of put.io's routes are tested. The account's route is restored
+/// afterwards.
+pub async fn run(
+    app_data: &Data<AppData>,
+    file_id: Option<u64>,
+    routes: &[String],
+    connections: &[usize],
+    duration: Duration,
+) -> Result<Vec<Measurement>> {
+    let file_id = match file_id {
+        Some(file_id) => file_id,
+        None => {
+            let root_folder_id = *app_data.root_folder_id.read().unwrap();
+            let tree = fetch_file_tree(app_data, root_folder_id).await?;
+            let file = largest_file(&tree)
+                .context("No files in the putioarr folder to test with, pass one with --file")?;
+            info!("Testing with {}", file.name);
+            file.id
+        }
+    };
+
+    let original = app_data
+        .putio
+        .account_info()
+        .await?
+        .info
+        .settings
+        .tunnel_route_name;
+    let routes = if routes.is_empty() {
+        match app_data.putio.tunnel_routes().await {
+            Ok(routes) => routes.into_iter().map(|r| r.name).collect(),
+            Err(e) => {
+                warn!(
+                    "Unable to list tunnel routes, testing the current one: {}",
+                    e
+                );
+                vec![original.clone()]
+            }
+        }
+    } else {
+        routes.to_vec()
+    };
+
+    let result = measure_routes(app_data, file_id, &routes, connections, duration).await;
+    if let Err(e) = app_data.putio.set_tunnel_route(&original).await {
+        warn!("Unable to restore tunnel route {}: {}", original, e);
+    }
+    result
+}
+
+async fn measure_routes(
+    app_data: &Data<AppData>,
+    file_id: u64,
+    routes: &[String],
+    connections: &[usize],
+    duration: Duration,
+) -> Result<Vec<Measurement>> {
+    let mut measurements = Vec::new();
+    for route in routes {
+        app_data.putio.set_tunnel_route(route).await?;
+        // Download URLs point at the route that was active when they were requested
+        let url = app_data.putio.url(file_id).await?;
+        for &count in connections {
+            info!("Testing route {} with {} connections", route, count);
+            let start = Instant::now();
+            let bytes: u64 = futures::future::join_all(
+                (0..count).map(|_| download_for(app_data, &url, duration)),
+            )
+            .await
+            .into_iter()
+            .sum::<Result<u64>>()?;
+            measurements.push(Measurement {
+                route: route.clone(),
+                connections: count,
+                bytes,
+                speed: bytes as f64 / start.elapsed().as_secs_f64(),
+            });
+        }
+    }
+    Ok(measurements)
+}
+
+/// Downloads url over one connection until duration is up, starting over whenever the file is
+/// done, and returns the number of bytes received.
+async fn download_for(app_data: &AppData, url: &str, duration: Duration) -> Result<u64> {
+    let deadline = Instant::now() + duration;
+    let mut bytes = 0;
+    while Instant::now() < deadline {
+        let mut response = app_data.putio.download(url).await?;
+        loop {
+            let chunk = tokio::time::timeout_at(deadline.into(), response.chunk()).await;
+            match chunk {
+                Ok(Ok(Some(chunk))) => bytes += chunk.len() as u64,
+                Ok(Ok(None)) => break,
+                Ok(Err(e)) => return Err(e.into()),
+                Err(_) => return Ok(bytes),
+            }
+        }
+    }
+    Ok(bytes)
+}
+
+fn largest_file(node: &FileNode) -> Option<&FileNode> {
+    match node.file_type.as_str() {
+        "FOLDER" => node
+            .children
+            .iter()
+            .filter_map(largest_file)
+            .max_by_key(|file| file.size.unwrap_or(0)),
+        _ => Some(node),
+    }
+}
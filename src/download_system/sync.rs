@@ -0,0 +1,131 @@
+// One-way mirroring of an arbitrary put.io folder to a local directory, for folders that are
+// used outside the arr workflow. Reuses the download workers and target generation of the
+// transfer pipeline.
+use super::{
+    download::{self, download_all},
+    failure::summarize,
+    transfer::{recurse_download_targets, StageSpan},
+};
+use crate::AppData;
+use actix_web::web::Data;
+use anyhow::{bail, Context, Result};
+use clap::ValueEnum;
+use colored::Colorize;
+use std::{
+    collections::HashSet,
+    fs,
+    path::{Path, PathBuf},
+};
+use tokio::time::sleep;
+use tracing::{info, warn};
+
+/// What happens to local files that are no longer in the put.io folder.
+#[derive(Debug, Clone, Copy, ValueEnum)]
+pub enum DeletePolicy {
+    /// Never delete local files
+    Keep,
+    /// Delete local files that are no longer on put.io
+    Mirror,
+}
+
+/// Mirrors folder, a put.io path like `Movies/Documentaries` or a folder id, to the local
+/// directory to. Runs once, or every polling interval when watch is set.
+pub async fn run(
+    app_data: Data<AppData>,
+    folder: &str,
+    to: &str,
+    delete: DeletePolicy,
+    watch: bool,
+) -> Result<()> {
+    let folder_id = resolve_folder(&app_data, folder).await?;
+    let to = fs::canonicalize(to).with_context(|| format!("Unable to access {}", to))?;
+
+    let (dtx, drx) = async_channel::unbounded();
+    for id in 0..app_data.config.download_workers {
+        download::Worker::start(id, app_data.clone(), drx.clone());
+    }
+
+    loop {
+        match sync(&app_data, &dtx, folder_id, &to, delete).await {
+            Ok(_) => info!("{}: sync {}", folder, "done".blue()),
+            Err(e) if watch => warn!("{}: sync failed: {:#}", folder, e),
+            Err(e) => return Err(e),
+        }
+        if !watch {
+            return Ok(());
+        }
+        sleep(app_data.polling_interval()).await;
+    }
+}
+
+async fn sync(
+    app_data: &Data<AppData>,
+    dtx: &async_channel::Sender<download::DownloadTargetMessage>,
+    folder_id: u64,
+    to: &Path,
+    delete: DeletePolicy,
+) -> Result<()> {
+    let (Some(parent), Some(name)) = (to.parent(), to.file_name()) else {
+        bail!("Can't sync into {}", to.display());
+    };
+    let targets = recurse_download_targets(
+        app_data,
+        folder_id,
+        "sync",
+        Some(parent.to_string_lossy().to_string()),
+        Some(name.to_string_lossy().to_string()),
+    )
+    .await?;
+    info!("{}: {} targets on put.io", to.display(), targets.len());
+
+    let errors = download_all(dtx, &targets, &StageSpan::current()).await?;
+    if !errors.is_empty() {
+        bail!("Not all files could be downloaded: {}", summarize(&errors));
+    }
+
+    if let DeletePolicy::Mirror = delete {
+        let keep: HashSet<PathBuf> = targets.iter().map(|t| PathBuf::from(&t.to)).collect();
+        remove_stale(to, &keep)?;
+    }
+    Ok(())
+}
+
+/// Deletes files and directories under dir that aren't in keep.
+fn remove_stale(dir: &Path, keep: &HashSet<PathBuf>) -> Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if keep.contains(&path) {
+            if path.is_dir() {
+                remove_stale(&path, keep)?;
+            }
+            continue;
+        }
+        info!("{}: no longer on put.io, deleting", path.display());
+        if path.is_dir() {
+            fs::remove_dir_all(&path)?;
+        } else {
+            fs::remove_file(&path)?;
+        }
+    }
+    Ok(())
+}
+
+/// Resolves a folder id or a path of folder names below the put.io root to a folder id.
+async fn resolve_folder(app_data: &AppData, folder: &str) -> Result<u64> {
+    if let Ok(id) = folder.parse() {
+        return Ok(id);
+    }
+    let mut folder_id = 0;
+    for name in folder.split('/').filter(|name| !name.is_empty()) {
+        folder_id = app_data
+            .putio
+            .list_files(folder_id)
+            .await?
+            .files
+            .iter()
+            .find(|f| f.name == name && f.file_type == "FOLDER")
+            .map(|f| f.id)
+            .with_context(|| format!("Folder {} not found on put.io", folder))?;
+    }
+    Ok(folder_id)
+}
@@ -0,0 +1,170 @@
+// Limits download speed with a token bucket. One bucket is shared by all download workers for
+// max_download_rate_kbps and the speed_schedule, and every download gets its own for
+// max_worker_download_rate_kbps.
+use crate::SpeedSchedule;
+use anyhow::{bail, Context, Result};
+use chrono::{Datelike, Local, NaiveDateTime, NaiveTime, Weekday};
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant},
+};
+use tokio::time::sleep;
+use tracing::info;
+
+/// How often the speed schedule is looked at.
+const SCHEDULE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// A token bucket that refills at rate bytes per second and holds up to a second's worth.
+pub struct Throttle {
+    bucket: Mutex<Bucket>,
+    /// Bytes per second outside of the schedule, or 0 for no limit. Changes at runtime.
+    default_rate: AtomicU64,
+    schedule: Vec<SpeedWindow>,
+}
+
+struct Bucket {
+    /// Bytes per second, or 0 for no limit.
+    rate: u64,
+    /// Bytes that may be received right away. Negative when receivers are waiting for more.
+    tokens: f64,
+    refilled_at: Instant,
+    scheduled_at: Option<Instant>,
+}
+
+/// A speed_schedule entry: rate applies from `from` until `to` on the given days. A window that
+/// ends before it starts runs past midnight, into the next day.
+pub struct SpeedWindow {
+    /// Days the window starts on, every day when empty.
+    days: Vec<Weekday>,
+    from: NaiveTime,
+    to: NaiveTime,
+    /// Bytes per second, or 0 for no limit.
+    rate: u64,
+}
+
+impl SpeedWindow {
+    fn contains(&self, now: NaiveDateTime) -> bool {
+        let starts_on = |day: Weekday| self.days.is_empty() || self.days.contains(&day);
+        let (time, day) = (now.time(), now.weekday());
+        if self.from <= self.to {
+            starts_on(day) && self.from <= time && time < self.to
+        } else {
+            (starts_on(day) && self.from <= time) || (starts_on(day.pred()) && time < self.to)
+        }
+    }
+}
+
+/// Parses speed_schedule, with days like "mon" and times like "08:00".
+pub fn speed_windows(schedule: &[SpeedSchedule]) -> Result<Vec<SpeedWindow>> {
+    let time = |time: &str| {
+        NaiveTime::parse_from_str(time, "%H:%M")
+            .with_context(|| format!("Invalid time {} in speed_schedule, use HH:MM", time))
+    };
+    schedule
+        .iter()
+        .map(|entry| {
+            let days = entry
+                .days
+                .iter()
+                .map(|day| match day.parse::<Weekday>() {
+                    Ok(day) => Ok(day),
+                    Err(_) => bail!("Invalid day {} in speed_schedule, use mon to sun", day),
+                })
+                .collect::<Result<_>>()?;
+            Ok(SpeedWindow {
+                days,
+                from: time(&entry.from)?,
+                to: time(&entry.to)?,
+                rate: entry.rate_kbps * 1024,
+            })
+        })
+        .collect()
+}
+
+impl Throttle {
+    /// A throttle allowing rate_kbps KB/s, or no limit with None or 0, except while a window of
+    /// the schedule applies. The first window that applies wins.
+    pub fn new(rate_kbps: Option<u64>, schedule: Vec<SpeedWindow>) -> Self {
+        let rate = rate_kbps.unwrap_or(0) * 1024;
+        Self {
+            bucket: Mutex::new(Bucket {
+                rate,
+                tokens: rate as f64,
+                refilled_at: Instant::now(),
+                scheduled_at: None,
+            }),
+            default_rate: AtomicU64::new(rate),
+            schedule,
+        }
+    }
+
+    /// Waits until bytes may be received. Bytes are taken from the bucket right away, so
+    /// concurrent receivers queue up behind each other instead of all waking up at once.
+    pub async fn consume(&self, bytes: u64) {
+        let wait = {
+            let mut bucket = self.bucket.lock().unwrap();
+            let now = Instant::now();
+            if !self.schedule.is_empty()
+                && bucket
+                    .scheduled_at
+                    .is_none_or(|at| now.duration_since(at) >= SCHEDULE_INTERVAL)
+            {
+                bucket.scheduled_at = Some(now);
+                let rate = self.scheduled_rate(Local::now().naive_local());
+                if rate != bucket.rate {
+                    match rate {
+                        0 => info!("Download speed is no longer limited"),
+                        rate => info!("Download speed is now limited to {} KB/s", rate / 1024),
+                    }
+                    bucket.rate = rate;
+                    bucket.tokens = bucket.tokens.min(rate as f64);
+                }
+            }
+            if bucket.rate == 0 {
+                return;
+            }
+            let elapsed = now.duration_since(bucket.refilled_at).as_secs_f64();
+            let rate = bucket.rate as f64;
+            bucket.tokens = (bucket.tokens + elapsed * rate).min(rate);
+            bucket.refilled_at = now;
+            bucket.tokens -= bytes as f64;
+            match bucket.tokens < 0.0 {
+                true => Duration::from_secs_f64(-bucket.tokens / rate),
+                false => Duration::ZERO,
+            }
+        };
+        if !wait.is_zero() {
+            sleep(wait).await;
+        }
+    }
+
+    /// The rate outside of the schedule in KB/s, or None for no limit.
+    pub fn rate_kbps(&self) -> Option<u64> {
+        match self.default_rate.load(Ordering::Relaxed) / 1024 {
+            0 => None,
+            rate => Some(rate),
+        }
+    }
+
+    /// Changes the rate outside of the schedule to rate_kbps KB/s, or no limit with None or 0. It
+    /// applies right away, unless a window of the schedule does.
+    pub fn set_rate(&self, rate_kbps: Option<u64>) {
+        let rate = rate_kbps.unwrap_or(0) * 1024;
+        self.default_rate.store(rate, Ordering::Relaxed);
+        let mut bucket = self.bucket.lock().unwrap();
+        bucket.rate = self.scheduled_rate(Local::now().naive_local());
+        bucket.tokens = bucket.tokens.min(bucket.rate as f64);
+    }
+
+    fn scheduled_rate(&self, now: NaiveDateTime) -> u64 {
+        self.schedule
+            .iter()
+            .find(|window| window.contains(now))
+            .map_or(self.default_rate.load(Ordering::Relaxed), |window| {
+                window.rate
+            })
+    }
+}
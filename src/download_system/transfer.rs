@@ -1,42 +1,130 @@
+use super::{backlog, download::partial_path, extract::is_archive, failure::FailureCategory};
 use crate::{
-    services::putio::{self, PutIOTransfer},
-    AppData,
+    category_folders,
+    report::Lifecycle,
+    services::{
+        notify::{notify, notify_failure},
+        putio::{FileResponse, PutIOTransfer, PutIOTransferStatus},
+    },
+    state::{Source, Stage},
+    AppData, UnicodeNormalization,
 };
 use actix_web::web::Data;
+use anyhow::Context;
 use anyhow::Result;
 use async_channel::Sender;
 use async_recursion::async_recursion;
 use colored::*;
-use log::{error, info, warn};
+use icu_normalizer::{ComposingNormalizer, DecomposingNormalizer};
+use regex::Regex;
 use serde::{Deserialize, Serialize};
-use std::{fmt::Display, path::Path};
+use std::{
+    collections::{HashMap, HashSet},
+    fmt::Display,
+    fs,
+    future::Future,
+    path::Path,
+    sync::atomic::Ordering,
+};
 use tokio::time::sleep;
+use tracing::{error, info, info_span, instrument::Instrumented, warn, Instrument, Span};
+
+/// Number of consecutive authentication failures after which we consider the api key rejected.
+const AUTH_FAILURE_THRESHOLD: u32 = 3;
+/// How often to retry put.io once the api key has been rejected.
+const AUTH_RETRY_INTERVAL: std::time::Duration = std::time::Duration::from_secs(300);
+
+/// The span of a transfer together with the span of the stage it is in. Both are entered around
+/// the stage, since filters on span fields, like `[transfer{hash=abcd1234}]`, only apply while the
+/// matching span is entered, not its children.
+#[derive(Debug, Clone)]
+pub struct StageSpan {
+    transfer: Span,
+    stage: Span,
+}
+
+impl StageSpan {
+    /// The span that is current, for work that doesn't belong to a transfer.
+    pub fn current() -> Self {
+        Self {
+            transfer: Span::none(),
+            stage: Span::current(),
+        }
+    }
+
+    pub fn in_scope<T>(&self, f: impl FnOnce() -> T) -> T {
+        self.transfer.in_scope(|| self.stage.in_scope(f))
+    }
+
+    /// The span of the stage alone, to create spans under.
+    pub fn stage(&self) -> &Span {
+        &self.stage
+    }
+}
+
+pub trait InStage: Future + Sized {
+    /// Runs the future in the stage of a transfer.
+    fn in_stage(self, span: StageSpan) -> Instrumented<Instrumented<Self>> {
+        self.instrument(span.stage).instrument(span.transfer)
+    }
+}
+
+impl<F: Future> InStage for F {}
 
 #[derive(Clone)]
 pub struct Transfer {
     pub name: String,
+    pub local_name: String,
     pub file_id: Option<u64>,
     pub hash: Option<String>,
     pub transfer_id: u64,
+    /// Download priority set by the client, higher goes first.
+    pub priority: i64,
     pub targets: Option<Vec<DownloadTarget>>,
+    pub lifecycle: Lifecycle,
+    /// Parent of the spans of the stages the transfer goes through.
+    pub span: Span,
     pub app_data: Data<AppData>,
 }
 
 impl Transfer {
     pub async fn get_download_targets(&self) -> Result<Vec<DownloadTarget>> {
         info!("{}: generating targets", self);
-        let default = "0000".to_string();
-        let hash = self.hash.as_ref().unwrap_or(&default).as_str();
-        recurse_download_targets(&self.app_data, self.file_id.unwrap(), hash, None, true).await
+        let file_id = self.file_id.context("Transfer has no files")?;
+        let tree = cached_file_tree(&self.app_data, file_id, self.hash.as_deref()).await?;
+        let hash = self.hash.as_deref().unwrap_or("0000");
+        let base_path = category_folders::incomplete_directory(&self.app_data, Some(hash));
+        if let Some(video) = flattened_video(&self.app_data, &tree, hash) {
+            info!("{}: downloading single video without its folder", self);
+            return Ok(tree_download_targets(
+                &self.app_data,
+                video,
+                hash,
+                base_path,
+                Some(flattened_name(&self.app_data, hash, video)),
+            ));
+        }
+        Ok(tree_download_targets(
+            &self.app_data,
+            &tree,
+            hash,
+            base_path,
+            Some(self.local_name.clone()),
+        ))
+    }
+
+    /// Key under which the state database tracks this transfer.
+    pub fn key(&self) -> String {
+        transfer_key(self.hash.as_deref(), self.transfer_id)
     }
 
-    pub fn get_top_level(&self) -> DownloadTarget {
-        self.targets
-            .clone()
-            .unwrap()
-            .into_iter()
-            .find(|t| t.top_level)
-            .unwrap()
+    /// Span of a pipeline stage, like queued, download, import, seeding and cleanup, under the
+    /// transfer's span.
+    pub fn span(&self, stage: &str) -> StageSpan {
+        StageSpan {
+            stage: info_span!(parent: &self.span, "stage", stage = %stage),
+            transfer: self.span.clone(),
+        }
     }
 
     pub fn from(app_data: Data<AppData>, transfer: &PutIOTransfer) -> Self {
@@ -44,9 +132,21 @@ impl Transfer {
         Self {
             transfer_id: transfer.id,
             name: name.clone(),
+            local_name: name.clone(),
             file_id: transfer.file_id,
+            priority: transfer
+                .hash
+                .as_deref()
+                .map_or(0, |hash| app_data.state.priority(hash)),
             targets: None,
             hash: transfer.hash.clone(),
+            lifecycle: Lifecycle::new(transfer),
+            span: info_span!(
+                parent: None,
+                "transfer",
+                hash = %short_hash(transfer.hash.as_deref().unwrap_or("0000")),
+                transfer_id = transfer.id
+            ),
             app_data,
         }
     }
@@ -61,83 +161,622 @@ impl Display for Transfer {
     }
 }
 
+/// Key under which the state database tracks a transfer: its hash, or its id for transfers
+/// without one.
+pub fn transfer_key(hash: Option<&str>, transfer_id: u64) -> String {
+    match hash {
+        Some(hash) => hash.to_lowercase(),
+        None => transfer_id.to_string(),
+    }
+}
+
+/// Returns the prefix of a transfer hash that is used to identify it in logs.
+pub fn short_hash(hash: &str) -> &str {
+    &hash[..hash.len().min(8)]
+}
+
+/// Applies the rename rules to the name of a transfer's top-level file or folder.
+pub fn rename(app_data: &AppData, name: &str) -> String {
+    normalize(app_data, &apply_rules(&app_data.rename_rules, name))
+}
+
+/// Applies unicode_normalization to a file name we write.
+fn normalize(app_data: &AppData, name: &str) -> String {
+    match app_data.config.unicode_normalization {
+        UnicodeNormalization::None => name.to_string(),
+        UnicodeNormalization::Nfc => ComposingNormalizer::new_nfc().normalize(name),
+        UnicodeNormalization::Nfd => DecomposingNormalizer::new_nfd().normalize(name),
+    }
+}
+
+/// Applies the rename_files rules to the name of a downloaded file. The name a file got is
+/// recorded, so it keeps it when the rules change, and verification and imports still find it.
+fn rename_file(app_data: &AppData, hash: &str, file: &FileNode) -> String {
+    if let Some(name) = app_data.state.renamed_file(hash, file.id) {
+        return name;
+    }
+    let name = apply_rules(&app_data.file_rename_rules, &file.name);
+    if name != file.name {
+        info!("{}: renaming to {}", file.name, name);
+        app_data.state.set_renamed_file(hash, file.id, &name);
+    }
+    name
+}
+
+/// Applies rename rules in order. Names that would end up empty are left alone.
+fn apply_rules(rules: &[(Regex, String)], name: &str) -> String {
+    let mut renamed = name.to_string();
+    for (regex, replacement) in rules {
+        renamed = regex
+            .replace_all(&renamed, replacement.as_str())
+            .trim()
+            .to_string();
+    }
+    if renamed.is_empty() {
+        return name.to_string();
+    }
+    renamed
+}
+
+/// Returns the local name for each transfer, keyed by transfer id.
+///
+/// A transfer keeps the name it was given when it was first picked up, see
+/// [`assign_local_names`], so it doesn't change once a transfer it collided with is gone. Others
+/// are named here: names are rewritten by the rename rules first, and a name that another transfer
+/// has, or that is already taken in the download directory, gets the short hash appended. Of
+/// transfers that share a name, the oldest keeps the plain one.
+pub fn local_names<'a>(
+    app_data: &AppData,
+    transfers: impl IntoIterator<Item = &'a PutIOTransfer>,
+) -> HashMap<u64, String> {
+    let stored = app_data.state.local_names();
+    let mut taken: HashSet<String> = stored.values().cloned().collect();
+    let mut names = HashMap::new();
+    let mut unnamed = vec![];
+    for t in transfers {
+        match t.hash.as_ref().and_then(|h| stored.get(&h.to_lowercase())) {
+            Some(name) => {
+                names.insert(t.id, name.clone());
+            }
+            None => unnamed.push(t),
+        }
+    }
+
+    unnamed.sort_by_key(|t| t.id);
+    for t in unnamed {
+        let name = rename(app_data, &t.name);
+        let local_name = if taken.contains(&name) || taken_on_disk(app_data, t, &name) {
+            let suffix = match &t.hash {
+                Some(hash) => short_hash(hash).to_string(),
+                None => t.id.to_string(),
+            };
+            disambiguate(&name, &suffix)
+        } else {
+            name
+        };
+        taken.insert(local_name.clone());
+        names.insert(t.id, local_name);
+    }
+    names
+}
+
+/// Like [`local_names`], and stores the names of transfers that didn't have one yet.
+pub fn assign_local_names<'a>(
+    app_data: &AppData,
+    transfers: impl IntoIterator<Item = &'a PutIOTransfer>,
+) -> HashMap<u64, String> {
+    let transfers: Vec<&PutIOTransfer> = transfers.into_iter().collect();
+    let names = local_names(app_data, transfers.iter().copied());
+    for t in transfers {
+        if let Some(hash) = &t.hash {
+            app_data.state.set_local_name(hash, &names[&t.id]);
+        }
+    }
+    names
+}
+
+/// Whether files of another transfer, like one that is gone from put.io, are in the way of name.
+/// Transfers without a hash would find their own files, since their names aren't stored, and so
+/// would ones picked up before names were.
+fn taken_on_disk(app_data: &AppData, t: &PutIOTransfer, name: &str) -> bool {
+    let Some(hash) = t.hash.as_deref() else {
+        return false;
+    };
+    app_data
+        .state
+        .stage(&transfer_key(Some(hash), t.id))
+        .is_none()
+        && Path::new(&category_folders::download_directory(app_data, Some(hash)))
+            .join(name)
+            .exists()
+}
+
+/// Returns the ids of transfers that duplicate another one with the same hash, like a grab that
+/// was sent twice. Of each set the one in picked_up is kept, or else the one furthest along, then
+/// the oldest.
+pub fn duplicate_ids<'a>(
+    transfers: impl IntoIterator<Item = &'a PutIOTransfer>,
+    picked_up: &[u64],
+) -> HashSet<u64> {
+    let mut by_hash = HashMap::<String, Vec<&PutIOTransfer>>::new();
+    for t in transfers {
+        if let Some(hash) = &t.hash {
+            by_hash.entry(hash.to_lowercase()).or_default().push(t);
+        }
+    }
+
+    let mut duplicates = HashSet::new();
+    for mut group in by_hash.into_values().filter(|g| g.len() > 1) {
+        group.sort_by_key(|t| {
+            (
+                !picked_up.contains(&t.id),
+                !t.is_downloadable(),
+                t.status == PutIOTransferStatus::Error,
+                t.id,
+            )
+        });
+        duplicates.extend(group[1..].iter().map(|t| t.id));
+    }
+    duplicates
+}
+
+/// Removes a transfer that duplicates kept, along with its files unless they are kept's.
+async fn remove_duplicate(app_data: &AppData, t: &PutIOTransfer, kept: Option<&PutIOTransfer>) {
+    if let Err(e) = app_data.putio.remove_transfer(t.id).await {
+        warn!("  {}: unable to remove duplicate transfer: {}", t, e);
+        return;
+    }
+    info!("  {}: removed duplicate transfer", t);
+    let shared = kept.is_some_and(|k| k.file_id == t.file_id);
+    if let (Some(file_id), false) = (t.file_id, shared) {
+        if let Err(e) = app_data.putio.delete_file(file_id).await {
+            warn!(
+                "  {}: unable to delete files of duplicate transfer: {}",
+                t, e
+            );
+        }
+    }
+}
+
+/// Appends a suffix to a name, keeping a file extension at the end.
+fn disambiguate(name: &str, suffix: &str) -> String {
+    let path = Path::new(name);
+    match (path.file_stem(), path.extension()) {
+        (Some(stem), Some(ext))
+            if ext.len() <= 4 && ext.to_string_lossy().chars().all(char::is_alphanumeric) =>
+        {
+            format!(
+                "{} [{}].{}",
+                stem.to_string_lossy(),
+                suffix,
+                ext.to_string_lossy()
+            )
+        }
+        _ => format!("{} [{}]", name, suffix),
+    }
+}
+
+/// A file or folder on put.io with everything below it. The trees of finished transfers are cached
+/// in the state database, since they no longer change.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct FileNode {
+    pub id: u64,
+    pub name: String,
+    pub file_type: String,
+    pub size: Option<u64>,
+    pub crc32: Option<String>,
+    pub children: Vec<FileNode>,
+}
+
+impl FileNode {
+    fn from_response(file: FileResponse) -> Self {
+        Self {
+            id: file.id,
+            name: file.name,
+            file_type: file.file_type,
+            size: file.size,
+            crc32: file.crc32,
+            children: vec![],
+        }
+    }
+
+    /// Paths and sizes of the files in the tree, with paths starting at the tree's top.
+    pub fn files(&self) -> Vec<(String, u64)> {
+        match self.file_type.as_str() {
+            "FOLDER" => self
+                .children
+                .iter()
+                .flat_map(FileNode::files)
+                .map(|(path, size)| (format!("{}/{}", self.name, path), size))
+                .collect(),
+            _ => vec![(self.name.clone(), self.size.unwrap_or(0))],
+        }
+    }
+
+    /// Number of files in the tree, not counting folders.
+    pub fn file_count(&self) -> usize {
+        match self.file_type.as_str() {
+            "FOLDER" => self.children.iter().map(FileNode::file_count).sum(),
+            _ => 1,
+        }
+    }
+}
+
+/// Lists a put.io file or folder with everything below it.
 #[async_recursion]
-async fn recurse_download_targets(
+pub(crate) async fn fetch_file_tree(app_data: &Data<AppData>, file_id: u64) -> Result<FileNode> {
+    let response = app_data.putio.list_files(file_id).await?;
+    let is_folder = response.parent.file_type == "FOLDER";
+    let mut node = FileNode::from_response(response.parent);
+    if is_folder {
+        for file in response.files {
+            let child = match file.file_type.as_str() {
+                "FOLDER" => fetch_file_tree(app_data, file.id).await?,
+                _ => FileNode::from_response(file),
+            };
+            node.children.push(child);
+        }
+    }
+    Ok(node)
+}
+
+/// Returns the cached file tree of a transfer, listing it on put.io and caching it when needed.
+pub(crate) async fn cached_file_tree(
+    app_data: &Data<AppData>,
+    file_id: u64,
+    hash: Option<&str>,
+) -> Result<FileNode> {
+    let cached = hash
+        .and_then(|hash| app_data.state.file_tree(hash))
+        .and_then(|tree| serde_json::from_str(&tree).ok());
+    if let Some(tree) = cached {
+        return Ok(tree);
+    }
+    let tree = fetch_file_tree(app_data, file_id).await?;
+    if let Some(hash) = hash {
+        app_data
+            .state
+            .set_file_tree(hash, &serde_json::to_string(&tree)?);
+    }
+    Ok(tree)
+}
+
+/// Extensions of the files download_file_types = ["subtitle"] downloads.
+const SUBTITLE_EXTENSIONS: &[&str] = &["srt", "sub", "idx", "ass", "ssa", "vtt", "sup"];
+/// Extensions of the files download_file_types = ["ebook"] downloads, which put.io has no type for.
+const EBOOK_EXTENSIONS: &[&str] = &["epub", "mobi", "azw", "azw3", "pdf", "cbz", "cbr", "fb2"];
+/// Extensions of videos and audio, for files put.io didn't type, like those from archives.
+const VIDEO_EXTENSIONS: &[&str] = &["mkv", "mp4", "m4v", "avi", "ts", "wmv", "mov", "mpg"];
+const AUDIO_EXTENSIONS: &[&str] = &["mp3", "flac", "m4a", "m4b", "ogg", "opus", "wav", "aac"];
+
+/// Kinds of files download_file_types takes besides extensions. Those other than "subtitle" are
+/// put.io file types.
+pub const FILE_TYPES: &[&str] = &[
+    "video", "audio", "subtitle", "ebook", "image", "archive", "pdf", "text",
+];
+
+/// Decides which files of a transfer are downloaded, using the filter overrides for its category
+/// where there are any.
+pub(crate) struct Filters<'a> {
+    skip_directories: &'a [String],
+    junk_files: &'a [String],
+    min_file_size: u64,
+    file_types: &'a [String],
+}
+
+impl<'a> Filters<'a> {
+    pub fn new(app_data: &'a AppData, hash: &str) -> Self {
+        let config = &app_data.config;
+        let overrides = app_data
+            .state
+            .category(hash)
+            .and_then(|category| config.filters.get(&category));
+        Self {
+            skip_directories: overrides
+                .and_then(|o| o.skip_directories.as_deref())
+                .unwrap_or(&config.skip_directories),
+            junk_files: overrides
+                .and_then(|o| o.junk_files.as_deref())
+                .unwrap_or(&config.junk_files),
+            min_file_size: overrides
+                .and_then(|o| o.min_file_size_mb)
+                .unwrap_or(config.min_file_size_mb)
+                * 1024
+                * 1024,
+            file_types: overrides
+                .and_then(|o| o.download_file_types.as_deref())
+                .unwrap_or(&config.download_file_types),
+        }
+    }
+
+    fn skips_directory(&self, name: &str) -> bool {
+        self.skip_directories.contains(&name.to_lowercase())
+    }
+
+    /// Why a file at path is skipped, if it is. Only videos are held to min_file_size_mb, so
+    /// subtitles make it through.
+    fn skips_file(&self, path: &str, file: &FileNode) -> Option<&'static str> {
+        if is_junk(self.junk_files, path) {
+            Some("junk file")
+        } else if file.file_type == "VIDEO"
+            && file.size.is_some_and(|size| size < self.min_file_size)
+        {
+            Some("file below min_file_size_mb")
+        } else {
+            None
+        }
+    }
+
+    /// Whether download_file_types takes the file, by its put.io file type or extension.
+    fn wants_file(&self, file: &FileNode) -> bool {
+        let extension = Path::new(&file.name)
+            .extension()
+            .map(|e| e.to_string_lossy().to_lowercase());
+        let extension = extension.as_deref();
+        self.file_types
+            .iter()
+            .any(|file_type| match file_type.strip_prefix('.') {
+                Some(wanted) => extension.is_some_and(|e| e.eq_ignore_ascii_case(wanted)),
+                None if file_type.eq_ignore_ascii_case("subtitle") => {
+                    extension.is_some_and(|e| SUBTITLE_EXTENSIONS.contains(&e))
+                }
+                None if file_type.eq_ignore_ascii_case("ebook") => {
+                    extension.is_some_and(|e| EBOOK_EXTENSIONS.contains(&e))
+                }
+                None => file.file_type.eq_ignore_ascii_case(file_type),
+            })
+    }
+
+    /// Collects the files below node that pass the filters.
+    fn files<'n>(&self, node: &'n FileNode, path: &Path, files: &mut Vec<&'n FileNode>) {
+        let path = path.join(&node.name);
+        match node.file_type.as_str() {
+            "FOLDER" if !self.skips_directory(&node.name) => {
+                for child in &node.children {
+                    self.files(child, &path, files);
+                }
+            }
+            "FOLDER" => {}
+            _ if self.wants_file(node)
+                && self.skips_file(&path.to_string_lossy(), node).is_none() =>
+            {
+                files.push(node)
+            }
+            _ => {}
+        }
+    }
+}
+
+/// With flatten_single_file, returns the video to download straight into the download directory
+/// when a transfer's folder holds nothing else worth downloading, like subtitles.
+pub(crate) fn flattened_video<'a>(
+    app_data: &AppData,
+    tree: &'a FileNode,
+    hash: &str,
+) -> Option<&'a FileNode> {
+    if !app_data.config.flatten_single_file || tree.file_type != "FOLDER" {
+        return None;
+    }
+    let mut files = Vec::new();
+    Filters::new(app_data, hash).files(tree, Path::new(""), &mut files);
+    match files[..] {
+        [video] if video.file_type == "VIDEO" => Some(video),
+        _ => None,
+    }
+}
+
+/// Name a flattened video is downloaded as.
+pub(crate) fn flattened_name(app_data: &AppData, hash: &str, video: &FileNode) -> String {
+    rename(app_data, &rename_file(app_data, hash, video))
+}
+
+/// Lists a put.io file or folder and returns the targets to download it to override_base_path,
+/// or the download directory.
+pub(crate) async fn recurse_download_targets(
     app_data: &Data<AppData>,
     file_id: u64,
     hash: &str,
     override_base_path: Option<String>,
-    top_level: bool,
+    top_level_name: Option<String>,
 ) -> Result<Vec<DownloadTarget>> {
-    let base_path = "."; //override_base_path.unwrap_or(app_data.config.download_directory.clone());
+    let tree = fetch_file_tree(app_data, file_id).await?;
+    Ok(tree_download_targets(
+        app_data,
+        &tree,
+        hash,
+        override_base_path,
+        top_level_name,
+    ))
+}
+
+/// Turns a file tree into download targets. Files get their download URL once they are fetched.
+pub(crate) fn tree_download_targets(
+    app_data: &Data<AppData>,
+    node: &FileNode,
+    hash: &str,
+    override_base_path: Option<String>,
+    top_level_name: Option<String>,
+) -> Vec<DownloadTarget> {
+    let filters = Filters::new(app_data, hash);
+    filtered_download_targets(
+        app_data,
+        &filters,
+        node,
+        hash,
+        override_base_path,
+        top_level_name,
+    )
+}
+
+fn filtered_download_targets(
+    app_data: &Data<AppData>,
+    filters: &Filters,
+    node: &FileNode,
+    hash: &str,
+    override_base_path: Option<String>,
+    top_level_name: Option<String>,
+) -> Vec<DownloadTarget> {
+    let base_path = override_base_path
+        .unwrap_or_else(|| category_folders::download_directory(app_data, Some(hash)));
+    let top_level = top_level_name.is_some();
     let mut targets = Vec::<DownloadTarget>::new();
-    let response = putio::list_files(&app_data.config.putio.api_key, file_id).await?;
+    let name = top_level_name.unwrap_or_else(|| node.name.clone());
     let to = Path::new(&base_path)
-        .join(&response.parent.name)
+        .join(normalize(app_data, &name))
         .to_string_lossy()
         .to_string();
 
-    match response.parent.file_type.as_str() {
-        "FOLDER" => {
-            if !app_data
-                .config
-                .skip_directories
-                .contains(&response.parent.name.to_lowercase())
-            {
-                let new_base_path = to.clone();
-
-                targets.push(DownloadTarget {
-                    from: None,
-                    target_type: TargetType::Directory,
-                    to,
-                    top_level,
-                    transfer_hash: hash.to_string(),
-                });
-
-                for file in response.files {
-                    targets.append(
-                        &mut recurse_download_targets(
-                            app_data,
-                            file.id,
-                            hash,
-                            Some(new_base_path.clone()),
-                            false,
-                        )
-                        .await?,
-                    );
-                }
+    if node.file_type != "FOLDER" {
+        if let Some(reason) = filters.skips_file(&to, node) {
+            info!("{}: skipping {}", to, reason);
+            return targets;
+        }
+    }
+
+    match node.file_type.as_str() {
+        "FOLDER" if !filters.skips_directory(&node.name) => {
+            let new_base_path = to.clone();
+
+            targets.push(DownloadTarget {
+                from: None,
+                file_id: None,
+                size: None,
+                crc32: None,
+                target_type: TargetType::Directory,
+                to,
+                top_level,
+                transfer_hash: hash.to_string(),
+                file_kind: FileKind::Other,
+            });
+
+            for child in &node.children {
+                targets.append(&mut filtered_download_targets(
+                    app_data,
+                    filters,
+                    child,
+                    hash,
+                    Some(new_base_path.clone()),
+                    None,
+                ));
             }
         }
-        "VIDEO" => {
-            // Get download URL for file
-            let url = putio::url(&app_data.config.putio.api_key, response.parent.id).await?;
+        "FOLDER" => {}
+        // Keeps its name, since unrar finds the volumes of an archive by theirs
+        _ if is_archive(&node.name)
+            && (app_data.config.extract_archives || filters.wants_file(node)) =>
+        {
             targets.push(DownloadTarget {
-                from: Some(url),
+                from: None,
+                file_id: Some(node.id),
+                size: node.size,
+                crc32: node.crc32.clone(),
                 target_type: TargetType::File,
                 to,
                 top_level,
                 transfer_hash: hash.to_string(),
+                file_kind: FileKind::Other,
+            });
+        }
+        _ if filters.wants_file(node) => {
+            let to = match top_level {
+                true => to,
+                false => Path::new(&base_path)
+                    .join(normalize(app_data, &rename_file(app_data, hash, node)))
+                    .to_string_lossy()
+                    .to_string(),
+            };
+            targets.push(DownloadTarget {
+                from: None,
+                file_id: Some(node.id),
+                size: node.size,
+                crc32: node.crc32.clone(),
+                target_type: TargetType::File,
+                to,
+                top_level,
+                transfer_hash: hash.to_string(),
+                file_kind: FileKind::of(&node.name, Some(&node.file_type)),
             });
         }
         _ => {}
     }
 
-    Ok(targets)
+    targets
+}
+
+/// Whether the file at path matches one of the junk patterns. Patterns are matched
+/// case-insensitively against the file name, a leading `*` matches any prefix.
+fn is_junk(patterns: &[String], path: &str) -> bool {
+    let Some(name) = Path::new(path).file_name() else {
+        return false;
+    };
+    let name = name.to_string_lossy().to_lowercase();
+    patterns.iter().any(|pattern| {
+        let pattern = pattern.to_lowercase();
+        match pattern.strip_prefix('*') {
+            Some(suffix) => name.ends_with(suffix),
+            None => name == pattern,
+        }
+    })
 }
 
 #[derive(Clone)]
 pub enum TransferMessage {
-    QueuedForDownload(Transfer),
-    Downloaded(Transfer),
+    /// A transfer was added to the download queue.
+    QueuedForDownload,
+    Downloaded(Box<Transfer>),
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct DownloadTarget {
+    /// Download URL, fetched for file_id when left out.
     pub from: Option<String>,
+    /// put.io file to get a fresh download URL for.
+    #[serde(default)]
+    pub file_id: Option<u64>,
+    /// Size and CRC32 put.io reports for the file, to verify it against.
+    #[serde(default)]
+    pub size: Option<u64>,
+    #[serde(default)]
+    pub crc32: Option<String>,
     pub to: String,
     pub target_type: TargetType,
     pub top_level: bool,
     pub transfer_hash: String,
+    #[serde(default)]
+    pub file_kind: FileKind,
+}
+
+/// What a downloaded file is, to tell whether an arr imports it.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum FileKind {
+    /// Also what targets recorded before there were other kinds are, since only videos were
+    /// downloaded then.
+    #[default]
+    Video,
+    Audio,
+    Ebook,
+    /// Subtitles, archives and whatever else the arrs don't report importing.
+    Other,
+}
+
+impl FileKind {
+    /// The kind of a file by its put.io file type, or by its extension without one.
+    pub fn of(name: &str, file_type: Option<&str>) -> Self {
+        let extension = Path::new(name)
+            .extension()
+            .map(|e| e.to_string_lossy().to_lowercase())
+            .unwrap_or_default();
+        let extension = extension.as_str();
+        match file_type {
+            Some("VIDEO") => Self::Video,
+            Some("AUDIO") => Self::Audio,
+            _ if EBOOK_EXTENSIONS.contains(&extension) => Self::Ebook,
+            None if VIDEO_EXTENSIONS.contains(&extension) => Self::Video,
+            None if AUDIO_EXTENSIONS.contains(&extension) => Self::Audio,
+            _ => Self::Other,
+        }
+    }
 }
 
 impl Display for DownloadTarget {
@@ -161,7 +800,7 @@ pub enum TargetType {
 /// 2. Maintains a list of seen transfer IDs to avoid re-processing
 /// 3. Polls Put.io API at configured intervals to check for new transfers
 /// 4. When a new downloadable transfer is found:
-///    - Queues it for download by sending QueuedForDownload message
+///    - Adds it to the download queue and sends a QueuedForDownload message
 ///    - Marks it as seen to avoid duplicate processing
 /// 5. Cleans up the seen transfers list by removing completed/deleted transfers
 ///
@@ -172,66 +811,285 @@ pub enum TargetType {
 /// # Returns
 /// Result indicating success or failure of the monitoring process
 pub async fn produce_transfers(app_data: Data<AppData>, tx: Sender<TransferMessage>) -> Result<()> {
-    let putio_check_interval = std::time::Duration::from_secs(app_data.config.polling_interval);
     let target_folder_id = {
         let folder_id = app_data.root_folder_id.read().unwrap();
         *folder_id
     };
     let mut seen = Vec::<u64>::new();
+    // Whether we held the lock on the last pass
+    let mut active = false;
+    let mut seen_streams = Vec::<u64>::new();
+    let mut auth_failures = 0;
+    let mut backlog_alert = false;
     info!("Starting to monitor transfers.");
 
     // Set the start time
     let mut start = std::time::Instant::now();
+    let mut last_tick = std::time::Instant::now();
 
     loop {
-        if let Ok(list_transfer_response) =
-            putio::list_transfers(&app_data.config.putio.api_key).await
-        {
-            // filter for transfers with root_folder_id as parent
-            let transfers: Vec<&PutIOTransfer> = list_transfer_response
-                .transfers
-                .iter()
-                .filter(|t| t.save_parent_id == Some(target_folder_id))
-                .collect();
+        app_data
+            .state
+            .increment("seconds_active", last_tick.elapsed().as_secs());
+        last_tick += std::time::Duration::from_secs(last_tick.elapsed().as_secs());
+
+        // Without the lock, what was going on stops, and is picked up again from the state
+        // database once InstanceLock::keep takes over again
+        if app_data.standing_by() {
+            app_data.queue.clear();
+            seen.clear();
+            active = false;
+            sleep(app_data.polling_interval()).await;
+            continue;
+        }
+        if !active {
+            super::remove_partials(&app_data);
+            active = true;
+        }
 
-            info!("Found {} transfers", transfers.len());
+        if let Err(e) = category_folders::refresh(&app_data, target_folder_id).await {
+            warn!("Unable to list category folders: {}", e);
+        }
 
-            for putio_transfer in &transfers {
-                let transfer = Transfer::from(app_data.clone(), putio_transfer);
+        let list_transfer_response = match app_data.putio.list_transfers().await {
+            Ok(response) => {
+                auth_failures = 0;
+                if app_data.auth_error.swap(false, Ordering::SeqCst) {
+                    info!("put.io accepts our api key again, resuming");
+                }
+                response
+            }
+            Err(e) if e.to_string().contains("401 Unauthorized") => {
+                auth_failures += 1;
+                if auth_failures >= AUTH_FAILURE_THRESHOLD {
+                    if !app_data.auth_error.swap(true, Ordering::SeqCst) {
+                        let message = format!(
+                            "put.io rejected the api key {} times in a row, pausing until it is accepted again",
+                            auth_failures
+                        );
+                        error!("{}", message);
+                        notify(&app_data.config, "auth_error", &message).await;
+                    }
+                    sleep(AUTH_RETRY_INTERVAL).await;
+                } else {
+                    warn!("List put.io transfers failed: {}. Retrying..", e);
+                    sleep(app_data.polling_interval()).await;
+                }
+                continue;
+            }
+            Err(e) => {
+                warn!("List put.io transfers failed: {}. Retrying..", e);
+                sleep(app_data.polling_interval()).await;
+                continue;
+            }
+        };
 
-                if seen.contains(&putio_transfer.id) || !putio_transfer.is_downloadable() {
-                    info!("  {}", putio_transfer);
-                    continue;
+        forget_vanished(&app_data, &list_transfer_response.transfers);
+
+        // filter for transfers with root_folder_id as parent, leaving out streams
+        let mut transfers: Vec<&PutIOTransfer> = list_transfer_response
+            .transfers
+            .iter()
+            .filter(|t| category_folders::owns(&app_data, target_folder_id, t))
+            .filter(|t| {
+                if t.is_stream() && !seen_streams.contains(&t.id) {
+                    info!("  {}: skipping {:?} transfer", t, t.type_);
+                    seen_streams.push(t.id);
                 }
+                !t.is_stream()
+            })
+            .collect();
 
-                info!("  {}: ready for download", transfer);
-                tx.send(TransferMessage::QueuedForDownload(transfer))
-                    .await?;
-                seen.push(putio_transfer.id);
+        info!("Found {} transfers", transfers.len());
+
+        let duplicates = duplicate_ids(transfers.iter().copied(), &seen);
+        for t in transfers.iter().filter(|t| duplicates.contains(&t.id)) {
+            let kept = transfers
+                .iter()
+                .find(|k| k.hash == t.hash && !duplicates.contains(&k.id))
+                .copied();
+            remove_duplicate(&app_data, t, kept).await;
+        }
+        transfers.retain(|t| !duplicates.contains(&t.id));
+
+        let names = assign_local_names(&app_data, transfers.iter().copied());
+
+        for putio_transfer in &transfers {
+            category_folders::reconcile(&app_data, putio_transfer);
+            // Added again into the folder it was saved to, which keeps its category folder
+            let folder_id = putio_transfer.save_parent_id.unwrap_or(target_folder_id);
+            if putio_transfer.status == PutIOTransferStatus::Error
+                && readd(&app_data, putio_transfer, folder_id).await
+            {
+                continue;
             }
+            let transfer = Transfer {
+                local_name: names[&putio_transfer.id].clone(),
+                ..Transfer::from(app_data.clone(), putio_transfer)
+            };
 
-            // Remove any transfers from seen that are not in the active transfers
-            let active_ids: Vec<u64> = transfers.into_iter().map(|t| t.id).collect();
-            seen.retain(|t| active_ids.contains(t));
-
-            // Log status when 60 seconds have passed since last time
-            if start.elapsed().as_secs() >= 60 {
-                info!(
-                    "Active transfers: {}",
-                    list_transfer_response.transfers.len()
-                );
-                list_transfer_response
-                    .transfers
-                    .iter()
-                    .for_each(|t| info!("  {}", Transfer::from(app_data.clone(), t)));
-
-                start = std::time::Instant::now();
+            if seen.contains(&putio_transfer.id) || !putio_transfer.is_downloadable() {
+                info!("  {}", putio_transfer);
+                continue;
+            }
+            if app_data
+                .state
+                .failure(&transfer.key())
+                .is_some_and(|f| f.failed)
+            {
+                info!("  {}: failed, skipping", putio_transfer);
+                continue;
+            }
+            match app_data.state.stage(&transfer.key()) {
+                // Downloaded before a restart. The arrs may have moved the files already, so
+                // downloading again would bring them back.
+                Some(Stage::Downloaded) => {
+                    transfer
+                        .span("seeding")
+                        .in_scope(|| info!("  {}: downloaded before restarting", transfer));
+                    seen.push(putio_transfer.id);
+                    let targets = recorded_targets(&app_data, &transfer.key());
+                    tx.send(TransferMessage::Downloaded(Box::new(Transfer {
+                        targets: Some(targets),
+                        ..transfer
+                    })))
+                    .await?;
+                    continue;
+                }
+                Some(Stage::Kept) => {
+                    info!("  {}: done, kept on put.io", putio_transfer);
+                    seen.push(putio_transfer.id);
+                    continue;
+                }
+                Some(Stage::Downloading) => {
+                    let recorded = app_data.state.download_targets(&transfer.key());
+                    let done = recorded.iter().filter(|(_, done)| *done).count();
+                    transfer.span("queued").in_scope(|| {
+                        info!(
+                            "  {}: resuming download, {} of {} targets done",
+                            transfer,
+                            done,
+                            recorded.len()
+                        )
+                    });
+                }
+                None => {}
             }
 
-            sleep(putio_check_interval).await;
-        } else {
-            warn!("List put.io transfers failed. Retrying..");
+            transfer
+                .span("queued")
+                .in_scope(|| info!("  {}: ready for download", transfer));
+            app_data.queue.push(transfer);
+            tx.send(TransferMessage::QueuedForDownload).await?;
+            seen.push(putio_transfer.id);
+        }
+
+        backlog::check(&app_data, &mut backlog_alert).await;
+
+        // Remove any transfers from seen that are not in the active transfers
+        let active_ids: Vec<u64> = transfers.into_iter().map(|t| t.id).collect();
+        seen.retain(|t| active_ids.contains(t));
+        seen_streams.retain(|id| list_transfer_response.transfers.iter().any(|t| t.id == *id));
+
+        // Log status when 60 seconds have passed since last time
+        if start.elapsed().as_secs() >= 60 {
+            info!(
+                "Active transfers: {}",
+                list_transfer_response.transfers.len()
+            );
+            list_transfer_response
+                .transfers
+                .iter()
+                .for_each(|t| info!("  {}", Transfer::from(app_data.clone(), t)));
+
+            start = std::time::Instant::now();
+        }
+
+        // put.io calling back about a finished transfer cuts the wait short
+        tokio::select! {
+            _ = sleep(app_data.polling_interval()) => {}
+            _ = app_data.putio_callback.notified() => {}
+        }
+    }
+}
+
+/// Targets recorded for a transfer when it started downloading.
+fn recorded_targets(app_data: &AppData, key: &str) -> Vec<DownloadTarget> {
+    app_data
+        .state
+        .download_targets(key)
+        .into_iter()
+        .filter_map(|(target, _)| serde_json::from_str(&target).ok())
+        .collect()
+}
+
+/// Forgets the recorded downloads and local names of transfers that are no longer on put.io, like
+/// ones removed while putioarr wasn't running, and removes the partial files they left behind.
+pub(crate) fn forget_vanished(app_data: &AppData, transfers: &[PutIOTransfer]) {
+    let keys: HashSet<String> = transfers
+        .iter()
+        .map(|t| transfer_key(t.hash.as_deref(), t.id))
+        .collect();
+    app_data.disk_space.retain(&keys);
+    for key in app_data.state.tracked_transfers() {
+        if keys.contains(&key) {
             continue;
-        };
+        }
+        for (target, done) in app_data.state.download_targets(&key) {
+            let Ok(target) = serde_json::from_str::<DownloadTarget>(&target) else {
+                continue;
+            };
+            let partial = partial_path(&target.to);
+            if !done && fs::remove_file(&partial).is_ok() {
+                info!("Removed partial download {}", partial);
+            }
+        }
+        app_data.state.forget_transfer(&key);
+    }
+    for hash in app_data.state.local_names().into_keys() {
+        if !keys.contains(&hash) {
+            app_data.state.forget_local_name(&hash);
+        }
+    }
+}
+
+/// Adds a transfer that put.io failed to fetch, like a dead magnet, again from the magnet or
+/// torrent it was added from, up to readd_attempts times. Returns whether it was re-added.
+async fn readd(app_data: &Data<AppData>, t: &PutIOTransfer, folder_id: u64) -> bool {
+    let Some(hash) = &t.hash else {
+        return false;
+    };
+    let Some((source, readds)) = app_data.state.source(hash) else {
+        return false;
+    };
+    if readds >= app_data.config.readd_attempts {
+        return false;
+    }
+
+    if let Err(e) = app_data.putio.remove_transfer(t.id).await {
+        warn!("  {}: unable to remove failed transfer: {}", t, e);
+        return false;
+    }
+    let added = match &source {
+        Source::Magnet(url) => app_data.putio.add_transfer(folder_id, url).await,
+        Source::Torrent(bytes) => app_data.putio.upload_file(folder_id, bytes).await,
+    };
+    match added {
+        Ok(_) => {
+            app_data.state.record_readd(hash);
+            info!(
+                "  {}: failed on put.io ({}), added again ({} of {})",
+                t,
+                t.error_message.as_deref().unwrap_or("no error message"),
+                readds + 1,
+                app_data.config.readd_attempts
+            );
+        }
+        Err(e) => {
+            let message = format!("{}: unable to add failed transfer again: {}", t.name, e);
+            error!("  {}: unable to add failed transfer again: {}", t, e);
+            notify_failure(&app_data.config, FailureCategory::Putio, &message).await;
+        }
     }
+    true
 }
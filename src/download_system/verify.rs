@@ -0,0 +1,139 @@
+// Re-checks downloaded files against put.io, for users who suspect their storage silently
+// corrupted them.
+use super::{
+    download::{download_all, DownloadTargetMessage},
+    failure::summarize,
+    transfer::{local_names, DownloadTarget, TargetType, Transfer},
+};
+use crate::{category_folders, AppData};
+use actix_web::web::Data;
+use anyhow::{bail, Context, Result};
+use async_channel::Sender;
+use serde::Serialize;
+use std::{fs, io};
+use tracing::{info, warn};
+
+#[derive(Debug, Serialize)]
+pub struct Report {
+    /// Number of files checked.
+    pub checked: usize,
+    /// Files that were missing or corrupt and were downloaded again.
+    pub repaired: Vec<String>,
+}
+
+/// Checks the downloaded files of the transfer with the given hash against the size and CRC32
+/// put.io reports for them, and downloads missing or corrupt files again. The transfer has to be
+/// in the putioarr folder still.
+pub async fn verify(
+    app_data: &Data<AppData>,
+    dtx: &Sender<DownloadTargetMessage>,
+    hash: &str,
+) -> Result<Report> {
+    let root_folder_id = *app_data.root_folder_id.read().unwrap();
+    category_folders::refresh(app_data, root_folder_id).await?;
+    let transfers: Vec<_> = app_data
+        .putio
+        .list_transfers()
+        .await?
+        .transfers
+        .into_iter()
+        .filter(|t| category_folders::owns(app_data, root_folder_id, t) && !t.is_stream())
+        .collect();
+    let names = local_names(app_data, &transfers);
+    let putio_transfer = transfers
+        .iter()
+        .find(|t| {
+            t.hash
+                .as_deref()
+                .is_some_and(|h| h.eq_ignore_ascii_case(hash))
+        })
+        .with_context(|| format!("No transfer with hash {} in the putioarr folder", hash))?;
+    if !putio_transfer.is_downloadable() {
+        bail!("{} hasn't finished on put.io yet", putio_transfer.name);
+    }
+    let transfer = Transfer {
+        local_name: names[&putio_transfer.id].clone(),
+        ..Transfer::from(app_data.clone(), putio_transfer)
+    };
+
+    let targets = transfer.get_download_targets().await?;
+    let (directories, files): (Vec<_>, Vec<_>) = targets
+        .into_iter()
+        .partition(|t| t.target_type == TargetType::Directory);
+    let checked = files.len();
+    let mut repair = vec![];
+    for target in files {
+        let checked_target = target.clone();
+        match tokio::task::spawn_blocking(move || check(&checked_target)).await? {
+            Ok(()) => info!("{}: verified", target),
+            Err(problem) => {
+                warn!("{}: {}, downloading again", target, problem);
+                if let Err(e) = fs::remove_file(&target.to) {
+                    if e.kind() != io::ErrorKind::NotFound {
+                        bail!("Unable to remove {}: {}", target.to, e);
+                    }
+                }
+                repair.push(target);
+            }
+        }
+    }
+
+    let repaired: Vec<String> = repair.iter().map(|t| t.to.clone()).collect();
+    if !repair.is_empty() {
+        // Directories that went missing are created again, existing ones are left alone.
+        let targets: Vec<DownloadTarget> = directories.into_iter().chain(repair).collect();
+        let errors = download_all(dtx, &targets, &transfer.span("verify")).await?;
+        if !errors.is_empty() {
+            bail!(
+                "Not all files of {} could be downloaded again: {}",
+                transfer,
+                summarize(&errors)
+            );
+        }
+    }
+    info!(
+        "{}: verified {} files, downloaded {} again",
+        transfer,
+        checked,
+        repaired.len()
+    );
+    Ok(Report { checked, repaired })
+}
+
+/// Describes what is wrong with a downloaded file, if anything.
+fn check(target: &DownloadTarget) -> std::result::Result<(), String> {
+    let metadata = fs::metadata(&target.to).map_err(|_| String::from("missing"))?;
+    if let Some(size) = target.size {
+        if metadata.len() != size {
+            return Err(format!("size is {} instead of {}", metadata.len(), size));
+        }
+    }
+    if let Some(expected) = &target.crc32 {
+        let actual = crc32(&target.to).map_err(|e| format!("unreadable: {}", e))?;
+        if !actual.eq_ignore_ascii_case(expected) {
+            return Err(format!("CRC32 is {} instead of {}", actual, expected));
+        }
+    }
+    Ok(())
+}
+
+fn crc32(path: &str) -> io::Result<String> {
+    let mut hasher = crc32fast::Hasher::new();
+    let mut file = fs::File::open(path)?;
+    io::copy(&mut file, &mut HashWriter(&mut hasher))?;
+    Ok(format!("{:08x}", hasher.finalize()))
+}
+
+/// Feeds everything written to it into a CRC32 hasher.
+struct HashWriter<'a>(&'a mut crc32fast::Hasher);
+
+impl io::Write for HashWriter<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.update(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
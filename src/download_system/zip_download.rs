@@ -0,0 +1,165 @@
+// Downloads the files of a transfer as a single zip that put.io creates of them, with
+// download_mode = "zip". Dozens of small files download a lot faster that way, and it takes a
+// single download URL instead of one per file.
+use super::{
+    download::{download_all, partial_path, DownloadTargetMessage},
+    transfer::{DownloadTarget, FileKind, StageSpan, TargetType},
+};
+use crate::{utils::set_owner, AppData, DownloadMode};
+use actix_web::web::Data;
+use anyhow::{bail, Result};
+use async_channel::Sender;
+use std::{
+    fs::{self, File},
+    io,
+    path::Path,
+    time::{Duration, Instant},
+};
+use tokio::time::sleep;
+use tracing::{info, warn};
+use zip::ZipArchive;
+
+/// How long put.io gets to create a zip before the files are downloaded one by one instead.
+const ZIP_TIMEOUT: Duration = Duration::from_secs(60 * 60);
+
+/// Downloads targets like [`download_all`]. With download_mode = "zip", files are downloaded as
+/// a zip when there is more than one left to download. Files the zip turns out not to have, or
+/// all of them when the zip can't be downloaded, are downloaded one by one after all.
+pub async fn download_targets(
+    app_data: &Data<AppData>,
+    dtx: &Sender<DownloadTargetMessage>,
+    targets: &[DownloadTarget],
+    span: &StageSpan,
+) -> Result<Vec<String>> {
+    let (directories, files): (Vec<DownloadTarget>, Vec<DownloadTarget>) = targets
+        .iter()
+        .filter(|t| t.target_type == TargetType::Directory || !Path::new(&t.to).exists())
+        .cloned()
+        .partition(|t| t.target_type == TargetType::Directory);
+    if app_data.config.download_mode == DownloadMode::Files || files.len() < 2 {
+        return download_all(dtx, targets, span).await;
+    }
+
+    let errors = download_all(dtx, &directories, span).await?;
+    if !errors.is_empty() {
+        return Ok(errors);
+    }
+    let hash = files[0].transfer_hash.clone();
+    let zip_path =
+        Path::new(&app_data.config.download_directory).join(format!(".putioarr-{}.zip", hash));
+    let remaining = match fetch_zip(app_data, dtx, &files, &zip_path, span).await {
+        Ok(()) => {
+            let app_data = app_data.clone();
+            let path = zip_path.clone();
+            let span = span.clone();
+            let extracted = tokio::task::spawn_blocking(move || {
+                span.in_scope(|| extract(&app_data, &path, files))
+            })
+            .await?;
+            match extracted {
+                Ok(remaining) => remaining,
+                Err(e) => {
+                    let _ = fs::remove_file(&zip_path);
+                    bail!("Unable to extract {}: {:#}", zip_path.display(), e);
+                }
+            }
+        }
+        Err(e) => {
+            warn!(
+                "{}: unable to download as zip, downloading file by file: {:#}",
+                hash, e
+            );
+            files
+        }
+    };
+    let _ = fs::remove_file(&zip_path);
+    if remaining.is_empty() {
+        return Ok(vec![]);
+    }
+    info!(
+        "{}: {} files not in the zip, downloading them",
+        hash,
+        remaining.len()
+    );
+    download_all(dtx, &remaining, span).await
+}
+
+/// Has put.io create a zip of the files and downloads it to path, through the download workers.
+async fn fetch_zip(
+    app_data: &Data<AppData>,
+    dtx: &Sender<DownloadTargetMessage>,
+    files: &[DownloadTarget],
+    path: &Path,
+    span: &StageSpan,
+) -> Result<()> {
+    let file_ids: Vec<u64> = files.iter().filter_map(|t| t.file_id).collect();
+    let zip_id = app_data.putio.create_zip(&file_ids).await?;
+    info!(
+        "{}: waiting for put.io to zip {} files",
+        files[0].transfer_hash,
+        file_ids.len()
+    );
+    let started = Instant::now();
+    let url = loop {
+        if let Some(url) = app_data.putio.zip_url(zip_id).await? {
+            break url;
+        }
+        if started.elapsed() > ZIP_TIMEOUT {
+            bail!(
+                "put.io didn't finish the zip within {}s",
+                ZIP_TIMEOUT.as_secs()
+            );
+        }
+        sleep(app_data.polling_interval()).await;
+    };
+    let zip = DownloadTarget {
+        from: Some(url),
+        file_id: None,
+        size: None,
+        crc32: None,
+        to: path.to_string_lossy().to_string(),
+        target_type: TargetType::File,
+        top_level: false,
+        transfer_hash: files[0].transfer_hash.clone(),
+        file_kind: FileKind::Other,
+    };
+    let _ = fs::remove_file(path);
+    let errors = download_all(dtx, &[zip], span).await?;
+    if let Some(error) = errors.first() {
+        bail!("{}", error);
+    }
+    Ok(())
+}
+
+/// Extracts the files among targets from the zip. They are matched by size and CRC32, since the
+/// zip has the names on put.io, which can differ from the local ones. Returns the targets the zip
+/// didn't have.
+fn extract(
+    app_data: &AppData,
+    path: &Path,
+    mut targets: Vec<DownloadTarget>,
+) -> Result<Vec<DownloadTarget>> {
+    let mut zip = ZipArchive::new(File::open(path)?)?;
+    for i in 0..zip.len() {
+        let mut entry = zip.by_index(i)?;
+        if entry.is_dir() {
+            continue;
+        }
+        let crc32 = format!("{:08x}", entry.crc32());
+        let Some(index) = targets.iter().position(|t| {
+            t.size == Some(entry.size()) && t.crc32.as_deref() == Some(crc32.as_str())
+        }) else {
+            continue;
+        };
+        let target = targets.remove(index);
+        let tmp_path = partial_path(&target.to);
+        // Reading the entry to the end checks its CRC32
+        io::copy(&mut entry, &mut File::create(&tmp_path)?)?;
+        set_owner(app_data, Path::new(&tmp_path))?;
+        fs::rename(&tmp_path, &target.to)?;
+        app_data.state.finish_target(&target.to);
+        app_data.state.increment("files_added", 1);
+        info!("{}: extracted from zip", target);
+    }
+    Ok(targets)
+}
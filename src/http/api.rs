@@ -0,0 +1,278 @@
+// Management API for changing settings while running, without a restart.
+use crate::{
+    category_folders,
+    download_system::{pause, set_download_workers, set_orchestration_workers, verify::verify},
+    http::{
+        handlers::check_active,
+        routes::{unauthorized, validate_user},
+    },
+    status::status,
+    AppData,
+};
+use actix_web::{get, post, web, HttpRequest, HttpResponse};
+use anyhow::{bail, Context, Result};
+use log::info;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use toml_edit::{value, DocumentMut};
+
+#[derive(Serialize)]
+struct Settings {
+    download_workers: usize,
+    orchestration_workers: usize,
+    polling_interval: u64,
+    /// KB/s for all downloads together, or 0 for no limit.
+    max_download_rate_kbps: u64,
+}
+
+impl Settings {
+    fn current(app_data: &AppData) -> Self {
+        Self {
+            download_workers: app_data.download_pool.target(),
+            orchestration_workers: app_data.orchestration_pool.target(),
+            polling_interval: app_data.polling_interval().as_secs(),
+            max_download_rate_kbps: app_data.download_throttle.rate_kbps().unwrap_or(0),
+        }
+    }
+}
+
+/// Settings to change, the ones left out stay as they are.
+#[derive(Deserialize)]
+struct SettingsUpdate {
+    download_workers: Option<usize>,
+    orchestration_workers: Option<usize>,
+    polling_interval: Option<u64>,
+    /// 0 removes the limit.
+    max_download_rate_kbps: Option<u64>,
+    /// Also write the changes to the config file, so they survive a restart.
+    #[serde(default)]
+    persist: bool,
+}
+
+impl SettingsUpdate {
+    fn validate(&self) -> Result<()> {
+        if self.download_workers == Some(0) {
+            bail!("download_workers must be at least 1");
+        }
+        if self.orchestration_workers == Some(0) {
+            bail!("orchestration_workers must be at least 1");
+        }
+        if self.polling_interval == Some(0) {
+            bail!("polling_interval must be at least 1");
+        }
+        Ok(())
+    }
+}
+
+#[get("/api/settings")]
+async fn get_settings(req: HttpRequest, app_data: web::Data<AppData>) -> HttpResponse {
+    if validate_user(req, &app_data).await.is_err() {
+        return unauthorized();
+    }
+    HttpResponse::Ok().json(Settings::current(&app_data))
+}
+
+#[post("/api/settings")]
+async fn update_settings(
+    req: HttpRequest,
+    app_data: web::Data<AppData>,
+    update: web::Json<SettingsUpdate>,
+) -> HttpResponse {
+    if validate_user(req, &app_data).await.is_err() {
+        return unauthorized();
+    }
+    if let Err(e) = update.validate() {
+        return HttpResponse::BadRequest().body(e.to_string());
+    }
+
+    if let Some(count) = update.download_workers {
+        info!("Setting download workers to {}", count);
+        set_download_workers(&app_data, count);
+    }
+    if let Some(count) = update.orchestration_workers {
+        info!("Setting orchestration workers to {}", count);
+        set_orchestration_workers(&app_data, count);
+    }
+    if let Some(seconds) = update.polling_interval {
+        info!("Setting polling interval to {}s", seconds);
+        app_data.set_polling_interval(seconds);
+    }
+    if let Some(rate) = update.max_download_rate_kbps {
+        match rate {
+            0 => info!("Removing the download speed limit"),
+            rate => info!("Setting download speed limit to {} KB/s", rate),
+        }
+        app_data.download_throttle.set_rate(Some(rate));
+    }
+    if update.persist {
+        if let Err(e) = persist(&app_data.config_path, &update) {
+            return HttpResponse::InternalServerError().body(format!("{:#}", e));
+        }
+        info!("Saved settings to {}", app_data.config_path);
+    }
+
+    HttpResponse::Ok().json(Settings::current(&app_data))
+}
+
+/// Checks a transfer's downloaded files against put.io and downloads missing or corrupt ones
+/// again, responding once that is done.
+#[post("/api/verify/{hash}")]
+async fn verify_transfer(
+    req: HttpRequest,
+    app_data: web::Data<AppData>,
+    hash: web::Path<String>,
+) -> HttpResponse {
+    if validate_user(req, &app_data).await.is_err() {
+        return unauthorized();
+    }
+    if let Err(e) = check_active(&app_data) {
+        return HttpResponse::ServiceUnavailable().body(e.to_string());
+    }
+    let Some((dtx, _)) = app_data.channels.downloads() else {
+        return HttpResponse::ServiceUnavailable().body("Downloads aren't running");
+    };
+    match verify(&app_data, dtx, &hash).await {
+        Ok(report) => HttpResponse::Ok().json(report),
+        Err(e) => HttpResponse::InternalServerError().body(format!("{:#}", e)),
+    }
+}
+
+#[derive(Serialize)]
+struct PauseState {
+    hash: String,
+    paused: bool,
+}
+
+/// Pauses the download of a transfer, given by its put.io id or hash, until it is resumed. A
+/// running download stops between chunks and one that hasn't started yet is held back.
+#[post("/api/transfers/{id}/pause")]
+async fn pause_transfer(
+    req: HttpRequest,
+    app_data: web::Data<AppData>,
+    id: web::Path<String>,
+) -> HttpResponse {
+    if validate_user(req, &app_data).await.is_err() {
+        return unauthorized();
+    }
+    if let Err(e) = check_active(&app_data) {
+        return HttpResponse::ServiceUnavailable().body(e.to_string());
+    }
+    let hash = match transfer_hash(&app_data, &id).await {
+        Ok(Some(hash)) => hash,
+        Ok(None) => return HttpResponse::NotFound().body(format!("No transfer {}", id)),
+        Err(e) => return HttpResponse::InternalServerError().body(format!("{:#}", e)),
+    };
+    pause::pause(&app_data, &hash);
+    HttpResponse::Ok().json(PauseState { hash, paused: true })
+}
+
+#[post("/api/transfers/{id}/resume")]
+async fn resume_transfer(
+    req: HttpRequest,
+    app_data: web::Data<AppData>,
+    id: web::Path<String>,
+) -> HttpResponse {
+    if validate_user(req, &app_data).await.is_err() {
+        return unauthorized();
+    }
+    if let Err(e) = check_active(&app_data) {
+        return HttpResponse::ServiceUnavailable().body(e.to_string());
+    }
+    let hash = match transfer_hash(&app_data, &id).await {
+        Ok(Some(hash)) => hash,
+        Ok(None) => return HttpResponse::NotFound().body(format!("No transfer {}", id)),
+        Err(e) => return HttpResponse::InternalServerError().body(format!("{:#}", e)),
+    };
+    if let Err(e) = pause::resume(&app_data, &hash).await {
+        return HttpResponse::InternalServerError().body(format!("{:#}", e));
+    }
+    HttpResponse::Ok().json(PauseState {
+        hash,
+        paused: false,
+    })
+}
+
+/// The hash of the transfer in the putioarr folder with the given put.io id or hash. Transfers
+/// without a hash can't be paused, so they aren't found.
+async fn transfer_hash(app_data: &AppData, id: &str) -> Result<Option<String>> {
+    let root_folder_id = *app_data.root_folder_id.read().unwrap();
+    Ok(app_data
+        .putio
+        .list_transfers()
+        .await?
+        .transfers
+        .into_iter()
+        .filter(|t| category_folders::owns(app_data, root_folder_id, t))
+        .find(|t| {
+            t.id.to_string() == id
+                || t.hash
+                    .as_deref()
+                    .is_some_and(|h| h.eq_ignore_ascii_case(id))
+        })
+        .and_then(|t| t.hash))
+}
+
+/// Returns where every transfer is in the pipeline, with the progress and speed of its files, for
+/// dashboards and scripts.
+#[get("/api/status")]
+async fn pipeline_status(req: HttpRequest, app_data: web::Data<AppData>) -> HttpResponse {
+    if validate_user(req, &app_data).await.is_err() {
+        return unauthorized();
+    }
+    match status(&app_data).await {
+        Ok(status) => HttpResponse::Ok().json(status),
+        Err(e) => HttpResponse::InternalServerError().body(format!("{:#}", e)),
+    }
+}
+
+#[derive(Deserialize)]
+struct HistoryQuery {
+    limit: Option<usize>,
+}
+
+/// Returns the latest completion reports, newest first, when report_history is enabled.
+#[get("/api/history")]
+async fn history(
+    req: HttpRequest,
+    app_data: web::Data<AppData>,
+    query: web::Query<HistoryQuery>,
+) -> HttpResponse {
+    if validate_user(req, &app_data).await.is_err() {
+        return unauthorized();
+    }
+    let reports = match app_data.state.reports(query.limit.unwrap_or(100)) {
+        Ok(reports) => reports,
+        Err(e) => return HttpResponse::InternalServerError().body(format!("{:#}", e)),
+    };
+    let reports: Vec<serde_json::Value> = reports
+        .iter()
+        .filter_map(|report| serde_json::from_str(report).ok())
+        .collect();
+    HttpResponse::Ok().json(reports)
+}
+
+/// Writes changed settings to the config file, keeping its comments and layout.
+fn persist(config_path: &str, update: &SettingsUpdate) -> Result<()> {
+    let mut config = fs::read_to_string(config_path)
+        .with_context(|| format!("Unable to read {}", config_path))?
+        .parse::<DocumentMut>()
+        .with_context(|| format!("Unable to parse {}", config_path))?;
+    if let Some(count) = update.download_workers {
+        config["download_workers"] = value(count as i64);
+    }
+    if let Some(count) = update.orchestration_workers {
+        config["orchestration_workers"] = value(count as i64);
+    }
+    if let Some(seconds) = update.polling_interval {
+        config["polling_interval"] = value(seconds as i64);
+    }
+    match update.max_download_rate_kbps {
+        Some(0) => {
+            config.remove("max_download_rate_kbps");
+        }
+        Some(rate) => config["max_download_rate_kbps"] = value(rate as i64),
+        None => {}
+    }
+    fs::write(config_path, config.to_string())
+        .with_context(|| format!("Unable to write {}", config_path))
+}
@@ -0,0 +1,144 @@
+// Serves the download directory over HTTP, for importing on a host without shared storage.
+use crate::{
+    http::routes::{unauthorized, validate_user},
+    AppData,
+};
+use actix_web::{get, web, HttpRequest, HttpResponse};
+use anyhow::{Context, Result};
+use futures::stream;
+use serde::Serialize;
+use std::{
+    fs,
+    io::SeekFrom,
+    path::{Path, PathBuf},
+};
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+
+/// Size of the chunks files are sent in.
+const CHUNK_SIZE: usize = 256 * 1024;
+
+#[derive(Serialize)]
+struct Entry {
+    name: String,
+    directory: bool,
+    size: u64,
+}
+
+/// Sends a file from the download directory, or lists a directory in it as JSON. Supports single
+/// byte ranges, so interrupted transfers can be resumed.
+#[get("/files/{path:.*}")]
+async fn serve_file(
+    req: HttpRequest,
+    app_data: web::Data<AppData>,
+    path: web::Path<String>,
+) -> HttpResponse {
+    if !app_data.config.serve_downloads {
+        return HttpResponse::NotFound().finish();
+    }
+    if validate_user(req.clone(), &app_data).await.is_err() {
+        return unauthorized();
+    }
+    let Some(path) = resolve(&app_data.config.download_directory, &path) else {
+        return HttpResponse::NotFound().finish();
+    };
+
+    let result = if path.is_dir() {
+        list(&path).map(|entries| HttpResponse::Ok().json(entries))
+    } else {
+        send(&req, &path).await
+    };
+    result.unwrap_or_else(|e| HttpResponse::InternalServerError().body(format!("{:#}", e)))
+}
+
+/// Resolves a request path inside the download directory. Paths that lead outside of it, through
+/// `..` or symlinks, are refused.
+fn resolve(download_directory: &str, path: &str) -> Option<PathBuf> {
+    let root = fs::canonicalize(download_directory).ok()?;
+    let path = fs::canonicalize(root.join(path)).ok()?;
+    path.starts_with(&root).then_some(path)
+}
+
+fn list(dir: &Path) -> Result<Vec<Entry>> {
+    let mut entries = fs::read_dir(dir)?
+        .map(|entry| {
+            let entry = entry?;
+            let metadata = entry.metadata()?;
+            Ok(Entry {
+                name: entry.file_name().to_string_lossy().to_string(),
+                directory: metadata.is_dir(),
+                size: metadata.len(),
+            })
+        })
+        .collect::<Result<Vec<_>>>()?;
+    entries.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(entries)
+}
+
+async fn send(req: &HttpRequest, path: &Path) -> Result<HttpResponse> {
+    let mut file = tokio::fs::File::open(path)
+        .await
+        .with_context(|| format!("Unable to open {}", path.display()))?;
+    let size = file.metadata().await?.len();
+    let range = req
+        .headers()
+        .get("Range")
+        .and_then(|range| range.to_str().ok())
+        .map(|range| parse_range(range, size));
+
+    let (mut response, start, end) = match range {
+        None => (HttpResponse::Ok(), 0, size),
+        Some(Some((start, end))) => {
+            let mut response = HttpResponse::PartialContent();
+            response.insert_header((
+                "Content-Range",
+                format!("bytes {}-{}/{}", start, end - 1, size),
+            ));
+            (response, start, end)
+        }
+        Some(None) => {
+            return Ok(HttpResponse::RangeNotSatisfiable()
+                .insert_header(("Content-Range", format!("bytes */{}", size)))
+                .finish())
+        }
+    };
+    file.seek(SeekFrom::Start(start)).await?;
+
+    let body = stream::unfold((file, end - start), |(mut file, remaining)| async move {
+        if remaining == 0 {
+            return None;
+        }
+        let mut buffer = vec![0; CHUNK_SIZE.min(remaining as usize)];
+        match file.read(&mut buffer).await {
+            Ok(0) => None,
+            Ok(n) => {
+                buffer.truncate(n);
+                Some((
+                    Ok::<_, std::io::Error>(web::Bytes::from(buffer)),
+                    (file, remaining - n as u64),
+                ))
+            }
+            Err(e) => Some((Err(e), (file, 0))),
+        }
+    });
+    Ok(response
+        .insert_header(("Accept-Ranges", "bytes"))
+        .content_type("application/octet-stream")
+        .no_chunking(end - start)
+        .streaming(body))
+}
+
+/// Parses a Range header with a single range into a start and an exclusive end. Returns None when
+/// the range can't be satisfied.
+fn parse_range(range: &str, size: u64) -> Option<(u64, u64)> {
+    let (start, end) = range.strip_prefix("bytes=")?.split_once('-')?;
+    let (start, end) = match (start.trim(), end.trim()) {
+        ("", suffix) => (size.saturating_sub(suffix.parse().ok()?), size),
+        (start, "") => (start.parse().ok()?, size),
+        (start, end) => (
+            start.parse().ok()?,
+            end.parse::<u64>().ok()?.saturating_add(1),
+        ),
+    };
+    let end = end.min(size);
+    (start < end).then_some((start, end))
+}
@@ -1,25 +1,88 @@
 use crate::{
+    archive,
+    category_folders,
+    download_system::{
+        failure::FailureCategory,
+        pause,
+        queue::QueueMove,
+        transfer::{
+            duplicate_ids, flattened_name, flattened_video, local_names, transfer_key, FileNode,
+        },
+        verify::verify,
+    },
     // downloader::DownloadStatus,
-    services::putio::{self, PutIOTransfer},
-    services::transmission::{TransmissionRequest, TransmissionTorrent},
+    services::putio::PutIOTransferStatus,
+    services::transmission::{
+        TransmissionRequest, TransmissionTorrent, TransmissionTorrentFile,
+        TransmissionTorrentStatus,
+    },
+    services::{notify::notify, putio::PutIOTransfer},
+    state::Source,
+    utils::{free_space, total_space},
     AppData,
 };
 use actix_web::web;
-use anyhow::Result;
+use anyhow::{bail, Result};
 use base64::Engine;
 use colored::Colorize;
 use lava_torrent::torrent::v1::Torrent;
-use log::info;
+use log::{info, warn};
 use magnet_url::Magnet;
 use serde_json::json;
+use std::{
+    collections::HashMap, future::Future, path::Path, sync::atomic::Ordering, time::Duration,
+};
+use tokio::time::sleep;
+
+/// Number of times adding a transfer to put.io is attempted before failing the client's request.
+const ADD_ATTEMPTS: u32 = 4;
+/// Delay before the first retry of adding a transfer, doubled for every further one.
+const ADD_RETRY_DELAY: Duration = Duration::from_secs(2);
+
+/// Refuses requests that change transfers while another instance holds the lock on the putioarr
+/// folder, as that instance is the one handling them.
+pub(crate) fn check_active(app_data: &AppData) -> Result<()> {
+    if app_data.standing_by() {
+        bail!("Standing by for another putioarr instance, which handles changes to transfers");
+    }
+    Ok(())
+}
+
+/// Refuses new transfers when the put.io account is running out of space, since put.io would
+/// otherwise accept them and leave them stuck.
+pub(crate) async fn check_putio_disk_space(app_data: &web::Data<AppData>) -> Result<()> {
+    let min_free = app_data.config.putio_min_free_space_gb * 1_073_741_824;
+    let account_info = match app_data.putio.account_info().await {
+        Ok(account_info) => account_info,
+        Err(e) => {
+            warn!("Unable to check put.io disk space: {}", e);
+            return Ok(());
+        }
+    };
+
+    let avail = account_info.info.disk.avail;
+    if avail < min_free {
+        let message = format!(
+            "Not enough space on put.io: {:.2} GB available, {} GB required",
+            avail as f64 / 1_073_741_824.0,
+            app_data.config.putio_min_free_space_gb
+        );
+        notify(&app_data.config, "putio_disk_full", &message).await;
+        bail!(message);
+    }
+    Ok(())
+}
 
 pub(crate) async fn handle_torrent_add(
-    api_token: &str,
+    app_data: &web::Data<AppData>,
     target_folder_id: u64,
     payload: &web::Json<TransmissionRequest>,
 ) -> Result<Option<serde_json::Value>> {
     let arguments = payload.arguments.as_ref().unwrap().as_object().unwrap();
     info!("request to add, arguments: {:?}", arguments);
+    let category = added_category(app_data, arguments);
+    let save_folder_id =
+        category_folders::folder_for(app_data, target_folder_id, category.as_deref()).await?;
 
     if arguments.contains_key("metainfo") {
         // .torrent files
@@ -27,10 +90,18 @@ pub(crate) async fn handle_torrent_add(
         let bytes = base64::engine::general_purpose::STANDARD
             .decode(b64)
             .unwrap();
-        putio::upload_file(api_token, target_folder_id, &bytes).await?;
+        let torrent = Torrent::read_from_bytes(&bytes);
+        let hash = torrent.as_ref().ok().map(|t| t.info_hash());
+        add_idempotent(app_data, target_folder_id, hash.as_deref(), || {
+            app_data.putio.upload_file(save_folder_id, &bytes)
+        })
+        .await?;
 
-        match Torrent::read_from_bytes(bytes) {
+        match torrent {
             Ok(t) => {
+                set_added_priority(app_data, arguments, &t.info_hash());
+                set_category(app_data, category.as_deref(), &t.info_hash());
+                remember_source(app_data, &t.info_hash(), Source::Torrent(bytes.clone()));
                 // let name = t.name;
                 info!(
                     "{}: torrent uploaded",
@@ -42,7 +113,16 @@ pub(crate) async fn handle_torrent_add(
     } else {
         // Magnet links
         let magnet_url = arguments["filename"].as_str().unwrap();
-        putio::add_transfer(api_token, target_folder_id, magnet_url).await?;
+        let hash = Magnet::new(magnet_url).ok().and_then(|m| m.xt);
+        add_idempotent(app_data, target_folder_id, hash.as_deref(), || {
+            app_data.putio.add_transfer(save_folder_id, magnet_url)
+        })
+        .await?;
+        if let Some(hash) = hash {
+            set_added_priority(app_data, arguments, &hash);
+            set_category(app_data, category.as_deref(), &hash);
+            remember_source(app_data, &hash, Source::Magnet(magnet_url.to_string()));
+        }
         match Magnet::new(magnet_url) {
             Ok(m) if m.dn.is_some() => {
                 info!(
@@ -58,8 +138,150 @@ pub(crate) async fn handle_torrent_add(
     Ok(None)
 }
 
+/// Adds a transfer with add, retrying transient failures with a growing delay. Before every
+/// attempt put.io is checked for a transfer with the same hash, since an attempt that timed out
+/// may have gone through anyway, and clients sometimes send the same grab twice.
+async fn add_idempotent<F, Fut>(
+    app_data: &web::Data<AppData>,
+    folder_id: u64,
+    hash: Option<&str>,
+    add: F,
+) -> Result<()>
+where
+    F: Fn() -> Fut,
+    Fut: Future<Output = Result<()>>,
+{
+    let mut attempt = 1;
+    loop {
+        if let Some(hash) = hash {
+            if transfer_exists(app_data, folder_id, hash).await {
+                info!("{}: already on put.io, not adding it again", hash);
+                return Ok(());
+            }
+        }
+        match add().await {
+            Ok(()) => return Ok(()),
+            Err(e) if attempt < ADD_ATTEMPTS && is_transient(&e) => {
+                warn!(
+                    "Adding transfer failed, retrying ({} of {}): {}",
+                    attempt,
+                    ADD_ATTEMPTS - 1,
+                    e
+                );
+                sleep(ADD_RETRY_DELAY * 2u32.pow(attempt - 1)).await;
+                attempt += 1;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Whether put.io already has a transfer with this hash in the folder. When put.io can't be asked,
+/// the transfer is assumed to be missing.
+async fn transfer_exists(app_data: &web::Data<AppData>, folder_id: u64, hash: &str) -> bool {
+    match app_data.putio.list_transfers().await {
+        Ok(response) => response.transfers.iter().any(|t| {
+            category_folders::owns(app_data, folder_id, t)
+                && t.hash
+                    .as_deref()
+                    .is_some_and(|h| h.eq_ignore_ascii_case(hash))
+        }),
+        Err(e) => {
+            warn!("Unable to check for an existing transfer: {}", e);
+            false
+        }
+    }
+}
+
+/// Whether adding a transfer failed for a reason that may go away, like a timeout or a put.io
+/// server error, rather than put.io rejecting the transfer.
+fn is_transient(e: &anyhow::Error) -> bool {
+    if e.downcast_ref::<reqwest::Error>().is_some() {
+        return true;
+    }
+    let message = e.to_string();
+    [
+        "429 Too Many Requests",
+        "500 Internal Server Error",
+        "502 Bad Gateway",
+        "503 Service Unavailable",
+        "504 Gateway Timeout",
+    ]
+    .iter()
+    .any(|status| message.contains(status))
+}
+
+/// Keeps what a transfer was added from for re-adding it, for its provenance and in the torrent
+/// archive, when enabled.
+fn remember_source(app_data: &web::Data<AppData>, hash: &str, source: Source) {
+    if app_data.config.readd_attempts > 0 || app_data.config.provenance {
+        app_data.state.set_source(hash, &source);
+    }
+    if let Some(dir) = &app_data.config.torrent_archive {
+        archive::store(dir, app_data.config.torrent_archive_days, hash, &source);
+    }
+}
+
+/// Remembers the bandwidthPriority of a newly added torrent, so it is honored once put.io
+/// finishes the transfer.
+fn set_added_priority(
+    app_data: &web::Data<AppData>,
+    arguments: &serde_json::Map<String, serde_json::Value>,
+    hash: &str,
+) {
+    if let Some(priority) = arguments
+        .get("bandwidthPriority")
+        .and_then(|p| p.as_i64())
+        .filter(|p| *p != 0)
+    {
+        app_data.state.set_priority(hash, priority);
+    }
+}
+
+/// The category of a newly added torrent, which selects its filter overrides. Clients pass it as
+/// the first label, or as a subdirectory of the download directory.
+fn added_category(
+    app_data: &web::Data<AppData>,
+    arguments: &serde_json::Map<String, serde_json::Value>,
+) -> Option<String> {
+    let label = arguments
+        .get("labels")
+        .and_then(|labels| labels.get(0))
+        .and_then(|label| label.as_str());
+    // As the client sees the download directory, or as we do
+    let config = &app_data.config;
+    let directories = config
+        .arr
+        .iter()
+        .filter_map(|arr| arr.reported_download_directory.as_ref())
+        .chain(&config.reported_download_directory)
+        .chain([&config.download_directory]);
+    let download_dir = arguments
+        .get("download-dir")
+        .and_then(|dir| dir.as_str())
+        .and_then(|dir| {
+            directories
+                .filter_map(|directory| Path::new(dir).strip_prefix(directory).ok())
+                .next()
+        })
+        .and_then(|dir| dir.file_name())
+        .and_then(|name| name.to_str());
+    label
+        .or(download_dir)
+        .filter(|c| !c.is_empty())
+        .map(String::from)
+}
+
+/// Remembers the category of a newly added torrent.
+fn set_category(app_data: &web::Data<AppData>, category: Option<&str>, hash: &str) {
+    if let Some(category) = category {
+        app_data.state.set_category(hash, category);
+    }
+}
+
 pub(crate) async fn handle_torrent_remove(
-    api_token: &str,
+    app_data: &web::Data<AppData>,
+    target_folder_id: u64,
     payload: &web::Json<TransmissionRequest>,
 ) -> Option<serde_json::Value> {
     // TODO: leanup all the unwrap stuff
@@ -82,12 +304,18 @@ pub(crate) async fn handle_torrent_remove(
         .as_bool()
         .unwrap();
 
-    let putio_transfers: Vec<PutIOTransfer> = putio::list_transfers(api_token)
+    let putio_transfers: Vec<PutIOTransfer> = app_data
+        .putio
+        .list_transfers()
         .await
         .unwrap()
         .transfers
         .into_iter()
-        .filter(|t| ids.contains(&t.hash.clone().unwrap_or(String::from("no_hash")).as_str()))
+        .filter(|t| category_folders::owns(app_data, target_folder_id, t))
+        .filter(|t| {
+            ids.contains(&t.hash.clone().unwrap_or(String::from("no_hash")).as_str())
+                || ids.contains(&t.id.to_string().as_str())
+        })
         .collect();
 
     info!("found {} put.io transfers", putio_transfers.len());
@@ -96,10 +324,22 @@ pub(crate) async fn handle_torrent_remove(
         // log a message
         info!("{}: removing", format!("[ffff: {:?}]", t.name).magenta());
 
-        putio::remove_transfer(api_token, t.id).await.unwrap();
+        app_data.putio.remove_transfer(t.id).await.unwrap();
+        let key = transfer_key(t.hash.as_deref(), t.id);
+        app_data.state.clear_failures(&key);
+        app_data.state.forget_transfer(&key);
+        if let Some(hash) = &t.hash {
+            app_data.state.forget_source(hash);
+            app_data.state.forget_file_tree(hash);
+            app_data.state.forget_category(hash);
+            app_data.state.forget_renamed_files(hash);
+            pause::forget(app_data, hash);
+        }
 
         if t.userfile_exists && delete_local_data {
-            putio::delete_file(api_token, t.file_id.unwrap())
+            app_data
+                .putio
+                .delete_file(t.file_id.unwrap())
                 .await
                 .unwrap();
         }
@@ -108,39 +348,385 @@ pub(crate) async fn handle_torrent_remove(
     None
 }
 
+/// Lists the transfers with the fields the client asks for, or all of them when it doesn't say.
 pub(crate) async fn handle_torrent_get(
-    api_token: &str,
+    app_data: &web::Data<AppData>,
     target_folder_id: u64,
+    payload: &web::Json<TransmissionRequest>,
+) -> Result<Option<serde_json::Value>> {
+    let fields: Option<Vec<&str>> = payload
+        .arguments
+        .as_ref()
+        .and_then(|arguments| arguments.get("fields"))
+        .and_then(|fields| fields.as_array())
+        .map(|fields| fields.iter().filter_map(|f| f.as_str()).collect());
+    let torrents: Vec<serde_json::Value> = torrents(app_data, target_folder_id)
+        .await?
+        .into_iter()
+        .map(|torrent| {
+            let mut torrent = json!(torrent);
+            if let (Some(fields), Some(torrent)) = (&fields, torrent.as_object_mut()) {
+                torrent.retain(|name, _| fields.contains(&name.as_str()));
+            }
+            torrent
+        })
+        .collect();
+    let torrents = json!(torrents);
+
+    let mut arguments = serde_json::Map::new();
+    arguments.insert(String::from("torrents"), torrents);
+
+    Ok(Some(json!(arguments)))
+}
+
+/// Reports the space left in the download directory. Clients ask for the path they know as the
+/// download directory, which may not exist here, so we always look at ours.
+pub(crate) fn handle_free_space(
     app_data: &web::Data<AppData>,
-) -> Option<serde_json::Value> {
-    let transfers = putio::list_transfers(api_token).await.unwrap().transfers;
+    payload: &web::Json<TransmissionRequest>,
+) -> Result<Option<serde_json::Value>> {
+    let download_directory = &app_data.config.download_directory;
+    let path = payload
+        .arguments
+        .as_ref()
+        .and_then(|arguments| arguments.get("path"))
+        .and_then(|path| path.as_str())
+        .unwrap_or(download_directory);
+    Ok(Some(json!({
+        "path": path,
+        "size-bytes": free_space(download_directory)?,
+        "total_size": total_space(download_directory)?,
+    })))
+}
+
+/// Reports transfer counts and speeds, along with what was downloaded this session and in total.
+pub(crate) async fn handle_session_stats(
+    app_data: &web::Data<AppData>,
+    target_folder_id: u64,
+) -> Result<Option<serde_json::Value>> {
+    let torrents = torrents(app_data, target_folder_id).await?;
+    let paused = torrents
+        .iter()
+        .filter(|t| t.status == TransmissionTorrentStatus::Stopped)
+        .count();
+    let stats = app_data.state.stats()?;
+    let startup = &app_data.startup_stats;
+    Ok(Some(json!({
+        "activeTorrentCount": torrents.len() - paused,
+        "pausedTorrentCount": paused,
+        "torrentCount": torrents.len(),
+        "downloadSpeed": torrents.iter().map(|t| t.rate_download).sum::<i64>(),
+        "uploadSpeed": torrents.iter().map(|t| t.rate_upload).sum::<i64>(),
+        "cumulative-stats": {
+            "downloadedBytes": stats.downloaded_bytes,
+            "uploadedBytes": 0,
+            "filesAdded": stats.files_added,
+            "sessionCount": stats.session_count,
+            "secondsActive": stats.seconds_active,
+        },
+        "current-stats": {
+            "downloadedBytes": stats.downloaded_bytes.saturating_sub(startup.downloaded_bytes),
+            "uploadedBytes": 0,
+            "filesAdded": stats.files_added.saturating_sub(startup.files_added),
+            "sessionCount": 1,
+            "secondsActive": app_data.started_at.elapsed().as_secs(),
+        },
+    })))
+}
+
+/// Lists the transfers in the putioarr folder the way Transmission reports torrents.
+pub(crate) async fn torrents(
+    app_data: &web::Data<AppData>,
+    target_folder_id: u64,
+) -> Result<Vec<TransmissionTorrent>> {
+    if app_data.auth_error.load(Ordering::SeqCst) {
+        bail!("put.io authentication failed, check the put.io api key");
+    }
+    let transfers = app_data.putio.list_transfers().await?.transfers;
+    let transfers: Vec<PutIOTransfer> = transfers
+        .into_iter()
+        .filter(|t| category_folders::owns(app_data, target_folder_id, t) && !t.is_stream())
+        .collect();
+    // Duplicates are removed on the next poll, until then clients only see the one we keep
+    let duplicates = duplicate_ids(&transfers, &app_data.queue.transfer_ids());
     let transfers: Vec<PutIOTransfer> = transfers
         .into_iter()
-        .filter(|t| t.save_parent_id == Some(target_folder_id))
+        .filter(|t| !duplicates.contains(&t.id))
         .collect();
+    let names = local_names(app_data, &transfers);
+    let queue_positions = queue_positions(app_data, &transfers);
+    let disk_full = app_data.disk_full.load(Ordering::SeqCst);
 
     let transmission_transfers = transfers.into_iter().map(|t| async {
+        let name = names[&t.id].clone();
+        let fetch_failed = t.status == PutIOTransferStatus::Error;
         let mut tt: TransmissionTorrent = t.into();
-        tt.download_dir = app_data.config.download_directory.clone();
+        tt.name = name;
+        tt.queue_position = queue_positions[&tt.id];
+        if let Some(hash) = &tt.hash_string {
+            tt.bandwidth_priority = app_data.state.priority(hash);
+            let tree = app_data
+                .state
+                .file_tree(hash)
+                .and_then(|tree| serde_json::from_str::<FileNode>(&tree).ok());
+            if let Some(tree) = tree {
+                tt.file_count = tree.file_count() as u32;
+                tt.files = tree
+                    .files()
+                    .into_iter()
+                    .map(|(name, length)| TransmissionTorrentFile {
+                        name,
+                        length,
+                        bytes_completed: (length as f64 * tt.percent_done) as u64,
+                    })
+                    .collect();
+                // Clients look for the download under its name
+                if let Some(video) = flattened_video(app_data, &tree, hash) {
+                    tt.name = flattened_name(app_data, hash, video);
+                }
+            }
+        }
+        tt.download_dir = category_folders::reported_directory(app_data, tt.hash_string.as_deref());
+        let key = transfer_key(tt.hash_string.as_deref(), tt.id);
+        if let Some(failure) = app_data.state.failure(&key).filter(|f| f.failed) {
+            let category = failure
+                .category
+                .as_deref()
+                .and_then(FailureCategory::from_name)
+                .unwrap_or(FailureCategory::Download);
+            tt.error_string = Some(format!(
+                "Download failed after {} attempts, {}: {}. {}",
+                failure.attempts,
+                category.description(),
+                failure.last_error,
+                category.remediation()
+            ));
+            // Stopped with an error, so the arrs handle it as a failed download
+            tt.status = TransmissionTorrentStatus::Stopped;
+            tt.error = 3;
+        } else if fetch_failed {
+            let category = FailureCategory::Putio;
+            let error = tt.error_string.as_deref().unwrap_or("no error message");
+            tt.error_string = Some(format!(
+                "{}: {}. {}",
+                category.description(),
+                error,
+                category.remediation()
+            ));
+            tt.status = TransmissionTorrentStatus::Stopped;
+            tt.error = 2;
+        }
+        let stalled = tt
+            .hash_string
+            .as_ref()
+            .is_some_and(|hash| app_data.stalled.lock().unwrap().contains(hash));
+        if stalled && tt.error_string.is_none() {
+            tt.error_string = Some(String::from(
+                "Download stalled, no data received from put.io",
+            ));
+        }
+        let paused = tt
+            .hash_string
+            .as_ref()
+            .is_some_and(|hash| app_data.pauses.is_paused(hash));
+        if paused && tt.status != TransmissionTorrentStatus::Seeding {
+            tt.status = TransmissionTorrentStatus::Stopped;
+        } else if app_data.disk_space.is_waiting(&key) {
+            // Queued, so the arrs wait instead of taking it for stalled
+            tt.status = TransmissionTorrentStatus::Queued;
+        }
+        if disk_full && tt.error_string.is_none() {
+            tt.error_string = Some(String::from(
+                "Download directory is full, downloads are paused",
+            ));
+        }
         tt
     });
-    let transmission_transfers: Vec<TransmissionTorrent> =
-        futures::future::join_all(transmission_transfers).await;
+    Ok(futures::future::join_all(transmission_transfers).await)
+}
 
-    let torrents = json!(transmission_transfers);
+/// Returns the queue position for each transfer, keyed by transfer id. Transfers waiting in our
+/// download queue come first, followed by the ones still queued on put.io, then the rest.
+fn queue_positions(
+    app_data: &web::Data<AppData>,
+    transfers: &[PutIOTransfer],
+) -> HashMap<u64, usize> {
+    let mut order = app_data.queue.transfer_ids();
+    let mut rest: Vec<&PutIOTransfer> = transfers
+        .iter()
+        .filter(|t| !order.contains(&t.id))
+        .collect();
+    rest.sort_by_key(|t| {
+        let queued_on_putio = matches!(
+            t.status,
+            PutIOTransferStatus::InQueue | PutIOTransferStatus::Waiting
+        );
+        (!queued_on_putio, t.id)
+    });
+    order.extend(rest.into_iter().map(|t| t.id));
+    order
+        .into_iter()
+        .enumerate()
+        .map(|(position, id)| (id, position))
+        .collect()
+}
 
-    let mut arguments = serde_json::Map::new();
-    arguments.insert(String::from("torrents"), torrents);
+/// Reorders the local download queue. Transfers that are not waiting for download are left
+/// alone, as put.io's own queue can't be changed.
+pub(crate) fn handle_queue_move(
+    app_data: &web::Data<AppData>,
+    payload: &web::Json<TransmissionRequest>,
+    direction: QueueMove,
+) -> Result<Option<serde_json::Value>> {
+    let Some(ids) = payload
+        .arguments
+        .as_ref()
+        .and_then(|a| a.get("ids"))
+        .and_then(|ids| ids.as_array())
+    else {
+        bail!("queue move requires ids");
+    };
+    info!("moving torrents {:?}: {:?}", direction, ids);
+
+    app_data.queue.move_transfers(
+        |t| matches_id(ids, t.transfer_id, t.hash.as_deref()),
+        direction,
+    );
+    Ok(None)
+}
+
+/// Whether a transfer is one of the given ids. Transmission accepts both numeric ids and hash
+/// strings.
+fn matches_id(ids: &[serde_json::Value], transfer_id: u64, hash: Option<&str>) -> bool {
+    ids.iter().any(|id| match id {
+        serde_json::Value::Number(n) => n.as_u64() == Some(transfer_id),
+        serde_json::Value::String(s) => hash.is_some_and(|h| h.eq_ignore_ascii_case(s)),
+        _ => false,
+    })
+}
 
-    Some(json!(arguments))
+/// Hashes of the transfers a request selects by its ids, or of all transfers without ids.
+async fn selected_hashes(
+    app_data: &web::Data<AppData>,
+    target_folder_id: u64,
+    payload: &web::Json<TransmissionRequest>,
+) -> Result<Vec<String>> {
+    let ids = payload
+        .arguments
+        .as_ref()
+        .and_then(|a| a.get("ids"))
+        .and_then(|ids| ids.as_array())
+        .cloned()
+        .unwrap_or_default();
+    Ok(app_data
+        .putio
+        .list_transfers()
+        .await?
+        .transfers
+        .into_iter()
+        .filter(|t| category_folders::owns(app_data, target_folder_id, t))
+        .filter(|t| ids.is_empty() || matches_id(&ids, t.id, t.hash.as_deref()))
+        .filter_map(|t| t.hash)
+        .collect())
+}
+
+/// Handles torrent-stop. put.io can't pause a transfer, so this only pauses our download of it.
+pub(crate) async fn handle_torrent_stop(
+    app_data: &web::Data<AppData>,
+    target_folder_id: u64,
+    payload: &web::Json<TransmissionRequest>,
+) -> Result<Option<serde_json::Value>> {
+    for hash in selected_hashes(app_data, target_folder_id, payload).await? {
+        pause::pause(app_data, &hash);
+    }
+    Ok(None)
 }
 
+/// Handles torrent-start and torrent-start-now, which resume downloads paused by torrent-stop.
+/// torrent-start-now also moves them to the top of the download queue.
+pub(crate) async fn handle_torrent_start(
+    app_data: &web::Data<AppData>,
+    target_folder_id: u64,
+    payload: &web::Json<TransmissionRequest>,
+    now: bool,
+) -> Result<Option<serde_json::Value>> {
+    let hashes = selected_hashes(app_data, target_folder_id, payload).await?;
+    for hash in &hashes {
+        pause::resume(app_data, hash).await?;
+    }
+    if now {
+        app_data.queue.move_transfers(
+            |t| {
+                t.hash
+                    .as_ref()
+                    .is_some_and(|h| hashes.iter().any(|s| s.eq_ignore_ascii_case(h)))
+            },
+            QueueMove::Top,
+        );
+    }
+    Ok(None)
+}
+
+/// Handles torrent-verify by checking the downloaded files against the size and CRC32 put.io
+/// reports for them, and downloading missing or corrupt ones again. Like Transmission, this
+/// happens in the background.
+pub(crate) async fn handle_torrent_verify(
+    app_data: &web::Data<AppData>,
+    target_folder_id: u64,
+    payload: &web::Json<TransmissionRequest>,
+) -> Result<Option<serde_json::Value>> {
+    let Some((dtx, _)) = app_data.channels.downloads() else {
+        bail!("Downloads aren't running");
+    };
+    for hash in selected_hashes(app_data, target_folder_id, payload).await? {
+        let app_data = app_data.clone();
+        let dtx = dtx.clone();
+        actix_rt::spawn(async move {
+            if let Err(e) = verify(&app_data, &dtx, &hash).await {
+                warn!("{}: unable to verify: {:#}", hash, e);
+            }
+        });
+    }
+    Ok(None)
+}
+
+/// Handles torrent-set. Only bandwidthPriority is supported, which orders the download queue so
+/// interactive grabs can go ahead of bulk ones.
 pub(crate) async fn handle_torrent_set(
-    api_token: &str,
+    app_data: &web::Data<AppData>,
+    target_folder_id: u64,
     payload: &web::Json<TransmissionRequest>,
-) -> Option<serde_json::Value> {
-    // TODO: leanup all the unwrap stuff
-    let arguments = payload.arguments.as_ref().unwrap().as_object().unwrap();
-    info!("request to remove, arguments: {:?}", arguments);
-    None
+) -> Result<Option<serde_json::Value>> {
+    let Some(arguments) = payload.arguments.as_ref().and_then(|a| a.as_object()) else {
+        bail!("torrent-set requires arguments");
+    };
+    info!("request to set, arguments: {:?}", arguments);
+    let Some(priority) = arguments.get("bandwidthPriority").and_then(|p| p.as_i64()) else {
+        return Ok(None);
+    };
+
+    // Without ids the change applies to all torrents.
+    let ids = arguments
+        .get("ids")
+        .and_then(|ids| ids.as_array())
+        .cloned()
+        .unwrap_or_default();
+    let selected = |transfer_id: u64, hash: Option<&str>| {
+        ids.is_empty() || matches_id(&ids, transfer_id, hash)
+    };
+
+    for t in app_data.putio.list_transfers().await?.transfers {
+        if !category_folders::owns(app_data, target_folder_id, &t)
+            || !selected(t.id, t.hash.as_deref())
+        {
+            continue;
+        }
+        if let Some(hash) = &t.hash {
+            app_data.state.set_priority(hash, priority);
+        }
+    }
+    app_data
+        .queue
+        .set_priority(|t| selected(t.transfer_id, t.hash.as_deref()), priority);
+    Ok(None)
 }
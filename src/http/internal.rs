@@ -0,0 +1,92 @@
+// Internal API between a frontend, which serves the Transmission RPC next to sonarr/radarr, and a
+// downloader, which talks to put.io and downloads on the host with the storage.
+use crate::{
+    http::routes::handle_rpc, services::transmission::TransmissionRequest, AppData, Config,
+    DeploymentMode,
+};
+use actix_web::{
+    http::{header::ContentType, StatusCode},
+    post, web, HttpRequest, HttpResponse,
+};
+use anyhow::{Context, Result};
+use std::time::Duration;
+
+/// How long a frontend waits for the downloader. Adding a transfer retries put.io for a while, so
+/// this is generous.
+const FORWARD_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// Connection from a frontend to its downloader.
+pub struct Downloader {
+    url: String,
+    api_key: String,
+    client: reqwest::Client,
+}
+
+impl Downloader {
+    pub fn new(config: &Config) -> Result<Self> {
+        let url = config
+            .downloader_url
+            .clone()
+            .context("mode frontend requires downloader_url")?;
+        let api_key = config
+            .internal_api_key
+            .clone()
+            .context("mode frontend requires internal_api_key")?;
+        let client = reqwest::Client::builder()
+            .user_agent(format!("putioarr v{}", env!("CARGO_PKG_VERSION")))
+            .timeout(FORWARD_TIMEOUT)
+            .build()?;
+        Ok(Self {
+            url: url.trim_end_matches('/').to_string(),
+            api_key,
+            client,
+        })
+    }
+}
+
+/// Passes a client's RPC request on to the downloader and its response back to the client.
+pub(crate) async fn forward(
+    app_data: &AppData,
+    payload: &TransmissionRequest,
+) -> Result<HttpResponse> {
+    let downloader = app_data
+        .downloader
+        .as_ref()
+        .context("No downloader configured")?;
+    let response = downloader
+        .client
+        .post(format!("{}/internal/rpc", downloader.url))
+        .bearer_auth(&downloader.api_key)
+        .json(payload)
+        .send()
+        .await
+        .context("Unable to reach the downloader")?;
+    let status = StatusCode::from_u16(response.status().as_u16())?;
+    let body = response.bytes().await?;
+    Ok(HttpResponse::build(status)
+        .content_type(ContentType::json())
+        .body(body))
+}
+
+/// Handles RPC requests a frontend forwarded, authenticated with internal_api_key.
+#[post("/internal/rpc")]
+async fn internal_rpc(
+    payload: web::Json<TransmissionRequest>,
+    req: HttpRequest,
+    app_data: web::Data<AppData>,
+) -> HttpResponse {
+    if app_data.config.mode != DeploymentMode::Downloader {
+        return HttpResponse::NotFound().finish();
+    }
+    let authorized = req
+        .headers()
+        .get("Authorization")
+        .and_then(|header| header.to_str().ok())
+        .and_then(|header| header.strip_prefix("Bearer "))
+        .zip(app_data.config.internal_api_key.as_deref())
+        .is_some_and(|(given, key)| given == key);
+    if !authorized {
+        return HttpResponse::Unauthorized().body("unauthorized");
+    }
+    handle_rpc(&app_data, &payload).await
+}
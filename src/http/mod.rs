@@ -1,2 +1,11 @@
+pub mod api;
+pub mod files;
 pub mod handlers;
+pub mod internal;
+pub mod proxy;
+pub mod qbittorrent;
+pub mod rate_limit;
 pub mod routes;
+pub mod sabnzbd;
+pub mod ui;
+pub mod webhooks;
@@ -0,0 +1,65 @@
+// Support for running behind reverse proxies listed in trusted_proxies.
+use crate::AppData;
+use actix_web::{dev::ServiceRequest, http::header::HeaderMap, HttpRequest};
+use ipnet::IpNet;
+use std::net::{IpAddr, SocketAddr};
+
+/// Returns the user a trusted reverse proxy authenticated the request for, taken from the
+/// configured auth_header. Requests from any other address can't use the header.
+pub(crate) fn proxy_user(req: &HttpRequest, app_data: &AppData) -> Option<String> {
+    let header = app_data.config.auth_header.as_ref()?;
+    let peer = req.peer_addr()?.ip().to_canonical();
+    if !is_trusted(&app_data.trusted_proxies, peer) {
+        return None;
+    }
+    let user = req.headers().get(header.as_str())?.to_str().ok()?.trim();
+    (!user.is_empty()).then(|| user.to_string())
+}
+
+/// Returns the address of the client that sent the request. Forwarding headers are only
+/// honored when the request comes from a trusted proxy, as anyone can set them.
+pub(crate) fn client_ip(
+    peer: Option<SocketAddr>,
+    headers: &HeaderMap,
+    trusted_proxies: &[IpNet],
+) -> Option<IpAddr> {
+    let peer = peer?.ip().to_canonical();
+    if !is_trusted(trusted_proxies, peer) {
+        return Some(peer);
+    }
+
+    // Every proxy appends the address it got the request from, so the client is the rightmost
+    // address that isn't one of our proxies.
+    let forwarded_for: Vec<IpAddr> = headers
+        .get_all("X-Forwarded-For")
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .filter_map(|address| address.trim().parse::<IpAddr>().ok())
+        .map(|address| address.to_canonical())
+        .collect();
+    if let Some(client) = forwarded_for
+        .iter()
+        .rev()
+        .find(|address| !is_trusted(trusted_proxies, **address))
+    {
+        return Some(*client);
+    }
+
+    headers
+        .get("X-Real-IP")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.trim().parse::<IpAddr>().ok())
+        .or(Some(peer))
+}
+
+/// Client address for the access log.
+pub(crate) fn log_client_ip(req: &ServiceRequest, trusted_proxies: &[IpNet]) -> String {
+    match client_ip(req.peer_addr(), req.headers(), trusted_proxies) {
+        Some(address) => address.to_string(),
+        None => String::from("-"),
+    }
+}
+
+fn is_trusted(trusted_proxies: &[IpNet], address: IpAddr) -> bool {
+    trusted_proxies.iter().any(|proxy| proxy.contains(&address))
+}
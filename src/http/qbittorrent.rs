@@ -0,0 +1,479 @@
+// Emulation of the qBittorrent WebUI API, for clients that prefer it over Transmission. Requests
+// are translated into Transmission ones and go through the same handlers.
+use crate::{
+    category_folders,
+    http::{
+        handlers::{
+            check_active, check_putio_disk_space, handle_torrent_add, handle_torrent_remove,
+            torrents,
+        },
+        routes::validate_user,
+    },
+    redact::redact,
+    services::transmission::{TransmissionRequest, TransmissionTorrent, TransmissionTorrentStatus},
+    AppData, ClientApi,
+};
+use actix_web::{
+    cookie::Cookie, get, http::header::CONTENT_TYPE, post, web, HttpRequest, HttpResponse,
+};
+use anyhow::{bail, Context, Result};
+use base64::Engine;
+use log::{error, info};
+use password_hash::rand_core::{OsRng, RngCore};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::{
+    collections::HashMap,
+    path::Path,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+/// Versions we claim to be. Clients pick API features by the WebUI API version.
+const VERSION: &str = "v4.6.0";
+const WEBAPI_VERSION: &str = "2.9.3";
+
+/// How long a session stays valid without being used, like qBittorrent's default session timeout.
+const SESSION_TIMEOUT: Duration = Duration::from_secs(3600);
+
+/// Session ids handed out by login, with when they were last used.
+#[derive(Default)]
+pub struct Sessions(Mutex<HashMap<String, Instant>>);
+
+impl Sessions {
+    /// Starts a session, dropping the ones that timed out, and returns its id.
+    fn start(&self) -> String {
+        let sid = format!("{:016x}{:016x}", OsRng.next_u64(), OsRng.next_u64());
+        let mut sessions = self.0.lock().unwrap();
+        sessions.retain(|_, last_used| last_used.elapsed() < SESSION_TIMEOUT);
+        sessions.insert(sid.clone(), Instant::now());
+        sid
+    }
+
+    /// Whether the session is one we started and hasn't timed out, which keeps it alive.
+    fn touch(&self, sid: &str) -> bool {
+        let mut sessions = self.0.lock().unwrap();
+        match sessions.get_mut(sid) {
+            Some(last_used) if last_used.elapsed() < SESSION_TIMEOUT => {
+                *last_used = Instant::now();
+                true
+            }
+            Some(_) => {
+                sessions.remove(sid);
+                false
+            }
+            None => false,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct Login {
+    username: String,
+    password: String,
+}
+
+#[derive(Deserialize)]
+struct InfoQuery {
+    category: Option<String>,
+    hashes: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct Hashes {
+    hashes: String,
+    #[serde(default, rename = "deleteFiles")]
+    delete_files: bool,
+}
+
+/// A torrent as /api/v2/torrents/info reports it.
+#[derive(Serialize)]
+struct Torrent {
+    hash: String,
+    name: String,
+    size: i64,
+    total_size: i64,
+    progress: f64,
+    dlspeed: i64,
+    upspeed: i64,
+    eta: u64,
+    state: &'static str,
+    category: String,
+    save_path: String,
+    content_path: String,
+    ratio: f32,
+    ratio_limit: f32,
+    seeding_time_limit: i64,
+    num_seeds: u32,
+    num_leechs: u32,
+    priority: usize,
+}
+
+/// Whether the qBittorrent API is the one clients should use, as opposed to Transmission's.
+fn enabled(app_data: &AppData) -> bool {
+    app_data.config.client_api == ClientApi::Qbittorrent
+}
+
+/// Accepts a session from login, or the same authentication as the Transmission RPC.
+async fn authorized(req: &HttpRequest, app_data: &web::Data<AppData>) -> bool {
+    let session = req
+        .cookie("SID")
+        .is_some_and(|sid| app_data.qbittorrent_sessions.touch(sid.value()));
+    session || validate_user(req.clone(), app_data).await.is_ok()
+}
+
+/// Runs a handler for an authorized request, and answers 404 unless client_api is qbittorrent
+/// and 403 without a session, like qBittorrent does.
+macro_rules! guarded {
+    ($req:expr, $app_data:expr, $body:expr) => {{
+        if !enabled(&$app_data) {
+            return HttpResponse::NotFound().finish();
+        }
+        if !authorized(&$req, &$app_data).await {
+            return HttpResponse::Forbidden().body("Forbidden");
+        }
+        match $body {
+            Ok(response) => response,
+            Err(e) => {
+                error!("{:#}", e);
+                HttpResponse::InternalServerError().body(redact(&format!("{:#}", e)))
+            }
+        }
+    }};
+}
+
+#[post("/api/v2/auth/login")]
+async fn login(form: web::Form<Login>, app_data: web::Data<AppData>) -> HttpResponse {
+    if !enabled(&app_data) {
+        return HttpResponse::NotFound().finish();
+    }
+    if form.username != app_data.config.username
+        || !app_data
+            .password_cache
+            .verify(&app_data.config.password, &form.password)
+            .await
+    {
+        return HttpResponse::Ok().body("Fails.");
+    }
+    let sid = app_data.qbittorrent_sessions.start();
+    HttpResponse::Ok()
+        .cookie(Cookie::build("SID", sid).path("/").http_only(true).finish())
+        .body("Ok.")
+}
+
+#[get("/api/v2/app/version")]
+async fn version(req: HttpRequest, app_data: web::Data<AppData>) -> HttpResponse {
+    guarded!(
+        req,
+        app_data,
+        Ok::<_, anyhow::Error>(HttpResponse::Ok().body(VERSION))
+    )
+}
+
+#[get("/api/v2/app/webapiVersion")]
+async fn webapi_version(req: HttpRequest, app_data: web::Data<AppData>) -> HttpResponse {
+    guarded!(
+        req,
+        app_data,
+        Ok::<_, anyhow::Error>(HttpResponse::Ok().body(WEBAPI_VERSION))
+    )
+}
+
+/// The preferences clients check: where downloads go, and that qBittorrent won't remove or queue
+/// torrents on its own.
+#[get("/api/v2/app/preferences")]
+async fn preferences(req: HttpRequest, app_data: web::Data<AppData>) -> HttpResponse {
+    guarded!(
+        req,
+        app_data,
+        Ok::<_, anyhow::Error>(HttpResponse::Ok().json(json!({
+            "save_path": category_folders::reported_directory(&app_data, None),
+            "max_ratio_enabled": false,
+            "max_ratio": -1,
+            "max_seeding_time_enabled": false,
+            "max_seeding_time": -1,
+            "max_ratio_act": 0,
+            "queueing_enabled": false,
+            "dht": true,
+        })))
+    )
+}
+
+/// The categories with a filters table. putioarr has no fixed set, so creating one always works.
+#[get("/api/v2/torrents/categories")]
+async fn categories(req: HttpRequest, app_data: web::Data<AppData>) -> HttpResponse {
+    guarded!(req, app_data, {
+        let directory = category_folders::reported_directory(&app_data, None);
+        let directory = Path::new(&directory);
+        let categories: serde_json::Map<_, _> = app_data
+            .config
+            .filters
+            .keys()
+            .map(|name| {
+                let save_path = match app_data.config.category_directories {
+                    true => directory.join(name),
+                    false => directory.to_path_buf(),
+                };
+                (name.clone(), json!({ "name": name, "savePath": save_path }))
+            })
+            .collect();
+        Ok::<_, anyhow::Error>(HttpResponse::Ok().json(categories))
+    })
+}
+
+#[post("/api/v2/torrents/createCategory")]
+async fn create_category(req: HttpRequest, app_data: web::Data<AppData>) -> HttpResponse {
+    guarded!(
+        req,
+        app_data,
+        Ok::<_, anyhow::Error>(HttpResponse::Ok().finish())
+    )
+}
+
+#[get("/api/v2/torrents/info")]
+async fn info(
+    req: HttpRequest,
+    app_data: web::Data<AppData>,
+    query: web::Query<InfoQuery>,
+) -> HttpResponse {
+    guarded!(req, app_data, list(&app_data, &query).await)
+}
+
+async fn list(app_data: &web::Data<AppData>, query: &InfoQuery) -> Result<HttpResponse> {
+    let root_folder_id = *app_data.root_folder_id.read().unwrap();
+    let hashes: Option<Vec<String>> = query
+        .hashes
+        .as_ref()
+        .map(|hashes| hashes.split('|').map(str::to_lowercase).collect());
+    let torrents: Vec<Torrent> = torrents(app_data, root_folder_id)
+        .await?
+        .into_iter()
+        .filter_map(|t| torrent(app_data, t))
+        .filter(|t| query.category.as_ref().is_none_or(|c| &t.category == c))
+        .filter(|t| hashes.as_ref().is_none_or(|h| h.contains(&t.hash)))
+        .collect();
+    Ok(HttpResponse::Ok().json(torrents))
+}
+
+fn torrent(app_data: &AppData, t: TransmissionTorrent) -> Option<Torrent> {
+    let hash = t.hash_string?.to_lowercase();
+    let state = match t.status {
+        _ if t.error != 0 => "error",
+        // Stalled, or waiting for disk space
+        _ if t.error_string.is_some() => "stalledDL",
+        TransmissionTorrentStatus::Downloading => "downloading",
+        TransmissionTorrentStatus::Queued => "queuedDL",
+        TransmissionTorrentStatus::CheckWait | TransmissionTorrentStatus::Check => "checkingDL",
+        TransmissionTorrentStatus::SeedingWait => "queuedUP",
+        TransmissionTorrentStatus::Seeding => "uploading",
+        TransmissionTorrentStatus::Stopped if t.is_finished => "pausedUP",
+        TransmissionTorrentStatus::Stopped => "pausedDL",
+    };
+    Some(Torrent {
+        category: app_data.state.category(&hash).unwrap_or_default(),
+        content_path: Path::new(&t.download_dir)
+            .join(&t.name)
+            .to_string_lossy()
+            .to_string(),
+        hash,
+        name: t.name,
+        size: t.total_size,
+        total_size: t.total_size,
+        progress: t.percent_done,
+        dlspeed: t.rate_download,
+        upspeed: t.rate_upload,
+        eta: t.eta,
+        state,
+        save_path: t.download_dir,
+        ratio: 0.0,
+        ratio_limit: -2.0,
+        seeding_time_limit: -2,
+        num_seeds: t.peers_sending_to_us,
+        num_leechs: t.peers_getting_from_us,
+        priority: t.queue_position + 1,
+    })
+}
+
+/// Adds magnet links from `urls` and .torrent files from `torrents`, in a multipart or URL encoded
+/// form.
+#[post("/api/v2/torrents/add")]
+async fn add(req: HttpRequest, app_data: web::Data<AppData>, body: web::Bytes) -> HttpResponse {
+    guarded!(req, app_data, add_all(&req, &app_data, &body).await)
+}
+
+async fn add_all(
+    req: &HttpRequest,
+    app_data: &web::Data<AppData>,
+    body: &[u8],
+) -> Result<HttpResponse> {
+    let content_type = req
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(|c| c.to_str().ok())
+        .unwrap_or_default();
+    let fields = match content_type.starts_with("multipart/form-data") {
+        true => multipart(content_type, body)?,
+        false => serde_urlencoded::from_bytes::<Vec<(String, String)>>(body)?
+            .into_iter()
+            .map(|(name, value)| (name, None, value.into_bytes()))
+            .collect(),
+    };
+    let text = |name: &str| {
+        fields
+            .iter()
+            .find(|(n, _, _)| n == name)
+            .map(|(_, _, value)| String::from_utf8_lossy(value).to_string())
+    };
+    let mut arguments = serde_json::Map::new();
+    if let Some(category) = text("category").filter(|c| !c.is_empty()) {
+        arguments.insert(String::from("labels"), json!([category]));
+    }
+
+    let mut added = Vec::new();
+    for url in text("urls").unwrap_or_default().lines() {
+        let url = url.trim();
+        if !url.is_empty() {
+            added.push(json!({ "filename": url }));
+        }
+    }
+    for (_, _, torrent) in fields
+        .iter()
+        .filter(|(n, f, _)| n == "torrents" && f.is_some())
+    {
+        let metainfo = base64::engine::general_purpose::STANDARD.encode(torrent);
+        added.push(json!({ "metainfo": metainfo }));
+    }
+    if added.is_empty() {
+        bail!("No urls or torrents to add");
+    }
+
+    check_active(app_data)?;
+    check_putio_disk_space(app_data).await?;
+    let root_folder_id = *app_data.root_folder_id.read().unwrap();
+    for source in added {
+        let mut arguments = arguments.clone();
+        arguments.extend(source.as_object().cloned().unwrap_or_default());
+        let request = web::Json(TransmissionRequest {
+            method: String::from("torrent-add"),
+            arguments: Some(json!(arguments)),
+        });
+        handle_torrent_add(app_data, root_folder_id, &request).await?;
+    }
+    Ok(HttpResponse::Ok().body("Ok."))
+}
+
+#[post("/api/v2/torrents/delete")]
+async fn delete(
+    req: HttpRequest,
+    app_data: web::Data<AppData>,
+    form: web::Form<Hashes>,
+) -> HttpResponse {
+    guarded!(
+        req,
+        app_data,
+        async {
+            check_active(&app_data)?;
+            let root_folder_id = *app_data.root_folder_id.read().unwrap();
+            let ids: Vec<&str> = form.hashes.split('|').collect();
+            info!("qBittorrent client removes {:?}", ids);
+            let request = web::Json(TransmissionRequest {
+                method: String::from("torrent-remove"),
+                arguments: Some(json!({ "ids": ids, "delete-local-data": form.delete_files })),
+            });
+            handle_torrent_remove(&app_data, root_folder_id, &request).await;
+            Ok::<_, anyhow::Error>(HttpResponse::Ok().finish())
+        }
+        .await
+    )
+}
+
+/// Moves torrents to the top of the download queue, which for putioarr means a high priority.
+#[post("/api/v2/torrents/topPrio")]
+async fn top_priority(
+    req: HttpRequest,
+    app_data: web::Data<AppData>,
+    form: web::Form<Hashes>,
+) -> HttpResponse {
+    guarded!(req, app_data, {
+        check_active(&app_data).map(|_| {
+            for hash in form.hashes.split('|') {
+                app_data.state.set_priority(hash, 1);
+            }
+            HttpResponse::Ok().finish()
+        })
+    })
+}
+
+/// Seeding happens on put.io, which has its own limits, so share limits are accepted and ignored.
+#[post("/api/v2/torrents/setShareLimits")]
+async fn set_share_limits(req: HttpRequest, app_data: web::Data<AppData>) -> HttpResponse {
+    guarded!(
+        req,
+        app_data,
+        Ok::<_, anyhow::Error>(HttpResponse::Ok().finish())
+    )
+}
+
+/// The fields of a multipart/form-data body, as name, file name and value.
+type Field = (String, Option<String>, Vec<u8>);
+
+fn multipart(content_type: &str, body: &[u8]) -> Result<Vec<Field>> {
+    let boundary = content_type
+        .split(';')
+        .find_map(|param| param.trim().strip_prefix("boundary="))
+        .context("Multipart form without boundary")?
+        .trim_matches('"');
+    let delimiter = format!("--{}", boundary).into_bytes();
+
+    let mut fields = Vec::new();
+    for part in split(body, &delimiter).into_iter().skip(1) {
+        // The closing delimiter ends in "--"
+        if part.starts_with(b"--") {
+            break;
+        }
+        let part = part.strip_prefix(b"\r\n").unwrap_or(part);
+        let part = part.strip_suffix(b"\r\n").unwrap_or(part);
+        let Some(header_end) = find(part, b"\r\n\r\n") else {
+            continue;
+        };
+        let headers = String::from_utf8_lossy(&part[..header_end]);
+        let disposition = headers
+            .lines()
+            .find(|line| {
+                line.to_ascii_lowercase()
+                    .starts_with("content-disposition:")
+            })
+            .unwrap_or_default();
+        let Some(name) = parameter(disposition, "name") else {
+            continue;
+        };
+        let filename = parameter(disposition, "filename");
+        fields.push((name, filename, part[header_end + 4..].to_vec()));
+    }
+    Ok(fields)
+}
+
+/// A parameter of a Content-Disposition header, like `name="urls"`.
+fn parameter(header: &str, name: &str) -> Option<String> {
+    header.split(';').find_map(|param| {
+        let (key, value) = param.trim().split_once('=')?;
+        (key.eq_ignore_ascii_case(name)).then(|| value.trim_matches('"').to_string())
+    })
+}
+
+fn split<'a>(haystack: &'a [u8], delimiter: &[u8]) -> Vec<&'a [u8]> {
+    let mut parts = Vec::new();
+    let mut rest = haystack;
+    while let Some(i) = find(rest, delimiter) {
+        parts.push(&rest[..i]);
+        rest = &rest[i + delimiter.len()..];
+    }
+    parts.push(rest);
+    parts
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack
+        .windows(needle.len())
+        .position(|window| window == needle)
+}
@@ -0,0 +1,45 @@
+// Per-client rate limiting, so a misconfigured client can't hammer put.io through us.
+use crate::{http::proxy::client_ip, Config};
+use actix_governor::{
+    governor::middleware::NoOpMiddleware, GovernorConfig, GovernorConfigBuilder, KeyExtractor,
+    SimpleKeyExtractionError,
+};
+use actix_web::dev::ServiceRequest;
+use ipnet::IpNet;
+use std::net::IpAddr;
+
+/// Tells clients apart by their address, looking through trusted proxies.
+#[derive(Clone)]
+pub struct ClientIpKey {
+    trusted_proxies: Vec<IpNet>,
+}
+
+impl KeyExtractor for ClientIpKey {
+    type Key = IpAddr;
+    type KeyExtractionError = SimpleKeyExtractionError<&'static str>;
+
+    fn extract(&self, req: &ServiceRequest) -> Result<Self::Key, Self::KeyExtractionError> {
+        client_ip(req.peer_addr(), req.headers(), &self.trusted_proxies)
+            .ok_or_else(|| SimpleKeyExtractionError::new("Unable to determine client address"))
+    }
+}
+
+/// Whether rate_limit_per_second enables rate limiting.
+pub fn enabled(config: &Config) -> bool {
+    config.rate_limit_per_second > 0
+}
+
+/// Rate limiter settings from the config.
+pub fn governor_config(
+    config: &Config,
+    trusted_proxies: &[IpNet],
+) -> GovernorConfig<ClientIpKey, NoOpMiddleware> {
+    GovernorConfigBuilder::default()
+        .requests_per_second(config.rate_limit_per_second.max(1))
+        .burst_size(config.rate_limit_burst.max(1))
+        .key_extractor(ClientIpKey {
+            trusted_proxies: trusted_proxies.to_vec(),
+        })
+        .finish()
+        .expect("period and burst size are never zero")
+}
@@ -1,22 +1,28 @@
 use crate::{
+    category_folders,
+    download_system::{backlog::Backlog, queue::QueueMove},
     http::handlers::{
-        handle_torrent_add, handle_torrent_get, handle_torrent_remove, handle_torrent_set,
+        check_active, check_putio_disk_space, handle_free_space, handle_queue_move,
+        handle_session_stats, handle_torrent_add, handle_torrent_get, handle_torrent_remove,
+        handle_torrent_set, handle_torrent_start, handle_torrent_stop, handle_torrent_verify,
     },
-    services::{
-        putio,
-        transmission::{TransmissionConfig, TransmissionRequest, TransmissionResponse},
-    },
-    AppData,
+    http::{internal, proxy::proxy_user},
+    redact::redact,
+    services::transmission::{TransmissionConfig, TransmissionRequest, TransmissionResponse},
+    utils::free_space,
+    AppData, DeploymentMode,
 };
 use actix_web::{
+    error::{InternalError, JsonPayloadError},
     get,
     http::header::{ContentType, Header},
     post, web, HttpRequest, HttpResponse,
 };
 use actix_web_httpauth::headers::authorization::{Authorization, Basic};
 use anyhow::{bail, Context, Result};
-use log::{error, info};
+use log::{debug, error, info};
 use serde_json::json;
+use std::sync::atomic::Ordering;
 
 const SESSION_ID: &str = "useless-session-id";
 
@@ -26,12 +32,6 @@ pub(crate) async fn rpc_post(
     req: HttpRequest,
     app_data: web::Data<AppData>,
 ) -> HttpResponse {
-    let putio_api_token = &app_data.config.putio.api_key;
-    let target_folder_id = {
-        let folder_id = app_data.root_folder_id.read().unwrap();
-        *folder_id
-    };
-
     // Not sure if necessary since we might just look at the session id.
     if validate_user(req, &app_data).await.is_err() {
         return HttpResponse::Conflict()
@@ -42,25 +42,146 @@ pub(crate) async fn rpc_post(
 
     info!("client rpc request for {}", payload.method);
 
+    if app_data.config.mode == DeploymentMode::Frontend {
+        return match internal::forward(&app_data, &payload).await {
+            Ok(response) => response,
+            Err(e) => {
+                error!("{:#}", e);
+                transmission_error(e)
+            }
+        };
+    }
+    handle_rpc(&app_data, &payload).await
+}
+
+/// Handles a Transmission RPC request, from a client or forwarded by a frontend.
+pub(crate) async fn handle_rpc(
+    app_data: &web::Data<AppData>,
+    payload: &web::Json<TransmissionRequest>,
+) -> HttpResponse {
+    let target_folder_id = {
+        let folder_id = app_data.root_folder_id.read().unwrap();
+        *folder_id
+    };
+
+    // A standby instance answers what only reads, and leaves changes to the active instance
+    let read_only = matches!(
+        payload.method.as_str(),
+        "session-get" | "session-stats" | "free-space" | "torrent-get"
+    );
+    if !read_only {
+        if let Err(e) = check_active(app_data) {
+            return transmission_error(e);
+        }
+    }
+
     let arguments = match payload.method.as_str() {
-        "session-get" => Some(json!(TransmissionConfig {
-            download_dir: app_data.config.download_directory.clone(),
-            ..Default::default()
-        })),
-        "torrent-get" => handle_torrent_get(putio_api_token, target_folder_id, &app_data).await,
-        "torrent-set" => handle_torrent_set(putio_api_token, &payload).await,
-        "queue-move-top" => None,
-        "torrent-remove" => handle_torrent_remove(putio_api_token, &payload).await,
+        "session-get" => {
+            let defaults = TransmissionConfig::default();
+            Some(json!(TransmissionConfig {
+                download_dir: category_folders::reported_directory(app_data, None),
+                seed_ratio_limit: app_data
+                    .config
+                    .seed_ratio_limit
+                    .unwrap_or(defaults.seed_ratio_limit),
+                ..defaults
+            }))
+        }
+        "session-stats" => match handle_session_stats(app_data, target_folder_id).await {
+            Ok(v) => v,
+            Err(e) => {
+                error!("{}", e);
+                return transmission_error(e);
+            }
+        },
+        "free-space" => match handle_free_space(app_data, payload) {
+            Ok(v) => v,
+            Err(e) => {
+                error!("{}", e);
+                return transmission_error(e);
+            }
+        },
+        "torrent-get" => match handle_torrent_get(app_data, target_folder_id, payload).await {
+            Ok(v) => v,
+            Err(e) => {
+                error!("{}", e);
+                return transmission_error(e);
+            }
+        },
+        "torrent-set" => match handle_torrent_set(app_data, target_folder_id, payload).await {
+            Ok(v) => v,
+            Err(e) => {
+                error!("{}", e);
+                return transmission_error(e);
+            }
+        },
+        "queue-move-top" | "queue-move-up" | "queue-move-down" | "queue-move-bottom" => {
+            let direction = match payload.method.as_str() {
+                "queue-move-top" => QueueMove::Top,
+                "queue-move-up" => QueueMove::Up,
+                "queue-move-down" => QueueMove::Down,
+                _ => QueueMove::Bottom,
+            };
+            match handle_queue_move(app_data, payload, direction) {
+                Ok(v) => v,
+                Err(e) => {
+                    error!("{}", e);
+                    return transmission_error(e);
+                }
+            }
+        }
+        "torrent-start" | "torrent-start-now" => {
+            let now = payload.method == "torrent-start-now";
+            match handle_torrent_start(app_data, target_folder_id, payload, now).await {
+                Ok(v) => v,
+                Err(e) => {
+                    error!("{}", e);
+                    return transmission_error(e);
+                }
+            }
+        }
+        "torrent-stop" => match handle_torrent_stop(app_data, target_folder_id, payload).await {
+            Ok(v) => v,
+            Err(e) => {
+                error!("{}", e);
+                return transmission_error(e);
+            }
+        },
+        "torrent-verify" => {
+            match handle_torrent_verify(app_data, target_folder_id, payload).await {
+                Ok(v) => v,
+                Err(e) => {
+                    error!("{}", e);
+                    return transmission_error(e);
+                }
+            }
+        }
+        "torrent-remove" => handle_torrent_remove(app_data, target_folder_id, payload).await,
         "torrent-add" => {
-            match handle_torrent_add(putio_api_token, target_folder_id, &payload).await {
+            if let Err(e) = check_putio_disk_space(app_data).await {
+                error!("{}", e);
+                return transmission_error(e);
+            }
+            match handle_torrent_add(app_data, target_folder_id, payload).await {
                 Ok(v) => v,
                 Err(e) => {
                     error!("{}", e);
-                    return HttpResponse::BadRequest().body(e.to_string());
+                    return HttpResponse::BadRequest().body(redact(&e.to_string()));
                 }
             }
         }
-        _ => panic!("Unknwon method {}", payload.method),
+        _ => {
+            debug!(
+                "Unknown method {} with arguments {:?}",
+                payload.method, payload.arguments
+            );
+            return HttpResponse::Ok().content_type(ContentType::json()).json(
+                TransmissionResponse {
+                    result: String::from("unknown method"),
+                    arguments: None,
+                },
+            );
+        }
     };
 
     let response = TransmissionResponse {
@@ -73,6 +194,31 @@ pub(crate) async fn rpc_post(
         .json(response)
 }
 
+/// Turns oversized request bodies into a readable Transmission error instead of a bare 413.
+pub(crate) fn json_error_handler(err: JsonPayloadError, _req: &HttpRequest) -> actix_web::Error {
+    let limit = match &err {
+        JsonPayloadError::OverflowKnownLength { limit, .. }
+        | JsonPayloadError::Overflow { limit } => *limit,
+        _ => return err.into(),
+    };
+    let message = format!(
+        "Request exceeds the JSON payload limit of {} MB, raise json_payload_limit_mb to accept it",
+        limit / 1_048_576
+    );
+    error!("{}", message);
+    InternalError::from_response(err, transmission_error(anyhow::anyhow!(message))).into()
+}
+
+/// Transmission reports errors through the result field of an otherwise successful response.
+pub(crate) fn transmission_error(e: anyhow::Error) -> HttpResponse {
+    HttpResponse::Ok()
+        .content_type(ContentType::json())
+        .json(TransmissionResponse {
+            result: redact(&e.to_string()),
+            arguments: None,
+        })
+}
+
 /// Pretty much only used for authentication.
 #[get("/transmission/rpc")]
 async fn rpc_get(req: HttpRequest, app_data: web::Data<AppData>) -> HttpResponse {
@@ -86,11 +232,114 @@ async fn rpc_get(req: HttpRequest, app_data: web::Data<AppData>) -> HttpResponse
         .body("")
     // HttpResponse::Ok().body("Hello world!")
 }
-async fn validate_user(req: HttpRequest, app_data: &web::Data<AppData>) -> Result<()> {
+/// Backlog and workers of the download system in the Prometheus text format.
+#[get("/metrics")]
+async fn metrics(req: HttpRequest, app_data: web::Data<AppData>) -> HttpResponse {
+    if validate_user(req, &app_data).await.is_err() {
+        return unauthorized();
+    }
+
+    let backlog = Backlog::measure(&app_data);
+    let metrics = [
+        (
+            "putioarr_queued_transfers",
+            "gauge",
+            "Transfers waiting in the download queue.",
+            backlog.queued as f64,
+        ),
+        (
+            "putioarr_orchestration_channel_depth",
+            "gauge",
+            "Messages waiting for an orchestration worker.",
+            backlog.orchestration as f64,
+        ),
+        (
+            "putioarr_download_channel_depth",
+            "gauge",
+            "Files and directories waiting for a download worker.",
+            backlog.downloads as f64,
+        ),
+        (
+            "putioarr_oldest_queued_seconds",
+            "gauge",
+            "How long the oldest transfer in the download queue has been waiting.",
+            backlog.oldest.as_secs_f64(),
+        ),
+        (
+            "putioarr_download_workers",
+            "gauge",
+            "Download workers currently running.",
+            app_data.download_pool.active() as f64,
+        ),
+        (
+            "putioarr_download_worker_restarts_total",
+            "counter",
+            "Download workers restarted after failing.",
+            app_data.download_pool.restarts() as f64,
+        ),
+        (
+            "putioarr_orchestration_worker_restarts_total",
+            "counter",
+            "Orchestration workers restarted after failing.",
+            app_data.orchestration_pool.restarts() as f64,
+        ),
+        (
+            "putioarr_disk_full",
+            "gauge",
+            "Whether downloads are paused because the download directory is below min_free_space_gb.",
+            app_data.disk_full.load(Ordering::SeqCst) as u8 as f64,
+        ),
+        (
+            "putioarr_free_space_bytes",
+            "gauge",
+            "Free space in the download directory.",
+            free_space(&app_data.config.download_directory).map_or(f64::NAN, |free| free as f64),
+        ),
+        (
+            "putioarr_transfers_waiting_for_space",
+            "gauge",
+            "Transfers held back until they fit in the download directory.",
+            app_data.disk_space.waiting() as f64,
+        ),
+    ];
+    let body: String = metrics
+        .iter()
+        .map(|(name, kind, help, value)| {
+            format!(
+                "# HELP {name} {help}\n# TYPE {name} {kind}\n{name} {value}\n",
+                name = name,
+                kind = kind,
+                help = help,
+                value = value
+            )
+        })
+        .collect();
+
+    HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4")
+        .body(body)
+}
+
+/// Asks for credentials on endpoints outside the Transmission RPC.
+pub(crate) fn unauthorized() -> HttpResponse {
+    HttpResponse::Unauthorized()
+        .insert_header(("WWW-Authenticate", "Basic realm=\"putioarr\""))
+        .body("unauthorized")
+}
+
+pub(crate) async fn validate_user(req: HttpRequest, app_data: &web::Data<AppData>) -> Result<()> {
+    if proxy_user(&req, app_data).is_some() {
+        return Ok(());
+    }
     let auth = Authorization::<Basic>::parse(&req)?;
     let user_username = auth.as_ref().user_id();
     let user_password = auth.as_ref().password().context("No password given")?;
-    if user_username == app_data.config.username && user_password == app_data.config.password {
+    if user_username == app_data.config.username
+        && app_data
+            .password_cache
+            .verify(&app_data.config.password, user_password)
+            .await
+    {
         Ok(())
     } else {
         bail!("Username or password mismatch")
@@ -0,0 +1,292 @@
+// Emulation of the SABnzbd API, so putioarr can take a usenet download client slot in the arrs.
+// put.io fetches the URLs added through it like any other transfer; NZB files can't be added.
+use crate::{
+    category_folders,
+    download_system::transfer::transfer_key,
+    http::handlers::{
+        check_active, check_putio_disk_space, handle_torrent_add, handle_torrent_remove, torrents,
+    },
+    services::transmission::{TransmissionRequest, TransmissionTorrent, TransmissionTorrentStatus},
+    state::Stage,
+    AppData,
+};
+use actix_web::{route, web, HttpResponse};
+use anyhow::{bail, Context, Result};
+use log::{error, info};
+use magnet_url::Magnet;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::path::Path;
+
+/// The SABnzbd version we claim to be.
+const VERSION: &str = "4.2.1";
+
+#[derive(Deserialize)]
+struct ApiQuery {
+    mode: Option<String>,
+    apikey: Option<String>,
+    /// Sub command, like delete in mode=queue.
+    name: Option<String>,
+    value: Option<String>,
+    cat: Option<String>,
+    priority: Option<i64>,
+    del_files: Option<u8>,
+}
+
+/// A transfer as SABnzbd reports it: in the queue while put.io or putioarr is downloading it, in
+/// the history once it is downloaded or failed.
+enum Slot {
+    Queue(Value),
+    History(Value),
+}
+
+/// Handles every mode of the SABnzbd API. Like SABnzbd, errors are reported with a status of false
+/// rather than an HTTP error.
+#[route("/api", method = "GET", method = "POST")]
+async fn api(query: web::Query<ApiQuery>, app_data: web::Data<AppData>) -> HttpResponse {
+    let Some(api_key) = &app_data.config.sabnzbd_api_key else {
+        return HttpResponse::NotFound().finish();
+    };
+    let mode = query.mode.as_deref().unwrap_or_default();
+    if mode != "version" && query.apikey.as_ref() != Some(api_key) {
+        return HttpResponse::Ok().json(json!({ "status": false, "error": "API Key Incorrect" }));
+    }
+    match handle(mode, &query, &app_data).await {
+        Ok(response) => HttpResponse::Ok().json(response),
+        Err(e) => {
+            error!("{:#}", e);
+            HttpResponse::Ok().json(json!({ "status": false, "error": format!("{:#}", e) }))
+        }
+    }
+}
+
+async fn handle(mode: &str, query: &ApiQuery, app_data: &web::Data<AppData>) -> Result<Value> {
+    let root_folder_id = *app_data.root_folder_id.read().unwrap();
+    match (mode, query.name.as_deref()) {
+        ("version", _) => Ok(json!({ "version": VERSION })),
+        ("get_config", _) => Ok(config(app_data)),
+        ("fullstatus", _) => Ok(json!({
+            "status": { "completedir": category_folders::reported_directory(app_data, None) }
+        })),
+        ("queue" | "history", Some("delete")) => {
+            check_active(app_data)?;
+            let ids: Vec<&str> = query
+                .value
+                .as_deref()
+                .unwrap_or_default()
+                .split(',')
+                .collect();
+            info!("SABnzbd client removes {:?}", ids);
+            let request = web::Json(TransmissionRequest {
+                method: String::from("torrent-remove"),
+                arguments: Some(json!({
+                    "ids": ids,
+                    "delete-local-data": query.del_files == Some(1),
+                })),
+            });
+            handle_torrent_remove(app_data, root_folder_id, &request).await;
+            Ok(json!({ "status": true, "nzo_ids": ids }))
+        }
+        ("queue", _) => {
+            let slots: Vec<Value> = slots(app_data, root_folder_id, query)
+                .await?
+                .into_iter()
+                .filter_map(|slot| match slot {
+                    Slot::Queue(slot) => Some(slot),
+                    Slot::History(_) => None,
+                })
+                .enumerate()
+                .map(|(index, mut slot)| {
+                    slot["index"] = json!(index);
+                    slot
+                })
+                .collect();
+            Ok(json!({ "queue": { "paused": false, "noofslots": slots.len(), "slots": slots } }))
+        }
+        ("history", _) => {
+            let slots: Vec<Value> = slots(app_data, root_folder_id, query)
+                .await?
+                .into_iter()
+                .filter_map(|slot| match slot {
+                    Slot::History(slot) => Some(slot),
+                    Slot::Queue(_) => None,
+                })
+                .collect();
+            Ok(json!({ "history": { "noofslots": slots.len(), "slots": slots } }))
+        }
+        ("addurl", _) => add_url(app_data, root_folder_id, query).await,
+        ("addfile", _) => bail!("NZB files are not supported, put.io can only fetch URLs"),
+        (mode, _) => bail!("Unsupported mode {}", mode),
+    }
+}
+
+/// The configuration the arrs check: where downloads end up, and which categories exist. Every
+/// category with a filters table counts as one.
+fn config(app_data: &AppData) -> Value {
+    let mut categories =
+        vec![json!({ "name": "*", "dir": "", "priority": 0, "pp": "3", "script": "None" })];
+    categories.extend(app_data.config.filters.keys().map(|name| {
+        // Relative to complete_dir, like in SABnzbd
+        let dir = match app_data.config.category_directories {
+            true => name.as_str(),
+            false => "",
+        };
+        json!({ "name": name, "dir": dir, "priority": -100, "pp": "", "script": "Default" })
+    }));
+    json!({
+        "config": {
+            "misc": {
+                "complete_dir": category_folders::reported_directory(app_data, None),
+                "pre_check": false,
+                "enable_tv_sorting": false,
+                "enable_movie_sorting": false,
+                "enable_date_sorting": false,
+                "tv_categories": [],
+                "movie_categories": [],
+                "date_categories": [],
+                "history_retention": "",
+            },
+            "categories": categories,
+            "sorters": [],
+        }
+    })
+}
+
+async fn slots(
+    app_data: &web::Data<AppData>,
+    root_folder_id: u64,
+    query: &ApiQuery,
+) -> Result<Vec<Slot>> {
+    Ok(torrents(app_data, root_folder_id)
+        .await?
+        .into_iter()
+        .map(|t| slot(app_data, t))
+        .filter(|(category, _)| {
+            query
+                .cat
+                .as_deref()
+                .is_none_or(|cat| cat == "*" || cat == category)
+        })
+        .map(|(_, slot)| slot)
+        .collect())
+}
+
+/// Returns the category of a transfer and its slot.
+fn slot(app_data: &AppData, t: TransmissionTorrent) -> (String, Slot) {
+    let nzo_id = transfer_key(t.hash_string.as_deref(), t.id);
+    let category = app_data.state.category(&nzo_id).unwrap_or_default();
+    let stage = app_data.state.stage(&nzo_id);
+
+    if matches!(stage, Some(Stage::Downloaded | Stage::Kept)) || t.error != 0 {
+        let (status, fail_message) = match t.error {
+            0 => ("Completed", String::new()),
+            _ => ("Failed", t.error_string.clone().unwrap_or_default()),
+        };
+        let storage = Path::new(&t.download_dir).join(&t.name);
+        let slot = json!({
+            "nzo_id": nzo_id,
+            "name": t.name,
+            "nzb_name": t.name,
+            "category": category,
+            "bytes": t.total_size,
+            "download_time": t.seconds_downloading,
+            "storage": storage,
+            "status": status,
+            "fail_message": fail_message,
+        });
+        return (category, Slot::History(slot));
+    }
+
+    let status = match t.status {
+        _ if t.error_string.is_some() => "Paused",
+        _ if stage == Some(Stage::Downloading) => "Downloading",
+        TransmissionTorrentStatus::Downloading => "Downloading",
+        TransmissionTorrentStatus::CheckWait | TransmissionTorrentStatus::Check => "Checking",
+        _ => "Queued",
+    };
+    let mb = t.total_size as f64 / 1_048_576.0;
+    let mbleft = t.left_until_done as f64 / 1_048_576.0;
+    let percentage = match t.total_size {
+        0 => 0,
+        size => (size - t.left_until_done) * 100 / size,
+    };
+    let slot = json!({
+        "nzo_id": nzo_id,
+        "filename": t.name,
+        "cat": category,
+        "status": status,
+        "priority": priority(t.bandwidth_priority),
+        "mb": format!("{:.2}", mb),
+        "mbleft": format!("{:.2}", mbleft),
+        "percentage": percentage.to_string(),
+        "timeleft": timeleft(t.eta),
+    });
+    (category, Slot::Queue(slot))
+}
+
+fn priority(bandwidth_priority: i64) -> &'static str {
+    match bandwidth_priority {
+        p if p > 0 => "High",
+        p if p < 0 => "Low",
+        _ => "Normal",
+    }
+}
+
+/// Formats seconds like SABnzbd does, as H:MM:SS.
+fn timeleft(seconds: u64) -> String {
+    format!(
+        "{}:{:02}:{:02}",
+        seconds / 3600,
+        seconds / 60 % 60,
+        seconds % 60
+    )
+}
+
+/// Adds the URL in name and answers with the nzo_id of the new transfer, which is its hash, or
+/// its put.io id when put.io fetches a plain URL.
+async fn add_url(
+    app_data: &web::Data<AppData>,
+    root_folder_id: u64,
+    query: &ApiQuery,
+) -> Result<Value> {
+    let url = query.name.as_deref().context("No URL to add")?;
+    let category = query.cat.as_deref().filter(|c| !c.is_empty() && *c != "*");
+    check_active(app_data)?;
+    check_putio_disk_space(app_data).await?;
+    let request = web::Json(TransmissionRequest {
+        method: String::from("torrent-add"),
+        arguments: Some(json!({
+            "filename": url,
+            "labels": category.into_iter().collect::<Vec<_>>(),
+            "bandwidthPriority": match query.priority {
+                Some(1 | 2) => 1,
+                Some(-1) => -1,
+                _ => 0,
+            },
+        })),
+    });
+    handle_torrent_add(app_data, root_folder_id, &request).await?;
+
+    let hash = Magnet::new(url).ok().and_then(|m| m.xt);
+    let transfer = app_data
+        .putio
+        .list_transfers()
+        .await?
+        .transfers
+        .into_iter()
+        .filter(|t| category_folders::owns(app_data, root_folder_id, t))
+        .find(|t| match &hash {
+            Some(hash) => t
+                .hash
+                .as_deref()
+                .is_some_and(|h| h.eq_ignore_ascii_case(hash)),
+            None => t.source.as_deref() == Some(url),
+        })
+        .context("Unable to find the added transfer on put.io")?;
+    let nzo_id = transfer_key(transfer.hash.as_deref(), transfer.id);
+    // Transfers without a hash only have their id to remember the category by
+    if let (None, Some(category)) = (&transfer.hash, category) {
+        app_data.state.set_category(&nzo_id, category);
+    }
+    Ok(json!({ "status": true, "nzo_ids": [nzo_id] }))
+}
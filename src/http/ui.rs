@@ -0,0 +1,39 @@
+// Web UI showing what the pipeline is doing, served from assets embedded into the binary.
+use crate::{
+    http::routes::{unauthorized, validate_user},
+    AppData,
+};
+use actix_web::{get, http::header, web, HttpRequest, HttpResponse};
+use rust_embed::Embed;
+
+#[derive(Embed)]
+#[folder = "ui/"]
+struct Assets;
+
+#[get("/ui")]
+async fn index() -> HttpResponse {
+    HttpResponse::PermanentRedirect()
+        .insert_header((header::LOCATION, "/ui/"))
+        .finish()
+}
+
+#[get("/ui/{path:.*}")]
+async fn asset(
+    req: HttpRequest,
+    app_data: web::Data<AppData>,
+    path: web::Path<String>,
+) -> HttpResponse {
+    if validate_user(req, &app_data).await.is_err() {
+        return unauthorized();
+    }
+    let path = match path.as_str() {
+        "" => "index.html",
+        path => path,
+    };
+    match Assets::get(path) {
+        Some(file) => HttpResponse::Ok()
+            .content_type(file.metadata.mimetype())
+            .body(file.data.into_owned()),
+        None => HttpResponse::NotFound().finish(),
+    }
+}
@@ -0,0 +1,99 @@
+// Webhooks other services call to tell us about events right away, instead of us finding out the
+// next time we ask them.
+use crate::{
+    http::routes::{unauthorized, validate_user},
+    AppData,
+};
+use actix_web::{post, web, HttpRequest, HttpResponse};
+use log::{debug, info};
+use serde::Deserialize;
+use sha1::{Digest, Sha1};
+
+/// What Sonarr and Radarr send to a webhook connection, leaving out what isn't used here.
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ArrWebhook {
+    event_type: String,
+    /// Hash of the download an imported file came from.
+    download_id: Option<String>,
+    episode_file: Option<WebhookFile>,
+    movie_file: Option<WebhookFile>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct WebhookFile {
+    /// Where the file was imported from, left out by older versions.
+    source_path: Option<String>,
+}
+
+/// Takes the "On Import" notifications of a Sonarr or Radarr webhook connection, so a transfer
+/// waiting to be imported is done with as soon as its files are. Imports without a source path
+/// have the transfer checked against the history right away instead.
+#[post("/webhooks/arr")]
+async fn arr_webhook(
+    req: HttpRequest,
+    app_data: web::Data<AppData>,
+    payload: web::Json<ArrWebhook>,
+) -> HttpResponse {
+    if validate_user(req, &app_data).await.is_err() {
+        return unauthorized();
+    }
+    let payload = payload.into_inner();
+    // Download is the event of an import, Test the one of the connection's test button
+    if payload.event_type != "Download" {
+        debug!("Ignoring {} webhook event", payload.event_type);
+        return HttpResponse::NoContent().finish();
+    }
+    let Some(hash) = payload.download_id else {
+        return HttpResponse::NoContent().finish();
+    };
+    let path = payload
+        .episode_file
+        .or(payload.movie_file)
+        .and_then(|file| file.source_path);
+    if app_data.import_reports.report(&hash, path.clone()) {
+        info!(
+            "{}: import of {} reported",
+            hash.to_lowercase(),
+            path.as_deref().unwrap_or("a file")
+        );
+    }
+    HttpResponse::NoContent().finish()
+}
+
+/// The callback_url put.io is given for transfers we add, with a token it has to call back with.
+/// The token comes from the api key, so it stays the same across restarts but can't be guessed.
+pub fn putio_callback_url(url: &str, api_key: &str) -> String {
+    let separator = if url.contains('?') { '&' } else { '?' };
+    format!("{}{}token={}", url, separator, callback_token(api_key))
+}
+
+fn callback_token(api_key: &str) -> String {
+    let digest = Sha1::new()
+        .chain_update("putioarr callback ")
+        .chain_update(api_key)
+        .finalize();
+    format!("{:x}", digest)
+}
+
+#[derive(Deserialize)]
+struct CallbackQuery {
+    token: String,
+}
+
+/// Called by put.io when a transfer added with its callback_url finished, so the transfer is
+/// downloaded right away instead of after the next poll. Polling goes on all the same, for
+/// transfers added otherwise and callbacks that don't make it.
+#[post("/webhooks/putio")]
+async fn putio_webhook(
+    app_data: web::Data<AppData>,
+    query: web::Query<CallbackQuery>,
+) -> HttpResponse {
+    if query.token != callback_token(&app_data.config.putio.api_key) {
+        return unauthorized();
+    }
+    info!("put.io reported a finished transfer");
+    app_data.putio_callback.notify_one();
+    HttpResponse::NoContent().finish()
+}
@@ -0,0 +1,263 @@
+// Keeps two putioarr instances from processing the same put.io folder at the same time, which
+// would have them download and delete the same transfers.
+//
+// The lock is a lease stored on put.io itself, so it works across hosts: a folder named
+// `<instance id>.<expiry>` inside LOCK_FOLDER in the putioarr folder. The holder renews it while
+// running, and a lease that isn't renewed expires, so a crashed instance doesn't block the folder
+// forever.
+//
+// An instance that loses the lock stops what it was doing, see [`while_held`], and stands by until
+// it can take the lock again.
+use crate::services::putio::PutIOApi;
+use anyhow::{bail, Context, Result};
+use log::{error, info, warn};
+use std::{
+    future::Future,
+    sync::Mutex,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tokio::{sync::watch, time::sleep};
+
+const LOCK_FOLDER: &str = ".putioarr-lock";
+/// How long a lease is valid. It is renewed once half of it has passed.
+const LEASE_SECONDS: u64 = 300;
+/// How often [`InstanceLock::keep`] checks whether the lease needs renewing.
+const RENEW_INTERVAL: Duration = Duration::from_secs(LEASE_SECONDS / 3);
+
+pub struct InstanceLock {
+    instance_id: String,
+    lease: Mutex<Option<Lease>>,
+    /// Times the lock was lost, so work started while holding it can tell it has to stop.
+    losses: watch::Sender<u64>,
+}
+
+#[derive(Clone, Copy)]
+struct Lease {
+    file_id: u64,
+    expires: u64,
+}
+
+/// A lease found in the lock folder.
+struct Holder {
+    instance_id: String,
+    file_id: u64,
+    expires: u64,
+}
+
+impl InstanceLock {
+    pub fn new(instance_id: String) -> Self {
+        Self {
+            instance_id,
+            lease: Mutex::new(None),
+            losses: watch::Sender::new(0),
+        }
+    }
+
+    /// Whether we hold the lock, or last we knew did.
+    pub fn is_held(&self) -> bool {
+        self.lease.lock().unwrap().is_some()
+    }
+
+    /// Takes the lock on the putioarr folder, failing when another live instance holds it.
+    pub async fn acquire(&self, putio: &dyn PutIOApi, root_folder_id: u64) -> Result<()> {
+        let lock_folder_id = lock_folder(putio, root_folder_id).await?;
+        let now = now();
+        for holder in holders(putio, lock_folder_id).await? {
+            if holder.instance_id != self.instance_id && holder.expires > now {
+                bail!(
+                    "Another putioarr instance ({}) is processing this put.io folder, its lock expires in {}s",
+                    holder.instance_id,
+                    holder.expires - now
+                );
+            }
+            // Our own lease from before a restart, or one that expired
+            putio.delete_file(holder.file_id).await?;
+        }
+
+        let lease = self.create_lease(putio, lock_folder_id).await?;
+
+        // Another instance may have taken the lock at the same time, the oldest lease wins.
+        if let Some(holder) = holders(putio, lock_folder_id)
+            .await?
+            .into_iter()
+            .find(|h| h.instance_id != self.instance_id && h.file_id < lease.file_id)
+        {
+            putio.delete_file(lease.file_id).await?;
+            bail!(
+                "Another putioarr instance ({}) took the lock on this put.io folder first",
+                holder.instance_id
+            );
+        }
+
+        info!(
+            "Acquired lock on the putioarr folder as {}",
+            self.instance_id
+        );
+        *self.lease.lock().unwrap() = Some(lease);
+        Ok(())
+    }
+
+    /// Extends the lease once half of it has passed. Fails when the lock was lost, e.g. because
+    /// put.io was unreachable for longer than the lease and another instance took over.
+    pub async fn renew(&self, putio: &dyn PutIOApi, root_folder_id: u64) -> Result<()> {
+        let Some(lease) = *self.lease.lock().unwrap() else {
+            return self.acquire(putio, root_folder_id).await;
+        };
+        let now = now();
+        if lease.expires > now + LEASE_SECONDS / 2 {
+            return Ok(());
+        }
+
+        let lock_folder_id = lock_folder(putio, root_folder_id).await?;
+        let holders = holders(putio, lock_folder_id).await?;
+        if let Some(holder) = holders
+            .iter()
+            .find(|h| h.instance_id != self.instance_id && h.expires > now)
+        {
+            *self.lease.lock().unwrap() = None;
+            self.losses.send_modify(|losses| *losses += 1);
+            bail!(
+                "Lost the lock on the putioarr folder to instance {}",
+                holder.instance_id
+            );
+        }
+        if lease.expires <= now {
+            warn!("Lock on the putioarr folder expired before it could be renewed");
+        }
+
+        let new_lease = self.create_lease(putio, lock_folder_id).await?;
+        *self.lease.lock().unwrap() = Some(new_lease);
+        for holder in holders {
+            if holder.file_id != new_lease.file_id {
+                if let Err(e) = putio.delete_file(holder.file_id).await {
+                    warn!("Unable to remove old lock lease: {}", e);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Renews the lease for as long as we run, and tries to take the lock every standby_interval
+    /// while we don't hold it. This runs apart from polling, so a poll that is slow or waits out
+    /// an auth failure can't let the lease expire while downloads go on.
+    pub async fn keep(
+        &self,
+        putio: &dyn PutIOApi,
+        root_folder_id: u64,
+        standby_interval: Duration,
+    ) {
+        loop {
+            let held = self.is_held();
+            let renewed = match self.renew(putio, root_folder_id).await {
+                Ok(_) => true,
+                Err(e) if held && !self.is_held() => {
+                    error!("{:#}, standing by", e);
+                    false
+                }
+                Err(e) if !held => {
+                    info!("Standing by: {:#}", e);
+                    false
+                }
+                Err(e) => {
+                    error!("{:#}", e);
+                    self.lose_if_expired();
+                    false
+                }
+            };
+            let interval = if renewed {
+                RENEW_INTERVAL
+            } else {
+                standby_interval
+            };
+            sleep(interval).await;
+        }
+    }
+
+    /// Gives up on a lease that expired without being renewed, as another instance may have taken
+    /// over since.
+    fn lose_if_expired(&self) {
+        let mut lease = self.lease.lock().unwrap();
+        if lease.is_some_and(|lease| lease.expires <= now()) {
+            *lease = None;
+            self.losses.send_modify(|losses| *losses += 1);
+            error!("Lock on the putioarr folder expired before it could be renewed, standing by");
+        }
+    }
+
+    /// Gives up the lock so another instance can take over right away.
+    pub async fn release(&self, putio: &dyn PutIOApi) {
+        let lease = self.lease.lock().unwrap().take();
+        if let Some(lease) = lease {
+            match putio.delete_file(lease.file_id).await {
+                Ok(_) => info!("Released lock on the putioarr folder"),
+                Err(e) => warn!("Unable to release lock on the putioarr folder: {}", e),
+            }
+        }
+    }
+
+    async fn create_lease(&self, putio: &dyn PutIOApi, lock_folder_id: u64) -> Result<Lease> {
+        let expires = now() + LEASE_SECONDS;
+        let response = putio
+            .create_folder(&format!("{}.{}", self.instance_id, expires), lock_folder_id)
+            .await
+            .context("Unable to create lock lease on put.io")?;
+        Ok(Lease {
+            file_id: response.file.id,
+            expires,
+        })
+    }
+}
+
+/// Runs work until it is done, or until the lock is lost, as the instance that took over does it
+/// from then on. Returns None when the work was stopped. Without a lock, work always runs to the
+/// end.
+pub async fn while_held<F: Future>(lock: Option<&InstanceLock>, work: F) -> Option<F::Output> {
+    let Some(lock) = lock else {
+        return Some(work.await);
+    };
+    let mut losses = lock.losses.subscribe();
+    let lost = *losses.borrow();
+    tokio::select! {
+        output = work => Some(output),
+        _ = losses.wait_for(|losses| *losses > lost) => None,
+    }
+}
+
+/// Returns the id of the lock folder, creating it when needed.
+async fn lock_folder(putio: &dyn PutIOApi, root_folder_id: u64) -> Result<u64> {
+    let files = putio.list_files(root_folder_id).await?.files;
+    if let Some(folder) = files
+        .iter()
+        .find(|f| f.name == LOCK_FOLDER && f.file_type == "FOLDER")
+    {
+        return Ok(folder.id);
+    }
+    Ok(putio
+        .create_folder(LOCK_FOLDER, root_folder_id)
+        .await
+        .context("Unable to create lock folder on put.io")?
+        .file
+        .id)
+}
+
+async fn holders(putio: &dyn PutIOApi, lock_folder_id: u64) -> Result<Vec<Holder>> {
+    let files = putio.list_files(lock_folder_id).await?.files;
+    Ok(files
+        .into_iter()
+        .filter_map(|f| {
+            let (instance_id, expires) = f.name.rsplit_once('.')?;
+            Some(Holder {
+                instance_id: instance_id.to_string(),
+                file_id: f.id,
+                expires: expires.parse().ok()?,
+            })
+        })
+        .collect())
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
@@ -1,23 +1,78 @@
-use std::sync::{Mutex, RwLock, RwLockWriteGuard};
+use std::{
+    collections::{HashMap, HashSet},
+    net::{IpAddr, SocketAddr, TcpListener, ToSocketAddrs},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Mutex, RwLock, RwLockWriteGuard,
+    },
+    time::{Duration, Instant},
+};
 
-use crate::{http::routes, services::putio};
-use actix_web::{middleware::Logger, web, App, HttpServer};
+use crate::{
+    download_system::{
+        backlog::Channels,
+        import_watch::ImportReports,
+        pause::Pauses,
+        queue::DownloadQueue,
+        scaling::WorkerPool,
+        space::DiskSpace,
+        sync::DeletePolicy,
+        throttle::{self, Throttle},
+        transfer::FILE_TYPES,
+    },
+    http::{api, files, internal, proxy, qbittorrent, rate_limit, routes, sabnzbd, ui, webhooks},
+    instance_lock::InstanceLock,
+    services::{
+        arr::{self, ArrClient},
+        putio::{PutIOApi, PutIoClient},
+    },
+};
+use actix_cors::Cors;
+use actix_governor::Governor;
+use actix_web::{
+    http::header::{self, HeaderName},
+    middleware::{Compress, Condition, Logger},
+    web, App, HttpServer,
+};
 use anyhow::{bail, Context, Result};
 use clap::{Parser, Subcommand};
 use directories::ProjectDirs;
-use env_logger::TimestampPrecision;
 use figment::{
     providers::{Format, Serialized, Toml},
     Figment,
 };
-use log::{error, info};
+use ipnet::IpNet;
+use log::{error, info, warn};
+use nix::{sys::stat::Mode, unistd::Uid};
+use redact::RedactingWriter;
+use regex::Regex;
 use serde::{Deserialize, Serialize};
-use utils::{generate_config, get_token};
+use socket2::{Domain, Protocol, Socket, Type};
+use tokio::sync::Notify;
+use tracing_subscriber::{
+    fmt::time::ChronoUtc, layer::SubscriberExt, util::SubscriberInitExt, EnvFilter, Layer,
+};
+use utils::{
+    generate_config, get_token, get_token_browser, hash_password, prepare_download_directory,
+    write_token,
+};
 
+mod archive;
+mod category_folders;
 mod download_system;
 mod http;
+mod instance_lock;
+mod provenance;
+mod putio_trash;
+mod redact;
+mod report;
 mod services;
+mod state;
+mod status;
+mod telemetry;
 mod utils;
+mod views;
 
 /// put.io to sonarr/radarr proxy
 #[derive(Parser)]
@@ -31,30 +86,219 @@ struct Cli {
 enum Commands {
     /// Run the proxy
     Run(RunArgs),
+    /// Download finished transfers once and exit, for running from cron or a systemd timer
+    RunOnce(RunArgs),
     /// Generate a put.io API token
-    GetToken,
+    GetToken(GetTokenArgs),
     /// Generate config
-    GenerateConfig(RunArgs),
+    GenerateConfig(GenerateConfigArgs),
+    /// Hash a password for use in the config
+    HashPassword,
+    /// Mirror a put.io folder to a local directory
+    Sync(SyncArgs),
+    /// Export or import the state database, for moving putioarr to another host
+    State(StateArgs),
+    /// Check a downloaded transfer's files against put.io and download missing or corrupt ones again
+    Verify(VerifyArgs),
+    /// Measure download speed from put.io per tunnel route and number of connections
+    Speedtest(SpeedtestArgs),
 }
 
 #[derive(Parser)]
 struct RunArgs {
     #[arg(short, long = "config", default_value_t = ProjectDirs::from("nl", "evenflow", "putioarr").unwrap().config_dir().join("config.toml").into_os_string().into_string().unwrap(), env("APP_CONFIG_PATH"))]
     pub config_path: String,
+    /// Run against an in-memory fake put.io instead of a real account
+    #[cfg(feature = "fake-putio")]
+    #[arg(long)]
+    pub demo: bool,
+}
+
+#[derive(Parser)]
+struct GenerateConfigArgs {
+    #[command(flatten)]
+    run: RunArgs,
+    /// Take the port, credentials, download directory and umask from a Transmission daemon's
+    /// settings.json
+    #[arg(long, value_name = "SETTINGS_JSON")]
+    from_transmission: Option<String>,
+}
+
+#[derive(Parser)]
+struct GetTokenArgs {
+    /// Print the token as JSON, for scripts
+    #[arg(long)]
+    json: bool,
+    /// Write the token into this existing config file as putio.api_key
+    #[arg(long)]
+    write_config: Option<String>,
+    /// Give up when the code isn't linked within this many seconds
+    #[arg(long)]
+    timeout: Option<u64>,
+    /// Log in to put.io in the browser instead of entering a code on put.io/link
+    #[arg(long)]
+    browser: bool,
+    /// put.io app to log in with in the browser. Its callback URL has to allow
+    /// http://localhost:<port>/callback
+    #[arg(long, default_value_t = 6487)]
+    client_id: u64,
+    /// Port to catch the browser's redirect on
+    #[arg(long, default_value_t = 8765)]
+    port: u16,
+}
+
+#[derive(Parser)]
+struct SyncArgs {
+    #[command(flatten)]
+    run: RunArgs,
+    /// put.io folder to mirror, as a path like "Movies/Documentaries" or a folder id
+    #[arg(long)]
+    folder: String,
+    /// Local directory to mirror into
+    #[arg(long)]
+    to: String,
+    /// What to do with local files that are no longer on put.io
+    #[arg(long, value_enum, default_value_t = DeletePolicy::Keep)]
+    delete: DeletePolicy,
+    /// Keep running and sync again every polling_interval
+    #[arg(long)]
+    watch: bool,
+}
+
+#[derive(Parser)]
+struct VerifyArgs {
+    #[command(flatten)]
+    run: RunArgs,
+    /// Info hash of the transfer
+    hash: String,
+}
+
+#[derive(Parser)]
+struct SpeedtestArgs {
+    #[command(flatten)]
+    run: RunArgs,
+    /// put.io file id to download, the largest file in the putioarr folder when left out
+    #[arg(long)]
+    file: Option<u64>,
+    /// Tunnel routes to test, all of put.io's routes when left out
+    #[arg(long, value_delimiter = ',')]
+    routes: Vec<String>,
+    /// Numbers of parallel connections to test
+    #[arg(long, value_delimiter = ',', default_values_t = [1, 4, 8])]
+    connections: Vec<usize>,
+    /// Seconds to download for per route and number of connections
+    #[arg(long, default_value_t = 10)]
+    seconds: u64,
+}
+
+#[derive(Parser)]
+struct StateArgs {
+    #[command(flatten)]
+    run: RunArgs,
+    #[command(subcommand)]
+    command: StateCommand,
+}
+
+#[derive(Subcommand)]
+enum StateCommand {
+    /// Write the state database as JSON
+    Export {
+        /// File to write to, stdout when left out
+        #[arg(long)]
+        output: Option<String>,
+    },
+    /// Replace the state database with a JSON export
+    Import {
+        /// File written by `state export`
+        file: String,
+    },
 }
 
 #[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct Config {
+    arr: Vec<ArrConfig>,
+    auth_header: Option<String>,
+    backlog_max_age_minutes: u64,
+    backlog_max_depth: usize,
     bind_address: String,
+    bind_addresses: Vec<String>,
+    category_directories: bool,
+    category_folders: bool,
+    cleanup_mode: CleanupMode,
+    client_api: ClientApi,
+    compression: bool,
+    cors_origins: Vec<String>,
+    dir_mode: Option<u32>,
+    detect_imports_on_disk: bool,
     download_directory: String,
+    download_file_types: Vec<String>,
+    download_mode: DownloadMode,
+    extract_archives: bool,
+    incomplete_directory: Option<String>,
     download_workers: usize,
+    download_workers_max: Option<usize>,
+    download_connect_timeout_seconds: u64,
+    download_stall_retries: u32,
+    download_stall_seconds: u64,
+    download_workers_min: usize,
+    downloader_url: Option<String>,
+    file_mode: Option<u32>,
+    filters: HashMap<String, FilterOverrides>,
+    flatten_single_file: bool,
+    free_space_reserve_gb: u64,
+    gid: Option<u32>,
+    http2: bool,
+    instance_lock: bool,
+    instance_name: Option<String>,
+    internal_api_key: Option<String>,
+    junk_files: Vec<String>,
+    json_payload_limit_mb: usize,
+    keep_remote_files: bool,
+    keep_remote_transfers: bool,
+    log_format: LogFormat,
     loglevel: String,
+    max_attempts: u32,
+    max_download_rate_kbps: Option<u64>,
+    max_worker_download_rate_kbps: Option<u64>,
+    min_file_size_mb: u64,
+    min_free_space_gb: u64,
+    mode: DeploymentMode,
+    network_filesystem: bool,
+    notification_url: Option<String>,
+    opensubtitles: Option<OpenSubtitlesConfig>,
     orchestration_workers: usize,
+    otlp: Option<OtlpConfig>,
     password: String,
     polling_interval: u64,
     port: u16,
+    provenance: bool,
+    putio_min_free_space_gb: u64,
+    readd_attempts: u32,
+    serve_downloads: bool,
+    rename: Vec<RenameRule>,
+    report_file: Option<String>,
+    report_history: bool,
+    report_url: Option<String>,
+    rename_files: Vec<RenameRule>,
+    rate_limit_burst: u32,
+    rate_limit_per_second: u64,
+    remote_trash_days: Option<u64>,
+    reported_download_directory: Option<String>,
+    sabnzbd_api_key: Option<String>,
+    seed_ratio_limit: Option<f32>,
+    seed_time_limit_minutes: Option<u64>,
+    setgid_directories: bool,
     skip_directories: Vec<String>,
+    speed_schedule: Vec<SpeedSchedule>,
+    standby: bool,
+    state_file: Option<String>,
+    symlink_views: Option<String>,
+    torrent_archive: Option<String>,
+    torrent_archive_days: u64,
+    trusted_proxies: Vec<String>,
     uid: u32,
+    umask: Option<u32>,
+    unicode_normalization: UnicodeNormalization,
     username: String,
     putio: PutioConfig,
 }
@@ -62,17 +306,706 @@ pub struct Config {
 #[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct PutioConfig {
     api_key: String,
+    /// Where put.io reaches /webhooks/putio, see [`webhooks::putio_callback_url`].
+    callback_url: Option<String>,
+}
+
+/// Which parts of putioarr run in this process. A frontend serves the Transmission RPC and
+/// forwards it to a downloader, which does everything else.
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum DeploymentMode {
+    All,
+    Frontend,
+    Downloader,
+}
+
+/// Which download client's API putioarr emulates for the arrs.
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ClientApi {
+    Transmission,
+    Qbittorrent,
+}
+
+/// What happens to the downloaded files of a transfer once it was imported.
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum CleanupMode {
+    /// Leave them for the arrs or the user to deal with.
+    Keep,
+    Delete,
+    /// Delete them once put.io stopped seeding, for arrs that hardlink them into their library.
+    DeleteAfterSeeding,
+}
+
+/// How the files of a transfer are downloaded, see [`download_system::zip_download`].
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum DownloadMode {
+    Files,
+    Zip,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    Text,
+    Json,
+}
+
+/// Unicode normalization form of the file names we write.
+#[derive(Debug, Deserialize, Serialize, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum UnicodeNormalization {
+    None,
+    Nfc,
+    Nfd,
+}
+
+/// Filters that replace the global ones for transfers added with a category.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct FilterOverrides {
+    skip_directories: Option<Vec<String>>,
+    junk_files: Option<Vec<String>>,
+    min_file_size_mb: Option<u64>,
+    download_file_types: Option<Vec<String>>,
+}
+
+/// Rewrites the names of transfers, see [`rename_rules`].
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct RenameRule {
+    pattern: String,
+    replacement: String,
+}
+
+/// Limits download speed during part of the day, see [`throttle::speed_windows`].
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct SpeedSchedule {
+    #[serde(default)]
+    days: Vec<String>,
+    from: String,
+    to: String,
+    rate_kbps: u64,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct OpenSubtitlesConfig {
+    api_key: String,
+    username: String,
+    password: String,
+    #[serde(default = "default_subtitle_languages")]
+    languages: Vec<String>,
+}
+
+fn default_subtitle_languages() -> Vec<String> {
+    vec![String::from("en")]
 }
 
+/// Where to export spans to, see [`telemetry::layer`].
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct OtlpConfig {
+    /// OTLP/HTTP traces endpoint, like http://localhost:4318/v1/traces.
+    endpoint: String,
+    #[serde(default = "default_service_name")]
+    service_name: String,
+}
+
+fn default_service_name() -> String {
+    String::from("putioarr")
+}
+
+/// An arr instance to wait for imports from, for transfers of its category.
 #[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct ArrConfig {
+    name: String,
     url: String,
     api_key: String,
+    /// Category it adds transfers with, or None for transfers no other instance claims.
+    category: Option<String>,
+    #[serde(default)]
+    kind: ArrKind,
+    /// Where it sees download_directory, in place of reported_download_directory.
+    reported_download_directory: Option<String>,
+}
+
+/// Which of the arrs an instance is, as their APIs and what they import differ.
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum ArrKind {
+    #[default]
+    Sonarr,
+    Radarr,
+    Whisparr,
+    Lidarr,
+    Readarr,
 }
 
 pub struct AppData {
     pub config: Config,
     root_folder_id: RwLock<u64>,
+    pub category_folders: category_folders::CategoryFolders,
+    /// The password that verified last, when it's an argon2 hash.
+    pub password_cache: utils::PasswordCache,
+    /// Sessions of clients logged in to the qBittorrent API.
+    pub qbittorrent_sessions: qbittorrent::Sessions,
+    /// The uid to hand created files to, or None when we lack the privileges to do so.
+    pub chown_uid: Option<u32>,
+    /// The gid to hand created files to, when configured and we have the privileges to do so.
+    pub chown_gid: Option<u32>,
+    /// Set while downloads are paused because the download directory is low on space.
+    pub disk_full: AtomicBool,
+    /// Hashes of transfers with a download that kept stalling.
+    pub stalled: Mutex<HashSet<String>>,
+    /// Space claimed by the transfers being downloaded, and the ones waiting for space.
+    pub disk_space: DiskSpace,
+    /// Clients of the arr instances to wait for imports from.
+    pub arrs: Vec<Box<dyn ArrClient>>,
+    /// Imports the arrs reported through their webhook.
+    pub import_reports: ImportReports,
+    /// Woken when put.io calls back about a finished transfer, to list transfers right away.
+    pub putio_callback: Notify,
+    pub putio: Box<dyn PutIOApi>,
+    pub state: state::Store,
+    /// Set while put.io keeps rejecting our api key.
+    pub auth_error: AtomicBool,
+    /// Transfers waiting for an orchestration worker.
+    pub queue: DownloadQueue,
+    /// Reverse proxies whose auth_header we trust.
+    pub trusted_proxies: Vec<IpNet>,
+    /// Compiled rename rules, applied in order to the names of transfers.
+    pub rename_rules: Vec<(Regex, String)>,
+    /// Compiled rename_files rules, applied in order to the names of downloaded files.
+    pub file_rename_rules: Vec<(Regex, String)>,
+    /// The downloader requests are forwarded to, in frontend mode.
+    pub downloader: Option<internal::Downloader>,
+    /// Lock on the putioarr folder, unless instance_lock is disabled.
+    pub lock: Option<InstanceLock>,
+    pub channels: Channels,
+    pub download_pool: WorkerPool,
+    /// Limits the speed of all downloads together to max_download_rate_kbps and the speed_schedule.
+    pub download_throttle: Throttle,
+    /// Transfers a client paused.
+    pub pauses: Pauses,
+    pub activity: status::Activity,
+    pub orchestration_pool: WorkerPool,
+    /// Stats at startup, to tell those of this session apart for session-stats.
+    pub startup_stats: state::Stats,
+    pub started_at: Instant,
+    /// polling_interval in seconds, which can be changed while running.
+    polling_interval: AtomicU64,
+    /// Where the config was loaded from, to persist changes made through the API.
+    config_path: String,
+}
+
+impl AppData {
+    /// Whether another instance holds the lock on the putioarr folder, so we leave transfers to it
+    /// and only answer what doesn't change them.
+    pub fn standing_by(&self) -> bool {
+        self.config.mode != DeploymentMode::Frontend
+            && self.lock.as_ref().is_some_and(|lock| !lock.is_held())
+    }
+
+    pub fn polling_interval(&self) -> Duration {
+        Duration::from_secs(self.polling_interval.load(Ordering::SeqCst))
+    }
+
+    pub fn set_polling_interval(&self, seconds: u64) {
+        self.polling_interval.store(seconds, Ordering::SeqCst);
+    }
+}
+
+/// Creates the putioarr folder on put.io if it doesn't exist yet and returns its id.
+async fn resolve_root_folder(putio: &dyn PutIOApi) -> Result<u64> {
+    match putio.create_folder("putioarr", 0).await {
+        Ok(response) => {
+            info!("Created putioarr folder on put.io");
+            Ok(response.file.id)
+        }
+        Err(e) if e.to_string().contains("400 Bad Request") => {
+            info!("putioarr folder already exists on put.io");
+            let file_list = putio
+                .list_files(0)
+                .await
+                .context("Failed to get folder ID")?;
+            file_list
+                .files
+                .iter()
+                .find(|f| f.name == "putioarr" && f.file_type == "FOLDER")
+                .map(|f| f.id)
+                .context("Failed to get folder ID: putioarr folder not found on put.io")
+        }
+        Err(e) => Err(e.context("Failed to create putioarr folder")),
+    }
+}
+
+/// Returns the addresses to listen on: `bind_addresses` when given, `bind_address` and `port`
+/// otherwise.
+fn listen_addresses(config: &Config) -> Result<Vec<SocketAddr>> {
+    if config.bind_addresses.is_empty() {
+        return (config.bind_address.as_str(), config.port)
+            .to_socket_addrs()
+            .with_context(|| format!("Invalid bind_address {}", config.bind_address))
+            .map(|addresses| addresses.collect());
+    }
+
+    config
+        .bind_addresses
+        .iter()
+        .map(|address| {
+            address
+                .parse::<SocketAddr>()
+                .with_context(|| format!("Invalid address {} in bind_addresses", address))
+        })
+        .collect()
+}
+
+/// Parses `trusted_proxies`, which holds single addresses or networks in CIDR notation.
+fn trusted_proxies(config: &Config) -> Result<Vec<IpNet>> {
+    config
+        .trusted_proxies
+        .iter()
+        .map(|proxy| {
+            proxy
+                .parse::<IpNet>()
+                .or_else(|_| proxy.parse::<IpAddr>().map(IpNet::from))
+                .with_context(|| format!("Invalid address {} in trusted_proxies", proxy))
+        })
+        .collect()
+}
+
+/// Compiles rename rules. Replacements may refer to capture groups as $1 or ${name}.
+fn rename_rules(rules: &[RenameRule]) -> Result<Vec<(Regex, String)>> {
+    rules
+        .iter()
+        .map(|rule| {
+            let regex = Regex::new(&rule.pattern)
+                .with_context(|| format!("Invalid rename pattern {}", rule.pattern))?;
+            Ok((regex, rule.replacement.clone()))
+        })
+        .collect()
+}
+
+/// CORS policy for browser-based clients hosted on one of the given origins, or on any origin
+/// when the list contains "*".
+fn cors(origins: &[String]) -> Cors {
+    let cors = if origins.iter().any(|origin| origin == "*") {
+        Cors::default().allow_any_origin()
+    } else {
+        origins
+            .iter()
+            .fold(Cors::default(), |cors, origin| cors.allowed_origin(origin))
+    };
+    cors.allowed_methods(["GET", "POST"])
+        .allowed_headers([
+            header::AUTHORIZATION,
+            header::CONTENT_TYPE,
+            HeaderName::from_static("x-transmission-session-id"),
+        ])
+        .expose_headers([HeaderName::from_static("x-transmission-session-id")])
+        .supports_credentials()
+        .max_age(3600)
+}
+
+/// Binds a listening socket. IPv6 sockets are made IPv6-only when an IPv4 address on the same
+/// port is configured as well, otherwise the two would conflict on dual-stack hosts.
+fn bind(address: SocketAddr, all: &[SocketAddr]) -> Result<TcpListener> {
+    let socket = Socket::new(
+        Domain::for_address(address),
+        Type::STREAM,
+        Some(Protocol::TCP),
+    )?;
+    if address.is_ipv6()
+        && all
+            .iter()
+            .any(|a| a.is_ipv4() && a.port() == address.port())
+    {
+        socket.set_only_v6(true)?;
+    }
+    socket.set_reuse_address(true)?;
+    socket.bind(&address.into())?;
+    socket.listen(1024)?;
+    Ok(socket.into())
+}
+
+/// Loads the config file, filling in defaults for everything that is optional.
+fn load_config(config_path: &str) -> Result<Config> {
+    Ok(Figment::new()
+        .join(Serialized::default("bind_address", "0.0.0.0"))
+        .join(Serialized::default("bind_addresses", Vec::<String>::new()))
+        .join(Serialized::default("arr", Vec::<ArrConfig>::new()))
+        .join(Serialized::default("category_directories", false))
+        .join(Serialized::default("category_folders", false))
+        .join(Serialized::default("cleanup_mode", "keep"))
+        .join(Serialized::default("client_api", "transmission"))
+        .join(Serialized::default("compression", true))
+        .join(Serialized::default("cors_origins", Vec::<String>::new()))
+        .join(Serialized::default("backlog_max_age_minutes", 0))
+        .join(Serialized::default("backlog_max_depth", 0))
+        .join(Serialized::default("detect_imports_on_disk", false))
+        .join(Serialized::default("download_connect_timeout_seconds", 30))
+        .join(Serialized::default(
+            "download_file_types",
+            vec!["video", "subtitle"],
+        ))
+        .join(Serialized::default("download_mode", "files"))
+        .join(Serialized::default("extract_archives", false))
+        .join(Serialized::default("download_stall_retries", 3))
+        .join(Serialized::default("download_stall_seconds", 300))
+        .join(Serialized::default("download_workers", 4))
+        .join(Serialized::default("download_workers_min", 1))
+        .join(Serialized::default(
+            "filters",
+            HashMap::<String, FilterOverrides>::new(),
+        ))
+        .join(Serialized::default("flatten_single_file", false))
+        .join(Serialized::default("free_space_reserve_gb", 1))
+        .join(Serialized::default("http2", true))
+        .join(Serialized::default("instance_lock", true))
+        .join(Serialized::default("json_payload_limit_mb", 16))
+        .join(Serialized::default("keep_remote_files", false))
+        .join(Serialized::default("keep_remote_transfers", false))
+        .join(Serialized::default("orchestration_workers", 10))
+        .join(Serialized::default("log_format", "text"))
+        .join(Serialized::default("loglevel", "info"))
+        .join(Serialized::default("max_attempts", 3))
+        .join(Serialized::default("min_file_size_mb", 0))
+        .join(Serialized::default("min_free_space_gb", 1))
+        .join(Serialized::default("mode", "all"))
+        .join(Serialized::default("network_filesystem", false))
+        .join(Serialized::default("polling_interval", 10))
+        .join(Serialized::default("port", 9091))
+        .join(Serialized::default("provenance", false))
+        .join(Serialized::default("putio_min_free_space_gb", 1))
+        .join(Serialized::default("rate_limit_burst", 50))
+        .join(Serialized::default("readd_attempts", 0))
+        .join(Serialized::default("rename", Vec::<RenameRule>::new()))
+        .join(Serialized::default("report_history", false))
+        .join(Serialized::default(
+            "rename_files",
+            Vec::<RenameRule>::new(),
+        ))
+        .join(Serialized::default("rate_limit_per_second", 10))
+        .join(Serialized::default("serve_downloads", false))
+        .join(Serialized::default("setgid_directories", false))
+        .join(Serialized::default(
+            "speed_schedule",
+            Vec::<SpeedSchedule>::new(),
+        ))
+        .join(Serialized::default("standby", false))
+        .join(Serialized::default("torrent_archive_days", 30))
+        .join(Serialized::default("trusted_proxies", Vec::<String>::new()))
+        .join(Serialized::default("uid", 1000))
+        .join(Serialized::default("unicode_normalization", "none"))
+        .join(Serialized::default(
+            "skip_directories",
+            vec!["sample", "extras"],
+        ))
+        .join(Serialized::default(
+            "junk_files",
+            vec![
+                "*.exe",
+                "*.lnk",
+                "*.url",
+                "*.scr",
+                "*.bat",
+                "rarbg.txt",
+                "rarbg_do_not_mirror.exe",
+            ],
+        ))
+        .merge(Toml::file(config_path))
+        .extract()?)
+}
+
+/// Creates the put.io client. Downloads use download_stall_seconds as their idle timeout.
+fn putio_client(config: &Config) -> Result<PutIoClient> {
+    let idle_timeout = (config.download_stall_seconds > 0)
+        .then(|| Duration::from_secs(config.download_stall_seconds));
+    PutIoClient::new(
+        &config.putio.api_key,
+        Duration::from_secs(config.download_connect_timeout_seconds),
+        idle_timeout,
+        config
+            .putio
+            .callback_url
+            .as_deref()
+            .map(|url| webhooks::putio_callback_url(url, &config.putio.api_key)),
+    )
+}
+
+/// Location of the state database, next to the config file unless configured otherwise.
+fn state_file(args: &RunArgs, config: &Config) -> PathBuf {
+    match &config.state_file {
+        Some(state_file) => PathBuf::from(state_file),
+        None => Path::new(&args.config_path).with_file_name("putioarr.db"),
+    }
+}
+
+/// Sets up logging. Timestamps are left out of text logs when a service manager already adds
+/// them. JSON logs always have them, and the spans each line belongs to. Spans are exported as
+/// well when an OTLP endpoint is configured.
+fn init_logging(config: &Config) -> Result<()> {
+    let log_timestamp = in_container::in_container() || nix::unistd::isatty(0).unwrap_or(false);
+
+    let mut secrets = vec![config.putio.api_key.clone(), config.password.clone()];
+    secrets.extend(config.internal_api_key.clone());
+    secrets.extend(config.sabnzbd_api_key.clone());
+    secrets.extend(config.arr.iter().map(|arr| arr.api_key.clone()));
+    if let Some(opensubtitles) = &config.opensubtitles {
+        secrets.push(opensubtitles.api_key.clone());
+        secrets.push(opensubtitles.password.clone());
+    }
+    redact::set_secrets(secrets);
+    let fmt = tracing_subscriber::fmt::layer()
+        .with_writer(|| RedactingWriter)
+        .with_target(false)
+        .with_timer(ChronoUtc::new(String::from("%FT%TZ")));
+    let fmt = match config.log_format {
+        LogFormat::Json => {
+            colored::control::set_override(false);
+            fmt.json()
+                .with_ansi(false)
+                .with_current_span(true)
+                .with_span_list(true)
+                .boxed()
+        }
+        LogFormat::Text if log_timestamp => fmt.boxed(),
+        LogFormat::Text => fmt.without_time().boxed(),
+    };
+    let otlp = match &config.otlp {
+        Some(otlp) => Some(
+            telemetry::layer(otlp, config.instance_name.as_deref())
+                .context("Unable to set up the OTLP exporter")?,
+        ),
+        None => None,
+    };
+    tracing_subscriber::registry()
+        .with(
+            EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new(&config.loglevel)),
+        )
+        .with(fmt)
+        .with(otlp)
+        .init();
+    Ok(())
+}
+
+/// Sets up everything the proxy and the sync command share: the put.io client, the state
+/// database, the download directory and the putioarr folder on put.io.
+async fn init(args: &RunArgs, config: &Config) -> Result<web::Data<AppData>> {
+    if let Some(umask) = config.umask {
+        nix::sys::stat::umask(Mode::from_bits_truncate(umask));
+    }
+    let chown_uid = if Uid::effective().is_root() {
+        Some(config.uid)
+    } else {
+        if Uid::effective().as_raw() != config.uid {
+            warn!(
+                "Not running as root, skipping ownership changes. Downloads will be owned by uid {} instead of {}",
+                Uid::effective(),
+                config.uid
+            );
+        }
+        None
+    };
+    let chown_gid = config.gid.filter(|_| chown_uid.is_some());
+    if config.gid.is_some() && chown_gid.is_none() {
+        warn!("Not running as root, skipping group changes");
+    }
+
+    #[cfg(feature = "fake-putio")]
+    let putio: Box<dyn PutIOApi> = if args.demo {
+        info!("Running in demo mode against a fake put.io");
+        Box::new(services::fake_putio::FakePutIO::new())
+    } else {
+        Box::new(putio_client(config)?)
+    };
+    #[cfg(not(feature = "fake-putio"))]
+    let putio: Box<dyn PutIOApi> = Box::new(putio_client(config)?);
+
+    let state = match state::Store::open(&state_file(args, config)) {
+        Ok(state) => state,
+        Err(e) => {
+            error!("{:#}", e);
+            bail!(e)
+        }
+    };
+    state.increment("session_count", 1);
+    let startup_stats = state.stats().unwrap_or_default();
+    let paused = state.paused();
+    info!(
+        "Lifetime stats: {:.2} GB downloaded in {} files, {} transfers completed, {} failures",
+        startup_stats.downloaded_bytes as f64 / 1_073_741_824.0,
+        startup_stats.files_added,
+        startup_stats.transfers_completed,
+        startup_stats.failures
+    );
+
+    let lock = if config.instance_lock {
+        match config
+            .instance_name
+            .clone()
+            .map_or_else(|| state.instance_id(), Ok)
+        {
+            Ok(instance_id) => Some(InstanceLock::new(instance_id)),
+            Err(e) => {
+                error!("Unable to get instance id: {:#}", e);
+                bail!(e)
+            }
+        }
+    } else {
+        None
+    };
+    if config.standby && lock.is_none() {
+        bail!("standby requires instance_lock");
+    }
+
+    let trusted_proxies = match trusted_proxies(config) {
+        Ok(trusted_proxies) => trusted_proxies,
+        Err(e) => {
+            error!("{:#}", e);
+            bail!(e)
+        }
+    };
+    if config.auth_header.is_some() && trusted_proxies.is_empty() {
+        warn!("auth_header is ignored since trusted_proxies is empty");
+    }
+
+    let (rename_rules, file_rename_rules) = match rename_rules(&config.rename)
+        .and_then(|r| Ok((r, rename_rules(&config.rename_files)?)))
+    {
+        Ok(rules) => rules,
+        Err(e) => {
+            error!("{:#}", e);
+            bail!(e)
+        }
+    };
+
+    if config.client_api == ClientApi::Qbittorrent && config.mode == DeploymentMode::Frontend {
+        error!("client_api qbittorrent is not supported in mode frontend");
+        bail!("client_api qbittorrent is not supported in mode frontend")
+    }
+    let file_types = config.download_file_types.iter().chain(
+        config
+            .filters
+            .values()
+            .filter_map(|f| f.download_file_types.as_ref())
+            .flatten(),
+    );
+    for file_type in file_types {
+        let known = FILE_TYPES.contains(&file_type.to_lowercase().as_str());
+        if !known && !file_type.starts_with('.') {
+            error!("Unknown download_file_types entry: {}", file_type);
+            bail!("Unknown download_file_types entry: {}", file_type)
+        }
+    }
+    if config.sabnzbd_api_key.is_some() && config.mode == DeploymentMode::Frontend {
+        error!("sabnzbd_api_key is not supported in mode frontend");
+        bail!("sabnzbd_api_key is not supported in mode frontend")
+    }
+
+    let speed_windows = match throttle::speed_windows(&config.speed_schedule) {
+        Ok(speed_windows) => speed_windows,
+        Err(e) => {
+            error!("{:#}", e);
+            bail!(e)
+        }
+    };
+
+    let downloader = match config.mode {
+        DeploymentMode::Frontend => match internal::Downloader::new(config) {
+            Ok(downloader) => Some(downloader),
+            Err(e) => {
+                error!("{:#}", e);
+                bail!(e)
+            }
+        },
+        DeploymentMode::Downloader if config.internal_api_key.is_none() => {
+            error!("mode downloader requires internal_api_key");
+            bail!("mode downloader requires internal_api_key")
+        }
+        _ => None,
+    };
+
+    let app_data = web::Data::new(AppData {
+        config: config.clone(),
+        root_folder_id: RwLock::new(0),
+        category_folders: Default::default(),
+        password_cache: Default::default(),
+        qbittorrent_sessions: Default::default(),
+        chown_uid,
+        chown_gid,
+        disk_full: AtomicBool::new(false),
+        stalled: Mutex::new(HashSet::new()),
+        disk_space: DiskSpace::default(),
+        arrs: arr::clients(&config.arr),
+        import_reports: ImportReports::default(),
+        putio_callback: Notify::new(),
+        putio,
+        state,
+        auth_error: AtomicBool::new(false),
+        queue: DownloadQueue::default(),
+        trusted_proxies,
+        rename_rules,
+        file_rename_rules,
+        downloader,
+        lock,
+        channels: Channels::default(),
+        download_pool: WorkerPool::default(),
+        download_throttle: Throttle::new(config.max_download_rate_kbps, speed_windows),
+        pauses: Pauses::new(paused),
+        activity: Default::default(),
+        orchestration_pool: WorkerPool::default(),
+        startup_stats,
+        started_at: Instant::now(),
+        polling_interval: AtomicU64::new(config.polling_interval),
+        config_path: args.config_path.clone(),
+    });
+
+    // A frontend leaves put.io and the download directory to its downloader
+    if config.mode == DeploymentMode::Frontend {
+        info!("Running as frontend, forwarding requests to the downloader");
+        return Ok(app_data);
+    }
+
+    if let Err(e) = prepare_download_directory(&app_data) {
+        error!("{:#}", e);
+        bail!(e)
+    }
+
+    match app_data.putio.account_info().await {
+        Ok(account_info) => {
+            info!(
+                "Logged in as user: {} (ID: {}) with email: {}",
+                account_info.info.username, account_info.info.user_id, account_info.info.mail
+            );
+            info!(
+                "Available space: {:.2} GB out of {:.2} GB ({:.2}%)",
+                account_info.info.disk.avail as f64 / 1_073_741_824.0,
+                account_info.info.disk.size as f64 / 1_073_741_824.0,
+                account_info.info.disk.avail as f64 / account_info.info.disk.size as f64 * 100.0
+            );
+        }
+        Err(e) => {
+            error!("{}", e);
+            bail!(e)
+        }
+    }
+
+    // create putioarr folder on put.io if it doesn't exist and remember its id
+    match resolve_root_folder(app_data.putio.as_ref()).await {
+        Ok(folder_id) => {
+            info!("putioarr folder ID: {}", folder_id);
+            let mut config_folder_id: RwLockWriteGuard<u64> =
+                app_data.root_folder_id.write().unwrap();
+            *config_folder_id = folder_id;
+        }
+        Err(e) => {
+            error!("{:#}", e);
+            bail!(e);
+        }
+    };
+
+    Ok(app_data)
 }
 
 const VERSION: &str = env!("CARGO_PKG_VERSION");
@@ -83,133 +1016,362 @@ async fn main() -> Result<()> {
 
     match &cli.command {
         Commands::Run(args) => {
-            let config: Config = Figment::new()
-                .join(Serialized::default("bind_address", "0.0.0.0"))
-                .join(Serialized::default("download_workers", 4))
-                .join(Serialized::default("orchestration_workers", 10))
-                .join(Serialized::default("loglevel", "info"))
-                .join(Serialized::default("polling_interval", 10))
-                .join(Serialized::default("port", 9091))
-                .join(Serialized::default("uid", 1000))
-                .join(Serialized::default(
-                    "skip_directories",
-                    vec!["sample", "extras"],
-                ))
-                .merge(Toml::file(&args.config_path))
-                .extract()?;
-
-            let log_timestamp = if in_container::in_container() {
-                Some(TimestampPrecision::Seconds)
-            } else if let Ok(istty) = nix::unistd::isatty(0) {
-                if istty {
-                    Some(TimestampPrecision::Seconds)
-                } else {
-                    None
-                }
-            } else {
-                None
-            };
-
-            env_logger::Builder::new()
-                .default_format()
-                .format_module_path(false)
-                .format_target(false)
-                .format_timestamp(log_timestamp)
-                .parse_filters(config.loglevel.as_str())
-                .init();
-
+            let config = load_config(&args.config_path)?;
+            init_logging(&config)?;
             info!("Starting putioarr, version {}", VERSION);
+            let app_data = init(args, &config).await?;
 
-            let app_data = web::Data::new(AppData {
-                config: config.clone(),
-                root_folder_id: RwLock::new(0),
-            });
-
-            match putio::account_info(&app_data.config.putio.api_key).await {
-                Ok(account_info) => {
-                    info!(
-                        "Logged in as user: {} (ID: {}) with email: {}",
-                        account_info.info.username,
-                        account_info.info.user_id,
-                        account_info.info.mail
-                    );
-                    info!(
-                        "Available space: {:.2} GB out of {:.2} GB ({:.2}%)",
-                        account_info.info.disk.avail as f64 / 1_073_741_824.0,
-                        account_info.info.disk.size as f64 / 1_073_741_824.0,
-                        account_info.info.disk.avail as f64 / account_info.info.disk.size as f64
-                            * 100.0
-                    );
-                }
-                Err(e) => {
-                    error!("{}", e);
-                    bail!(e)
+            let frontend = config.mode == DeploymentMode::Frontend;
+            if let Some(lock) = app_data.lock.as_ref().filter(|_| !frontend) {
+                let root_folder_id = *app_data.root_folder_id.read().unwrap();
+                match lock.acquire(app_data.putio.as_ref(), root_folder_id).await {
+                    Ok(_) => {}
+                    // In standby mode we serve clients read-only, and take over once the active
+                    // instance goes away
+                    Err(e) if config.standby => info!("Standing by: {:#}", e),
+                    Err(e) => {
+                        error!("{:#}", e);
+                        bail!(e);
+                    }
                 }
             }
 
-            // create putioarr folder on put.io if it doesn't exist
-            match putio::create_folder(&app_data.config.putio.api_key, "putioarr", 0).await {
-                Ok(_) => info!("Created putioarr folder on put.io"),
+            let listen_addresses = match listen_addresses(&config) {
+                Ok(addresses) => addresses,
                 Err(e) => {
-                    if e.to_string().contains("400 Bad Request") {
-                        info!("putioarr folder already exists on put.io");
-                    } else {
-                        error!("Failed to create putioarr folder: {}", e);
-                        bail!(e);
-                    }
-                    // get folder ID of putioarr folder and store it in config
-                    match putio::list_files(&app_data.config.putio.api_key, 0).await {
-                        Ok(file_list) => {
-                            // find folder with name "putioarr"
-                            let folder_id = file_list
-                                .files
-                                .iter()
-                                .find(|f| f.name == "putioarr")
-                                .unwrap()
-                                .id;
-                            info!("putioarr folder ID: {}", folder_id);
-                            let mut config_folder_id: RwLockWriteGuard<u64> =
-                                app_data.root_folder_id.write().unwrap();
-                            *config_folder_id = folder_id;
-                        }
-                        Err(e) => {
-                            error!("Failed to get folder ID: {}", e);
-                            bail!(e);
-                        }
-                    }
+                    error!("{:#}", e);
+                    bail!(e)
                 }
             };
 
-            let data_for_download_system = app_data.clone();
-            download_system::start(data_for_download_system)
-                .await
-                .unwrap();
+            if !frontend {
+                let data_for_download_system = app_data.clone();
+                download_system::start(data_for_download_system)
+                    .await
+                    .unwrap();
+            }
 
-            info!(
-                "Starting web server at http://{}:{}",
-                config.bind_address, config.port
-            );
-            HttpServer::new(move || {
+            let compression = config.compression;
+            let cors_origins = config.cors_origins.clone();
+            let rate_limit = rate_limit::enabled(&config);
+            let governor_config = rate_limit::governor_config(&config, &app_data.trusted_proxies);
+            let json_payload_limit = config.json_payload_limit_mb * 1_048_576;
+            let server_data = app_data.clone();
+            let mut server = HttpServer::new(move || {
+                let app_data = server_data.clone();
                 App::new()
-                    .wrap(Logger::new(
-                        "%a \"%r\" %s %b \"%{Referer}i\" \"%{User-Agent}i\" %T",
+                    .wrap(Condition::new(compression, Compress::default()))
+                    .wrap({
+                        let trusted_proxies = app_data.trusted_proxies.clone();
+                        Logger::new(
+                            "%{client}xi \"%r\" %s %b \"%{Referer}i\" \"%{User-Agent}i\" %T",
+                        )
+                        .custom_request_replace("client", move |req| {
+                            proxy::log_client_ip(req, &trusted_proxies)
+                        })
+                    })
+                    .wrap(Condition::new(rate_limit, Governor::new(&governor_config)))
+                    .wrap(Condition::new(
+                        !cors_origins.is_empty(),
+                        cors(&cors_origins),
                     ))
                     .app_data(app_data.clone())
+                    .app_data(
+                        web::JsonConfig::default()
+                            .limit(json_payload_limit)
+                            .error_handler(routes::json_error_handler),
+                    )
+                    .app_data(web::PayloadConfig::new(json_payload_limit))
                     .service(routes::rpc_post)
                     .service(routes::rpc_get)
-            })
-            .bind((config.bind_address, config.port))?
-            .run()
-            .await
-            .context("Unable to start http server")
+                    .service(routes::metrics)
+                    .service(api::get_settings)
+                    .service(api::update_settings)
+                    .service(api::verify_transfer)
+                    .service(api::pause_transfer)
+                    .service(api::resume_transfer)
+                    .service(api::history)
+                    .service(api::pipeline_status)
+                    .service(files::serve_file)
+                    .service(ui::index)
+                    .service(ui::asset)
+                    .service(internal::internal_rpc)
+                    .service(qbittorrent::login)
+                    .service(qbittorrent::version)
+                    .service(qbittorrent::webapi_version)
+                    .service(qbittorrent::preferences)
+                    .service(qbittorrent::categories)
+                    .service(qbittorrent::create_category)
+                    .service(qbittorrent::info)
+                    .service(qbittorrent::add)
+                    .service(qbittorrent::delete)
+                    .service(qbittorrent::top_priority)
+                    .service(qbittorrent::set_share_limits)
+                    .service(sabnzbd::api)
+                    .service(webhooks::arr_webhook)
+                    .service(webhooks::putio_webhook)
+            });
+            for address in &listen_addresses {
+                info!("Starting web server at http://{}", address);
+                let listener = bind(*address, &listen_addresses)
+                    .with_context(|| format!("Unable to bind to {}", address))?;
+                server = if config.http2 {
+                    server.listen_auto_h2c(listener)?
+                } else {
+                    server.listen(listener)?
+                };
+            }
+            let result = server.run().await.context("Unable to start http server");
+            if let Some(lock) = app_data.lock.as_ref().filter(|_| !frontend) {
+                lock.release(app_data.putio.as_ref()).await;
+            }
+            telemetry::shutdown();
+            result
         }
-        Commands::GetToken => {
-            get_token().await?;
+        Commands::RunOnce(args) => {
+            let config = load_config(&args.config_path)?;
+            init_logging(&config)?;
+            let app_data = init(args, &config).await?;
+            let result = download_system::once::run(app_data).await;
+            if let Err(e) = &result {
+                error!("{:#}", e);
+            }
+            telemetry::shutdown();
+            result
+        }
+        Commands::GetToken(args) => {
+            let timeout = args.timeout.map(Duration::from_secs);
+            let token = if args.browser {
+                get_token_browser(args.client_id, args.port, timeout).await?
+            } else {
+                get_token(timeout).await?
+            };
+            if let Some(config_path) = &args.write_config {
+                write_token(config_path, &token)?;
+                eprintln!("Wrote token to {}", config_path);
+            }
+            if args.json {
+                println!("{}", serde_json::json!({ "token": token }));
+            } else {
+                println!("Put.io API token: {token}");
+            }
             Ok(())
         }
         Commands::GenerateConfig(args) => {
-            generate_config(&args.config_path).await?;
+            generate_config(&args.run.config_path, args.from_transmission.as_deref()).await?;
+            Ok(())
+        }
+        Commands::HashPassword => hash_password(),
+        Commands::Sync(args) => {
+            let config = load_config(&args.run.config_path)?;
+            init_logging(&config)?;
+            let app_data = init(&args.run, &config).await?;
+            let result = download_system::sync::run(
+                app_data,
+                &args.folder,
+                &args.to,
+                args.delete,
+                args.watch,
+            )
+            .await;
+            if let Err(e) = &result {
+                error!("{:#}", e);
+            }
+            telemetry::shutdown();
+            result
+        }
+        Commands::Verify(args) => {
+            let config = load_config(&args.run.config_path)?;
+            init_logging(&config)?;
+            let app_data = init(&args.run, &config).await?;
+            let (dtx, drx) = async_channel::unbounded();
+            for id in 0..config.download_workers {
+                download_system::download::Worker::start(id, app_data.clone(), drx.clone());
+            }
+            let report = download_system::verify::verify(&app_data, &dtx, &args.hash).await;
+            match report {
+                Ok(report) => {
+                    println!(
+                        "Checked {} files, downloaded {} again",
+                        report.checked,
+                        report.repaired.len()
+                    );
+                    Ok(())
+                }
+                Err(e) => {
+                    error!("{:#}", e);
+                    Err(e)
+                }
+            }
+        }
+        Commands::Speedtest(args) => {
+            let config = load_config(&args.run.config_path)?;
+            init_logging(&config)?;
+            let app_data = init(&args.run, &config).await?;
+            let measurements = download_system::speedtest::run(
+                &app_data,
+                args.file,
+                &args.routes,
+                &args.connections,
+                Duration::from_secs(args.seconds),
+            )
+            .await;
+            match measurements {
+                Ok(measurements) => {
+                    println!("{:<20} {:>11} {:>12}", "Route", "Connections", "MB/s");
+                    for m in measurements {
+                        println!(
+                            "{:<20} {:>11} {:>12.2}",
+                            m.route,
+                            m.connections,
+                            m.speed / 1_048_576.0
+                        );
+                    }
+                    Ok(())
+                }
+                Err(e) => {
+                    error!("{:#}", e);
+                    Err(e)
+                }
+            }
+        }
+        Commands::State(args) => {
+            let config = load_config(&args.run.config_path)?;
+            let state = state::Store::open(&state_file(&args.run, &config))?;
+            match &args.command {
+                StateCommand::Export { output } => {
+                    let snapshot = serde_json::to_string_pretty(&state.export()?)?;
+                    match output {
+                        Some(output) => std::fs::write(output, snapshot + "\n")
+                            .with_context(|| format!("Unable to write {}", output))?,
+                        None => println!("{}", snapshot),
+                    }
+                }
+                StateCommand::Import { file } => {
+                    let snapshot = std::fs::read_to_string(file)
+                        .with_context(|| format!("Unable to read {}", file))?;
+                    state.import(&serde_json::from_str(&snapshot)?)?;
+                    println!("Imported state from {}", file);
+                }
+            }
             Ok(())
         }
     }
 }
+
+#[cfg(all(test, feature = "fake-putio"))]
+mod tests {
+    use super::*;
+    use services::fake_putio::FakePutIO;
+    use std::fs;
+    use tokio::time::sleep;
+
+    /// A directory of a test's own, removed once it is done.
+    struct TestDir(PathBuf);
+
+    impl TestDir {
+        fn new(name: &str) -> Self {
+            let path =
+                std::env::temp_dir().join(format!("putioarr-{}-{}", name, std::process::id()));
+            let _ = fs::remove_dir_all(&path);
+            fs::create_dir_all(&path).unwrap();
+            Self(path)
+        }
+    }
+
+    impl Drop for TestDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    #[actix_web::test]
+    async fn resolve_root_folder_creates_folder() {
+        let putio = FakePutIO::new();
+        let folder_id = resolve_root_folder(&putio).await.unwrap();
+        let files = putio.list_files(0).await.unwrap().files;
+        assert!(files
+            .iter()
+            .any(|f| f.id == folder_id && f.name == "putioarr" && f.file_type == "FOLDER"));
+    }
+
+    #[actix_web::test]
+    async fn resolve_root_folder_finds_existing_folder() {
+        let putio = FakePutIO::new();
+        let existing = putio.insert_file(0, "putioarr", "FOLDER");
+        assert_eq!(resolve_root_folder(&putio).await.unwrap(), existing);
+        assert_eq!(putio.list_files(0).await.unwrap().files.len(), 1);
+    }
+
+    #[actix_web::test]
+    async fn resolve_root_folder_fails_without_folder_in_listing() {
+        // Taken by a file, so creating the folder fails but the listing has no folder
+        let putio = FakePutIO::new();
+        putio.insert_file(0, "putioarr", "VIDEO");
+        let error = resolve_root_folder(&putio).await.unwrap_err();
+        assert!(error.to_string().contains("putioarr folder not found"));
+    }
+
+    #[actix_web::test]
+    async fn pipeline_downloads_and_cleans_up() {
+        let dir = TestDir::new("pipeline");
+        let downloads = dir.0.join("downloads");
+        let config_path = dir.0.join("config.toml");
+        fs::write(
+            &config_path,
+            format!(
+                "username = \"u\"\npassword = \"p\"\ndownload_directory = \"{}\"\n\
+                 polling_interval = 1\n[putio]\napi_key = \"key\"\n",
+                downloads.display()
+            ),
+        )
+        .unwrap();
+        let args = RunArgs {
+            config_path: config_path.to_string_lossy().to_string(),
+            demo: true,
+        };
+        let config = load_config(&args.config_path).unwrap();
+        let app_data = init(&args, &config).await.unwrap();
+        download_system::start(app_data.clone()).await.unwrap();
+
+        let root_folder_id = *app_data.root_folder_id.read().unwrap();
+        app_data
+            .putio
+            .add_transfer(
+                root_folder_id,
+                "magnet:?xt=urn:btih:abcdef0123456789abcdef0123456789abcdef01&dn=Some.Show.S01",
+            )
+            .await
+            .unwrap();
+
+        // Downloaded, then removed from put.io once put.io stopped seeding it
+        let video = downloads.join("Some.Show.S01/Some.Show.S01.mkv");
+        let done = async {
+            while !app_data
+                .putio
+                .list_transfers()
+                .await
+                .unwrap()
+                .transfers
+                .is_empty()
+            {
+                sleep(Duration::from_millis(100)).await;
+            }
+        };
+        tokio::time::timeout(Duration::from_secs(30), done)
+            .await
+            .expect("transfer was never removed from put.io");
+        assert_eq!(fs::read(&video).unwrap(), b"putioarr demo file\n");
+        let files = app_data
+            .putio
+            .list_files(root_folder_id)
+            .await
+            .unwrap()
+            .files;
+        assert!(files.iter().all(|f| f.name != "Some.Show.S01"));
+        assert!(app_data
+            .state
+            .stage("abcdef0123456789abcdef0123456789abcdef01")
+            .is_none());
+    }
+}
@@ -0,0 +1,92 @@
+// Records where completed downloads came from in a sidecar file, for reconciling them with put.io
+// later, cross-seeding tools and debugging.
+use crate::{
+    download_system::transfer::{DownloadTarget, TargetType, Transfer},
+    state::Source,
+    utils::set_owner,
+    AppData,
+};
+use anyhow::Result;
+use chrono::Utc;
+use lava_torrent::torrent::v1::Torrent;
+use log::warn;
+use magnet_url::Magnet;
+use serde_json::json;
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+/// Name of the sidecar written into downloaded folders. Single files get `<name>.putioarr.json`.
+const SIDECAR: &str = ".putioarr.json";
+
+/// Writes a sidecar with the transfer's hash, put.io ids and tracker next to its top-level target.
+/// Like notifications, this is best effort: failures are logged and never fail the transfer.
+pub fn record(app_data: &AppData, transfer: &Transfer, targets: &[DownloadTarget]) {
+    let Some(top_level) = targets.iter().find(|t| t.top_level) else {
+        return;
+    };
+    if let Err(e) = write(app_data, transfer, top_level, targets) {
+        warn!("{}: unable to record provenance: {}", transfer, e);
+    }
+}
+
+fn write(
+    app_data: &AppData,
+    transfer: &Transfer,
+    top_level: &DownloadTarget,
+    targets: &[DownloadTarget],
+) -> Result<()> {
+    let root = Path::new(&top_level.to);
+    let files: Vec<_> = targets
+        .iter()
+        .filter(|t| t.target_type == TargetType::File)
+        .map(|t| {
+            let path = Path::new(&t.to).strip_prefix(root).unwrap_or(Path::new(""));
+            json!({ "path": path, "file_id": t.file_id })
+        })
+        .collect();
+    let tracker = transfer
+        .hash
+        .as_deref()
+        .and_then(|hash| app_data.state.source(hash))
+        .and_then(|(source, _)| tracker_host(&source));
+    let provenance = json!({
+        "hash": transfer.hash,
+        "name": transfer.name,
+        "transfer_id": transfer.transfer_id,
+        "file_id": transfer.file_id,
+        "tracker": tracker,
+        "downloaded_at": Utc::now().to_rfc3339(),
+        "files": files,
+    });
+
+    let path = sidecar_path(top_level);
+    fs::write(&path, serde_json::to_vec_pretty(&provenance)?)?;
+    set_owner(app_data, &path)?;
+    Ok(())
+}
+
+fn sidecar_path(top_level: &DownloadTarget) -> PathBuf {
+    let path = Path::new(&top_level.to);
+    match top_level.target_type {
+        TargetType::Directory => path.join(SIDECAR),
+        TargetType::File => {
+            let name = path.file_name().unwrap_or_default().to_string_lossy();
+            path.with_file_name(format!("{}{}", name, SIDECAR))
+        }
+    }
+}
+
+/// Host of the first tracker of a torrent or magnet link. The rest of the URL is left out, since
+/// private trackers put passkeys in it.
+fn tracker_host(source: &Source) -> Option<String> {
+    let url = match source {
+        Source::Magnet(url) => Magnet::new(url).ok()?.tr.into_iter().next(),
+        Source::Torrent(bytes) => Torrent::read_from_bytes(bytes).ok()?.announce,
+    }?;
+    let url = urldecode::decode(url);
+    let rest = url.split_once("://").map_or(url.as_str(), |(_, rest)| rest);
+    let host = rest.split(['/', ':', '?']).next()?;
+    (!host.is_empty()).then(|| host.to_string())
+}
@@ -0,0 +1,43 @@
+// With remote_trash_days, the files of transfers that are done are moved into
+// `putioarr/putioarr-trash` on put.io instead of being deleted right away, so a mistake can still
+// be undone there. They go into a folder per day, and days older than remote_trash_days are
+// deleted for good whenever something is trashed.
+use crate::{category_folders::find_or_create, AppData};
+use anyhow::{Context, Result};
+use chrono::{Days, NaiveDate, Utc};
+use log::{info, warn};
+
+/// Folder in the putioarr folder holding the trashed files.
+const TRASH_FOLDER: &str = "putioarr-trash";
+
+/// Moves a file or folder into today's trash folder, then deletes the days older than days.
+pub async fn trash(app_data: &AppData, file_id: u64, days: u64) -> Result<()> {
+    let root_folder_id = *app_data.root_folder_id.read().unwrap();
+    let trash_id = find_or_create(app_data, TRASH_FOLDER, root_folder_id)
+        .await
+        .context("Unable to create trash folder on put.io")?;
+    let today = Utc::now().date_naive();
+    let day_id = find_or_create(app_data, &today.to_string(), trash_id).await?;
+    app_data.putio.move_file(file_id, day_id).await?;
+    if let Err(e) = empty(app_data, trash_id, today, days).await {
+        warn!("Unable to empty put.io trash: {}", e);
+    }
+    Ok(())
+}
+
+/// Deletes the day folders in the trash that are more than days old.
+async fn empty(app_data: &AppData, trash_id: u64, today: NaiveDate, days: u64) -> Result<()> {
+    let Some(oldest) = today.checked_sub_days(Days::new(days)) else {
+        return Ok(());
+    };
+    for folder in app_data.putio.list_files(trash_id).await?.files {
+        let Ok(day) = folder.name.parse::<NaiveDate>() else {
+            continue;
+        };
+        if day < oldest {
+            app_data.putio.delete_file(folder.id).await?;
+            info!("Deleted files trashed on {} from put.io", day);
+        }
+    }
+    Ok(())
+}
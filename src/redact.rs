@@ -0,0 +1,86 @@
+// Keeps credentials out of logs and error messages.
+use std::{io, sync::OnceLock};
+
+/// URL and magnet parameters whose values are credentials. Tracker URLs (`tr`) are included,
+/// since private trackers embed passkeys in them.
+const SENSITIVE_PARAMS: &[&str] = &[
+    "access_token",
+    "api_key",
+    "apikey",
+    "oauth_token",
+    "passkey",
+    "password",
+    "sig",
+    "signature",
+    "token",
+    "tr",
+];
+
+/// Secrets shorter than this aren't replaced literally, as that would mangle unrelated text.
+const MIN_SECRET_LENGTH: usize = 6;
+
+const REDACTED: &str = "[REDACTED]";
+
+static SECRETS: OnceLock<Vec<String>> = OnceLock::new();
+
+/// Registers configured secrets, like the put.io api key, so they are redacted wherever they
+/// show up. Only the first call has an effect.
+pub fn set_secrets(secrets: Vec<String>) {
+    let secrets = secrets
+        .into_iter()
+        .filter(|s| s.len() >= MIN_SECRET_LENGTH)
+        .collect();
+    let _ = SECRETS.set(secrets);
+}
+
+/// Returns s with registered secrets and the values of sensitive URL parameters replaced.
+pub fn redact(s: &str) -> String {
+    let mut redacted = s.to_string();
+    for secret in SECRETS.get().into_iter().flatten() {
+        redacted = redacted.replace(secret.as_str(), REDACTED);
+    }
+    redact_params(&redacted)
+}
+
+/// Replaces the values of `?name=value` and `&name=value` pairs for sensitive names.
+fn redact_params(s: &str) -> String {
+    let mut result = String::with_capacity(s.len());
+    let mut rest = s;
+    while let Some(i) = rest.find(['?', '&']) {
+        let (head, tail) = rest.split_at(i + 1);
+        result.push_str(head);
+        rest = tail;
+
+        let Some(param) = SENSITIVE_PARAMS
+            .iter()
+            .find(|p| rest.starts_with(**p) && rest[p.len()..].starts_with('='))
+        else {
+            continue;
+        };
+        result.push_str(param);
+        result.push('=');
+        result.push_str(REDACTED);
+        let value = &rest[param.len() + 1..];
+        let end = value
+            .find(|c: char| c == '&' || c == '"' || c == ')' || c.is_whitespace())
+            .unwrap_or(value.len());
+        rest = &value[end..];
+    }
+    result.push_str(rest);
+    result
+}
+
+/// Log writer that redacts every line before writing it to stdout.
+pub struct RedactingWriter;
+
+impl io::Write for RedactingWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let line = String::from_utf8_lossy(buf);
+        io::stdout().write_all(redact(&line).as_bytes())?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        io::stdout().flush()
+    }
+}
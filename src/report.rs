@@ -0,0 +1,192 @@
+// Completion reports: a JSON document per transfer once it is done with, for users building their
+// own analytics on top of putioarr.
+use crate::{
+    download_system::transfer::{TargetType, Transfer},
+    services::putio::PutIOTransfer,
+    AppData,
+};
+use anyhow::Result;
+use chrono::{DateTime, NaiveDateTime, Utc};
+use log::{info, warn};
+use serde::Serialize;
+use std::{fs::OpenOptions, io::Write, time::Duration};
+
+/// Number of reports kept in the state database with report_history.
+const HISTORY_SIZE: usize = 1000;
+
+/// When a transfer went through each stage, for reporting on it once it is done.
+#[derive(Debug, Clone)]
+pub struct Lifecycle {
+    pub added_at: Option<DateTime<Utc>>,
+    pub putio_finished_at: Option<DateTime<Utc>>,
+    pub queued_at: DateTime<Utc>,
+    pub download_started_at: Option<DateTime<Utc>>,
+    pub downloaded_at: Option<DateTime<Utc>>,
+    /// Download attempts it took, including the successful one.
+    pub attempts: u32,
+    /// Size put.io reports for the transfer.
+    pub size: u64,
+}
+
+impl Lifecycle {
+    pub fn new(transfer: &PutIOTransfer) -> Self {
+        Self {
+            added_at: putio_time(Some(&transfer.created_at)),
+            putio_finished_at: putio_time(transfer.finished_at.as_deref()),
+            queued_at: Utc::now(),
+            download_started_at: None,
+            downloaded_at: None,
+            attempts: 0,
+            size: transfer.size.unwrap_or(0).max(0) as u64,
+        }
+    }
+}
+
+/// put.io reports times in UTC without a timezone.
+fn putio_time(time: Option<&str>) -> Option<DateTime<Utc>> {
+    NaiveDateTime::parse_from_str(time?, "%FT%T")
+        .ok()
+        .map(|time| time.and_utc())
+}
+
+#[derive(Debug, Clone, Copy, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Outcome {
+    /// Downloaded and removed from put.io once it stopped seeding.
+    Cleaned,
+    /// Downloaded, but removed from put.io by someone else.
+    Removed,
+    /// Ran out of download attempts.
+    Failed,
+}
+
+#[derive(Debug, Serialize)]
+struct Report<'a> {
+    hash: Option<&'a str>,
+    name: &'a str,
+    transfer_id: u64,
+    category: Option<String>,
+    outcome: Outcome,
+    error: Option<&'a str>,
+    size: u64,
+    files: usize,
+    attempts: u32,
+    added_at: Option<DateTime<Utc>>,
+    putio_finished_at: Option<DateTime<Utc>>,
+    queued_at: DateTime<Utc>,
+    download_started_at: Option<DateTime<Utc>>,
+    downloaded_at: Option<DateTime<Utc>>,
+    finished_at: DateTime<Utc>,
+    stages: Stages,
+    /// Bytes per second while downloading.
+    average_speed: Option<f64>,
+}
+
+/// Seconds spent in each stage.
+#[derive(Debug, Serialize)]
+struct Stages {
+    putio: Option<i64>,
+    queued: Option<i64>,
+    downloading: Option<i64>,
+    seeding: Option<i64>,
+}
+
+fn seconds(from: Option<DateTime<Utc>>, to: Option<DateTime<Utc>>) -> Option<i64> {
+    Some((to? - from?).num_seconds().max(0))
+}
+
+/// Sends the completion report of a transfer to report_url, report_file and the report history,
+/// whichever are enabled. Like notifications, reports are best effort: failures are only logged.
+pub async fn report(
+    app_data: &AppData,
+    transfer: &Transfer,
+    outcome: Outcome,
+    error: Option<&str>,
+) {
+    let config = &app_data.config;
+    if config.report_url.is_none() && config.report_file.is_none() && !config.report_history {
+        return;
+    }
+
+    let lifecycle = &transfer.lifecycle;
+    let files: Vec<_> = transfer
+        .targets
+        .iter()
+        .flatten()
+        .filter(|t| t.target_type == TargetType::File)
+        .collect();
+    let size = match files.iter().filter_map(|t| t.size).sum() {
+        0 => lifecycle.size,
+        size => size,
+    };
+    let finished_at = Utc::now();
+    let downloading = seconds(lifecycle.download_started_at, lifecycle.downloaded_at);
+    let report = Report {
+        hash: transfer.hash.as_deref(),
+        name: &transfer.name,
+        transfer_id: transfer.transfer_id,
+        category: transfer
+            .hash
+            .as_deref()
+            .and_then(|hash| app_data.state.category(hash)),
+        outcome,
+        error,
+        size,
+        files: files.len(),
+        attempts: lifecycle.attempts,
+        added_at: lifecycle.added_at,
+        putio_finished_at: lifecycle.putio_finished_at,
+        queued_at: lifecycle.queued_at,
+        download_started_at: lifecycle.download_started_at,
+        downloaded_at: lifecycle.downloaded_at,
+        finished_at,
+        stages: Stages {
+            putio: seconds(lifecycle.added_at, lifecycle.putio_finished_at),
+            queued: seconds(Some(lifecycle.queued_at), lifecycle.download_started_at),
+            downloading,
+            seeding: seconds(lifecycle.downloaded_at, Some(finished_at)),
+        },
+        average_speed: downloading
+            .filter(|s| *s > 0)
+            .map(|s| size as f64 / s as f64),
+    };
+    let report = match serde_json::to_string(&report) {
+        Ok(report) => report,
+        Err(e) => {
+            warn!("{}: unable to create report: {}", transfer, e);
+            return;
+        }
+    };
+
+    if let Some(url) = &config.report_url {
+        let result = reqwest::Client::new()
+            .post(url)
+            .timeout(Duration::from_secs(10))
+            .header("Content-Type", "application/json")
+            .body(report.clone())
+            .send()
+            .await
+            .and_then(|r| r.error_for_status());
+        match result {
+            Ok(_) => info!("{}: sent report", transfer),
+            Err(e) => warn!("{}: unable to send report: {}", transfer, e),
+        }
+    }
+    if let Some(path) = &config.report_file {
+        if let Err(e) = append(path, &report) {
+            warn!("{}: unable to write report to {}: {}", transfer, path, e);
+        }
+    }
+    if config.report_history {
+        app_data
+            .state
+            .add_report(transfer.hash.as_deref(), &report, HISTORY_SIZE);
+    }
+}
+
+/// Appends a report to a file with one JSON document per line.
+fn append(path: &str, report: &str) -> Result<()> {
+    let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+    writeln!(file, "{}", report)?;
+    Ok(())
+}
@@ -0,0 +1,256 @@
+// Asks the arrs whether they imported a download, so transfers stay on put.io until they did.
+// see https://sonarr.tv/docs/api/#/History, the other arrs have forks of the same API.
+use crate::{
+    category_folders::reported_path,
+    download_system::transfer::{DownloadTarget, FileKind, TargetType},
+    AppData, ArrConfig, ArrKind,
+};
+use anyhow::{bail, Result};
+use async_trait::async_trait;
+use serde::Deserialize;
+use std::{collections::HashSet, time::Duration};
+use tracing::instrument;
+
+/// History records asked for per request.
+const PAGE_SIZE: usize = 250;
+/// Pages read at most, for downloads with a history too long to be worth reading.
+const MAX_PAGES: usize = 20;
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct HistoryResponse {
+    records: Vec<HistoryRecord>,
+    #[serde(default)]
+    total_records: usize,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct HistoryRecord {
+    event_type: String,
+    #[serde(default)]
+    data: HistoryData,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(rename_all = "camelCase")]
+struct HistoryData {
+    dropped_path: Option<String>,
+}
+
+/// An arr instance, which reports what it imported of a download in its history.
+#[async_trait]
+pub trait ArrClient: Send + Sync {
+    fn config(&self) -> &ArrConfig;
+
+    /// Whether it imports files of this kind, and so reports importing them.
+    fn imports(&self, file_kind: FileKind) -> bool;
+
+    /// Paths in the download directory of the files it imported from the download with this hash.
+    async fn imported_paths(&self, hash: &str) -> Result<HashSet<String>>;
+}
+
+/// Creates the clients of the configured instances, which share a connection pool.
+pub fn clients(configs: &[ArrConfig]) -> Vec<Box<dyn ArrClient>> {
+    let client = reqwest::Client::new();
+    configs
+        .iter()
+        .map(|config| {
+            let history = History {
+                config: config.clone(),
+                client: client.clone(),
+            };
+            match config.kind {
+                ArrKind::Sonarr => Box::new(Sonarr(history)) as Box<dyn ArrClient>,
+                ArrKind::Radarr => Box::new(Radarr(history)),
+                ArrKind::Whisparr => Box::new(Whisparr(history)),
+                ArrKind::Lidarr => Box::new(Lidarr(history)),
+                ArrKind::Readarr => Box::new(Readarr(history)),
+            }
+        })
+        .collect()
+}
+
+/// The history endpoint all arrs have, under the version of their API.
+struct History {
+    config: ArrConfig,
+    client: reqwest::Client,
+}
+
+impl History {
+    /// Dropped paths of the imported_event records of the download, reading page by page.
+    async fn imported_paths(
+        &self,
+        version: &str,
+        imported_event: &str,
+        hash: &str,
+    ) -> Result<HashSet<String>> {
+        let url = format!(
+            "{}/api/{}/history",
+            self.config.url.trim_end_matches('/'),
+            version
+        );
+        let download_id = hash.to_uppercase();
+        let mut imported = HashSet::new();
+        for page in 1..=MAX_PAGES {
+            let response = self
+                .client
+                .get(&url)
+                .timeout(Duration::from_secs(30))
+                .header("X-Api-Key", &self.config.api_key)
+                .query(&[
+                    ("downloadId", download_id.as_str()),
+                    ("page", page.to_string().as_str()),
+                    ("pageSize", PAGE_SIZE.to_string().as_str()),
+                ])
+                .send()
+                .await?;
+            if !response.status().is_success() {
+                bail!(
+                    "{} history request failed: {}",
+                    self.config.name,
+                    response.status()
+                );
+            }
+            let history: HistoryResponse = response.json().await?;
+            let count = history.records.len();
+            imported.extend(
+                history
+                    .records
+                    .into_iter()
+                    .filter(|record| record.event_type == imported_event)
+                    .filter_map(|record| record.data.dropped_path),
+            );
+            if count < PAGE_SIZE || page * PAGE_SIZE >= history.total_records {
+                break;
+            }
+        }
+        Ok(imported)
+    }
+}
+
+/// Sonarr v3 and v4, which kept the v3 API.
+struct Sonarr(History);
+
+#[async_trait]
+impl ArrClient for Sonarr {
+    fn config(&self) -> &ArrConfig {
+        &self.0.config
+    }
+
+    fn imports(&self, file_kind: FileKind) -> bool {
+        file_kind == FileKind::Video
+    }
+
+    async fn imported_paths(&self, hash: &str) -> Result<HashSet<String>> {
+        self.0
+            .imported_paths("v3", "downloadFolderImported", hash)
+            .await
+    }
+}
+
+/// Radarr v3 up to v5, which kept the v3 API.
+struct Radarr(History);
+
+#[async_trait]
+impl ArrClient for Radarr {
+    fn config(&self) -> &ArrConfig {
+        &self.0.config
+    }
+
+    fn imports(&self, file_kind: FileKind) -> bool {
+        file_kind == FileKind::Video
+    }
+
+    async fn imported_paths(&self, hash: &str) -> Result<HashSet<String>> {
+        self.0
+            .imported_paths("v3", "downloadFolderImported", hash)
+            .await
+    }
+}
+
+/// Whisparr, a fork of Radarr with its API.
+struct Whisparr(History);
+
+#[async_trait]
+impl ArrClient for Whisparr {
+    fn config(&self) -> &ArrConfig {
+        &self.0.config
+    }
+
+    fn imports(&self, file_kind: FileKind) -> bool {
+        file_kind == FileKind::Video
+    }
+
+    async fn imported_paths(&self, hash: &str) -> Result<HashSet<String>> {
+        self.0
+            .imported_paths("v3", "downloadFolderImported", hash)
+            .await
+    }
+}
+
+/// Lidarr, which has a history event per imported track.
+struct Lidarr(History);
+
+#[async_trait]
+impl ArrClient for Lidarr {
+    fn config(&self) -> &ArrConfig {
+        &self.0.config
+    }
+
+    fn imports(&self, file_kind: FileKind) -> bool {
+        file_kind == FileKind::Audio
+    }
+
+    async fn imported_paths(&self, hash: &str) -> Result<HashSet<String>> {
+        self.0.imported_paths("v1", "trackFileImported", hash).await
+    }
+}
+
+/// Readarr, which has a history event per imported ebook or audiobook file.
+struct Readarr(History);
+
+#[async_trait]
+impl ArrClient for Readarr {
+    fn config(&self) -> &ArrConfig {
+        &self.0.config
+    }
+
+    fn imports(&self, file_kind: FileKind) -> bool {
+        matches!(file_kind, FileKind::Ebook | FileKind::Audio)
+    }
+
+    async fn imported_paths(&self, hash: &str) -> Result<HashSet<String>> {
+        self.0.imported_paths("v1", "bookFileImported", hash).await
+    }
+}
+
+/// The arr instance a transfer of this category belongs to: the one configured for the category,
+/// or else the one without a category.
+pub fn arr_for<'a>(app_data: &'a AppData, category: Option<&str>) -> Option<&'a dyn ArrClient> {
+    let arrs = &app_data.arrs;
+    arrs.iter()
+        .find(|arr| {
+            let config = arr.config();
+            config.category.is_some() && config.category.as_deref() == category
+        })
+        .or_else(|| arrs.iter().find(|arr| arr.config().category.is_none()))
+        .map(|arr| arr.as_ref())
+}
+
+/// Whether the arr imported every file among targets it imports, like the videos extracted from
+/// archives but not the archives or subtitles, according to its history of the download. Its history
+/// has the paths as it sees them, see reported_download_directory.
+#[instrument(skip_all, fields(arr = %arr.config().name))]
+pub async fn check_imported(
+    app_data: &AppData,
+    arr: &dyn ArrClient,
+    hash: &str,
+    targets: &[DownloadTarget],
+) -> Result<bool> {
+    let imported = arr.imported_paths(hash).await?;
+    Ok(targets
+        .iter()
+        .filter(|t| t.target_type == TargetType::File && arr.imports(t.file_kind))
+        .all(|t| imported.contains(&reported_path(app_data, Some(hash), &t.to))))
+}
@@ -0,0 +1,410 @@
+// In-memory put.io backend, used for integration tests and `putioarr run --demo`.
+use super::putio::{
+    AccountInfoResponse, CreateFolderResponse, FileResponse, GetTransferResponse, ListFileResponse,
+    ListTransferResponse, PutIOApi, PutIOTransfer, TunnelRoute,
+};
+use anyhow::{bail, Context, Result};
+use async_trait::async_trait;
+use lava_torrent::torrent::v1::Torrent;
+use magnet_url::Magnet;
+use serde_json::json;
+use std::{
+    collections::HashMap,
+    io::{Cursor, Write},
+    sync::Mutex,
+};
+use zip::{write::SimpleFileOptions, ZipWriter};
+
+/// Number of times a transfer is reported as seeding before it completes.
+const SEEDING_POLLS: u32 = 3;
+
+/// A fake put.io account. Added transfers finish right away with a single small video file and
+/// seed for a few polls before completing, which is enough to drive the whole pipeline.
+pub struct FakePutIO {
+    state: Mutex<State>,
+}
+
+struct State {
+    next_id: u64,
+    transfers: Vec<FakeTransfer>,
+    files: HashMap<u64, FakeFile>,
+    tunnel_route: String,
+}
+
+struct FakeTransfer {
+    id: u64,
+    name: String,
+    hash: String,
+    file_id: u64,
+    save_parent_id: u64,
+    seeding_polls: u32,
+}
+
+struct FakeFile {
+    id: u64,
+    parent_id: u64,
+    name: String,
+    file_type: String,
+    content: Vec<u8>,
+}
+
+impl FakeFile {
+    fn response(&self) -> FileResponse {
+        FileResponse {
+            content_type: if self.file_type == "FOLDER" {
+                String::from("application/x-directory")
+            } else {
+                String::from("video/x-matroska")
+            },
+            id: self.id,
+            name: self.name.clone(),
+            file_type: self.file_type.clone(),
+            size: Some(self.content.len() as u64),
+            crc32: Some(format!("{:08x}", crc32fast::hash(&self.content))),
+        }
+    }
+}
+
+impl FakeTransfer {
+    fn to_putio(&self) -> Result<PutIOTransfer> {
+        let status = if self.seeding_polls > 0 {
+            "SEEDING"
+        } else {
+            "COMPLETED"
+        };
+        Ok(serde_json::from_value(json!({
+            "availability": 100,
+            "completion_percent": 100,
+            "created_at": "2024-01-01T00:00:00",
+            "created_torrent": false,
+            "file_id": self.file_id,
+            "finished_at": "2024-01-01T00:00:00",
+            "hash": self.hash,
+            "id": self.id,
+            "is_private": false,
+            "name": self.name,
+            "percent_done": 100,
+            "save_parent_id": self.save_parent_id,
+            "simulated": true,
+            "size": DEMO_CONTENT.len(),
+            "started_at": "2024-01-01T00:00:00",
+            "status": status,
+            "type": "TORRENT",
+            "userfile_exists": true,
+        }))?)
+    }
+}
+
+const DEMO_CONTENT: &[u8] = b"putioarr demo file\n";
+
+/// Parent of the zips, which aren't in any folder.
+const ZIPS_FOLDER: u64 = u64::MAX;
+
+impl FakePutIO {
+    pub fn new() -> Self {
+        let root = FakeFile {
+            id: 0,
+            parent_id: 0,
+            name: String::from("root"),
+            file_type: String::from("FOLDER"),
+            content: vec![],
+        };
+        Self {
+            state: Mutex::new(State {
+                next_id: 1,
+                transfers: vec![],
+                files: HashMap::from([(0, root)]),
+                tunnel_route: String::from("demo"),
+            }),
+        }
+    }
+
+    /// Adds a finished transfer with a folder holding a single video file.
+    fn add(&self, folder_id: u64, name: &str, hash: Option<String>) {
+        let mut state = self.state.lock().unwrap();
+        let folder = state.insert_file(folder_id, name, "FOLDER", vec![]);
+        state.insert_file(
+            folder,
+            &format!("{}.mkv", name),
+            "VIDEO",
+            DEMO_CONTENT.to_vec(),
+        );
+        let id = state.next_id();
+        let hash = hash.unwrap_or_else(|| format!("{:040x}", id));
+        state.transfers.push(FakeTransfer {
+            id,
+            name: name.to_string(),
+            hash,
+            file_id: folder,
+            save_parent_id: folder_id,
+            seeding_polls: SEEDING_POLLS,
+        });
+    }
+}
+
+#[cfg(test)]
+impl FakePutIO {
+    /// Adds a file or folder, for tests that need the account in a certain state.
+    pub fn insert_file(&self, parent_id: u64, name: &str, file_type: &str) -> u64 {
+        self.state
+            .lock()
+            .unwrap()
+            .insert_file(parent_id, name, file_type, vec![])
+    }
+}
+
+impl Default for FakePutIO {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl State {
+    fn next_id(&mut self) -> u64 {
+        let id = self.next_id;
+        self.next_id += 1;
+        id
+    }
+
+    fn insert_file(
+        &mut self,
+        parent_id: u64,
+        name: &str,
+        file_type: &str,
+        content: Vec<u8>,
+    ) -> u64 {
+        let id = self.next_id();
+        self.files.insert(
+            id,
+            FakeFile {
+                id,
+                parent_id,
+                name: name.to_string(),
+                file_type: file_type.to_string(),
+                content,
+            },
+        );
+        id
+    }
+
+    fn transfer(&mut self, transfer_id: u64) -> Result<&mut FakeTransfer> {
+        self.transfers
+            .iter_mut()
+            .find(|t| t.id == transfer_id)
+            .context("Error getting put.io transfer: 404 Not Found")
+    }
+}
+
+#[async_trait]
+impl PutIOApi for FakePutIO {
+    async fn account_info(&self) -> Result<AccountInfoResponse> {
+        let tunnel_route = self.state.lock().unwrap().tunnel_route.clone();
+        Ok(serde_json::from_value(json!({
+            "status": "OK",
+            "info": {
+                "account_active": true,
+                "account_status": "active",
+                "avatar_url": "",
+                "can_create_sub_account": false,
+                "disk": { "avail": 100u64 << 30, "size": 100u64 << 30, "used": 0 },
+                "is_eligible_for_friend_invitation": false,
+                "is_sub_account": false,
+                "mail": "demo@example.com",
+                "monthly_bandwidth_usage": 0,
+                "settings": {
+                    "beta_user": false,
+                    "dark_theme": false,
+                    "default_download_folder": 0,
+                    "dont_autoselect_subtitles": false,
+                    "fluid_layout": false,
+                    "hide_subtitles": false,
+                    "history_enabled": false,
+                    "is_invisible": false,
+                    "login_mails_enabled": false,
+                    "next_episode": false,
+                    "show_optimistic_usage": false,
+                    "sort_by": "NAME_ASC",
+                    "start_from": false,
+                    "subtitle_languages": [],
+                    "theater_mode": false,
+                    "theme": "auto",
+                    "trash_enabled": false,
+                    "tunnel_route_name": tunnel_route,
+                    "two_factor_enabled": false,
+                    "use_private_download_ip": false,
+                    "use_start_from": false,
+                },
+                "trash_size": 0,
+                "user_id": 1,
+                "username": "demo",
+            },
+        }))?)
+    }
+
+    async fn list_transfers(&self) -> Result<ListTransferResponse> {
+        let mut state = self.state.lock().unwrap();
+        let transfers = state
+            .transfers
+            .iter_mut()
+            .map(|t| {
+                t.seeding_polls = t.seeding_polls.saturating_sub(1);
+                t.to_putio()
+            })
+            .collect::<Result<_>>()?;
+        Ok(ListTransferResponse { transfers })
+    }
+
+    async fn get_transfer(&self, transfer_id: u64) -> Result<GetTransferResponse> {
+        let mut state = self.state.lock().unwrap();
+        let transfer = state.transfer(transfer_id)?;
+        transfer.seeding_polls = transfer.seeding_polls.saturating_sub(1);
+        Ok(GetTransferResponse {
+            transfer: transfer.to_putio()?,
+        })
+    }
+
+    async fn remove_transfer(&self, transfer_id: u64) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        state.transfer(transfer_id)?;
+        state.transfers.retain(|t| t.id != transfer_id);
+        Ok(())
+    }
+
+    async fn stop_seeding(&self, transfer_id: u64) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        state.transfer(transfer_id)?.seeding_polls = 0;
+        Ok(())
+    }
+
+    async fn delete_file(&self, file_id: u64) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        if file_id == 0 || state.files.remove(&file_id).is_none() {
+            bail!("Error removing put.io file/directory: 404 Not Found");
+        }
+        state.files.retain(|_, f| f.parent_id != file_id);
+        Ok(())
+    }
+
+    async fn add_transfer(&self, folder_id: u64, url: &str) -> Result<()> {
+        let (name, hash) = match Magnet::new(url) {
+            Ok(m) => (
+                m.dn.map(urldecode::decode)
+                    .unwrap_or_else(|| String::from("Demo transfer")),
+                m.xt.map(|xt| xt.to_lowercase()),
+            ),
+            Err(_) => (
+                url.rsplit('/')
+                    .next()
+                    .unwrap_or("Demo transfer")
+                    .to_string(),
+                None,
+            ),
+        };
+        self.add(folder_id, &name, hash);
+        Ok(())
+    }
+
+    async fn upload_file(&self, folder_id: u64, bytes: &[u8]) -> Result<()> {
+        let torrent = Torrent::read_from_bytes(bytes)?;
+        self.add(folder_id, &torrent.name, Some(torrent.info_hash()));
+        Ok(())
+    }
+
+    async fn list_files(&self, file_id: u64) -> Result<ListFileResponse> {
+        let state = self.state.lock().unwrap();
+        let parent = state
+            .files
+            .get(&file_id)
+            .context("Error listing put.io file/directory: 404 Not Found")?;
+        let mut files: Vec<FileResponse> = state
+            .files
+            .values()
+            .filter(|f| f.parent_id == file_id && f.id != 0)
+            .map(FakeFile::response)
+            .collect();
+        files.sort_by_key(|f| f.id);
+        Ok(ListFileResponse {
+            files,
+            parent: parent.response(),
+        })
+    }
+
+    async fn create_folder(&self, name: &str, parent_id: u64) -> Result<CreateFolderResponse> {
+        let mut state = self.state.lock().unwrap();
+        if state
+            .files
+            .values()
+            .any(|f| f.parent_id == parent_id && f.name == name && f.id != 0)
+        {
+            bail!("Error creating put.io folder: 400 Bad Request");
+        }
+        let id = state.insert_file(parent_id, name, "FOLDER", vec![]);
+        Ok(CreateFolderResponse {
+            file: state.files[&id].response(),
+        })
+    }
+
+    async fn move_file(&self, file_id: u64, parent_id: u64) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        if !state.files.contains_key(&parent_id) {
+            bail!("Error moving put.io file id:{}: 404 Not Found", file_id);
+        }
+        let file = state
+            .files
+            .get_mut(&file_id)
+            .context("Error moving put.io file: 404 Not Found")?;
+        file.parent_id = parent_id;
+        Ok(())
+    }
+
+    async fn url(&self, file_id: u64) -> Result<String> {
+        Ok(format!("fake://files/{}", file_id))
+    }
+
+    async fn tunnel_routes(&self) -> Result<Vec<TunnelRoute>> {
+        Ok(["demo", "demo-alt"]
+            .into_iter()
+            .map(|name| TunnelRoute {
+                name: name.to_string(),
+                description: format!("Fake {} route", name),
+            })
+            .collect())
+    }
+
+    async fn set_tunnel_route(&self, name: &str) -> Result<()> {
+        self.state.lock().unwrap().tunnel_route = name.to_string();
+        Ok(())
+    }
+
+    async fn download(&self, url: &str) -> Result<reqwest::Response> {
+        let file_id: u64 = url
+            .strip_prefix("fake://files/")
+            .context("Not a fake put.io url")?
+            .parse()?;
+        let state = self.state.lock().unwrap();
+        let file = state
+            .files
+            .get(&file_id)
+            .context("Error downloading put.io file: 404 Not Found")?;
+        Ok(http::Response::new(file.content.clone()).into())
+    }
+
+    async fn create_zip(&self, file_ids: &[u64]) -> Result<u64> {
+        let mut state = self.state.lock().unwrap();
+        let mut zip = ZipWriter::new(Cursor::new(Vec::new()));
+        for file_id in file_ids {
+            let file = state
+                .files
+                .get(file_id)
+                .context("Error creating put.io zip: 404 Not Found")?;
+            zip.start_file(file.name.as_str(), SimpleFileOptions::default())?;
+            zip.write_all(&file.content)?;
+        }
+        let content = zip.finish()?.into_inner();
+        Ok(state.insert_file(ZIPS_FOLDER, "demo.zip", "ARCHIVE", content))
+    }
+
+    async fn zip_url(&self, zip_id: u64) -> Result<Option<String>> {
+        Ok(Some(format!("fake://files/{}", zip_id)))
+    }
+}
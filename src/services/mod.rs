@@ -1,2 +1,7 @@
+pub mod arr;
+#[cfg(feature = "fake-putio")]
+pub mod fake_putio;
+pub mod notify;
+pub mod opensubtitles;
 pub mod putio;
 pub mod transmission;
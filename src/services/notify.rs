@@ -0,0 +1,53 @@
+use crate::{download_system::failure::FailureCategory, redact::redact, Config};
+use log::{info, warn};
+use serde_json::{json, Value};
+use std::time::Duration;
+
+/// Sends a notification to the configured webhook. Notifications are best effort: failures are
+/// logged and never returned to the caller.
+pub async fn notify(config: &Config, event: &str, message: &str) {
+    send(
+        config,
+        event,
+        json!({
+            "event": event,
+            "message": redact(message),
+        }),
+    )
+    .await
+}
+
+/// Sends a transfer_failed notification with the category of the failure and what to do about it.
+pub async fn notify_failure(config: &Config, category: FailureCategory, message: &str) {
+    send(
+        config,
+        "transfer_failed",
+        json!({
+            "event": "transfer_failed",
+            "message": redact(message),
+            "category": category.name(),
+            "remediation": category.remediation(),
+        }),
+    )
+    .await
+}
+
+async fn send(config: &Config, event: &str, body: Value) {
+    let Some(url) = &config.notification_url else {
+        return;
+    };
+
+    let client = reqwest::Client::new();
+    let result = client
+        .post(url)
+        .timeout(Duration::from_secs(10))
+        .json(&body)
+        .send()
+        .await
+        .and_then(|r| r.error_for_status());
+
+    match result {
+        Ok(_) => info!("Sent {} notification", event),
+        Err(e) => warn!("Unable to send {} notification: {}", event, e),
+    }
+}
@@ -0,0 +1,228 @@
+// Fetches subtitles for downloaded videos from OpenSubtitles, for users not running Bazarr.
+// see https://opensubtitles.stoplight.io/docs/opensubtitles-api
+use crate::{
+    download_system::transfer::{DownloadTarget, TargetType},
+    utils::set_owner,
+    AppData, OpenSubtitlesConfig,
+};
+use anyhow::{bail, Context, Result};
+use log::{info, warn};
+use serde::Deserialize;
+use serde_json::json;
+use std::{
+    fs::File,
+    io::{Read, Seek, SeekFrom},
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+const API_URL: &str = "https://api.opensubtitles.com/api/v1";
+/// The OpenSubtitles hash covers this many bytes at the start and at the end of a file.
+const HASH_CHUNK: u64 = 64 * 1024;
+
+#[derive(Deserialize)]
+struct LoginResponse {
+    token: String,
+}
+
+#[derive(Deserialize)]
+struct SearchResponse {
+    data: Vec<Subtitle>,
+}
+
+#[derive(Deserialize)]
+struct Subtitle {
+    attributes: SubtitleAttributes,
+}
+
+#[derive(Deserialize)]
+struct SubtitleAttributes {
+    language: Option<String>,
+    #[serde(default)]
+    moviehash_match: bool,
+    files: Vec<SubtitleFile>,
+}
+
+#[derive(Deserialize)]
+struct SubtitleFile {
+    file_id: u64,
+}
+
+#[derive(Deserialize)]
+struct DownloadResponse {
+    link: String,
+}
+
+/// A logged in OpenSubtitles session.
+struct Session<'a> {
+    config: &'a OpenSubtitlesConfig,
+    client: reqwest::Client,
+    token: String,
+}
+
+impl<'a> Session<'a> {
+    async fn login(config: &'a OpenSubtitlesConfig) -> Result<Self> {
+        let client = reqwest::Client::builder()
+            .user_agent(format!("putioarr v{}", env!("CARGO_PKG_VERSION")))
+            .timeout(Duration::from_secs(30))
+            .build()?;
+        let response = client
+            .post(format!("{}/login", API_URL))
+            .header("Api-Key", &config.api_key)
+            .json(&json!({
+                "username": config.username,
+                "password": config.password,
+            }))
+            .send()
+            .await?;
+        if !response.status().is_success() {
+            bail!("Error logging in to OpenSubtitles: {}", response.status());
+        }
+        let login: LoginResponse = response.json().await?;
+        Ok(Self {
+            config,
+            client,
+            token: login.token,
+        })
+    }
+
+    /// Finds the best subtitle for a video in each configured language, preferring ones that
+    /// were made for this exact file.
+    async fn search(&self, name: &str, hash: Option<&str>) -> Result<Vec<(String, u64)>> {
+        let mut query = vec![
+            ("query", name.to_string()),
+            ("languages", self.config.languages.join(",").to_lowercase()),
+        ];
+        if let Some(hash) = hash {
+            query.push(("moviehash", hash.to_string()));
+        }
+        let response = self
+            .client
+            .get(format!("{}/subtitles", API_URL))
+            .header("Api-Key", &self.config.api_key)
+            .query(&query)
+            .send()
+            .await?;
+        if !response.status().is_success() {
+            bail!("Error searching OpenSubtitles: {}", response.status());
+        }
+        let mut results: SearchResponse = response.json().await?;
+        results.data.sort_by_key(|s| !s.attributes.moviehash_match);
+
+        let mut found = Vec::new();
+        for language in &self.config.languages {
+            let language = language.to_lowercase();
+            let subtitle = results.data.iter().find(|s| {
+                s.attributes.language.as_deref() == Some(language.as_str())
+                    && !s.attributes.files.is_empty()
+            });
+            if let Some(subtitle) = subtitle {
+                found.push((language, subtitle.attributes.files[0].file_id));
+            }
+        }
+        Ok(found)
+    }
+
+    async fn download(&self, file_id: u64) -> Result<Vec<u8>> {
+        let response = self
+            .client
+            .post(format!("{}/download", API_URL))
+            .header("Api-Key", &self.config.api_key)
+            .bearer_auth(&self.token)
+            .json(&json!({ "file_id": file_id }))
+            .send()
+            .await?;
+        if !response.status().is_success() {
+            bail!(
+                "Error downloading from OpenSubtitles: {}",
+                response.status()
+            );
+        }
+        let download: DownloadResponse = response.json().await?;
+        let response = self.client.get(&download.link).send().await?;
+        if !response.status().is_success() {
+            bail!("Error downloading subtitle file: {}", response.status());
+        }
+        Ok(response.bytes().await?.to_vec())
+    }
+}
+
+/// Writes subtitles in the configured languages next to each downloaded video. Like
+/// notifications, this is best effort: failures are logged and never fail the transfer.
+pub async fn fetch_subtitles(app_data: &AppData, targets: &[DownloadTarget]) {
+    let Some(config) = &app_data.config.opensubtitles else {
+        return;
+    };
+    let videos: Vec<_> = targets
+        .iter()
+        .filter(|t| t.target_type == TargetType::File)
+        .collect();
+    if videos.is_empty() {
+        return;
+    }
+
+    let session = match Session::login(config).await {
+        Ok(session) => session,
+        Err(e) => {
+            warn!("Unable to fetch subtitles: {}", e);
+            return;
+        }
+    };
+    for video in videos {
+        if let Err(e) = fetch_for_video(app_data, &session, Path::new(&video.to)).await {
+            warn!("{}: unable to fetch subtitles: {}", video.to, e);
+        }
+    }
+}
+
+async fn fetch_for_video(app_data: &AppData, session: &Session<'_>, video: &Path) -> Result<()> {
+    let name = video
+        .file_stem()
+        .context("Video has no file name")?
+        .to_string_lossy()
+        .to_string();
+    let path = video.to_path_buf();
+    let hash = tokio::task::spawn_blocking(move || movie_hash(&path)).await??;
+
+    let subtitles = session.search(&name, hash.as_deref()).await?;
+    if subtitles.is_empty() {
+        info!("{}: no subtitles found", video.display());
+    }
+    for (language, file_id) in subtitles {
+        let to = subtitle_path(video, &language);
+        if to.exists() {
+            continue;
+        }
+        let content = session.download(file_id).await?;
+        tokio::fs::write(&to, content).await?;
+        set_owner(app_data, &to)?;
+        info!("{}: subtitle downloaded", to.display());
+    }
+    Ok(())
+}
+
+/// Where the subtitle in a language goes, e.g. `Movie.en.srt` for `Movie.mkv`.
+fn subtitle_path(video: &Path, language: &str) -> PathBuf {
+    let stem = video.file_stem().unwrap_or_default().to_string_lossy();
+    video.with_file_name(format!("{}.{}.srt", stem, language))
+}
+
+/// Computes the OpenSubtitles hash of a file: its size plus the sum of the little endian 64-bit
+/// words in its first and last 64 KiB. Files too small to hash are searched for by name only.
+fn movie_hash(path: &Path) -> Result<Option<String>> {
+    let mut file = File::open(path)?;
+    let size = file.metadata()?.len();
+    if size < HASH_CHUNK * 2 {
+        return Ok(None);
+    }
+    let mut hash = size;
+    let mut buffer = vec![0u8; HASH_CHUNK as usize];
+    for offset in [0, size - HASH_CHUNK] {
+        file.seek(SeekFrom::Start(offset))?;
+        file.read_exact(&mut buffer)?;
+        for word in buffer.chunks_exact(8) {
+            hash = hash.wrapping_add(u64::from_le_bytes(word.try_into()?));
+        }
+    }
+    Ok(Some(format!("{:016x}", hash)))
+}
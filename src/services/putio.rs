@@ -1,8 +1,418 @@
 use anyhow::{bail, Ok, Result};
+use async_trait::async_trait;
 use colored::Colorize;
 use reqwest::multipart;
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Deserializer, Serialize};
 use std::{collections::HashMap, time::Duration};
+use tracing::instrument;
+
+/// The put.io operations putioarr depends on. [`PutIoClient`] talks to the real API; with the
+/// `fake-putio` feature an in-memory implementation is available for tests and demo mode.
+#[async_trait]
+pub trait PutIOApi: Send + Sync {
+    async fn account_info(&self) -> Result<AccountInfoResponse>;
+    async fn list_transfers(&self) -> Result<ListTransferResponse>;
+    async fn get_transfer(&self, transfer_id: u64) -> Result<GetTransferResponse>;
+    async fn remove_transfer(&self, transfer_id: u64) -> Result<()>;
+    /// Stops a transfer that is seeding.
+    async fn stop_seeding(&self, transfer_id: u64) -> Result<()>;
+    async fn delete_file(&self, file_id: u64) -> Result<()>;
+    async fn add_transfer(&self, folder_id: u64, url: &str) -> Result<()>;
+    async fn upload_file(&self, folder_id: u64, bytes: &[u8]) -> Result<()>;
+    async fn list_files(&self, file_id: u64) -> Result<ListFileResponse>;
+    async fn create_folder(&self, name: &str, parent_id: u64) -> Result<CreateFolderResponse>;
+    /// Moves a file or folder into another folder.
+    async fn move_file(&self, file_id: u64, parent_id: u64) -> Result<()>;
+    async fn url(&self, file_id: u64) -> Result<String>;
+    /// Lists the routes downloads can take to put.io.
+    async fn tunnel_routes(&self) -> Result<Vec<TunnelRoute>>;
+    /// Changes the route the account's downloads take.
+    async fn set_tunnel_route(&self, name: &str) -> Result<()>;
+    /// Starts downloading a URL returned by [`PutIOApi::url`].
+    async fn download(&self, url: &str) -> Result<reqwest::Response>;
+    /// Has put.io create a zip of the files. Returns the id of the zip.
+    async fn create_zip(&self, file_ids: &[u64]) -> Result<u64>;
+    /// The download URL of a zip, or None while put.io is still creating it.
+    async fn zip_url(&self, zip_id: u64) -> Result<Option<String>>;
+}
+
+/// Client for the put.io API, authenticated with the user's api token.
+pub struct PutIoClient {
+    api_token: String,
+    /// Client for API calls, shared so connections to put.io are reused.
+    client: reqwest::Client,
+    /// Client for file downloads. Unlike API calls these can take hours, so they only get a
+    /// connect timeout and an idle timeout instead of an overall one.
+    download_client: reqwest::Client,
+    /// URL put.io calls when a transfer we added finished, see [`crate::http::webhooks`].
+    callback_url: Option<String>,
+}
+
+impl PutIoClient {
+    pub fn new(
+        api_token: &str,
+        download_connect_timeout: Duration,
+        download_idle_timeout: Option<Duration>,
+        callback_url: Option<String>,
+    ) -> Result<Self> {
+        let mut download_client =
+            reqwest::Client::builder().connect_timeout(download_connect_timeout);
+        if let Some(idle_timeout) = download_idle_timeout {
+            download_client = download_client.read_timeout(idle_timeout);
+        }
+        Ok(Self {
+            api_token: api_token.to_string(),
+            client: reqwest::Client::new(),
+            download_client: download_client.build()?,
+            callback_url,
+        })
+    }
+}
+
+#[async_trait]
+impl PutIOApi for PutIoClient {
+    #[instrument(skip(self))]
+    async fn account_info(&self) -> Result<AccountInfoResponse> {
+        let response = self
+            .client
+            .get("https://api.put.io/v2/account/info")
+            .header("authorization", format!("Bearer {}", self.api_token))
+            .send()
+            .await?;
+
+        if !response.status().is_success() {
+            bail!("Error getting put.io account info: {}", response.status());
+        }
+
+        Ok(response.json().await?)
+    }
+
+    #[instrument(skip(self))]
+    async fn list_transfers(&self) -> Result<ListTransferResponse> {
+        let response = self
+            .client
+            .get("https://api.put.io/v2/transfers/list")
+            .timeout(Duration::from_secs(10))
+            .header("authorization", format!("Bearer {}", self.api_token))
+            .send()
+            .await?;
+
+        if !response.status().is_success() {
+            bail!("Error getting put.io transfers: {}", response.status());
+        }
+
+        Ok(response.json().await?)
+    }
+
+    #[instrument(skip(self))]
+    async fn get_transfer(&self, transfer_id: u64) -> Result<GetTransferResponse> {
+        let response = self
+            .client
+            .get(format!("https://api.put.io/v2/transfers/{}", transfer_id))
+            .timeout(Duration::from_secs(10))
+            .header("authorization", format!("Bearer {}", self.api_token))
+            .send()
+            .await?;
+
+        if !response.status().is_success() {
+            bail!(
+                "Error getting put.io transfer id:{}: {}",
+                transfer_id,
+                response.status()
+            );
+        }
+
+        Ok(response.json().await?)
+    }
+
+    #[instrument(skip(self))]
+    async fn remove_transfer(&self, transfer_id: u64) -> Result<()> {
+        let form = multipart::Form::new().text("transfer_ids", transfer_id.to_string());
+        let response = self
+            .client
+            .post("https://api.put.io/v2/transfers/remove")
+            .timeout(Duration::from_secs(10))
+            .multipart(form)
+            .header("authorization", format!("Bearer {}", self.api_token))
+            .send()
+            .await?;
+
+        if !response.status().is_success() {
+            bail!(
+                "Error removing put.io transfer id:{}: {}",
+                transfer_id,
+                response.status()
+            );
+        }
+
+        Ok(())
+    }
+
+    #[instrument(skip(self))]
+    async fn stop_seeding(&self, transfer_id: u64) -> Result<()> {
+        let form = multipart::Form::new().text("transfer_ids", transfer_id.to_string());
+        let response = self
+            .client
+            .post("https://api.put.io/v2/transfers/cancel")
+            .timeout(Duration::from_secs(10))
+            .multipart(form)
+            .header("authorization", format!("Bearer {}", self.api_token))
+            .send()
+            .await?;
+
+        if !response.status().is_success() {
+            bail!(
+                "Error stopping put.io transfer id:{}: {}",
+                transfer_id,
+                response.status()
+            );
+        }
+
+        Ok(())
+    }
+
+    #[instrument(skip(self))]
+    async fn delete_file(&self, file_id: u64) -> Result<()> {
+        let form = multipart::Form::new().text("file_ids", file_id.to_string());
+        let response = self
+            .client
+            .post("https://api.put.io/v2/files/delete")
+            .timeout(Duration::from_secs(10))
+            .multipart(form)
+            .header("authorization", format!("Bearer {}", self.api_token))
+            .send()
+            .await?;
+
+        if !response.status().is_success() {
+            bail!(
+                "Error removing put.io file/directory id:{}: {}",
+                file_id,
+                response.status()
+            );
+        }
+
+        Ok(())
+    }
+
+    #[instrument(skip(self, url))]
+    async fn add_transfer(&self, folder_id: u64, url: &str) -> Result<()> {
+        let mut form = multipart::Form::new()
+            .text("url", url.to_string())
+            .text("save_parent_id", folder_id.to_string());
+        if let Some(callback_url) = &self.callback_url {
+            form = form.text("callback_url", callback_url.clone());
+        }
+        let response = self
+            .client
+            .post("https://api.put.io/v2/transfers/add")
+            .timeout(Duration::from_secs(10))
+            .multipart(form)
+            .header("authorization", format!("Bearer {}", self.api_token))
+            .send()
+            .await?;
+
+        if !response.status().is_success() {
+            bail!("Error adding url: {} to put.io: {}", url, response.status());
+        }
+
+        Ok(())
+    }
+
+    #[instrument(skip(self, bytes))]
+    async fn upload_file(&self, folder_id: u64, bytes: &[u8]) -> Result<()> {
+        let file_part = multipart::Part::bytes(bytes.to_owned()).file_name("foo.torrent");
+
+        let form = reqwest::multipart::Form::new()
+            .part("file", file_part)
+            .text("filename", "foo.torrent")
+            .text("parent_id", folder_id.to_string());
+
+        let response = self
+            .client
+            .post("https://upload.put.io/v2/files/upload")
+            .timeout(Duration::from_secs(10))
+            .header("authorization", format!("Bearer {}", self.api_token))
+            .multipart(form)
+            .send()
+            .await?;
+
+        if !response.status().is_success() {
+            bail!("Error uploading file to put.io: {}", response.status());
+        }
+        // Todo: error if invalid request
+        Ok(())
+    }
+
+    #[instrument(skip(self))]
+    async fn list_files(&self, file_id: u64) -> Result<ListFileResponse> {
+        let response = self
+            .client
+            .get(format!(
+                "https://api.put.io/v2/files/list?parent_id={}",
+                file_id
+            ))
+            .header("authorization", format!("Bearer {}", self.api_token))
+            .send()
+            .await?;
+
+        if !response.status().is_success() {
+            bail!(
+                "Error listing put.io file/direcotry id:{}: {}",
+                file_id,
+                response.status()
+            );
+        }
+
+        Ok(response.json().await?)
+    }
+
+    #[instrument(skip(self))]
+    async fn create_folder(&self, name: &str, parent_id: u64) -> Result<CreateFolderResponse> {
+        let folder_name = name.to_string();
+        let form = multipart::Form::new()
+            .text("name", folder_name)
+            .text("parent_id", parent_id.to_string());
+        let response = self
+            .client
+            .post("https://api.put.io/v2/files/create-folder")
+            .timeout(Duration::from_secs(10))
+            .multipart(form)
+            .header("authorization", format!("Bearer {}", self.api_token))
+            .send()
+            .await?;
+        if !response.status().is_success() {
+            bail!("Error creating put.io folder: {}", response.status());
+        }
+        Ok(response.json().await?)
+    }
+
+    #[instrument(skip(self))]
+    async fn move_file(&self, file_id: u64, parent_id: u64) -> Result<()> {
+        let form = multipart::Form::new()
+            .text("file_ids", file_id.to_string())
+            .text("parent_id", parent_id.to_string());
+        let response = self
+            .client
+            .post("https://api.put.io/v2/files/move")
+            .timeout(Duration::from_secs(10))
+            .multipart(form)
+            .header("authorization", format!("Bearer {}", self.api_token))
+            .send()
+            .await?;
+        if !response.status().is_success() {
+            bail!(
+                "Error moving put.io file id:{}: {}",
+                file_id,
+                response.status()
+            );
+        }
+        Ok(())
+    }
+
+    #[instrument(skip(self))]
+    async fn url(&self, file_id: u64) -> Result<String> {
+        let response = self
+            .client
+            .get(format!("https://api.put.io/v2/files/{}/url", file_id))
+            .header("authorization", format!("Bearer {}", self.api_token))
+            .send()
+            .await?;
+
+        if !response.status().is_success() {
+            bail!(
+                "Error getting url for put.io file id:{}: {}",
+                file_id,
+                response.status()
+            );
+        }
+
+        Ok(response.json::<URLResponse>().await?.url)
+    }
+
+    #[instrument(skip(self))]
+    async fn tunnel_routes(&self) -> Result<Vec<TunnelRoute>> {
+        let response = self
+            .client
+            .get("https://api.put.io/v2/tunnel/routes")
+            .timeout(Duration::from_secs(10))
+            .header("authorization", format!("Bearer {}", self.api_token))
+            .send()
+            .await?;
+        if !response.status().is_success() {
+            bail!("Error getting put.io tunnel routes: {}", response.status());
+        }
+        Ok(response.json::<TunnelRoutesResponse>().await?.routes)
+    }
+
+    #[instrument(skip(self))]
+    async fn set_tunnel_route(&self, name: &str) -> Result<()> {
+        let response = self
+            .client
+            .post("https://api.put.io/v2/account/settings")
+            .timeout(Duration::from_secs(10))
+            .header("authorization", format!("Bearer {}", self.api_token))
+            .json(&serde_json::json!({ "tunnel_route_name": name }))
+            .send()
+            .await?;
+        if !response.status().is_success() {
+            bail!("Error setting put.io tunnel route: {}", response.status());
+        }
+        Ok(())
+    }
+
+    #[instrument(skip_all)]
+    async fn download(&self, url: &str) -> Result<reqwest::Response> {
+        Ok(self
+            .download_client
+            .get(url)
+            .send()
+            .await?
+            .error_for_status()?)
+    }
+
+    #[instrument(skip_all)]
+    async fn create_zip(&self, file_ids: &[u64]) -> Result<u64> {
+        let file_ids: Vec<String> = file_ids.iter().map(u64::to_string).collect();
+        let form = multipart::Form::new().text("file_ids", file_ids.join(","));
+        let response = self
+            .client
+            .post("https://api.put.io/v2/zips/create")
+            .timeout(Duration::from_secs(30))
+            .multipart(form)
+            .header("authorization", format!("Bearer {}", self.api_token))
+            .send()
+            .await?;
+        if !response.status().is_success() {
+            bail!("Error creating put.io zip: {}", response.status());
+        }
+        Ok(response.json::<CreateZipResponse>().await?.zip_id)
+    }
+
+    #[instrument(skip(self))]
+    async fn zip_url(&self, zip_id: u64) -> Result<Option<String>> {
+        let response = self
+            .client
+            .get(format!("https://api.put.io/v2/zips/{}", zip_id))
+            .timeout(Duration::from_secs(10))
+            .header("authorization", format!("Bearer {}", self.api_token))
+            .send()
+            .await?;
+        if !response.status().is_success() {
+            bail!(
+                "Error getting put.io zip id:{}: {}",
+                zip_id,
+                response.status()
+            );
+        }
+        // url is false until the zip is ready
+        Ok(response
+            .json::<ZipResponse>()
+            .await?
+            .url
+            .as_str()
+            .map(str::to_string))
+    }
+}
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct PutIOAccountInfo {
@@ -38,6 +448,9 @@ pub enum PutIOTransferType {
     LiveStream,
     #[serde(rename = "N/A")]
     NA,
+    /// Types put.io may add later, so they don't break listing all transfers.
+    #[serde(other)]
+    Unknown,
 }
 
 #[derive(Debug, Deserialize)]
@@ -47,6 +460,7 @@ pub struct PutIOTransfer {
     pub client_ip: Option<String>,
     pub completion_percent: Option<u8>,
     pub created_at: String,
+    #[serde(default, deserialize_with = "null_as_default")]
     pub created_torrent: bool,
     pub current_ratio: Option<f32>,
     pub down_speed: Option<i64>,
@@ -58,6 +472,7 @@ pub struct PutIOTransfer {
     pub finished_at: Option<String>,
     pub hash: Option<String>,
     pub id: u64,
+    #[serde(default, deserialize_with = "null_as_default")]
     pub is_private: bool,
     pub name: String,
     pub peers_connected: Option<u32>,
@@ -66,6 +481,7 @@ pub struct PutIOTransfer {
     pub percent_done: Option<u8>,
     pub save_parent_id: Option<u64>,
     pub seconds_seeding: Option<u64>,
+    #[serde(default, deserialize_with = "null_as_default")]
     pub simulated: bool,
     pub size: Option<i64>,
     pub source: Option<String>,
@@ -79,6 +495,7 @@ pub struct PutIOTransfer {
     pub type_: PutIOTransferType, // `type` is a reserved keyword in Rust
     pub up_speed: Option<i64>,
     pub uploaded: Option<i64>,
+    #[serde(default, deserialize_with = "null_as_default")]
     pub userfile_exists: bool,
 }
 
@@ -101,6 +518,30 @@ impl PutIOTransfer {
     pub fn is_downloadable(&self) -> bool {
         self.file_id.is_some()
     }
+
+    /// Whether putioarr added this transfer, i.e. it saves into the putioarr folder. Transfers the
+    /// user added to put.io for other purposes are never touched.
+    pub fn is_owned(&self, root_folder_id: u64) -> bool {
+        self.save_parent_id == Some(root_folder_id)
+    }
+
+    /// Playlist and live stream transfers never end up with a final set of files, so there is
+    /// nothing for putioarr to download or for the arrs to import.
+    pub fn is_stream(&self) -> bool {
+        matches!(
+            self.type_,
+            PutIOTransferType::Playlist | PutIOTransferType::LiveStream
+        )
+    }
+}
+
+/// put.io leaves some fields null for transfers that aren't torrents.
+fn null_as_default<'de, D, T>(deserializer: D) -> std::result::Result<T, D::Error>
+where
+    D: Deserializer<'de>,
+    T: Default + Deserialize<'de>,
+{
+    Option::<T>::deserialize(deserializer).map(Option::unwrap_or_default)
 }
 
 #[derive(Debug, Deserialize)]
@@ -168,21 +609,6 @@ pub struct Settings {
     pub video_player: Option<String>,
 }
 
-pub async fn account_info(api_token: &str) -> Result<AccountInfoResponse> {
-    let client = reqwest::Client::new();
-    let response = client
-        .get("https://api.put.io/v2/account/info")
-        .header("authorization", format!("Bearer {}", api_token))
-        .send()
-        .await?;
-
-    if !response.status().is_success() {
-        bail!("Error getting put.io account info: {}", response.status());
-    }
-
-    Ok(response.json().await?)
-}
-
 #[derive(Debug, Deserialize)]
 pub struct ListTransferResponse {
     pub transfers: Vec<PutIOTransfer>,
@@ -193,138 +619,6 @@ pub struct GetTransferResponse {
     pub transfer: PutIOTransfer,
 }
 
-/// Returns the user's transfers.
-pub async fn list_transfers(api_token: &str) -> Result<ListTransferResponse> {
-    let client = reqwest::Client::new();
-    let response = client
-        .get("https://api.put.io/v2/transfers/list")
-        .timeout(Duration::from_secs(10))
-        .header("authorization", format!("Bearer {}", api_token))
-        .send()
-        .await?;
-
-    if !response.status().is_success() {
-        bail!("Error getting put.io transfers: {}", response.status());
-    }
-
-    // get response, filter for all transfers by save_parent_id = 0
-    let response_json: ListTransferResponse = response.json().await?;
-    let transfers = response_json
-        .transfers
-        .into_iter()
-        .filter(|transfer| transfer.save_parent_id == Some(1451896072))
-        .collect();
-
-    Ok(ListTransferResponse { transfers })
-}
-
-pub async fn get_transfer(api_token: &str, transfer_id: u64) -> Result<GetTransferResponse> {
-    let client = reqwest::Client::new();
-    let response = client
-        .get(format!("https://api.put.io/v2/transfers/{}", transfer_id))
-        .timeout(Duration::from_secs(10))
-        .header("authorization", format!("Bearer {}", api_token))
-        .send()
-        .await?;
-
-    if !response.status().is_success() {
-        bail!(
-            "Error getting put.io transfer id:{}: {}",
-            transfer_id,
-            response.status()
-        );
-    }
-
-    Ok(response.json().await?)
-}
-
-pub async fn remove_transfer(api_token: &str, transfer_id: u64) -> Result<()> {
-    let client = reqwest::Client::new();
-    let form = multipart::Form::new().text("transfer_ids", transfer_id.to_string());
-    let response = client
-        .post("https://api.put.io/v2/transfers/remove")
-        .timeout(Duration::from_secs(10))
-        .multipart(form)
-        .header("authorization", format!("Bearer {}", api_token))
-        .send()
-        .await?;
-
-    if !response.status().is_success() {
-        bail!(
-            "Error removing put.io transfer id:{}: {}",
-            transfer_id,
-            response.status()
-        );
-    }
-
-    Ok(())
-}
-
-pub async fn delete_file(api_token: &str, file_id: u64) -> Result<()> {
-    let client = reqwest::Client::new();
-    let form = multipart::Form::new().text("file_ids", file_id.to_string());
-    let response = client
-        .post("https://api.put.io/v2/files/delete")
-        .timeout(Duration::from_secs(10))
-        .multipart(form)
-        .header("authorization", format!("Bearer {}", api_token))
-        .send()
-        .await?;
-
-    if !response.status().is_success() {
-        bail!(
-            "Error removing put.io file/directory id:{}: {}",
-            file_id,
-            response.status()
-        );
-    }
-
-    Ok(())
-}
-
-pub async fn add_transfer(api_token: &str, folder_id: u64, url: &str) -> Result<()> {
-    let client = reqwest::Client::new();
-    let form = multipart::Form::new()
-        .text("url", url.to_string())
-        .text("save_parent_id", folder_id.to_string());
-    let response = client
-        .post("https://api.put.io/v2/transfers/add")
-        .timeout(Duration::from_secs(10))
-        .multipart(form)
-        .header("authorization", format!("Bearer {}", api_token))
-        .send()
-        .await?;
-
-    if !response.status().is_success() {
-        bail!("Error adding url: {} to put.io: {}", url, response.status());
-    }
-
-    Ok(())
-}
-
-pub async fn upload_file(api_token: &str, folder_id: u64, bytes: &[u8]) -> Result<()> {
-    let client = reqwest::Client::new();
-    let file_part = multipart::Part::bytes(bytes.to_owned()).file_name("foo.torrent");
-
-    let form = reqwest::multipart::Form::new()
-        .part("file", file_part)
-        .text("filename", "foo.torrent")
-        .text("parent_id", folder_id.to_string());
-
-    let response = client
-        .post("https://upload.put.io/v2/files/upload")
-        .timeout(Duration::from_secs(10))
-        .header("authorization", format!("Bearer {}", api_token))
-        .multipart(form)
-        .send()
-        .await?;
-
-    if !response.status().is_success() {
-        bail!("Error uploading file to put.io: {}", response.status());
-    }
-    // Todo: error if invalid request
-    Ok(())
-}
 #[derive(Debug, Serialize, Deserialize)]
 pub struct UrlResponse {
     pub url: String,
@@ -342,6 +636,9 @@ pub struct FileResponse {
     pub id: u64,
     pub name: String,
     pub file_type: String,
+    pub size: Option<u64>,
+    /// CRC32 of the file's contents as lowercase hex.
+    pub crc32: Option<String>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -349,115 +646,31 @@ pub struct CreateFolderResponse {
     pub file: FileResponse,
 }
 
-pub async fn list_files(api_token: &str, file_id: u64) -> Result<ListFileResponse> {
-    let client = reqwest::Client::new();
-    let response = client
-        .get(format!(
-            "https://api.put.io/v2/files/list?parent_id={}",
-            file_id
-        ))
-        .header("authorization", format!("Bearer {}", api_token))
-        .send()
-        .await?;
-
-    if !response.status().is_success() {
-        bail!(
-            "Error listing put.io file/direcotry id:{}: {}",
-            file_id,
-            response.status()
-        );
-    }
-
-    Ok(response.json().await?)
-}
-
-pub async fn create_folder(
-    api_token: &str,
-    name: &str,
-    parent_id: u64,
-) -> Result<CreateFolderResponse> {
-    let folder_name = name.to_string();
-    let client = reqwest::Client::new();
-    let form = multipart::Form::new()
-        .text("name", folder_name)
-        .text("parent_id", parent_id.to_string());
-    let response = client
-        .post("https://api.put.io/v2/files/create-folder")
-        .timeout(Duration::from_secs(10))
-        .multipart(form)
-        .header("authorization", format!("Bearer {}", api_token))
-        .send()
-        .await?;
-    if !response.status().is_success() {
-        bail!("Error creating put.io folder: {}", response.status());
-    }
-    Ok(response.json().await?)
-}
-
-pub async fn get_config(api_token: &str, key: &str) -> Result<String> {
-    let client = reqwest::Client::new();
-    let response = client
-        .get(format!("https://api.put.io/v2/users/config/{}", key))
-        .header("authorization", format!("Bearer {}", api_token))
-        .send()
-        .await?;
-    if !response.status().is_success() {
-        bail!("Error getting put.io config: {}", response.status());
-    }
-    Ok(response.text().await?)
+#[derive(Debug, Serialize, Deserialize)]
+pub struct URLResponse {
+    pub url: String,
 }
 
-pub async fn set_config(api_token: &str, key: &str, value: &str) -> Result<()> {
-    let client = reqwest::Client::new();
-    let form = multipart::Form::new().text("value", value.to_string());
-    let response = client
-        .post(format!("https://api.put.io/v2/users/config/{}", key))
-        .timeout(Duration::from_secs(10))
-        .multipart(form)
-        .header("authorization", format!("Bearer {}", api_token))
-        .send()
-        .await?;
-    if !response.status().is_success() {
-        bail!("Error setting put.io config: {}", response.status());
-    }
-    Ok(())
+#[derive(Debug, Deserialize)]
+struct CreateZipResponse {
+    zip_id: u64,
 }
 
-pub async fn delete_config(api_token: &str, key: &str) -> Result<()> {
-    let client = reqwest::Client::new();
-    let response = client
-        .delete(format!("https://api.put.io/v2/users/config/{}", key))
-        .header("authorization", format!("Bearer {}", api_token))
-        .send()
-        .await?;
-    if !response.status().is_success() {
-        bail!("Error deleting put.io config: {}", response.status());
-    }
-    Ok(())
+#[derive(Debug, Deserialize)]
+struct ZipResponse {
+    #[serde(default)]
+    url: serde_json::Value,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-pub struct URLResponse {
-    pub url: String,
+#[derive(Debug, Deserialize)]
+pub struct TunnelRoute {
+    pub name: String,
+    pub description: String,
 }
 
-pub async fn url(api_token: &str, file_id: u64) -> Result<String> {
-    let client = reqwest::Client::new();
-    let response = client
-        .get(format!("https://api.put.io/v2/files/{}/url", file_id))
-        .header("authorization", format!("Bearer {}", api_token))
-        .send()
-        .await?;
-
-    if !response.status().is_success() {
-        bail!(
-            "Error getting url for put.io file id:{}: {}",
-            file_id,
-            response.status()
-        );
-    }
-
-    Ok(response.json::<URLResponse>().await?.url)
+#[derive(Debug, Deserialize)]
+struct TunnelRoutesResponse {
+    routes: Vec<TunnelRoute>,
 }
 
 /// Returns a new OOB code.
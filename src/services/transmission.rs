@@ -13,7 +13,7 @@ pub struct TransmissionResponse {
     pub arguments: Option<serde_json::Value>,
 }
 
-#[derive(Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug)]
 pub struct TransmissionRequest {
     pub method: String,
     pub arguments: Option<serde_json::Value>,
@@ -60,17 +60,41 @@ pub struct TransmissionTorrent {
     pub download_dir: String,
     pub total_size: i64,
     pub left_until_done: i64,
+    /// Share of the transfer put.io has, from 0 to 1.
+    pub percent_done: f64,
     pub is_finished: bool,
     pub eta: u64,
     pub status: TransmissionTorrentStatus,
     pub seconds_downloading: i64,
     pub error_string: Option<String>,
+    /// 0 without an error, 2 when put.io failed to fetch the transfer (a tracker error in
+    /// Transmission) and 3 when downloading it failed for good (a local error).
+    pub error: u32,
     pub downloaded_ever: i64,
+    pub uploaded_ever: i64,
     pub seed_ratio_limit: f32,
     pub seed_ratio_mode: u32,
     pub seed_idle_limit: u64,
     pub seed_idle_mode: u32,
     pub file_count: u32,
+    pub peers_connected: u32,
+    pub peers_sending_to_us: u32,
+    pub peers_getting_from_us: u32,
+    pub rate_download: i64,
+    pub rate_upload: i64,
+    pub desired_available: i64,
+    pub queue_position: usize,
+    pub bandwidth_priority: i64,
+    pub files: Vec<TransmissionTorrentFile>,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct TransmissionTorrentFile {
+    /// Path within the torrent, starting with its folder.
+    pub name: String,
+    pub length: u64,
+    pub bytes_completed: u64,
 }
 
 impl From<PutIOTransfer> for TransmissionTorrent {
@@ -86,29 +110,49 @@ impl From<PutIOTransfer> for TransmissionTorrent {
         let now = Utc::now();
         let seconds_downloading = (now - started_at).num_seconds();
         let name = &t.name;
+        let left_until_done = max(t.size.unwrap_or(0) - t.downloaded.unwrap_or(0), 0);
+        let percent_done = match t.size.unwrap_or(0) {
+            size if size > 0 => (size - left_until_done) as f64 / size as f64,
+            _ => 0.0,
+        };
+        // put.io reports availability as a percentage of the remaining data
+        let desired_available =
+            left_until_done * i64::from(t.availability.unwrap_or(0).min(100)) / 100;
         Self {
             id: t.id,
             hash_string: t.hash,
             name: name.clone(),
             download_dir: String::from(""),
             total_size: t.size.unwrap_or(0),
-            left_until_done: max(t.size.unwrap_or(0) - t.downloaded.unwrap_or(0), 0),
+            left_until_done,
+            percent_done,
             is_finished: t.finished_at.is_some(),
             eta: t.estimated_time.unwrap_or(0),
             status: TransmissionTorrentStatus::from(t.status),
             seconds_downloading,
             error_string: t.error_message,
+            error: 0,
             downloaded_ever: t.downloaded.unwrap_or(0),
+            uploaded_ever: t.uploaded.unwrap_or(0),
             seed_ratio_limit: 0.0,
             seed_ratio_mode: 0,
             seed_idle_limit: 0,
             seed_idle_mode: 0,
             file_count: 1,
+            peers_connected: t.peers_connected.unwrap_or(0),
+            peers_sending_to_us: t.peers_sending_to_us.unwrap_or(0),
+            peers_getting_from_us: t.peers_getting_from_us.unwrap_or(0),
+            rate_download: t.down_speed.unwrap_or(0),
+            rate_upload: t.up_speed.unwrap_or(0),
+            desired_available,
+            queue_position: 0,
+            bandwidth_priority: 0,
+            files: vec![],
         }
     }
 }
 
-#[derive(Debug, Serialize)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum TransmissionTorrentStatus {
     Stopped = 0,
     CheckWait = 1,
@@ -119,6 +163,13 @@ pub enum TransmissionTorrentStatus {
     Seeding = 6,
 }
 
+/// The spec has statuses as numbers.
+impl Serialize for TransmissionTorrentStatus {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_u8(*self as u8)
+    }
+}
+
 impl From<PutIOTransferStatus> for TransmissionTorrentStatus {
     fn from(status: PutIOTransferStatus) -> Self {
         match status {
@@ -0,0 +1,992 @@
+// Persistent state that has to survive restarts, stored in a SQLite database.
+use anyhow::{bail, Context, Result};
+use base64::Engine;
+use log::warn;
+use password_hash::rand_core::{OsRng, RngCore};
+use rusqlite::{params, Connection, Row};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, HashMap},
+    path::Path,
+    sync::Mutex,
+    time::Duration,
+};
+
+/// Version of the format written by `putioarr state export`.
+const SNAPSHOT_VERSION: u32 = 1;
+
+/// Cumulative counters kept across restarts.
+#[derive(Debug, Default, Clone)]
+pub struct Stats {
+    pub downloaded_bytes: u64,
+    pub files_added: u64,
+    pub transfers_completed: u64,
+    pub failures: u64,
+    pub session_count: u64,
+    pub seconds_active: u64,
+}
+
+/// Failed download attempts of a transfer.
+#[derive(Debug, Clone)]
+pub struct Failure {
+    pub attempts: u32,
+    /// Set once the transfer ran out of attempts.
+    pub failed: bool,
+    pub last_error: String,
+    /// Name of the FailureCategory of the last error.
+    pub category: Option<String>,
+}
+
+/// How far a transfer got in the pipeline, kept so a restart picks up where it left off.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stage {
+    Downloading,
+    /// All targets were downloaded, the transfer is waiting for put.io to stop seeding.
+    Downloaded,
+    /// Done with, but left on put.io with keep_remote_transfers.
+    Kept,
+}
+
+impl Stage {
+    fn name(self) -> &'static str {
+        match self {
+            Self::Downloading => "downloading",
+            Self::Downloaded => "downloaded",
+            Self::Kept => "kept",
+        }
+    }
+}
+
+/// What a transfer was added from, kept so it can be added again.
+#[derive(Debug, Clone)]
+pub enum Source {
+    Magnet(String),
+    Torrent(Vec<u8>),
+}
+
+/// Everything in the state database, as written by `putioarr state export`.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Snapshot {
+    version: u32,
+    stats: BTreeMap<String, i64>,
+    meta: BTreeMap<String, String>,
+    priorities: BTreeMap<String, i64>,
+    #[serde(default)]
+    categories: BTreeMap<String, String>,
+    failures: Vec<FailureRecord>,
+    #[serde(default)]
+    renamed_files: Vec<RenamedFileRecord>,
+    sources: Vec<SourceRecord>,
+    #[serde(default)]
+    transfers: BTreeMap<String, String>,
+    #[serde(default)]
+    download_targets: Vec<DownloadTargetRecord>,
+    /// Oldest first.
+    #[serde(default)]
+    reports: Vec<ReportRecord>,
+    #[serde(default)]
+    paused: Vec<String>,
+    #[serde(default)]
+    local_names: BTreeMap<String, String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct FailureRecord {
+    key: String,
+    attempts: u32,
+    failed: bool,
+    last_error: String,
+    #[serde(default)]
+    category: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct RenamedFileRecord {
+    hash: String,
+    file_id: u64,
+    name: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct DownloadTargetRecord {
+    path: String,
+    key: String,
+    target: String,
+    done: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct ReportRecord {
+    hash: Option<String>,
+    report: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct SourceRecord {
+    hash: String,
+    magnet: Option<String>,
+    /// Base64 encoded torrent file.
+    torrent: Option<String>,
+    readds: u32,
+}
+
+pub struct Store {
+    connection: Mutex<Connection>,
+}
+
+impl Store {
+    /// Opens the state database at path, creating it if needed.
+    pub fn open(path: &Path) -> Result<Self> {
+        let connection = Connection::open(path)
+            .with_context(|| format!("Unable to open state database {}", path.display()))?;
+        // Instances in standby mode share the database with the active one.
+        connection.busy_timeout(Duration::from_secs(10))?;
+        connection.execute_batch(
+            "CREATE TABLE IF NOT EXISTS stats (
+                name TEXT PRIMARY KEY,
+                value INTEGER NOT NULL
+            );
+            CREATE TABLE IF NOT EXISTS meta (
+                name TEXT PRIMARY KEY,
+                value TEXT NOT NULL
+            );
+            CREATE TABLE IF NOT EXISTS priorities (
+                hash TEXT PRIMARY KEY,
+                priority INTEGER NOT NULL
+            );
+            CREATE TABLE IF NOT EXISTS failures (
+                key TEXT PRIMARY KEY,
+                attempts INTEGER NOT NULL,
+                failed INTEGER NOT NULL DEFAULT 0,
+                last_error TEXT NOT NULL
+            );
+            CREATE TABLE IF NOT EXISTS categories (
+                hash TEXT PRIMARY KEY,
+                category TEXT NOT NULL
+            );
+            CREATE TABLE IF NOT EXISTS file_trees (
+                hash TEXT PRIMARY KEY,
+                tree TEXT NOT NULL
+            );
+            CREATE TABLE IF NOT EXISTS renamed_files (
+                hash TEXT NOT NULL,
+                file_id INTEGER NOT NULL,
+                name TEXT NOT NULL,
+                PRIMARY KEY (hash, file_id)
+            );
+            CREATE TABLE IF NOT EXISTS transfers (
+                key TEXT PRIMARY KEY,
+                stage TEXT NOT NULL
+            );
+            CREATE TABLE IF NOT EXISTS download_targets (
+                path TEXT PRIMARY KEY,
+                key TEXT NOT NULL,
+                target TEXT NOT NULL,
+                done INTEGER NOT NULL DEFAULT 0
+            );
+            CREATE TABLE IF NOT EXISTS reports (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                hash TEXT,
+                report TEXT NOT NULL
+            );
+            CREATE TABLE IF NOT EXISTS paused (
+                hash TEXT PRIMARY KEY
+            );
+            CREATE TABLE IF NOT EXISTS local_names (
+                hash TEXT PRIMARY KEY,
+                name TEXT NOT NULL
+            );
+            CREATE TABLE IF NOT EXISTS sources (
+                hash TEXT PRIMARY KEY,
+                magnet TEXT,
+                torrent BLOB,
+                readds INTEGER NOT NULL DEFAULT 0
+            );",
+        )?;
+        add_column(&connection, "failures", "category", "TEXT")?;
+        Ok(Self {
+            connection: Mutex::new(connection),
+        })
+    }
+
+    /// Adds value to the named counter. Statistics are best effort, so failures are only logged.
+    pub fn increment(&self, name: &str, value: u64) {
+        let result = self.connection.lock().unwrap().execute(
+            "INSERT INTO stats (name, value) VALUES (?1, ?2)
+             ON CONFLICT(name) DO UPDATE SET value = value + excluded.value",
+            params![name, value as i64],
+        );
+        if let Err(e) = result {
+            warn!("Unable to update {} statistic: {}", name, e);
+        }
+    }
+
+    /// Remembers the priority a client gave a transfer. Like statistics, this is best effort.
+    pub fn set_priority(&self, hash: &str, priority: i64) {
+        let result = self.connection.lock().unwrap().execute(
+            "INSERT INTO priorities (hash, priority) VALUES (?1, ?2)
+             ON CONFLICT(hash) DO UPDATE SET priority = excluded.priority",
+            params![hash.to_lowercase(), priority],
+        );
+        if let Err(e) = result {
+            warn!("Unable to store priority of {}: {}", hash, e);
+        }
+    }
+
+    /// Returns the priority of a transfer, 0 when none was set.
+    pub fn priority(&self, hash: &str) -> i64 {
+        self.connection
+            .lock()
+            .unwrap()
+            .query_row(
+                "SELECT priority FROM priorities WHERE hash = ?1",
+                params![hash.to_lowercase()],
+                |row| row.get(0),
+            )
+            .unwrap_or(0)
+    }
+
+    pub fn forget_priority(&self, hash: &str) {
+        let result = self.connection.lock().unwrap().execute(
+            "DELETE FROM priorities WHERE hash = ?1",
+            params![hash.to_lowercase()],
+        );
+        if let Err(e) = result {
+            warn!("Unable to remove priority of {}: {}", hash, e);
+        }
+    }
+
+    /// Records a failed download attempt and returns the number of failed attempts so far.
+    pub fn record_failure(&self, key: &str, category: &str, error: &str) -> Result<u32> {
+        let connection = self.connection.lock().unwrap();
+        connection.execute(
+            "INSERT INTO failures (key, attempts, last_error, category) VALUES (?1, 1, ?2, ?3)
+             ON CONFLICT(key) DO UPDATE SET attempts = attempts + 1,
+                last_error = excluded.last_error, category = excluded.category",
+            params![key, error, category],
+        )?;
+        Ok(connection.query_row(
+            "SELECT attempts FROM failures WHERE key = ?1",
+            params![key],
+            |row| row.get(0),
+        )?)
+    }
+
+    /// Marks a transfer as failed for good, so it isn't attempted again.
+    pub fn mark_failed(&self, key: &str) {
+        let result = self.connection.lock().unwrap().execute(
+            "UPDATE failures SET failed = 1 WHERE key = ?1",
+            params![key],
+        );
+        if let Err(e) = result {
+            warn!("Unable to mark {} as failed: {}", key, e);
+        }
+    }
+
+    pub fn failure(&self, key: &str) -> Option<Failure> {
+        self.connection
+            .lock()
+            .unwrap()
+            .query_row(
+                "SELECT attempts, failed, last_error, category FROM failures WHERE key = ?1",
+                params![key],
+                |row| {
+                    Ok(Failure {
+                        attempts: row.get(0)?,
+                        failed: row.get(1)?,
+                        last_error: row.get(2)?,
+                        category: row.get(3)?,
+                    })
+                },
+            )
+            .ok()
+    }
+
+    /// Failures of all transfers, by transfer key.
+    pub fn failures(&self) -> Vec<(String, Failure)> {
+        let connection = self.connection.lock().unwrap();
+        query(
+            &connection,
+            "SELECT key, attempts, failed, last_error, category FROM failures",
+            |row| {
+                Ok((
+                    row.get(0)?,
+                    Failure {
+                        attempts: row.get(1)?,
+                        failed: row.get(2)?,
+                        last_error: row.get(3)?,
+                        category: row.get(4)?,
+                    },
+                ))
+            },
+        )
+        .unwrap_or_else(|e| {
+            warn!("Unable to list failures: {}", e);
+            vec![]
+        })
+    }
+
+    pub fn clear_failures(&self, key: &str) {
+        let result = self
+            .connection
+            .lock()
+            .unwrap()
+            .execute("DELETE FROM failures WHERE key = ?1", params![key]);
+        if let Err(e) = result {
+            warn!("Unable to clear failures of {}: {}", key, e);
+        }
+    }
+
+    /// Remembers what a transfer was added from. Like priorities, this is best effort.
+    pub fn set_source(&self, hash: &str, source: &Source) {
+        let (magnet, torrent) = match source {
+            Source::Magnet(url) => (Some(url.as_str()), None),
+            Source::Torrent(bytes) => (None, Some(bytes.as_slice())),
+        };
+        let result = self.connection.lock().unwrap().execute(
+            "INSERT INTO sources (hash, magnet, torrent) VALUES (?1, ?2, ?3)
+             ON CONFLICT(hash) DO UPDATE SET magnet = excluded.magnet, torrent = excluded.torrent",
+            params![hash.to_lowercase(), magnet, torrent],
+        );
+        if let Err(e) = result {
+            warn!("Unable to store source of {}: {}", hash, e);
+        }
+    }
+
+    /// Returns what a transfer was added from and how many times it was added again since.
+    pub fn source(&self, hash: &str) -> Option<(Source, u32)> {
+        self.connection
+            .lock()
+            .unwrap()
+            .query_row(
+                "SELECT magnet, torrent, readds FROM sources WHERE hash = ?1",
+                params![hash.to_lowercase()],
+                |row| {
+                    let magnet: Option<String> = row.get(0)?;
+                    let torrent: Option<Vec<u8>> = row.get(1)?;
+                    let source = match (magnet, torrent) {
+                        (Some(url), _) => Source::Magnet(url),
+                        (None, bytes) => Source::Torrent(bytes.unwrap_or_default()),
+                    };
+                    Ok((source, row.get(2)?))
+                },
+            )
+            .ok()
+    }
+
+    pub fn record_readd(&self, hash: &str) {
+        let result = self.connection.lock().unwrap().execute(
+            "UPDATE sources SET readds = readds + 1 WHERE hash = ?1",
+            params![hash.to_lowercase()],
+        );
+        if let Err(e) = result {
+            warn!("Unable to count re-add of {}: {}", hash, e);
+        }
+    }
+
+    pub fn forget_source(&self, hash: &str) {
+        let result = self.connection.lock().unwrap().execute(
+            "DELETE FROM sources WHERE hash = ?1",
+            params![hash.to_lowercase()],
+        );
+        if let Err(e) = result {
+            warn!("Unable to remove source of {}: {}", hash, e);
+        }
+    }
+
+    /// Remembers the category a client added a transfer with. Like priorities, this is best
+    /// effort.
+    pub fn set_category(&self, hash: &str, category: &str) {
+        let result = self.connection.lock().unwrap().execute(
+            "INSERT INTO categories (hash, category) VALUES (?1, ?2)
+             ON CONFLICT(hash) DO UPDATE SET category = excluded.category",
+            params![hash.to_lowercase(), category],
+        );
+        if let Err(e) = result {
+            warn!("Unable to store category of {}: {}", hash, e);
+        }
+    }
+
+    pub fn category(&self, hash: &str) -> Option<String> {
+        self.connection
+            .lock()
+            .unwrap()
+            .query_row(
+                "SELECT category FROM categories WHERE hash = ?1",
+                params![hash.to_lowercase()],
+                |row| row.get(0),
+            )
+            .ok()
+    }
+
+    pub fn forget_category(&self, hash: &str) {
+        let result = self.connection.lock().unwrap().execute(
+            "DELETE FROM categories WHERE hash = ?1",
+            params![hash.to_lowercase()],
+        );
+        if let Err(e) = result {
+            warn!("Unable to remove category of {}: {}", hash, e);
+        }
+    }
+
+    /// Records the local name a transfer was given, unless it has one already. Like priorities,
+    /// this is best effort.
+    pub fn set_local_name(&self, hash: &str, name: &str) {
+        let result = self.connection.lock().unwrap().execute(
+            "INSERT OR IGNORE INTO local_names (hash, name) VALUES (?1, ?2)",
+            params![hash.to_lowercase(), name],
+        );
+        if let Err(e) = result {
+            warn!("Unable to store local name of {}: {}", hash, e);
+        }
+    }
+
+    /// The local names of transfers, by lowercase hash.
+    pub fn local_names(&self) -> HashMap<String, String> {
+        let connection = self.connection.lock().unwrap();
+        query(&connection, "SELECT hash, name FROM local_names", |row| {
+            Ok((row.get(0)?, row.get(1)?))
+        })
+        .map(|names| names.into_iter().collect())
+        .unwrap_or_else(|e| {
+            warn!("Unable to read local names: {}", e);
+            HashMap::new()
+        })
+    }
+
+    pub fn forget_local_name(&self, hash: &str) {
+        let result = self.connection.lock().unwrap().execute(
+            "DELETE FROM local_names WHERE hash = ?1",
+            params![hash.to_lowercase()],
+        );
+        if let Err(e) = result {
+            warn!("Unable to remove local name of {}: {}", hash, e);
+        }
+    }
+
+    /// Records the name a rename rule gave a put.io file. Like priorities, this is best effort.
+    pub fn set_renamed_file(&self, hash: &str, file_id: u64, name: &str) {
+        let result = self.connection.lock().unwrap().execute(
+            "INSERT INTO renamed_files (hash, file_id, name) VALUES (?1, ?2, ?3)
+             ON CONFLICT(hash, file_id) DO UPDATE SET name = excluded.name",
+            params![hash.to_lowercase(), file_id as i64, name],
+        );
+        if let Err(e) = result {
+            warn!("Unable to store renamed file of {}: {}", hash, e);
+        }
+    }
+
+    pub fn renamed_file(&self, hash: &str, file_id: u64) -> Option<String> {
+        self.connection
+            .lock()
+            .unwrap()
+            .query_row(
+                "SELECT name FROM renamed_files WHERE hash = ?1 AND file_id = ?2",
+                params![hash.to_lowercase(), file_id as i64],
+                |row| row.get(0),
+            )
+            .ok()
+    }
+
+    pub fn forget_renamed_files(&self, hash: &str) {
+        let result = self.connection.lock().unwrap().execute(
+            "DELETE FROM renamed_files WHERE hash = ?1",
+            params![hash.to_lowercase()],
+        );
+        if let Err(e) = result {
+            warn!("Unable to remove renamed files of {}: {}", hash, e);
+        }
+    }
+
+    /// Remembers that a client paused a transfer, so it stays paused after a restart. Like
+    /// priorities, this is best effort.
+    pub fn set_paused(&self, hash: &str) {
+        let result = self.connection.lock().unwrap().execute(
+            "INSERT OR IGNORE INTO paused (hash) VALUES (?1)",
+            params![hash.to_lowercase()],
+        );
+        if let Err(e) = result {
+            warn!("Unable to store pause of {}: {}", hash, e);
+        }
+    }
+
+    /// Hashes of the paused transfers.
+    pub fn paused(&self) -> Vec<String> {
+        let connection = self.connection.lock().unwrap();
+        query(&connection, "SELECT hash FROM paused", |row| row.get(0)).unwrap_or_else(|e| {
+            warn!("Unable to list paused transfers: {}", e);
+            vec![]
+        })
+    }
+
+    pub fn forget_paused(&self, hash: &str) {
+        let result = self.connection.lock().unwrap().execute(
+            "DELETE FROM paused WHERE hash = ?1",
+            params![hash.to_lowercase()],
+        );
+        if let Err(e) = result {
+            warn!("Unable to remove pause of {}: {}", hash, e);
+        }
+    }
+
+    /// Caches the put.io file tree of a transfer, as JSON. Like priorities, this is best effort.
+    pub fn set_file_tree(&self, hash: &str, tree: &str) {
+        let result = self.connection.lock().unwrap().execute(
+            "INSERT INTO file_trees (hash, tree) VALUES (?1, ?2)
+             ON CONFLICT(hash) DO UPDATE SET tree = excluded.tree",
+            params![hash.to_lowercase(), tree],
+        );
+        if let Err(e) = result {
+            warn!("Unable to cache file tree of {}: {}", hash, e);
+        }
+    }
+
+    pub fn file_tree(&self, hash: &str) -> Option<String> {
+        self.connection
+            .lock()
+            .unwrap()
+            .query_row(
+                "SELECT tree FROM file_trees WHERE hash = ?1",
+                params![hash.to_lowercase()],
+                |row| row.get(0),
+            )
+            .ok()
+    }
+
+    pub fn forget_file_tree(&self, hash: &str) {
+        let result = self.connection.lock().unwrap().execute(
+            "DELETE FROM file_trees WHERE hash = ?1",
+            params![hash.to_lowercase()],
+        );
+        if let Err(e) = result {
+            warn!("Unable to remove file tree of {}: {}", hash, e);
+        }
+    }
+
+    /// Records that a transfer started downloading into targets, given as (path, JSON) pairs.
+    /// Targets recorded by an earlier attempt keep their completion status. Like priorities, this
+    /// is best effort.
+    pub fn start_download(&self, key: &str, targets: &[(String, String)]) {
+        let mut connection = self.connection.lock().unwrap();
+        let result = connection.transaction().and_then(|transaction| {
+            transaction.execute(
+                "INSERT INTO transfers (key, stage) VALUES (?1, ?2)
+                 ON CONFLICT(key) DO UPDATE SET stage = excluded.stage",
+                params![key, Stage::Downloading.name()],
+            )?;
+            for (path, target) in targets {
+                transaction.execute(
+                    "INSERT INTO download_targets (path, key, target) VALUES (?1, ?2, ?3)
+                     ON CONFLICT(path) DO UPDATE SET key = excluded.key, target = excluded.target",
+                    params![path, key, target],
+                )?;
+            }
+            transaction.commit()
+        });
+        if let Err(e) = result {
+            warn!("Unable to record download of {}: {}", key, e);
+        }
+    }
+
+    /// Marks the download target at path as done.
+    pub fn finish_target(&self, path: &str) {
+        let result = self.connection.lock().unwrap().execute(
+            "UPDATE download_targets SET done = 1 WHERE path = ?1",
+            params![path],
+        );
+        if let Err(e) = result {
+            warn!("Unable to record download of {}: {}", path, e);
+        }
+    }
+
+    /// Marks all targets of a transfer as downloaded.
+    pub fn finish_download(&self, key: &str) {
+        let result = self.connection.lock().unwrap().execute(
+            "UPDATE transfers SET stage = ?2 WHERE key = ?1",
+            params![key, Stage::Downloaded.name()],
+        );
+        if let Err(e) = result {
+            warn!("Unable to record download of {}: {}", key, e);
+        }
+    }
+
+    /// Records that a transfer is done with but stays on put.io, so it isn't downloaded again.
+    /// Its targets are forgotten, since the arrs may have moved the files already.
+    pub fn keep_transfer(&self, key: &str) {
+        let mut connection = self.connection.lock().unwrap();
+        let result = connection.transaction().and_then(|transaction| {
+            transaction.execute(
+                "INSERT INTO transfers (key, stage) VALUES (?1, ?2)
+                 ON CONFLICT(key) DO UPDATE SET stage = excluded.stage",
+                params![key, Stage::Kept.name()],
+            )?;
+            transaction.execute("DELETE FROM download_targets WHERE key = ?1", params![key])?;
+            transaction.commit()
+        });
+        if let Err(e) = result {
+            warn!("Unable to record kept transfer {}: {}", key, e);
+        }
+    }
+
+    pub fn stage(&self, key: &str) -> Option<Stage> {
+        let stage: String = self
+            .connection
+            .lock()
+            .unwrap()
+            .query_row(
+                "SELECT stage FROM transfers WHERE key = ?1",
+                params![key],
+                |row| row.get(0),
+            )
+            .ok()?;
+        [Stage::Downloading, Stage::Downloaded, Stage::Kept]
+            .into_iter()
+            .find(|s| s.name() == stage)
+    }
+
+    /// The targets recorded for a transfer, as JSON, with whether each is done.
+    pub fn download_targets(&self, key: &str) -> Vec<(String, bool)> {
+        let connection = self.connection.lock().unwrap();
+        let result = connection
+            .prepare("SELECT target, done FROM download_targets WHERE key = ?1 ORDER BY path")
+            .and_then(|mut statement| {
+                statement
+                    .query_map(params![key], |row| Ok((row.get(0)?, row.get(1)?)))?
+                    .collect()
+            });
+        result.unwrap_or_else(|e| {
+            warn!("Unable to load download targets of {}: {}", key, e);
+            vec![]
+        })
+    }
+
+    /// Paths of the targets of all recorded downloads.
+    pub fn download_target_paths(&self) -> Vec<String> {
+        let connection = self.connection.lock().unwrap();
+        query(&connection, "SELECT path FROM download_targets", |row| {
+            row.get(0)
+        })
+        .unwrap_or_else(|e| {
+            warn!("Unable to list download targets: {}", e);
+            vec![]
+        })
+    }
+
+    /// Keys of all transfers with a recorded download.
+    pub fn tracked_transfers(&self) -> Vec<String> {
+        let connection = self.connection.lock().unwrap();
+        query(&connection, "SELECT key FROM transfers", |row| row.get(0)).unwrap_or_else(|e| {
+            warn!("Unable to list tracked transfers: {}", e);
+            vec![]
+        })
+    }
+
+    pub fn forget_transfer(&self, key: &str) {
+        let mut connection = self.connection.lock().unwrap();
+        let result = connection.transaction().and_then(|transaction| {
+            transaction.execute("DELETE FROM transfers WHERE key = ?1", params![key])?;
+            transaction.execute("DELETE FROM download_targets WHERE key = ?1", params![key])?;
+            transaction.commit()
+        });
+        if let Err(e) = result {
+            warn!("Unable to remove download of {}: {}", key, e);
+        }
+    }
+
+    /// Stores a completion report, keeping only the latest keep reports. Like priorities, this is
+    /// best effort.
+    pub fn add_report(&self, hash: Option<&str>, report: &str, keep: usize) {
+        let connection = self.connection.lock().unwrap();
+        let result = connection
+            .execute(
+                "INSERT INTO reports (hash, report) VALUES (?1, ?2)",
+                params![hash.map(str::to_lowercase), report],
+            )
+            .and_then(|_| {
+                connection.execute(
+                    "DELETE FROM reports WHERE id <= (SELECT MAX(id) FROM reports) - ?1",
+                    params![keep as i64],
+                )
+            });
+        if let Err(e) = result {
+            warn!("Unable to store report of {:?}: {}", hash, e);
+        }
+    }
+
+    /// Returns the latest completion reports as JSON, newest first.
+    pub fn reports(&self, limit: usize) -> Result<Vec<String>> {
+        let connection = self.connection.lock().unwrap();
+        let mut statement =
+            connection.prepare("SELECT report FROM reports ORDER BY id DESC LIMIT ?1")?;
+        let rows = statement.query_map(params![limit as i64], |row| row.get(0))?;
+        Ok(rows.collect::<rusqlite::Result<_>>()?)
+    }
+
+    /// Returns the id that identifies this instance on put.io, generating it on first use.
+    pub fn instance_id(&self) -> Result<String> {
+        let connection = self.connection.lock().unwrap();
+        let id = format!("{:016x}", OsRng.next_u64());
+        connection.execute(
+            "INSERT OR IGNORE INTO meta (name, value) VALUES ('instance_id', ?1)",
+            params![id],
+        )?;
+        Ok(connection.query_row(
+            "SELECT value FROM meta WHERE name = 'instance_id'",
+            [],
+            |row| row.get(0),
+        )?)
+    }
+
+    /// Returns a snapshot of the whole database, for moving it to another host.
+    pub fn export(&self) -> Result<Snapshot> {
+        let connection = self.connection.lock().unwrap();
+        Ok(Snapshot {
+            version: SNAPSHOT_VERSION,
+            stats: query(&connection, "SELECT name, value FROM stats", |row| {
+                Ok((row.get(0)?, row.get(1)?))
+            })?
+            .into_iter()
+            .collect(),
+            meta: query(&connection, "SELECT name, value FROM meta", |row| {
+                Ok((row.get(0)?, row.get(1)?))
+            })?
+            .into_iter()
+            .collect(),
+            priorities: query(
+                &connection,
+                "SELECT hash, priority FROM priorities",
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )?
+            .into_iter()
+            .collect(),
+            categories: query(
+                &connection,
+                "SELECT hash, category FROM categories",
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )?
+            .into_iter()
+            .collect(),
+            failures: query(
+                &connection,
+                "SELECT key, attempts, failed, last_error, category FROM failures",
+                |row| {
+                    Ok(FailureRecord {
+                        key: row.get(0)?,
+                        attempts: row.get(1)?,
+                        failed: row.get(2)?,
+                        last_error: row.get(3)?,
+                        category: row.get(4)?,
+                    })
+                },
+            )?,
+            renamed_files: query(
+                &connection,
+                "SELECT hash, file_id, name FROM renamed_files",
+                |row| {
+                    Ok(RenamedFileRecord {
+                        hash: row.get(0)?,
+                        file_id: row.get::<_, i64>(1)? as u64,
+                        name: row.get(2)?,
+                    })
+                },
+            )?,
+            sources: query(
+                &connection,
+                "SELECT hash, magnet, torrent, readds FROM sources",
+                |row| {
+                    let torrent: Option<Vec<u8>> = row.get(2)?;
+                    Ok(SourceRecord {
+                        hash: row.get(0)?,
+                        magnet: row.get(1)?,
+                        torrent: torrent
+                            .map(|t| base64::engine::general_purpose::STANDARD.encode(t)),
+                        readds: row.get(3)?,
+                    })
+                },
+            )?,
+            transfers: query(&connection, "SELECT key, stage FROM transfers", |row| {
+                Ok((row.get(0)?, row.get(1)?))
+            })?
+            .into_iter()
+            .collect(),
+            download_targets: query(
+                &connection,
+                "SELECT path, key, target, done FROM download_targets",
+                |row| {
+                    Ok(DownloadTargetRecord {
+                        path: row.get(0)?,
+                        key: row.get(1)?,
+                        target: row.get(2)?,
+                        done: row.get(3)?,
+                    })
+                },
+            )?,
+            reports: query(
+                &connection,
+                "SELECT hash, report FROM reports ORDER BY id",
+                |row| {
+                    Ok(ReportRecord {
+                        hash: row.get(0)?,
+                        report: row.get(1)?,
+                    })
+                },
+            )?,
+            paused: query(&connection, "SELECT hash FROM paused", |row| row.get(0))?,
+            local_names: query(&connection, "SELECT hash, name FROM local_names", |row| {
+                Ok((row.get(0)?, row.get(1)?))
+            })?
+            .into_iter()
+            .collect(),
+        })
+    }
+
+    /// Replaces the contents of the database with a snapshot made by export.
+    pub fn import(&self, snapshot: &Snapshot) -> Result<()> {
+        if snapshot.version != SNAPSHOT_VERSION {
+            bail!("Unsupported state snapshot version {}", snapshot.version);
+        }
+        let mut connection = self.connection.lock().unwrap();
+        let transaction = connection.transaction()?;
+        transaction.execute_batch(
+            "DELETE FROM stats;
+             DELETE FROM meta;
+             DELETE FROM priorities;
+             DELETE FROM categories;
+             DELETE FROM failures;
+             DELETE FROM renamed_files;
+             DELETE FROM sources;
+             DELETE FROM transfers;
+             DELETE FROM download_targets;
+             DELETE FROM reports;
+             DELETE FROM paused;
+             DELETE FROM local_names;",
+        )?;
+        for (name, value) in &snapshot.stats {
+            transaction.execute(
+                "INSERT INTO stats (name, value) VALUES (?1, ?2)",
+                params![name, value],
+            )?;
+        }
+        for (name, value) in &snapshot.meta {
+            transaction.execute(
+                "INSERT INTO meta (name, value) VALUES (?1, ?2)",
+                params![name, value],
+            )?;
+        }
+        for (hash, priority) in &snapshot.priorities {
+            transaction.execute(
+                "INSERT INTO priorities (hash, priority) VALUES (?1, ?2)",
+                params![hash, priority],
+            )?;
+        }
+        for (hash, category) in &snapshot.categories {
+            transaction.execute(
+                "INSERT INTO categories (hash, category) VALUES (?1, ?2)",
+                params![hash, category],
+            )?;
+        }
+        for f in &snapshot.failures {
+            transaction.execute(
+                "INSERT INTO failures (key, attempts, failed, last_error, category)
+                 VALUES (?1, ?2, ?3, ?4, ?5)",
+                params![f.key, f.attempts, f.failed, f.last_error, f.category],
+            )?;
+        }
+        for r in &snapshot.renamed_files {
+            transaction.execute(
+                "INSERT INTO renamed_files (hash, file_id, name) VALUES (?1, ?2, ?3)",
+                params![r.hash, r.file_id as i64, r.name],
+            )?;
+        }
+        for s in &snapshot.sources {
+            let torrent = match &s.torrent {
+                Some(t) => Some(
+                    base64::engine::general_purpose::STANDARD
+                        .decode(t)
+                        .with_context(|| format!("Invalid torrent for {}", s.hash))?,
+                ),
+                None => None,
+            };
+            transaction.execute(
+                "INSERT INTO sources (hash, magnet, torrent, readds) VALUES (?1, ?2, ?3, ?4)",
+                params![s.hash, s.magnet, torrent, s.readds],
+            )?;
+        }
+        for (key, stage) in &snapshot.transfers {
+            transaction.execute(
+                "INSERT INTO transfers (key, stage) VALUES (?1, ?2)",
+                params![key, stage],
+            )?;
+        }
+        for t in &snapshot.download_targets {
+            transaction.execute(
+                "INSERT INTO download_targets (path, key, target, done) VALUES (?1, ?2, ?3, ?4)",
+                params![t.path, t.key, t.target, t.done],
+            )?;
+        }
+        for r in &snapshot.reports {
+            transaction.execute(
+                "INSERT INTO reports (hash, report) VALUES (?1, ?2)",
+                params![r.hash, r.report],
+            )?;
+        }
+        for hash in &snapshot.paused {
+            transaction.execute("INSERT INTO paused (hash) VALUES (?1)", params![hash])?;
+        }
+        for (hash, name) in &snapshot.local_names {
+            transaction.execute(
+                "INSERT INTO local_names (hash, name) VALUES (?1, ?2)",
+                params![hash, name],
+            )?;
+        }
+        transaction.commit()?;
+        Ok(())
+    }
+
+    pub fn stats(&self) -> Result<Stats> {
+        let connection = self.connection.lock().unwrap();
+        let mut statement = connection.prepare("SELECT name, value FROM stats")?;
+        let mut rows = statement.query([])?;
+        let mut stats = Stats::default();
+        while let Some(row) = rows.next()? {
+            let name: String = row.get(0)?;
+            let value = row.get::<_, i64>(1)? as u64;
+            match name.as_str() {
+                "downloaded_bytes" => stats.downloaded_bytes = value,
+                "files_added" => stats.files_added = value,
+                "transfers_completed" => stats.transfers_completed = value,
+                "failures" => stats.failures = value,
+                "session_count" => stats.session_count = value,
+                "seconds_active" => stats.seconds_active = value,
+                _ => {}
+            }
+        }
+        Ok(stats)
+    }
+}
+
+fn query<T>(
+    connection: &Connection,
+    sql: &str,
+    f: impl FnMut(&Row) -> rusqlite::Result<T>,
+) -> Result<Vec<T>> {
+    let mut statement = connection.prepare(sql)?;
+    let rows = statement.query_map([], f)?;
+    Ok(rows.collect::<rusqlite::Result<_>>()?)
+}
+
+/// Adds a column to a table created by an earlier version, unless it is already there.
+fn add_column(connection: &Connection, table: &str, column: &str, definition: &str) -> Result<()> {
+    let columns = query(
+        connection,
+        &format!("SELECT name FROM pragma_table_info('{}')", table),
+        |row| row.get::<_, String>(0),
+    )?;
+    if !columns.iter().any(|c| c == column) {
+        connection.execute(
+            &format!("ALTER TABLE {} ADD COLUMN {} {}", table, column, definition),
+            [],
+        )?;
+    }
+    Ok(())
+}
@@ -0,0 +1,248 @@
+// What the pipeline is doing right now: where each transfer is, the files being downloaded and
+// the failures so far. Served by /api/status and shown by the web UI.
+use crate::{
+    download_system::transfer::{transfer_key, DownloadTarget, TargetType},
+    http::handlers::torrents,
+    state::Stage,
+    AppData,
+};
+use actix_web::web;
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::{
+    collections::HashMap,
+    sync::{atomic::Ordering, Mutex},
+    time::{Duration, Instant},
+};
+
+/// How long the speed of a file download is measured over.
+const SPEED_WINDOW: Duration = Duration::from_secs(2);
+
+/// Where a transfer is in the pipeline.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Phase {
+    /// put.io is still working on it.
+    OnPutio,
+    Queued,
+    Paused,
+    Downloading,
+    AwaitingImport,
+    Seeding,
+    /// Done with, but left on put.io.
+    Kept,
+    Failed,
+}
+
+/// A file being downloaded.
+#[derive(Debug, Clone)]
+struct FileProgress {
+    downloaded: u64,
+    /// Bytes per second over the last measured window.
+    speed: u64,
+    window_start: Instant,
+    window_bytes: u64,
+    started_at: DateTime<Utc>,
+}
+
+impl FileProgress {
+    /// The measured speed, unless no data arrived for a while.
+    fn speed(&self) -> u64 {
+        match self.window_start.elapsed() > 2 * SPEED_WINDOW {
+            true => 0,
+            false => self.speed,
+        }
+    }
+}
+
+/// Tracks what the download system is working on, which isn't kept anywhere else.
+#[derive(Default)]
+pub struct Activity {
+    /// Files being downloaded, by local path.
+    files: Mutex<HashMap<String, FileProgress>>,
+    /// Downloaded transfers waiting to be imported or seeding, by transfer key.
+    phases: Mutex<HashMap<String, Phase>>,
+}
+
+impl Activity {
+    /// Starts tracking a file download, from scratch.
+    pub fn start_file(&self, target: &DownloadTarget) {
+        self.files.lock().unwrap().insert(
+            target.to.clone(),
+            FileProgress {
+                downloaded: 0,
+                speed: 0,
+                window_start: Instant::now(),
+                window_bytes: 0,
+                started_at: Utc::now(),
+            },
+        );
+    }
+
+    pub fn add_downloaded(&self, path: &str, bytes: u64) {
+        if let Some(file) = self.files.lock().unwrap().get_mut(path) {
+            file.downloaded += bytes;
+            file.window_bytes += bytes;
+            let elapsed = file.window_start.elapsed();
+            if elapsed >= SPEED_WINDOW {
+                file.speed = (file.window_bytes as f64 / elapsed.as_secs_f64()) as u64;
+                file.window_start = Instant::now();
+                file.window_bytes = 0;
+            }
+        }
+    }
+
+    pub fn finish_file(&self, path: &str) {
+        self.files.lock().unwrap().remove(path);
+    }
+
+    pub fn set_phase(&self, key: &str, phase: Phase) {
+        self.phases.lock().unwrap().insert(key.to_string(), phase);
+    }
+
+    pub fn clear_phase(&self, key: &str) {
+        self.phases.lock().unwrap().remove(key);
+    }
+}
+
+#[derive(Serialize)]
+pub struct Status {
+    pub transfers: Vec<TransferStatus>,
+    pub failures: Vec<FailureStatus>,
+    /// Set while downloads wait for free space in the download directory.
+    pub disk_full: bool,
+}
+
+#[derive(Serialize)]
+pub struct TransferStatus {
+    pub id: u64,
+    pub hash: Option<String>,
+    pub name: String,
+    pub phase: Phase,
+    /// How far put.io got, from 0 to 1.
+    pub putio_percent_done: f64,
+    /// Bytes per second put.io downloads and uploads the transfer at.
+    pub putio_download_speed: i64,
+    pub putio_upload_speed: i64,
+    pub size: i64,
+    /// Bytes downloaded to the download directory so far, and per second right now.
+    pub downloaded: u64,
+    pub download_speed: u64,
+    pub error: Option<String>,
+    /// Files to download, once the download started.
+    pub targets: Vec<TargetStatus>,
+}
+
+#[derive(Serialize)]
+pub struct TargetStatus {
+    pub path: String,
+    pub size: Option<u64>,
+    pub downloaded: u64,
+    /// Bytes per second, while the file is being downloaded.
+    pub speed: u64,
+    pub done: bool,
+    pub started_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Serialize)]
+pub struct FailureStatus {
+    /// Hash of the transfer, or its put.io id without one.
+    pub transfer: String,
+    pub attempts: u32,
+    /// Whether it ran out of attempts.
+    pub failed: bool,
+    pub category: Option<String>,
+    pub error: String,
+}
+
+/// Collects the status of the transfers in the putioarr folder.
+pub async fn status(app_data: &web::Data<AppData>) -> Result<Status> {
+    let root_folder_id = *app_data.root_folder_id.read().unwrap();
+    let torrents = torrents(app_data, root_folder_id).await?;
+    let queued = app_data.queue.transfer_ids();
+    let files = app_data.activity.files.lock().unwrap().clone();
+
+    let transfers = torrents
+        .into_iter()
+        .map(|t| {
+            let key = transfer_key(t.hash_string.as_deref(), t.id);
+            let hash = t.hash_string.as_deref();
+            let phase = if app_data.state.failure(&key).is_some_and(|f| f.failed) {
+                Phase::Failed
+            } else if hash.is_some_and(|h| app_data.pauses.is_paused(h)) {
+                Phase::Paused
+            } else if app_data.disk_space.is_waiting(&key) {
+                Phase::Queued
+            } else if let Some(phase) = app_data.activity.phases.lock().unwrap().get(&key) {
+                *phase
+            } else {
+                match app_data.state.stage(&key) {
+                    Some(Stage::Downloaded) => Phase::Seeding,
+                    Some(Stage::Kept) => Phase::Kept,
+                    Some(Stage::Downloading) => Phase::Downloading,
+                    None if queued.contains(&t.id) => Phase::Queued,
+                    None => Phase::OnPutio,
+                }
+            };
+            let targets: Vec<TargetStatus> = app_data
+                .state
+                .download_targets(&key)
+                .into_iter()
+                .filter_map(|(target, done)| {
+                    let target: DownloadTarget = serde_json::from_str(&target).ok()?;
+                    if target.target_type != TargetType::File {
+                        return None;
+                    }
+                    let file = files.get(&target.to);
+                    let downloaded = match (done, file) {
+                        (true, _) => target.size.unwrap_or(0),
+                        (false, Some(file)) => file.downloaded,
+                        (false, None) => 0,
+                    };
+                    Some(TargetStatus {
+                        size: target.size,
+                        downloaded,
+                        speed: file.map_or(0, FileProgress::speed),
+                        done,
+                        started_at: file.map(|f| f.started_at),
+                        path: target.to,
+                    })
+                })
+                .collect();
+            TransferStatus {
+                id: t.id,
+                hash: t.hash_string,
+                name: t.name,
+                phase,
+                putio_percent_done: t.percent_done,
+                putio_download_speed: t.rate_download,
+                putio_upload_speed: t.rate_upload,
+                size: t.total_size,
+                downloaded: targets.iter().map(|t| t.downloaded).sum(),
+                download_speed: targets.iter().map(|t| t.speed).sum(),
+                error: t.error_string,
+                targets,
+            }
+        })
+        .collect();
+
+    let failures = app_data
+        .state
+        .failures()
+        .into_iter()
+        .map(|(key, failure)| FailureStatus {
+            transfer: key,
+            attempts: failure.attempts,
+            failed: failure.failed,
+            category: failure.category,
+            error: failure.last_error,
+        })
+        .collect();
+
+    Ok(Status {
+        transfers,
+        failures,
+        disk_full: app_data.disk_full.load(Ordering::SeqCst),
+    })
+}
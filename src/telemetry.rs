@@ -0,0 +1,47 @@
+// Exports spans over OTLP, so tracing backends like Jaeger and Tempo show where the time of a
+// transfer goes: put.io API calls, file downloads or waiting for the arrs to import.
+use crate::OtlpConfig;
+use anyhow::Result;
+use opentelemetry::{trace::TracerProvider, KeyValue};
+use opentelemetry_otlp::{SpanExporter, WithExportConfig};
+use opentelemetry_sdk::{trace::SdkTracerProvider, Resource};
+use std::sync::OnceLock;
+use tracing::Subscriber;
+use tracing_subscriber::{registry::LookupSpan, Layer};
+
+static PROVIDER: OnceLock<SdkTracerProvider> = OnceLock::new();
+
+/// A layer that exports spans to the configured OTLP endpoint.
+pub fn layer<S>(config: &OtlpConfig, instance_name: Option<&str>) -> Result<impl Layer<S>>
+where
+    S: Subscriber + for<'span> LookupSpan<'span>,
+{
+    let exporter = SpanExporter::builder()
+        .with_http()
+        .with_endpoint(&config.endpoint)
+        .build()?;
+    let mut resource = Resource::builder().with_service_name(config.service_name.clone());
+    if let Some(instance_name) = instance_name {
+        resource = resource.with_attribute(KeyValue::new(
+            "service.instance.id",
+            instance_name.to_string(),
+        ));
+    }
+    let provider = SdkTracerProvider::builder()
+        .with_batch_exporter(exporter)
+        .with_resource(resource.build())
+        .build();
+    let tracer = provider.tracer("putioarr");
+    // Only set up once per process
+    let _ = PROVIDER.set(provider);
+    Ok(tracing_opentelemetry::layer().with_tracer(tracer))
+}
+
+/// Exports the spans that haven't been yet. Spans that end later are lost.
+pub fn shutdown() {
+    if let Some(provider) = PROVIDER.get() {
+        if let Err(e) = provider.shutdown() {
+            eprintln!("Unable to export the remaining spans: {}", e);
+        }
+    }
+}
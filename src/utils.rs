@@ -1,12 +1,32 @@
-use anyhow::Result;
-use serde::Serialize;
-use std::{fs, io::Write, path::Path, time::Duration};
+use actix_web::{web, App, HttpResponse, HttpServer};
+use anyhow::{anyhow, bail, Context as _, Result};
+use argon2::{Argon2, PasswordHash, PasswordHasher, PasswordVerifier};
+use file_owner::PathExt;
+use log::info;
+use password_hash::{
+    rand_core::{OsRng, RngCore},
+    SaltString,
+};
+use serde::{Deserialize, Serialize};
+use sha1::{Digest, Sha1};
+use std::{
+    collections::HashMap,
+    fs,
+    io::Write,
+    os::unix::fs::{MetadataExt, PermissionsExt},
+    path::Path,
+    process::{Command, Stdio},
+    sync::Mutex,
+    time::Duration,
+};
 use tinytemplate::TinyTemplate;
 use tokio::time::sleep;
+use toml_edit::{value, DocumentMut};
 
-use crate::services;
+use crate::{services, AppData};
 
-static TEMPLATE: &str = r#"# Required. Username and password that sonarr/radarr use to connect to the proxy
+static TEMPLATE: &str = r#"# Required. Username and password that sonarr/radarr use to connect to the proxy. The password can also be an
+# argon2 hash, as printed by `putioarr hash-password`, so the config doesn't hold it in plain text.
 username = "myusername"
 password = "mypassword"
 
@@ -14,24 +34,94 @@ password = "mypassword"
 # sonarr/radarr in order to import downloads
 download_directory = "/path/to/downloads"
 
+# Optional. Directory transfers are downloaded into before they are moved into
+# download_directory, once all their files are there. Has to be on the same filesystem.
+# incomplete_directory = "/path/to/incomplete"
+
+# Optional. Where sonarr/radarr see download_directory, when they run on another host or in a
+# container that mounts it elsewhere. Paths are reported to them below this directory instead, so
+# they need no remote path mapping. Can be set per [[arr]] instance as well.
+# reported_download_directory = "/downloads"
+
 # Optional bind address, default "0.0.0.0"
 bind_address = "0.0.0.0"
 
 # Optional TCP port, default 9091
 port = 9091
 
-# Optional log level, default "info"
+# Optional list of addresses to listen on, including the port. Overrides bind_address and port.
+# Use this to listen on both IPv4 and IPv6, e.g. ["0.0.0.0:9091", "[::]:9091"].
+# bind_addresses = ["0.0.0.0:9091", "[::]:9091"]
+
+# Optional, default "transmission". The download client API sonarr/radarr/whisparr connect to:
+# "transmission" or "qbittorrent". With "qbittorrent", add putioarr as a qBittorrent client with the
+# same username and password. Not supported in mode "frontend".
+# client_api = "qbittorrent"
+
+# Optional API key for a SABnzbd compatible API at /api, so putioarr can be added to
+# sonarr/radarr/whisparr as a SABnzbd download client. put.io fetches the URLs added through it;
+# NZB files are rejected. Unset by default, which disables the API. Not supported in mode "frontend".
+# sabnzbd_api_key = "a-long-random-key"
+
+# Optional, default true. Compress responses (gzip, brotli, zstd) for clients that accept it.
+compression = true
+
+# Optional origins that browser-based clients, like a Transmission web UI hosted elsewhere, may
+# call putioarr from. Use ["*"] to allow any origin. Default [], which disables CORS.
+# cors_origins = ["https://dashboard.example.com"]
+
+# Optional, default true. Accept HTTP/2 over plain TCP (h2c) next to HTTP/1.1.
+http2 = true
+
+# Optional, default 16. Maximum size in MB of a request body. Large .torrent files are sent base64
+# encoded by sonarr/radarr and can exceed smaller limits.
+json_payload_limit_mb = 16
+
+# Optional, default 10. Requests per second a single client may make on average, with bursts of up to
+# rate_limit_burst requests. Clients behind trusted_proxies are told apart by their forwarded
+# address. Set to 0 to disable rate limiting.
+rate_limit_per_second = 10
+rate_limit_burst = 50
+
+# Optional log level, default "info". Also takes filter directives, like
+# "info,[transfer{hash=abcd1234}]=debug" for everything about a single transfer. RUST_LOG, when
+# set, takes precedence.
 loglevel = "info"
 
+# Optional log format, default "text". "json" writes a JSON object per line, with the transfer,
+# target and worker each line belongs to, for log shippers like Loki.
+log_format = "text"
+
 # Optional UID, default 1000. Change the owner of the downloaded files to this UID. Requires root.
 uid = 1000
 
+# Optional GID. Change the group of the downloaded files to this GID as well. Requires root.
+# gid = 1000
+
 # Optional polling interval in secs, default 10.
 polling_interval = 10
 
 # Optional skip directories when downloading, default ["sample", "extras"]
 skip_directories = ["sample", "extras"]
 
+# Optional files that are never downloaded, matched case-insensitively against file names. A
+# leading * matches any prefix. Set to [] to download everything.
+# Default ["*.exe", "*.lnk", "*.url", "*.scr", "*.bat", "rarbg.txt", "rarbg_do_not_mirror.exe"]
+# junk_files = ["*.exe", "*.lnk", "*.url", "*.scr", "*.bat", "rarbg.txt", "rarbg_do_not_mirror.exe"]
+
+# Optional, default 0. Skip videos smaller than this many MB, like samples outside a sample folder.
+# min_file_size_mb = 0
+
+# Optional, default ["video", "subtitle"]. Files to download: "video", "audio", "image", "archive",
+# "pdf" and "text" go by the file type put.io gives them, "subtitle" takes .srt, .sub, .idx, .ass,
+# .ssa, .vtt and .sup files, "ebook" takes .epub, .mobi, .azw, .azw3, .pdf, .cbz, .cbr and .fb2
+# files, and entries like ".nfo" take files with that extension. Use ["audio"] for lidarr.
+# download_file_types = ["video", "subtitle"]
+
+# Optional, default "none". Normalize the unicode in names of downloaded files to "nfc" or "nfd",
+# so names with accents match what macOS or an SMB share reports and imports don't fail.
+# unicode_normalization = "nfc"
+
 # Optional number of orchestration workers, default 10. Unless there are many changes coming from
 # put.io, you shouldn't have to touch this number. 10 is already overkill.
 orchestration_workers = 10
@@ -39,9 +129,264 @@ orchestration_workers = 10
 # Optional number of download workers, default 4. This controls how many downloads we run in parallel.
 download_workers = 4
 
+# Optional download speed limit in KB/s for all downloads together, so putioarr leaves room for
+# streaming and other traffic. max_worker_download_rate_kbps limits each download worker on its own.
+# Unset by default, which means no limit.
+# max_download_rate_kbps = 20000
+# max_worker_download_rate_kbps = 5000
+
+# Optional number of seconds without receiving data after which a download is considered stalled,
+# default 300 (0 disables the check). Stalled downloads start over with a fresh URL, up to
+# download_stall_retries (default 3) times, before the transfer is reported as stalled.
+# download_stall_seconds = 300
+# download_stall_retries = 3
+
+# Optional number of seconds to wait for a download connection, default 30. Downloads have no
+# overall timeout, only this one and download_stall_seconds; put.io API calls time out after 10s.
+# download_connect_timeout_seconds = 30
+
+# Optional, default "files". With "zip", transfers with more than one file are downloaded as a
+# single zip put.io creates of them, which is a lot faster for many small files. The zip takes up
+# space in download_directory until its files are extracted.
+# download_mode = "files"
+
+# Optional, default false. Extract downloaded RAR and ZIP archives next to them, for releases the
+# arrs can't import as they are. The archives themselves aren't waited on to be imported. RAR
+# archives need the unrar command in PATH.
+# extract_archives = false
+
+# Optional range to autoscale download workers in. With download_workers_max set, download_workers
+# is the starting count, and a worker is added while files wait for download and the previous one
+# made downloads faster, or retired while nothing waits.
+# download_workers_min = 1
+# download_workers_max = 8
+
+# Optional, default false. Download transfers whose folder holds a single video straight into
+# download_directory, without the folder, for path mappings that handle loose files better.
+# flatten_single_file = false
+
+# Optional, default false. Write a .putioarr.json file into completed downloads (or next to single
+# files) recording the transfer hash, put.io ids and tracker host, for reconciling them later.
+# provenance = false
+
+# Optional, default false. Serve download_directory at /files/ with the same credentials as the
+# RPC endpoint, for importing on a host that doesn't share storage with putioarr.
+# serve_downloads = false
+
+# Optional, default false. Save transfers into putioarr/.categories/<category> on put.io, so put.io
+# records which arr or category added them. Transfers added to the putioarr folder by hand then
+# never get an arr's category, and categories survive losing the state database.
+# category_folders = true
+
+# Optional, default false. Download transfers into a subdirectory of download_directory named
+# after their category, like /downloads/tv-sonarr, so each arr imports from its own directory.
+# Together with category_folders, put.io and the download directory are organized the same way.
+# category_directories = true
+
+# Optional directory to keep symlinks to completed downloads in, as <category>/<date>/<name>, for
+# browsing them with other tools. Links to downloads that were imported and moved are removed.
+# symlink_views = "/downloads/views"
+
+# Optional number of times a transfer is downloaded before giving up on it, default 3. Failed
+# transfers are reported to the client with an error and are not retried until removed.
+# max_attempts = 3
+
+# Optional number of times a transfer that put.io fails to fetch, like a dead magnet, is removed
+# and added again before the error is reported to the client, default 0 (never).
+# readd_attempts = 0
+
+# Optional directory to keep a copy of every added torrent and magnet link in, named after the
+# info hash. Copies older than torrent_archive_days (default 30, 0 keeps them forever) are pruned.
+# torrent_archive = "/config/torrents"
+# torrent_archive_days = 30
+
+# Optional thresholds for the download backlog. A warning and a `backlog` notification are sent
+# when more than backlog_max_depth transfers wait for download, or one has been waiting longer
+# than backlog_max_age_minutes. Default 0, which disables the check.
+# backlog_max_depth = 0
+# backlog_max_age_minutes = 0
+
+# Optional, default false. Set the setgid bit on directories we create, so downloaded files inherit
+# the directory's group. Useful when running without root, since changing the owner to uid requires it.
+setgid_directories = false
+
+# Optional umask for the files and directories we create, and mode for the directories we create,
+# for when the umask a container inherits leaves directories sonarr/radarr can't traverse. Use
+# TOML's octal notation. By default the inherited umask applies.
+# umask = 0o002
+# dir_mode = 0o775
+
+# Optional mode of the downloaded files, e.g. 0o664. Once a transfer is downloaded, its files get
+# file_mode and its directories dir_mode, along with uid and gid, before sonarr/radarr are told it
+# is done, so they don't depend on the umask or on what was kept from an extracted archive.
+# file_mode = 0o664
+
+# Optional, default 1. Refuse new transfers when less than this many GB is available on put.io.
+putio_min_free_space_gb = 1
+
+# Optional URL that receives a JSON POST (\{"event": ..., "message": ...}) when something needs
+# attention, like put.io running out of space.
+# notification_url = "https://example.com/webhook"
+
+# Optional completion reports. Once a transfer is cleaned up or fails for good, a JSON report with
+# its size, the time spent in each stage, the average download speed, the number of attempts and
+# the outcome is POSTed to report_url, appended as a line to report_file, and with report_history
+# kept in the state database for /api/history (the latest 1000).
+# report_url = "https://example.com/reports"
+# report_file = "/config/reports.jsonl"
+# report_history = false
+
+# Optional, default false. Set when download_directory is an NFS or SMB mount: downloads are then
+# written in large chunks and synced once when done, and operations failing with ESTALE or EBUSY
+# are retried.
+# network_filesystem = false
+
+# Optional, default 1. Pause downloads, and stop starting new transfers, while less than this many
+# GB is free in download_directory. They resume once there is enough again.
+min_free_space_gb = 1
+
+# Optional, default 1. GB to leave free in download_directory once a transfer is downloaded. A
+# transfer that doesn't fit, counting what the transfers being downloaded still need, is held back
+# and shown to the arrs as queued until enough space frees up.
+# free_space_reserve_gb = 1
+
+# Optional, default false. Also count a downloaded transfer as imported once its files were moved out
+# of download_directory or hardlinked elsewhere, as the arrs do when they import. For transfers
+# without an [[arr]] instance, or whose instance can't be reached. Copied files aren't noticed.
+# detect_imports_on_disk = false
+
+# Optional, default "keep". What happens to the downloaded files of a transfer once it was
+# imported: "keep" leaves them, "delete" deletes them right away and "delete_after_seeding" once
+# put.io stopped seeding, which suits arrs that hardlink into their library. Files are only deleted
+# after the import was confirmed through an [[arr]] instance or detect_imports_on_disk, and not by
+# run-once.
+# cleanup_mode = "keep"
+
+# Optional. Stop seeding on put.io once a transfer reached this upload ratio, or seeded for this
+# many minutes, instead of waiting for put.io to stop on its own. seed_ratio_limit is also what
+# the arrs are told the download client's ratio limit is.
+# seed_ratio_limit = 1.0
+# seed_time_limit_minutes = 1440
+
+# Optional, default false. Leave the files of a transfer on put.io once it is done seeding, e.g.
+# to keep streaming them from put.io, and with keep_remote_transfers the transfer itself as well.
+# Kept transfers aren't downloaded again, also after a restart.
+# keep_remote_files = false
+# keep_remote_transfers = false
+
+# Optional. Move the files of transfers that are done into putioarr/putioarr-trash on put.io
+# instead of deleting them, and delete them from there after this many days.
+# remote_trash_days = 7
+
+# Optional path of the database holding state that survives restarts, like cumulative statistics.
+# Defaults to putioarr.db next to this config file.
+# state_file = "/path/to/putioarr.db"
+
+# Optional reverse proxies, as addresses or CIDR networks, that are trusted to authenticate users
+# and to report the client address through X-Forwarded-For or X-Real-IP.
+# trusted_proxies = ["127.0.0.1", "172.16.0.0/12"]
+
+# Optional header that a trusted proxy sets to the authenticated user, e.g. "X-Forwarded-User"
+# with Authelia or authentik. Requests from trusted_proxies carrying it skip the username and
+# password check.
+# auth_header = "X-Forwarded-User"
+
+# Optional, default true. Hold a lock on the putioarr folder on put.io, so a second instance
+# pointed at the same account refuses to start instead of racing this one for the same transfers.
+# instance_lock = true
+
+# Optional, default false. Run as a standby for another instance: wait until its lock expires or is
+# released, then take over. Instances may share state_file, but each needs its own instance_name.
+# A crashed instance's lock expires after 5 minutes. An instance that loses its lock, e.g. when cut
+# off from put.io for longer than that, stops its downloads and cleanups and stands by until it can
+# take over again. While standing by, the RPC, web UI and metrics keep answering, but requests that
+# change transfers are refused.
+# standby = false
+# instance_name = "nas-1"
+
+# Optional, default "all". With "frontend", this instance only serves the Transmission RPC and
+# forwards it to the downloader at downloader_url, so it can run next to sonarr/radarr while a
+# "downloader" instance does the downloading on the host with the storage. Both need the same
+# internal_api_key.
+# mode = "all"
+# downloader_url = "http://nas:9091"
+# internal_api_key = "a long random string"
+
+# Optional rules that rewrite the names of downloaded transfers, applied in order to the top-level
+# file or folder, both on disk and in the names reported to sonarr/radarr/whisparr. Patterns are
+# regular expressions, replacements may refer to groups as $1. Tables like these have to come
+# after all other top-level options.
+# [[rename]]
+# pattern = "^\\[[^\\]]*\\]\\s*"  # Drop a leading [Group] tag
+# replacement = ""
+
+# Optional rules like the ones above, applied to the names of individual downloaded files instead.
+# The name a file gets is remembered, so changing the rules doesn't rename files of transfers
+# that are already downloading.
+# [[rename_files]]
+# pattern = "\\.!ut$"  # Drop uTorrent's incomplete suffix
+# replacement = ""
+
+# Optional schedule of download speed limits, after all other top-level options. Outside of its
+# windows max_download_rate_kbps applies. Each window runs from `from` to `to` (HH:MM, local time)
+# on the given days (mon to sun, every day when left out), past midnight when `to` is before
+# `from`. The first window that matches wins, and a rate_kbps of 0 means no limit.
+# [[speed_schedule]]
+# days = ["mon", "tue", "wed", "thu", "fri"]
+# from = "08:00"
+# to = "23:00"
+# rate_kbps = 5000
+
+# Optional sonarr/radarr/lidarr/readarr instances to wait for, after all other top-level options. A downloaded
+# transfer stays on put.io until the instance of its category imported every file, according to
+# its history. Transfers go to the instance whose category matches the one they were added with,
+# or else to the instance without a category. Without instances, transfers are cleaned up once
+# put.io stops seeding them.
+# [[arr]]
+# name = "sonarr"
+# url = "http://sonarr:8989"
+# api_key = "MYSONARRAPIKEY"
+# category = "tv-sonarr"
+# Optional, default "sonarr". One of "sonarr" (v3 and v4), "radarr" (v3 to v5), "whisparr", "lidarr"
+# or "readarr". Videos are waited on to be imported by sonarr, radarr and whisparr, audio by lidarr,
+# and ebooks and audiobooks by readarr.
+# kind = "sonarr"
+# Optional. Overrides reported_download_directory for transfers of this instance.
+# reported_download_directory = "/data/downloads"
+
+# Optional filter overrides per category, for when what counts as junk differs between e.g. TV and
+# music. The category is the label a client adds a torrent with, or the subdirectory of
+# download_directory it asks to download to. Options left out fall back to the ones above.
+# [filters.tv-sonarr]
+# skip_directories = ["sample", "extras", "featurettes"]
+# junk_files = ["*.exe", "*.nfo"]
+# min_file_size_mb = 50
+# download_file_types = ["video", "subtitle", ".nfo"]
+
+# Optional OpenSubtitles account to fetch subtitles with, for when you don't run Bazarr. Subtitles
+# in each of the languages are saved next to downloaded videos as e.g. Movie.en.srt, before the
+# transfer is reported as done. Create an API consumer at https://www.opensubtitles.com/consumers.
+# [opensubtitles]
+# api_key = "MYOPENSUBTITLESAPIKEY"
+# username = "myusername"
+# password = "mypassword"
+# languages = ["en"]
+
+# Optional. Exports the spans of every transfer, the put.io API calls and the arr history checks
+# over OTLP/HTTP to a tracing backend like Jaeger or Tempo, to see where the time goes.
+# service_name defaults to "putioarr".
+# [otlp]
+# endpoint = "http://localhost:4318/v1/traces"
+# service_name = "putioarr"
+
 [putio]
 # Required. Putio API key. You can generate one using `putioarr get-token`
 api_key =  "{putio_api_key}"
+
+# Optional. External URL of putioarr's /webhooks/putio, which put.io calls when a transfer added
+# by a magnet or URL finished, so it's downloaded right away instead of after the next poll.
+# put.io has to be able to reach it. A token derived from api_key is added to it.
+# callback_url = "https://putioarr.example.com/webhooks/putio"
 "#;
 
 #[derive(Serialize)]
@@ -49,21 +394,109 @@ struct Context {
     putio_api_key: String,
 }
 
-pub async fn generate_config(config_path: &str) -> Result<()> {
-    // ensure config_path directory exists, if not, create it
-    if !Path::new(&config_path).exists() {
-        fs::create_dir_all(config_path)?;
+/// The settings of a Transmission daemon's settings.json that carry over to putioarr.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+struct TransmissionSettings {
+    rpc_port: Option<u16>,
+    rpc_bind_address: Option<String>,
+    rpc_username: Option<String>,
+    /// Plain text, or salted SHA-1 once the daemon has started with it.
+    rpc_password: Option<String>,
+    download_dir: Option<String>,
+    /// A number before Transmission 4, an octal string since.
+    umask: Option<serde_json::Value>,
+    /// KB/s, applied when speed_limit_down_enabled is set.
+    speed_limit_down: Option<u64>,
+    #[serde(default)]
+    speed_limit_down_enabled: bool,
+    #[serde(default)]
+    speed_limit_up_enabled: bool,
+    #[serde(default)]
+    alt_speed_enabled: bool,
+}
+
+fn read_transmission_settings(path: &str) -> Result<TransmissionSettings> {
+    let settings = fs::read_to_string(path).with_context(|| format!("Unable to read {}", path))?;
+    serde_json::from_str(&settings)
+        .with_context(|| format!("Unable to parse {} as Transmission settings", path))
+}
+
+/// Sets the options of a rendered config that Transmission has an equivalent for.
+fn apply_transmission_settings(config: &str, settings: &TransmissionSettings) -> Result<String> {
+    let mut config = config.parse::<DocumentMut>()?;
+    if let Some(port) = settings.rpc_port {
+        config["port"] = value(i64::from(port));
+    }
+    if let Some(address) = &settings.rpc_bind_address {
+        config["bind_address"] = value(address);
+    }
+    if let Some(username) = settings.rpc_username.as_deref().filter(|u| !u.is_empty()) {
+        config["username"] = value(username);
+    }
+    if let Some(password) = settings.rpc_password.as_deref().filter(|p| !p.is_empty()) {
+        config["password"] = match transmission_password_hash(password) {
+            Some((digest, salt)) => value(format!("{TRANSMISSION_SHA1}{salt}${digest}")),
+            None => value(password),
+        };
+    }
+    if let Some(dir) = &settings.download_dir {
+        config["download_directory"] = value(dir);
+    }
+    let umask = match &settings.umask {
+        Some(serde_json::Value::Number(umask)) => umask.as_u64(),
+        Some(serde_json::Value::String(umask)) => u64::from_str_radix(umask, 8).ok(),
+        _ => None,
+    };
+    if let Some(umask) = umask {
+        // Written in octal, like umasks usually are
+        let octal = format!("umask = 0o{:03o}", umask).parse::<DocumentMut>()?;
+        config["umask"] = octal["umask"].clone();
+    }
+    if let Some(limit) = settings
+        .speed_limit_down
+        .filter(|_| settings.speed_limit_down_enabled)
+    {
+        config["max_download_rate_kbps"] = value(limit as i64);
+    }
+    for (enabled, setting) in [
+        (settings.speed_limit_up_enabled, "speed-limit-up"),
+        (settings.alt_speed_enabled, "alt-speed"),
+    ] {
+        if enabled {
+            eprintln!("putioarr has no equivalent of {}, leaving it out", setting);
+        }
+    }
+    Ok(config.to_string())
+}
+
+/// Writes a config with a fresh put.io token. With from_transmission, the port, credentials,
+/// download directory, umask and download speed limit are taken from a Transmission daemon's
+/// settings.json.
+pub async fn generate_config(config_path: &str, from_transmission: Option<&str>) -> Result<()> {
+    // Read the settings first, so a wrong path doesn't waste linking a token
+    let transmission = from_transmission
+        .map(read_transmission_settings)
+        .transpose()?;
+
+    // ensure the directory of config_path exists, if not, create it
+    if let Some(dir) = Path::new(&config_path).parent() {
+        fs::create_dir_all(dir)?;
     }
 
     println!("Generating config {}", &config_path);
-    let putio_api_key = get_token().await?;
+    let putio_api_key = get_token(None).await?;
+    println!("Put.io API token: {putio_api_key}");
 
     let mut tt = TinyTemplate::new();
     tt.add_template("config", TEMPLATE)?;
 
     let context = Context { putio_api_key };
 
-    let rendered = tt.render("config", &context)?;
+    let mut rendered = tt.render("config", &context)?;
+    if let Some(settings) = &transmission {
+        rendered = apply_transmission_settings(&rendered, settings)?;
+    }
 
     if Path::new(&config_path).exists() {
         println!("Backing up config {}", &config_path);
@@ -78,32 +511,326 @@ pub async fn generate_config(config_path: &str) -> Result<()> {
     Ok(())
 }
 
-pub async fn get_token() -> Result<String> {
-    println!();
+/// Obtains a put.io API token by having the user link a code on put.io, giving up after timeout.
+/// Instructions go to stderr, so the caller's output on stdout stays machine readable.
+pub async fn get_token(timeout: Option<Duration>) -> Result<String> {
+    eprintln!();
     // Create new OOB code and prompt user to link
-    let oob_code = services::putio::get_oob().await.expect("fetching OOB code");
-    println!(
+    let oob_code = services::putio::get_oob().await?;
+    eprintln!(
         "Go to https://put.io/link and enter the code: {:#?}",
         oob_code
     );
-    println!("Waiting for token...");
+    eprintln!("Waiting for token...");
 
     // Every three seconds, check if the OOB code was linked to the user's account
     let three_seconds = Duration::from_secs(3);
+    let wait = async {
+        loop {
+            sleep(three_seconds).await;
 
-    loop {
-        sleep(three_seconds).await;
+            let get_oauth_token_result = services::putio::check_oob(oob_code.clone()).await;
+
+            match get_oauth_token_result {
+                Ok(token) => return token,
+                Err(_error) => {
+                    continue;
+                }
+            };
+        }
+    };
+    match timeout {
+        Some(timeout) => tokio::time::timeout(timeout, wait)
+            .await
+            .map_err(|_| anyhow!("Timed out waiting for the code to be linked")),
+        None => Ok(wait.await),
+    }
+}
 
-        let get_oauth_token_result = services::putio::check_oob(oob_code.clone()).await;
+/// Sets the put.io api key in an existing config file, leaving the rest of it as it is.
+pub fn write_token(config_path: &str, token: &str) -> Result<()> {
+    let mut config = fs::read_to_string(config_path)
+        .with_context(|| format!("Unable to read {}", config_path))?
+        .parse::<DocumentMut>()
+        .with_context(|| format!("Unable to parse {}", config_path))?;
+    config["putio"]["api_key"] = value(token);
+    fs::write(config_path, config.to_string())
+        .with_context(|| format!("Unable to write {}", config_path))
+}
 
-        match get_oauth_token_result {
-            Ok(token) => {
-                println!("Put.io API token: {token}");
-                return Ok(token);
-            }
-            Err(_error) => {
-                continue;
-            }
-        };
+/// Page put.io redirects to. The token is in the URL fragment, which only the browser sees, so the
+/// page passes it on as a query string.
+const OAUTH_CALLBACK_PAGE: &str = r#"<!DOCTYPE html>
+<html><body><script>
+location.replace("/token?" + location.hash.substring(1));
+</script></body></html>
+"#;
+
+/// Obtains a put.io API token through put.io's login page in the browser, catching the redirect
+/// back on a temporary listener on localhost. The put.io app has to allow
+/// `http://localhost:<port>/callback` as its callback URL.
+pub async fn get_token_browser(
+    client_id: u64,
+    port: u16,
+    timeout: Option<Duration>,
+) -> Result<String> {
+    let state = format!("{:016x}{:016x}", OsRng.next_u64(), OsRng.next_u64());
+    let redirect_uri = format!("http://localhost:{}/callback", port);
+    let url = reqwest::Url::parse_with_params(
+        "https://api.put.io/v2/oauth2/authenticate",
+        [
+            ("client_id", client_id.to_string()),
+            ("response_type", String::from("token")),
+            ("redirect_uri", redirect_uri),
+            ("state", state.clone()),
+        ],
+    )?;
+
+    let (tx, rx) = async_channel::bounded::<String>(1);
+    let server = HttpServer::new(move || {
+        let (tx, state) = (tx.clone(), state.clone());
+        App::new()
+            .route(
+                "/callback",
+                web::get().to(|| async {
+                    HttpResponse::Ok()
+                        .content_type("text/html")
+                        .body(OAUTH_CALLBACK_PAGE)
+                }),
+            )
+            .route(
+                "/token",
+                web::get().to(move |query: web::Query<HashMap<String, String>>| {
+                    let (tx, state) = (tx.clone(), state.clone());
+                    async move {
+                        if query.get("state") != Some(&state) {
+                            return HttpResponse::BadRequest().body("Unexpected OAuth state");
+                        }
+                        let Some(token) = query.get("access_token") else {
+                            return HttpResponse::BadRequest().body("No token received");
+                        };
+                        let _ = tx.try_send(token.clone());
+                        HttpResponse::Ok()
+                            .body("putioarr received the token, you can close this window.")
+                    }
+                }),
+            )
+    })
+    .workers(1)
+    .bind(("127.0.0.1", port))
+    .with_context(|| format!("Unable to listen on port {}", port))?
+    .run();
+    let handle = server.handle();
+    actix_rt::spawn(server);
+
+    eprintln!("Opening {} in the browser...", url);
+    if let Err(e) = open_browser(url.as_str()) {
+        eprintln!(
+            "Unable to open the browser ({}), open the address above yourself",
+            e
+        );
+    }
+    let token = match timeout {
+        Some(timeout) => tokio::time::timeout(timeout, rx.recv())
+            .await
+            .map_err(|_| anyhow!("Timed out waiting for the login in the browser"))?,
+        None => rx.recv().await,
+    };
+    handle.stop(true).await;
+    Ok(token?)
+}
+
+fn open_browser(url: &str) -> std::io::Result<()> {
+    let opener = if cfg!(target_os = "macos") {
+        "open"
+    } else {
+        "xdg-open"
+    };
+    Command::new(opener)
+        .arg(url)
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+        .map(|_| ())
+}
+
+/// Prompts for a password and prints its argon2 hash, which can be used as password in the config.
+pub fn hash_password() -> Result<()> {
+    let password = rpassword::prompt_password("Password: ")?;
+    if password != rpassword::prompt_password("Repeat password: ")? {
+        bail!("Passwords don't match");
+    }
+    let salt = SaltString::generate(&mut OsRng);
+    let hash = Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .map_err(|e| anyhow!("Unable to hash password: {}", e))?;
+    println!("{hash}");
+    Ok(())
+}
+
+/// Prefix of a Transmission rpc-password hash in our config, followed by its salt and digest.
+const TRANSMISSION_SHA1: &str = "$transmission-sha1$";
+
+/// Splits rpc-password into digest and salt when it's a hash, as Transmission writes it once the
+/// daemon has started: `{`, 40 lowercase hex digits of SHA-1 and an 8 character salt.
+fn transmission_password_hash(password: &str) -> Option<(&str, &str)> {
+    let hash = password.strip_prefix('{')?;
+    if hash.len() != 48 || !hash.is_char_boundary(40) {
+        return None;
+    }
+    let (digest, salt) = hash.split_at(40);
+    digest
+        .bytes()
+        .all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f'))
+        .then_some((digest, salt))
+}
+
+/// Checks a password against the configured one, which is an argon2 hash, a Transmission hash taken
+/// over by generate-config or plain text.
+pub fn verify_password(configured: &str, password: &str) -> bool {
+    if configured.starts_with("$argon2") {
+        if let Ok(hash) = PasswordHash::new(configured) {
+            return Argon2::default()
+                .verify_password(password.as_bytes(), &hash)
+                .is_ok();
+        }
     }
+    // Salted SHA-1 as Transmission keeps rpc-password, for configs imported from its settings
+    if let Some((salt, digest)) = configured
+        .strip_prefix(TRANSMISSION_SHA1)
+        .and_then(|hash| hash.split_once('$'))
+    {
+        let computed = Sha1::new()
+            .chain_update(password)
+            .chain_update(salt)
+            .finalize();
+        return format!("{:x}", computed) == digest;
+    }
+    configured == password
+}
+
+/// Remembers the password that last verified against an argon2 hash, so requests after the first
+/// one don't each pay for a full argon2 verify.
+pub struct PasswordCache {
+    /// Key of the digests, so the cached one is of no use outside this process.
+    key: [u8; 32],
+    accepted: Mutex<Option<Vec<u8>>>,
+}
+
+impl Default for PasswordCache {
+    fn default() -> Self {
+        let mut key = [0; 32];
+        OsRng.fill_bytes(&mut key);
+        Self {
+            key,
+            accepted: Default::default(),
+        }
+    }
+}
+
+impl PasswordCache {
+    /// Checks a password like verify_password does, verifying argon2 hashes off the worker thread
+    /// unless the password is the one that verified last.
+    pub async fn verify(&self, configured: &str, password: &str) -> bool {
+        if !configured.starts_with("$argon2") {
+            return verify_password(configured, password);
+        }
+        let digest = Sha1::new()
+            .chain_update(self.key)
+            .chain_update(password)
+            .finalize()
+            .to_vec();
+        if self.accepted.lock().unwrap().as_ref() == Some(&digest) {
+            return true;
+        }
+        let (configured, password) = (configured.to_string(), password.to_string());
+        let verified = web::block(move || verify_password(&configured, &password))
+            .await
+            .unwrap_or(false);
+        if verified {
+            *self.accepted.lock().unwrap() = Some(digest);
+        }
+        verified
+    }
+}
+
+/// Creates the download directory, and incomplete_directory when set, if they don't exist yet and
+/// makes sure we can write to them.
+pub fn prepare_download_directory(app_data: &AppData) -> Result<()> {
+    let download_directory = &app_data.config.download_directory;
+    prepare_directory(app_data, download_directory, "Download directory")?;
+    if let Some(incomplete_directory) = &app_data.config.incomplete_directory {
+        prepare_directory(app_data, incomplete_directory, "Incomplete directory")?;
+        // Transfers are moved out of it with a rename
+        if fs::metadata(incomplete_directory)?.dev() != fs::metadata(download_directory)?.dev() {
+            bail!(
+                "Incomplete directory {} is not on the same filesystem as download directory {}",
+                incomplete_directory,
+                download_directory
+            );
+        }
+    }
+    Ok(())
+}
+
+fn prepare_directory(app_data: &AppData, directory: &str, name: &str) -> Result<()> {
+    let path = Path::new(directory);
+    if !path.exists() {
+        fs::create_dir_all(path)
+            .with_context(|| format!("Unable to create {} {}", name.to_lowercase(), directory))?;
+        prepare_created_directory(app_data, path)?;
+        info!("Created {} {}", name.to_lowercase(), directory);
+    }
+
+    if !path.is_dir() {
+        bail!("{} {} is not a directory", name, directory);
+    }
+
+    let probe = path.join(".putioarr-write-test");
+    fs::File::create(&probe)
+        .and_then(|_| fs::remove_file(&probe))
+        .with_context(|| format!("{} {} is not writable", name, directory))?;
+
+    Ok(())
+}
+
+/// Applies the configured ownership and directory permissions to a directory.
+pub fn prepare_created_directory(app_data: &AppData, path: &Path) -> Result<()> {
+    set_owner(app_data, path)?;
+    let config = &app_data.config;
+    if config.dir_mode.is_some() || config.setgid_directories {
+        let mut permissions = fs::metadata(path)?.permissions();
+        let mut mode = config.dir_mode.unwrap_or(permissions.mode()) & 0o7777;
+        if config.setgid_directories {
+            mode |= 0o2000;
+        }
+        permissions.set_mode(mode);
+        fs::set_permissions(path, permissions)?;
+    }
+    Ok(())
+}
+
+/// Changes the owner of a path to the configured uid and gid, if we are allowed to.
+pub fn set_owner(app_data: &AppData, path: &Path) -> Result<()> {
+    match (app_data.chown_uid, app_data.chown_gid) {
+        (Some(uid), Some(gid)) => path.set_owner_group(uid, gid)?,
+        (Some(uid), None) => path.set_owner(uid)?,
+        (None, Some(gid)) => path.set_group(gid)?,
+        (None, None) => {}
+    }
+    Ok(())
+}
+
+/// Returns the number of bytes available to us on the filesystem containing path.
+pub fn free_space(path: &str) -> Result<u64> {
+    let stat = nix::sys::statvfs::statvfs(path)
+        .with_context(|| format!("Unable to get free space of {}", path))?;
+    Ok(stat.blocks_available() as u64 * stat.fragment_size() as u64)
+}
+
+/// Returns the size in bytes of the filesystem containing path.
+pub fn total_space(path: &str) -> Result<u64> {
+    let stat = nix::sys::statvfs::statvfs(path)
+        .with_context(|| format!("Unable to get size of {}", path))?;
+    Ok(stat.blocks() as u64 * stat.fragment_size() as u64)
 }
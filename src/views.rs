@@ -0,0 +1,59 @@
+// Keeps a tree of symlinks to completed downloads, organized by category and date, so other
+// tools can browse them without touching the paths sonarr/radarr import from.
+use anyhow::Result;
+use chrono::Local;
+use log::{info, warn};
+use std::{fs, os::unix::fs::symlink, path::Path};
+
+/// Category of transfers added without one.
+const UNCATEGORIZED: &str = "uncategorized";
+
+/// Links target into dir as `<category>/<YYYY-MM-DD>/<name>`, then removes links whose target
+/// is gone, like downloads that were imported. Views are best effort, so failures are only
+/// logged.
+pub fn link(dir: &str, category: Option<&str>, target: &Path) {
+    let dir = Path::new(dir);
+    let Some(name) = target.file_name() else {
+        return;
+    };
+    let day = Local::now().format("%Y-%m-%d").to_string();
+    let parent = dir.join(category.unwrap_or(UNCATEGORIZED)).join(day);
+    let link = parent.join(name);
+    let result = fs::create_dir_all(&parent).and_then(|_| {
+        if link.symlink_metadata().is_ok() {
+            fs::remove_file(&link)?;
+        }
+        symlink(target, &link)
+    });
+    match result {
+        Ok(_) => info!("{}: linked into {}", target.display(), parent.display()),
+        Err(e) => warn!(
+            "Unable to link {} into {}: {}",
+            target.display(),
+            dir.display(),
+            e
+        ),
+    }
+    if let Err(e) = prune(dir) {
+        warn!("Unable to prune symlink views {}: {}", dir.display(), e);
+    }
+}
+
+/// Removes dangling links below dir, and the directories that leaves empty.
+fn prune(dir: &Path) -> Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        let metadata = path.symlink_metadata()?;
+        if metadata.is_symlink() {
+            if !path.exists() {
+                fs::remove_file(&path)?;
+            }
+        } else if metadata.is_dir() {
+            prune(&path)?;
+            if fs::read_dir(&path)?.next().is_none() {
+                fs::remove_dir(&path)?;
+            }
+        }
+    }
+    Ok(())
+}